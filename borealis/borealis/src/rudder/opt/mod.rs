@@ -35,7 +35,13 @@ type ModelPassFn = fn(&OptimizationContext, &mut Model) -> bool;
 type ModelPass = (&'static str, ModelPassFn);
 
 // DO NOT OPTIMIZE AWAY
-pub const INTRINSICS: &[&'static str] = &["sail_tlbi"];
+pub const INTRINSICS: &[&'static str] = &[
+    "sail_tlbi",
+    "WaitForInterrupt",
+    "WaitForEvent",
+    "SendEvent",
+    "SendEventLocal",
+];
 
 // --- MODEL PASSES --- //
 static FUNCTION_PASS_RUNNER: ModelPass = ("function-pass-runner", run_function_passes);
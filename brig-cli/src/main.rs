@@ -2,15 +2,16 @@ use {
     cargo_metadata::{Artifact, Message, TargetKind, diagnostic::DiagnosticLevel},
     clap::{Parser, Subcommand},
     common::{
-        TestConfig,
+        ReplayConfig, TestConfig,
         ringbuffer::{Consumer, MaybeSplitBuffer, RingBuffer},
     },
-    elf::{ElfBytes, endian::AnyEndian, section::SectionHeader},
+    elf::{ElfBytes, abi::STT_FUNC, endian::AnyEndian, section::SectionHeader},
     itertools::Itertools,
     ovmf_prebuilt::{Arch, FileType, Source},
     std::{
+        collections::BTreeMap,
         fs::{self, File},
-        io::{BufReader, BufWriter, Write},
+        io::{BufRead, BufReader, BufWriter, Read, Write},
         path::{Path, PathBuf},
         process::{self, Stdio},
         sync::{
@@ -19,10 +20,26 @@ use {
         },
         thread::{self},
     },
-    tar::Header,
+    tar::{Archive, Header},
     walkdir::WalkDir,
 };
 
+/// Path of the file in the guest tarfile that brig persists translations to
+const TRANSLATION_CACHE_PATH: &str = "translation_cache.bin";
+
+/// Size of the file reserved for persisted translations, which brig overwrites
+/// in place
+const TRANSLATION_CACHE_SIZE: usize = 64 * 1024 * 1024;
+
+/// File the hyperport reader writes everything sent by brig to
+const HYPERPORT_TRACE_PATH: &str = "/tmp/hyperport.trace";
+
+/// Prefix of the lines of the hyperport trace holding record/replay events
+const REPLAY_EVENT_PREFIX: &str = "replay ";
+
+/// Prefix of the lines of the hyperport trace holding profiling samples
+const PROFILE_SAMPLE_PREFIX: &str = "profile ";
+
 #[derive(Parser)]
 #[command(version, about)]
 struct Cli {
@@ -57,6 +74,23 @@ struct Cli {
     #[arg(long)]
     gdb: bool,
 
+    /// Expose the second serial port on TCP port 1235, for GDB to debug guest
+    /// cores configured with the `gdb` key
+    #[arg(long)]
+    guest_gdb: bool,
+
+    /// Discard translations persisted by previous runs
+    #[arg(long)]
+    clear_translation_cache: bool,
+
+    /// Record asynchronous guest events to the supplied log file
+    #[arg(long, conflicts_with = "replay")]
+    record: Option<PathBuf>,
+
+    /// Replay asynchronous guest events from the supplied log file
+    #[arg(long)]
+    replay: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Option<Command>,
 }
@@ -64,6 +98,24 @@ struct Cli {
 #[derive(Subcommand)]
 enum Command {
     GdbCli,
+    /// Report where guest time was spent from the profiling samples of the
+    /// last run, without building or running brig
+    Profile {
+        /// Guest kernel ELF or System.map to symbolise guest PCs with
+        symbols: PathBuf,
+
+        /// Hyperport trace containing the profiling samples
+        #[arg(long, default_value = HYPERPORT_TRACE_PATH)]
+        trace: PathBuf,
+
+        /// Print folded stacks for flamegraphs instead of a report
+        #[arg(long)]
+        folded: bool,
+
+        /// Number of functions in the report
+        #[arg(long, default_value_t = 30)]
+        top: usize,
+    },
 }
 
 fn main() -> color_eyre::Result<()> {
@@ -71,6 +123,17 @@ fn main() -> color_eyre::Result<()> {
 
     let cli = Cli::parse();
 
+    if let Some(Command::Profile {
+        symbols,
+        trace,
+        folded,
+        top,
+    }) = &cli.command
+    {
+        profile_report(trace, symbols, *folded, *top);
+        return Ok(());
+    }
+
     if cli.no_build {
         todo!(
             "use existing artifacts somehow, useful for building on one machine and running on another"
@@ -85,6 +148,13 @@ fn main() -> color_eyre::Result<()> {
         _ => panic!("include, exclude and all test CLI flags are mutually exclusive"),
     };
 
+    let replay_config = match (&cli.record, &cli.replay) {
+        (None, None) => ReplayConfig::None,
+        (Some(_), None) => ReplayConfig::Record,
+        (None, Some(log)) => ReplayConfig::Replay(fs::read_to_string(log)?),
+        _ => panic!("record and replay CLI flags are mutually exclusive"),
+    };
+
     let artifacts = build_cargo("../brig", cli.release, cli.verbose);
 
     if let Some(Command::GdbCli) = cli.command {
@@ -92,7 +162,13 @@ fn main() -> color_eyre::Result<()> {
     }
 
     // create TAR file containing guest kernel, plugins, and configuration
-    let guest_tar = build_guest_tar("./guest_data", &artifacts, test_config);
+    let guest_tar = build_guest_tar(
+        "./guest_data",
+        &artifacts,
+        test_config,
+        replay_config,
+        cli.clear_translation_cache,
+    );
 
     // create an UEFI disk image of kernel
     let kernel_path = get_kernel_from_artifacts(&artifacts);
@@ -115,7 +191,12 @@ fn main() -> color_eyre::Result<()> {
     }
 
     // start QEMU with UEFI disk image
-    run_brig(&uefi_kernel_path, &guest_tar, cli.gdb);
+    run_brig(&uefi_kernel_path, &guest_tar, cli.gdb, cli.guest_gdb);
+
+    if let Some(log) = cli.record {
+        extract_replay_log(HYPERPORT_TRACE_PATH, &log);
+        println!("recorded asynchronous events to {log:?}");
+    }
 
     Ok(())
 }
@@ -187,10 +268,14 @@ fn build_cargo<P: AsRef<Path>>(path: P, release: bool, verbose: bool) -> Vec<Art
 /// * `platform.dts` is converted to `platform.dtb`
 /// * `cdylib` artifacts are placed in the `plugins` directory of the tarfile
 ///   (in addition to any in the guest data file)
+/// * the translation cache of the previous tarfile, if any, is carried over
+///   unless `clear_translation_cache` is set
 fn build_guest_tar<P: AsRef<Path>>(
     guest_data_path: P,
     artifacts: &[Artifact],
     test_config: TestConfig,
+    replay_config: ReplayConfig,
+    clear_translation_cache: bool,
 ) -> PathBuf {
     // todo: rewrite this to process guest_data files in iterator into tar file,
     // some left alone (plugins dir, config.json), others are converted like
@@ -228,6 +313,13 @@ fn build_guest_tar<P: AsRef<Path>>(
         .unwrap();
 
     let tar_path = target_dir.canonicalize().unwrap().join("guest.tar");
+
+    let mut translation_cache = if clear_translation_cache {
+        Vec::new()
+    } else {
+        read_translation_cache(&tar_path)
+    };
+
     let mut tar = tar::Builder::new(File::create(&tar_path).unwrap());
 
     let plugins = artifacts
@@ -280,11 +372,206 @@ fn build_guest_tar<P: AsRef<Path>>(
         tar.append(&header, data.as_slice()).unwrap();
     }
 
+    {
+        let data = postcard::to_allocvec(&replay_config).unwrap();
+
+        let mut header = Header::new_gnu();
+        header.set_path("replay_config.postcard").unwrap();
+        header.set_size(u64::try_from(data.len()).unwrap());
+        header.set_cksum();
+
+        tar.append(&header, data.as_slice()).unwrap();
+    }
+
+    {
+        translation_cache.resize(TRANSLATION_CACHE_SIZE, 0);
+
+        let mut header = Header::new_gnu();
+        header.set_path(TRANSLATION_CACHE_PATH).unwrap();
+        header.set_size(u64::try_from(translation_cache.len()).unwrap());
+        header.set_cksum();
+
+        tar.append(&header, translation_cache.as_slice()).unwrap();
+    }
+
     tar.into_inner().unwrap().flush().unwrap();
 
     tar_path
 }
 
+/// Reads the translation cache file from an existing guest tarfile, empty if
+/// there is no tarfile or it does not contain one
+fn read_translation_cache(tar_path: &Path) -> Vec<u8> {
+    let Ok(file) = File::open(tar_path) else {
+        return Vec::new();
+    };
+
+    let mut archive = Archive::new(BufReader::new(file));
+
+    archive
+        .entries()
+        .unwrap()
+        .map(Result::unwrap)
+        .find(|entry| entry.path().unwrap() == Path::new(TRANSLATION_CACHE_PATH))
+        .map(|mut entry| {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).unwrap();
+            data
+        })
+        .unwrap_or_default()
+}
+
+/// Copies the record/replay events from the hyperport trace into the supplied
+/// log file
+fn extract_replay_log<P1: AsRef<Path>, P2: AsRef<Path>>(trace_path: P1, log_path: P2) {
+    let trace = BufReader::new(File::open(trace_path).unwrap());
+    let mut log = BufWriter::new(File::create(log_path).unwrap());
+
+    trace
+        .lines()
+        .map(Result::unwrap)
+        .filter(|line| line.starts_with(REPLAY_EVENT_PREFIX))
+        .for_each(|line| writeln!(log, "{line}").unwrap());
+}
+
+/// Guest execution samples of a block, summed over every export and core
+#[derive(Debug, Default, Clone, Copy)]
+struct ProfileSample {
+    executions: u64,
+    cycles: u64,
+}
+
+/// Sums the profiling samples in the hyperport trace by guest virtual PC
+fn read_profile<P: AsRef<Path>>(trace_path: P) -> BTreeMap<u64, ProfileSample> {
+    let trace = BufReader::new(File::open(trace_path).unwrap());
+    let mut samples = BTreeMap::<u64, ProfileSample>::new();
+
+    trace
+        .lines()
+        .map(Result::unwrap)
+        .filter_map(|line| {
+            let fields = line
+                .strip_prefix(PROFILE_SAMPLE_PREFIX)?
+                .split_whitespace()
+                .collect::<Vec<_>>();
+
+            let [_core_id, pc, executions, cycles] = fields.as_slice() else {
+                panic!("malformed profiling sample {line:?}");
+            };
+
+            Some((
+                u64::from_str_radix(pc.trim_start_matches("0x"), 16).unwrap(),
+                executions.parse::<u64>().unwrap(),
+                cycles.parse::<u64>().unwrap(),
+            ))
+        })
+        .for_each(|(pc, executions, cycles)| {
+            let sample = samples.entry(pc).or_default();
+            sample.executions += executions;
+            sample.cycles += cycles;
+        });
+
+    samples
+}
+
+/// Reads function symbols by start address from an ELF file or a System.map
+fn read_symbols<P: AsRef<Path>>(path: P) -> BTreeMap<u64, String> {
+    let data = fs::read(path).unwrap();
+
+    if data.starts_with(b"\x7fELF") {
+        let file = ElfBytes::<AnyEndian>::minimal_parse(&data).expect("open guest ELF");
+        let (symbols, strings) = file
+            .symbol_table()
+            .expect("symbol table should be parseable")
+            .expect("guest ELF should have a symbol table");
+
+        symbols
+            .iter()
+            .filter(|symbol| symbol.st_symtype() == STT_FUNC && symbol.st_value != 0)
+            .map(|symbol| {
+                (
+                    symbol.st_value,
+                    strings.get(symbol.st_name as usize).unwrap().to_owned(),
+                )
+            })
+            .collect()
+    } else {
+        // lines of "<address> <type> <name>", of which text symbols are kept
+        String::from_utf8(data)
+            .expect("System.map should be UTF-8")
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let address = u64::from_str_radix(fields.next()?, 16).ok()?;
+                let kind = fields.next()?;
+                let name = fields.next()?;
+
+                matches!(kind, "t" | "T" | "w" | "W").then(|| (address, name.to_owned()))
+            })
+            .collect()
+    }
+}
+
+/// Name of the function containing the supplied guest PC
+fn symbolise(symbols: &BTreeMap<u64, String>, pc: u64) -> String {
+    symbols
+        .range(..=pc)
+        .next_back()
+        .map(|(_, name)| name.clone())
+        .unwrap_or_else(|| format!("{pc:#x}"))
+}
+
+/// Prints the guest functions most cycles were spent in, or folded stacks of
+/// function and block for flamegraphs
+///
+/// Cycles spent in blocks chained to the one entered by brig's block execution
+/// loop are attributed to the entered block.
+fn profile_report<P1: AsRef<Path>, P2: AsRef<Path>>(
+    trace_path: P1,
+    symbols_path: P2,
+    folded: bool,
+    top: usize,
+) {
+    let samples = read_profile(trace_path);
+    let symbols = read_symbols(symbols_path);
+
+    if folded {
+        samples.iter().for_each(|(pc, sample)| {
+            println!("{};{pc:#x} {}", symbolise(&symbols, *pc), sample.cycles)
+        });
+        return;
+    }
+
+    let mut functions = BTreeMap::<String, ProfileSample>::new();
+    samples.iter().for_each(|(pc, sample)| {
+        let function = functions.entry(symbolise(&symbols, *pc)).or_default();
+        function.executions += sample.executions;
+        function.cycles += sample.cycles;
+    });
+
+    let total_cycles = functions.values().map(|sample| sample.cycles).sum::<u64>();
+
+    println!(
+        "{} blocks in {} functions, {total_cycles} cycles",
+        samples.len(),
+        functions.len()
+    );
+    println!("{:>7} {:>16} {:>16}  function", "", "cycles", "executions");
+
+    functions
+        .iter()
+        .sorted_by_key(|(_, sample)| std::cmp::Reverse(sample.cycles))
+        .take(top)
+        .for_each(|(name, sample)| {
+            println!(
+                "{:>6.2}% {:>16} {:>16}  {name}",
+                sample.cycles as f64 * 100.0 / total_cycles.max(1) as f64,
+                sample.cycles,
+                sample.executions
+            )
+        });
+}
+
 /// dtc -I dts -O dtb -o $@ $<
 fn build_dtb<P0: AsRef<Path>, P1: AsRef<Path>, P2: AsRef<Path>>(
     guest_data_path: P0,
@@ -323,7 +610,7 @@ fn build_dtb<P0: AsRef<Path>, P1: AsRef<Path>, P2: AsRef<Path>>(
     (dtb_source_path, dtb_destination_path)
 }
 
-fn run_brig(kernel_path: &Path, guest_tar_path: &Path, gdb: bool) {
+fn run_brig(kernel_path: &Path, guest_tar_path: &Path, gdb: bool, guest_gdb: bool) {
     let prebuilt = ovmf_prebuilt::Prebuilt::fetch(
         Source::LATEST,
         guest_tar_path.parent().unwrap().join("ovmf"),
@@ -359,6 +646,13 @@ fn run_brig(kernel_path: &Path, guest_tar_path: &Path, gdb: bool) {
 
     cmd.arg("-nographic");
 
+    if guest_gdb {
+        // the first serial port is no longer implicitly attached to stdio once another
+        // is specified
+        cmd.args(["-serial", "mon:stdio"]);
+        cmd.args(["-serial", "tcp::1235,server=on,wait=off"]);
+    }
+
     #[cfg(target_arch = "x86_64")]
     {
         cmd.args(["-enable-kvm", "-cpu", "host"]);
@@ -409,12 +703,7 @@ fn run_brig(kernel_path: &Path, guest_tar_path: &Path, gdb: bool) {
     let terminate_clone = terminate.clone();
 
     let handle = thread::spawn(move || {
-        hyperport_reader(
-            mem_path,
-            "/tmp/hyperport.trace",
-            ready_clone,
-            terminate_clone,
-        )
+        hyperport_reader(mem_path, HYPERPORT_TRACE_PATH, ready_clone, terminate_clone)
     });
 
     while ready.load(Ordering::Relaxed) {}
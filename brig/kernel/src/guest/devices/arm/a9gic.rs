@@ -1,10 +1,13 @@
 use {
     crate::{
         guest::GuestExecutionContext,
-        host::objects::{
-            Object, ObjectId, ObjectStore, ToRegisterMappedDevice, ToTickable,
-            device::{Device, MemoryMappedDevice},
-            irq::IrqController,
+        host::{
+            dbt::models,
+            objects::{
+                Object, ObjectId, ObjectStore, ToRegisterMappedDevice, ToTickable,
+                device::{Device, MemoryMappedDevice},
+                irq::IrqController,
+            },
         },
     },
     alloc::{collections::BTreeMap, sync::Arc, vec::Vec},
@@ -66,15 +69,47 @@ impl InterruptId {
     }
 }
 
+/// Number of CPU interfaces, one per guest core, limited by the 8-bit target
+/// lists of the distributor
+const CPU_INTERFACE_COUNT: usize = 8;
+
+/// Number of lines banked per CPU interface, the SGIs followed by the PPIs
+const PRIVATE_LINE_COUNT: usize = 32;
+
+/// Number of software generated interrupts, at the start of the private lines
+const SGI_COUNT: usize = 16;
+
+const LINE_COUNT: usize = 1020;
+
 #[derive(Debug)]
 struct GlobalInterruptController {
     id: ObjectId,
-    lines: [IrqLine; 1020],
+    /// SGIs and PPIs of each CPU interface
+    private_lines: [[IrqLine; PRIVATE_LINE_COUNT]; CPU_INTERFACE_COUNT],
+    /// SPIs, routed to CPU interfaces by their target masks
+    shared_lines: [IrqLine; LINE_COUNT - PRIVATE_LINE_COUNT],
     distributor_enabled: AtomicBool,
-    cpu_enabled: AtomicBool,
-    cpu_pmr: AtomicU8,
-    cpu_irq_line_pending: AtomicUsize,
-    cpu_irq_line_running: AtomicUsize, // usize::MAX means None
+    cpu_interfaces: [CpuInterface; CPU_INTERFACE_COUNT],
+}
+
+/// Banked registers and state of the CPU interface of a single guest core
+#[derive(Debug)]
+struct CpuInterface {
+    enabled: AtomicBool,
+    pmr: AtomicU8,
+    irq_line_pending: AtomicUsize,
+    irq_line_running: AtomicUsize, // usize::MAX means None
+}
+
+impl CpuInterface {
+    fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            pmr: AtomicU8::new(0),
+            irq_line_pending: AtomicUsize::new(NO_LINE), // NO_LINE (usize::MAX) means None
+            irq_line_running: AtomicUsize::new(NO_LINE),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -87,6 +122,8 @@ struct IrqLine {
     cpu_mask: AtomicU8,
     config: AtomicU8,
     last_active: AtomicUsize, // NO_LINE (usize::MAX) means None
+    /// CPU interface that made an SGI pending, reported when it is acknowledged
+    sgi_source: AtomicU8,
 }
 
 impl IrqLine {
@@ -100,6 +137,7 @@ impl IrqLine {
             cpu_mask: AtomicU8::new(cpu_mask),
             config: AtomicU8::new(config),
             last_active: AtomicUsize::new(NO_LINE),
+            sgi_source: AtomicU8::new(0),
         }
     }
 
@@ -114,55 +152,75 @@ impl IrqLine {
 
 impl GlobalInterruptController {
     fn new() -> Self {
-        let lines = core::array::from_fn(|i| {
-            let mut config = 0u8;
-            let mut cpu_mask = 0u8;
-
-            if i < 16 {
-                config |= 2u8;
-            }
-
-            if i < 32 {
-                cpu_mask = 1u8;
-            }
-
-            IrqLine::new(config, cpu_mask)
-        });
-
         Self {
             id: ObjectId::new(),
-            lines,
+            // SGIs are edge triggered, and private lines always target their own CPU
+            private_lines: core::array::from_fn(|cpu| {
+                core::array::from_fn(|i| IrqLine::new(if i < SGI_COUNT { 2 } else { 0 }, 1 << cpu))
+            }),
+            shared_lines: core::array::from_fn(|_| IrqLine::new(0, 0)),
             distributor_enabled: AtomicBool::new(false),
-            cpu_enabled: AtomicBool::new(false),
-            cpu_pmr: AtomicU8::new(0),
-            cpu_irq_line_pending: AtomicUsize::new(NO_LINE), // NO_LINE (usize::MAX) means None
-            cpu_irq_line_running: AtomicUsize::new(NO_LINE),
+            cpu_interfaces: core::array::from_fn(|_| CpuInterface::new()),
         }
     }
 
-    fn lines_for_bitvector(&self, base: u64, len: u64, bits: u64) -> &[IrqLine] {
+    /// Line `index` as seen by the CPU interface `cpu`
+    fn line(&self, cpu: usize, index: usize) -> &IrqLine {
+        match index.checked_sub(PRIVATE_LINE_COUNT) {
+            None => &self.private_lines[cpu][index],
+            Some(shared) => &self.shared_lines[shared],
+        }
+    }
+
+    /// Whether line `index` is delivered to the CPU interface `cpu`
+    fn targets(&self, cpu: usize, index: usize) -> bool {
+        index < PRIVATE_LINE_COUNT
+            || self.line(cpu, index).cpu_mask.load(Ordering::Relaxed) & (1 << cpu) != 0
+    }
+
+    fn lines_for_bitvector(
+        &self,
+        cpu: usize,
+        base: u64,
+        len: u64,
+        bits: u64,
+    ) -> impl Iterator<Item = &IrqLine> {
         let start_index = (8 * base) / bits;
         let end_index = core::cmp::min(((8 * len) / bits) + start_index, 1019);
 
-        &self.lines[start_index as usize..end_index as usize]
+        (start_index as usize..end_index as usize).map(move |index| self.line(cpu, index))
+    }
+
+    /// Re-evaluates the highest priority pending interrupt of every CPU
+    /// interface of a started core
+    fn update_all(&self) {
+        (0..CPU_INTERFACE_COUNT)
+            .filter(|cpu| GuestExecutionContext::for_core(*cpu).is_some())
+            .for_each(|cpu| self.update(cpu));
     }
 
-    fn acknowledge(&self) -> u32 {
-        log::debug!("--- CPU ACKNOWLEDGE ---");
+    fn acknowledge(&self, cpu: usize) -> u32 {
+        log::debug!("--- CPU{cpu} ACKNOWLEDGE ---");
+
+        let interface = &self.cpu_interfaces[cpu];
+
+        // another CPU interface may have acknowledged our pending SPI
+        self.update(cpu);
 
         // src/captive/src/devices/arm/gic.cpp:354
-        let current_pending_index = self.cpu_irq_line_pending.load(Ordering::Relaxed);
+        let current_pending_index = interface.irq_line_pending.load(Ordering::Relaxed);
 
         if current_pending_index == NO_LINE {
-            self.cpu_irq_line_running.store(NO_LINE, Ordering::Relaxed);
+            interface.irq_line_running.store(NO_LINE, Ordering::Relaxed);
 
             log::debug!("no current pending");
             log::debug!("--- CPU ACK DONE ---");
             return 1023;
         }
 
-        let current_pending = &self.lines[current_pending_index];
-        if u32::from(current_pending.priority.load(Ordering::Relaxed)) >= self.running_priority() {
+        let current_pending = self.line(cpu, current_pending_index);
+        if u32::from(current_pending.priority.load(Ordering::Relaxed)) >= self.running_priority(cpu)
+        {
             log::debug!("current pending priority is lower than running priority");
             log::debug!("--- CPU ACK DONE 1023 ---");
 
@@ -172,7 +230,7 @@ impl GlobalInterruptController {
         log::debug!("updating last active");
         // irq->_last_active = _current_running;
         current_pending.last_active.store(
-            self.cpu_irq_line_running.load(Ordering::Relaxed),
+            interface.irq_line_running.load(Ordering::Relaxed),
             Ordering::Relaxed,
         );
 
@@ -182,34 +240,43 @@ impl GlobalInterruptController {
         // _current_running = irq;
 
         log::debug!("setting current running to {current_pending_index}");
-        self.cpu_irq_line_running
+        interface
+            .irq_line_running
             .store(current_pending_index, Ordering::Relaxed);
 
-        self.update();
+        self.update_all();
 
         log::debug!("--- CPU ACK DONE {current_pending_index} ---");
 
-        return u32::try_from(current_pending_index).unwrap();
+        let source = if current_pending_index < SGI_COUNT {
+            u32::from(current_pending.sgi_source.load(Ordering::Relaxed)) << 10
+        } else {
+            0
+        };
+
+        return source | u32::try_from(current_pending_index).unwrap();
     }
 
-    fn update(&self) {
-        log::debug!("--- CPU UPDATE ---");
+    fn update(&self, cpu: usize) {
+        log::debug!("--- CPU{cpu} UPDATE ---");
         //     //fprintf(stderr, "***** UPDATE\n");
 
+        let interface = &self.cpu_interfaces[cpu];
+
         // _current_pending = nullptr;
 
-        self.cpu_irq_line_pending.store(NO_LINE, Ordering::Relaxed);
+        interface.irq_line_pending.store(NO_LINE, Ordering::Relaxed);
 
         // if (!_enabled || !_gic._distributor._enabled) {
         // 	//fprintf(stderr, "rescind\n");
         // 	_irq.rescind();
         // 	return;
         // }
-        if !self.cpu_enabled.load(Ordering::Relaxed)
+        if !interface.enabled.load(Ordering::Relaxed)
             || !self.distributor_enabled.load(Ordering::Relaxed)
         {
             log::debug!("Distributor or CPU not enabled");
-            cpu_irq_rescind();
+            cpu_irq_rescind(cpu);
             log::debug!("--- CPU UPDATE DONE ---");
             return;
         }
@@ -219,7 +286,9 @@ impl GlobalInterruptController {
 
         let mut best_irq_idx = None;
 
-        for (i, line) in self.lines.iter().enumerate() {
+        for i in (0..LINE_COUNT).filter(|i| self.targets(cpu, *i)) {
+            let line = self.line(cpu, i);
+
             if line.enabled.load(Ordering::Relaxed)
                 && (line.pending.load(Ordering::Relaxed)
                     || (line.level_triggered() && line.raised.load(Ordering::Relaxed)))
@@ -227,7 +296,7 @@ impl GlobalInterruptController {
                 log::debug!("line {i} that is enabled, and pending, or level-triggered raised");
 
                 best_irq_idx = if let Some(best_irq_idx) = best_irq_idx {
-                    let best_irq: &IrqLine = &self.lines[best_irq_idx];
+                    let best_irq: &IrqLine = self.line(cpu, best_irq_idx);
                     if line.priority.load(Ordering::Relaxed)
                         < best_irq.priority.load(Ordering::Relaxed)
                     {
@@ -257,21 +326,25 @@ impl GlobalInterruptController {
         if let Some(best_irq_idx) = best_irq_idx {
             log::debug!("we have a best irq {best_irq_idx}");
 
-            let best_irq_priority = self.lines[best_irq_idx].priority.load(Ordering::Relaxed);
+            let best_irq_priority = self
+                .line(cpu, best_irq_idx)
+                .priority
+                .load(Ordering::Relaxed);
             log::debug!("best irq priority is: {best_irq_priority}");
             // 	if (best_irq->_priority < _pmr) {
-            if best_irq_priority < self.cpu_pmr.load(Ordering::Relaxed) {
+            if best_irq_priority < interface.pmr.load(Ordering::Relaxed) {
                 log::debug!("priority is better than pmr, so setting current pending");
 
                 // 		_current_pending = best_irq;
-                self.cpu_irq_line_pending
+                interface
+                    .irq_line_pending
                     .store(best_irq_idx, Ordering::Relaxed);
 
                 // 		if (best_irq->_priority < running_priority()) {
                 // 			//fprintf(stderr, "***** yoyoyo %p %u\n", best_irq,
                 // best_irq->index()); 			raise = true;
                 // 		}
-                if u32::from(best_irq_priority) < self.running_priority() {
+                if u32::from(best_irq_priority) < self.running_priority(cpu) {
                     log::debug!("priority is higher than running priority, raising irq");
                     raise = true;
                 }
@@ -281,33 +354,37 @@ impl GlobalInterruptController {
         if raise {
             // 	//fprintf(stderr, "raise\n");
             // 	_irq.raise();
-            cpu_irq_raise();
+            cpu_irq_raise(cpu);
         } else {
             // 	//fprintf(stderr, "rescind\n");
             // _irq.rescind();
-            cpu_irq_rescind();
+            cpu_irq_rescind(cpu);
         }
 
         log::debug!("--- CPU UPDATE DONE ---");
     }
 
-    fn running_priority(&self) -> u32 {
-        let line = self.cpu_irq_line_running.load(Ordering::Relaxed);
+    fn running_priority(&self, cpu: usize) -> u32 {
+        let line = self.cpu_interfaces[cpu]
+            .irq_line_running
+            .load(Ordering::Relaxed);
 
         if line == NO_LINE {
             0x100
         } else {
-            u32::from(self.lines[line].priority.load(Ordering::Relaxed))
+            u32::from(self.line(cpu, line).priority.load(Ordering::Relaxed))
         }
     }
 
-    fn eoi(&self, irqid: usize) {
-        log::debug!("--- CPU EOI line={irqid} ---");
+    fn eoi(&self, cpu: usize, irqid: usize) {
+        log::debug!("--- CPU{cpu} EOI line={irqid} ---");
+
+        let interface = &self.cpu_interfaces[cpu];
 
         // if (irqid >= _gic._irq_lines.size()) {
         //  return;
         // }
-        if (irqid >= self.lines.len()) {
+        if (irqid >= LINE_COUNT) {
             log::debug!("invalid line");
             log::debug!("--- CPU EOI DONE ---");
             return;
@@ -316,19 +393,19 @@ impl GlobalInterruptController {
         // if (_current_running == nullptr) {
         // 	return;
         // }
-        let current_running_idx = self.cpu_irq_line_running.load(Ordering::Relaxed);
+        let current_running_idx = interface.irq_line_running.load(Ordering::Relaxed);
         if (current_running_idx == NO_LINE) {
             log::debug!("nothing running");
             log::debug!("--- CPU EOI DONE ---");
             return;
         }
 
-        let current_running = &self.lines[current_running_idx];
+        let current_running = self.line(cpu, current_running_idx);
 
         log::debug!("current running = {current_running:?}");
 
         // GICIRQLine *irq = &_gic.get_irq_line(irqid);
-        let irq = &self.lines[irqid];
+        let irq = self.line(cpu, irqid);
 
         // if (irq != _current_running) {
         if irqid != current_running_idx {
@@ -352,13 +429,13 @@ impl GlobalInterruptController {
                 if (last_idx == irqid) {
                     log::debug!("found ourselves - removing from chain");
 
-                    self.lines[last_idx]
+                    self.line(cpu, last_idx)
                         .last_active
                         .store(irq.last_active.load(Ordering::Relaxed), Ordering::Relaxed);
                     break;
                 }
 
-                last_idx = self.lines[last_idx].last_active.load(Ordering::Relaxed);
+                last_idx = self.line(cpu, last_idx).last_active.load(Ordering::Relaxed);
                 log::debug!("next last idx = {last_idx}");
             }
         } else {
@@ -367,7 +444,7 @@ impl GlobalInterruptController {
             // }
             log::debug!("we are running, so prepend us to chain");
 
-            self.cpu_irq_line_running.store(
+            interface.irq_line_running.store(
                 current_running.last_active.load(Ordering::Relaxed),
                 Ordering::Relaxed,
             );
@@ -376,12 +453,52 @@ impl GlobalInterruptController {
         //fprintf(stderr, "  cr=%p\n", _current_running);
         log::debug!(
             "cpu_irq_line_running: {:x}",
-            self.cpu_irq_line_running.load(Ordering::Relaxed)
+            interface.irq_line_running.load(Ordering::Relaxed)
         );
 
-        self.update();
+        self.update(cpu);
         log::debug!("--- CPU EOI DONE ---");
     }
+
+    /// Makes an SGI pending on each CPU interface selected by a write of
+    /// `data` to GICD_SGIR from the CPU interface `source`
+    fn send_sgi(&self, source: usize, data: u32) {
+        let sgi = usize::try_from(data & 0xf).unwrap();
+
+        let targets = match (data >> 24) & 0b11 {
+            0b00 => (data >> 16) & 0xff,
+            0b01 => 0xff & !(1 << source),
+            0b10 => 1 << source,
+            _ => 0,
+        };
+
+        log::debug!("[GIC] SGI {sgi} from CPU{source} to {targets:#x}");
+
+        (0..CPU_INTERFACE_COUNT)
+            .filter(|cpu| targets & (1 << cpu) != 0)
+            .for_each(|cpu| {
+                let line = self.line(cpu, sgi);
+                line.sgi_source
+                    .store(u8::try_from(source).unwrap(), Ordering::Relaxed);
+                line.pending.store(true, Ordering::Relaxed);
+            });
+
+        self.update_all();
+    }
+}
+
+/// CPU interface of the guest core accessing the controller, or of core 0 when
+/// accessed outside of any core
+fn current_cpu() -> usize {
+    let cpu = unsafe { GuestExecutionContext::current().current_core.as_ref() }
+        .map_or(0, |core| core.core_id());
+
+    assert!(
+        cpu < CPU_INTERFACE_COUNT,
+        "guest core {cpu} has no GIC CPU interface, at most {CPU_INTERFACE_COUNT} are supported"
+    );
+
+    cpu
 }
 
 impl Object for GlobalInterruptController {
@@ -407,18 +524,24 @@ impl MemoryMappedDevice for GlobalInterruptController {
     fn read(&self, offset: u64, value: &mut [u8]) {
         log::debug!("read GIC @ {offset:x}");
 
+        let cpu = current_cpu();
+
         let response = match offset {
             0x1000 => {
                 // GICD_CTLR
                 self.distributor_enabled.load(Ordering::Relaxed) as u32
             }
-            0x1004 => 0x81f,
+            0x1004 => {
+                // GICD_TYPER, with the number of CPU interfaces in use
+                let cpus =
+                    u32::try_from(models::core_count().clamp(1, CPU_INTERFACE_COUNT)).unwrap();
+                0x81f | ((cpus - 1) << 5)
+            }
             0x1008 => 0x200143b,
             0x1100..=0x117c | 0x1300..=0x137c => 0,
             0x1800..=0x1bfb => {
                 // GICD_ITARGETS
-                self.lines_for_bitvector(offset - 0x1800, 4, 8)
-                    .iter()
+                self.lines_for_bitvector(cpu, offset - 0x1800, 4, 8)
                     .enumerate()
                     .fold(0, |acc, (index, line)| {
                         acc | ((line.cpu_mask.load(Ordering::Relaxed) as u32) << (index * 8))
@@ -426,8 +549,7 @@ impl MemoryMappedDevice for GlobalInterruptController {
             }
             0x1c00..=0x1dff => {
                 // GICD_ICFG
-                self.lines_for_bitvector(offset - 0x1c00, 4, 2)
-                    .iter()
+                self.lines_for_bitvector(cpu, offset - 0x1c00, 4, 2)
                     .enumerate()
                     .fold(0u32, |acc, (index, line)| {
                         acc | ((line.config.load(Ordering::Relaxed) as u32) << (index * 2)) as u32
@@ -435,11 +557,11 @@ impl MemoryMappedDevice for GlobalInterruptController {
             }
             0x2000 => {
                 // GICC_CTLR
-                self.cpu_enabled.load(Ordering::Relaxed) as u32
+                self.cpu_interfaces[cpu].enabled.load(Ordering::Relaxed) as u32
             }
             0x200c => {
                 // GICC_IAR
-                self.acknowledge()
+                self.acknowledge(cpu)
             }
             0x20fc => 0,
             _ => {
@@ -459,6 +581,8 @@ impl MemoryMappedDevice for GlobalInterruptController {
 
         log::debug!("write GIC @ {offset:x} = {data:x}");
 
+        let cpu = current_cpu();
+
         match offset {
             0x1000 => {
                 self.distributor_enabled
@@ -466,8 +590,7 @@ impl MemoryMappedDevice for GlobalInterruptController {
             }
             0x1100..=0x117f => {
                 // GICD_ISENABLE
-                self.lines_for_bitvector(offset - 0x1100, 4, 1)
-                    .iter()
+                self.lines_for_bitvector(cpu, offset - 0x1100, 4, 1)
                     .enumerate()
                     .for_each(|(index, line)| {
                         let enable = ((data >> index) & 1) == 1;
@@ -476,8 +599,7 @@ impl MemoryMappedDevice for GlobalInterruptController {
             }
             0x1180..=0x11ff => {
                 // GICD_ICENABLE
-                self.lines_for_bitvector(offset - 0x1180, 4, 1)
-                    .iter()
+                self.lines_for_bitvector(cpu, offset - 0x1180, 4, 1)
                     .enumerate()
                     .for_each(|(index, line)| {
                         let clear_enable = ((data >> index) & 1) == 1;
@@ -486,8 +608,7 @@ impl MemoryMappedDevice for GlobalInterruptController {
             }
             0x1380..=0x13ff => {
                 // GICD_ICACTIVE
-                self.lines_for_bitvector(offset - 0x1380, 4, 1)
-                    .iter()
+                self.lines_for_bitvector(cpu, offset - 0x1380, 4, 1)
                     .enumerate()
                     .for_each(|(index, line)| {
                         let clear_active = ((data >> index) & 1) == 1;
@@ -496,28 +617,31 @@ impl MemoryMappedDevice for GlobalInterruptController {
             }
             0x1400..=0x17fb => {
                 // GICD_IPRIORITY
-                self.lines_for_bitvector(offset - 0x1400, 4, 8)
-                    .iter()
+                self.lines_for_bitvector(cpu, offset - 0x1400, 4, 8)
                     .enumerate()
                     .for_each(|(index, line)| {
                         line.priority
                             .store(((data >> (index * 8)) & 0xff) as u8, Ordering::Relaxed)
                     })
             }
-            0x1800..=0x1bfb => {
+            0x1800..=0x181f => {
+                // GICD_ITARGETS of the private lines, which are read-only
+            }
+            0x1820..=0x1bfb => {
                 // GICD_ITARGETS
-                self.lines_for_bitvector(offset - 0x1800, 4, 8)
-                    .iter()
+                self.lines_for_bitvector(cpu, offset - 0x1800, 4, 8)
                     .enumerate()
                     .for_each(|(index, line)| {
                         line.cpu_mask
                             .store(((data >> (index * 8)) & 0xff) as u8, Ordering::Relaxed)
-                    })
+                    });
+
+                // pending SPIs may now be delivered to other CPU interfaces
+                self.update_all();
             }
             0x1c00..=0x1dff => {
                 // GICD_ICFG
-                self.lines_for_bitvector(offset - 0x1c00, 4, 2)
-                    .iter()
+                self.lines_for_bitvector(cpu, offset - 0x1c00, 4, 2)
                     .enumerate()
                     .for_each(|(index, line)| {
                         let cfg_val = ((data >> (index * 2)) & 0x3) as u8;
@@ -528,7 +652,8 @@ impl MemoryMappedDevice for GlobalInterruptController {
                     })
             }
             0x1f00 => {
-                todo!("sgir");
+                // GICD_SGIR
+                self.send_sgi(cpu, data);
             }
             0x1f10..=0x1f1f => {
                 // GICD_CPENDSGIR
@@ -541,15 +666,19 @@ impl MemoryMappedDevice for GlobalInterruptController {
             // GICC
             0x2000 => {
                 // GICC_CTLR
-                self.cpu_enabled.store((data & 1) == 1, Ordering::Relaxed);
+                self.cpu_interfaces[cpu]
+                    .enabled
+                    .store((data & 1) == 1, Ordering::Relaxed);
             }
             0x2004 => {
                 // GICC_PMR
-                self.cpu_pmr.store((data & 0xff) as u8, Ordering::Relaxed);
+                self.cpu_interfaces[cpu]
+                    .pmr
+                    .store((data & 0xff) as u8, Ordering::Relaxed);
             }
             0x2010 => {
-                // GICC_EOIR
-                self.eoi(usize::try_from(data).unwrap());
+                // GICC_EOIR, ignoring the source CPU of SGIs
+                self.eoi(cpu, usize::try_from(data & 0x3ff).unwrap());
             }
             _ => {
                 panic!("[GIC] Write offset: {offset:x} <= {data:x}");
@@ -559,48 +688,78 @@ impl MemoryMappedDevice for GlobalInterruptController {
 }
 
 impl IrqController for GlobalInterruptController {
+    /// Raises an interrupt from a device, which for a PPI is raised on every CPU
+    /// interface as devices are not banked per core
     fn raise(&self, line: usize) {
         let x = line;
-        let line = &self.lines[line];
+        let cpus = if line < PRIVATE_LINE_COUNT {
+            0..CPU_INTERFACE_COUNT
+        } else {
+            0..1
+        };
 
-        if let Ok(false) =
-            line.raised
-                .compare_exchange(false, true, Ordering::Relaxed, Ordering::Relaxed)
-        {
-            log::debug!("[GIC] raise irq {x}");
+        let mut raised = false;
+        for cpu in cpus {
+            let line = self.line(cpu, x);
 
-            if line.edge_triggered() {
-                line.pending.store(true, Ordering::Relaxed);
+            if let Ok(false) =
+                line.raised
+                    .compare_exchange(false, true, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                if line.edge_triggered() {
+                    line.pending.store(true, Ordering::Relaxed);
+                }
+
+                raised = true;
             }
+        }
 
-            self.update();
+        if raised {
+            log::debug!("[GIC] raise irq {x}");
+            self.update_all();
         }
     }
 
     fn rescind(&self, line: usize) {
         let x = line;
-        let line = &self.lines[line];
+        let cpus = if line < PRIVATE_LINE_COUNT {
+            0..CPU_INTERFACE_COUNT
+        } else {
+            0..1
+        };
 
-        if let Ok(true) =
-            line.raised
-                .compare_exchange(true, false, Ordering::Relaxed, Ordering::Relaxed)
-        {
+        let mut rescinded = false;
+        for cpu in cpus {
+            let line = self.line(cpu, x);
+
+            if let Ok(true) =
+                line.raised
+                    .compare_exchange(true, false, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                rescinded = true;
+            }
+        }
+
+        if rescinded {
             log::debug!("[GIC] rescind irq {x}");
-            self.update();
+            self.update_all();
         }
     }
 }
 
-fn cpu_irq_raise() {
-    log::debug!("cpu irq raise");
-    GuestExecutionContext::current()
-        .interrupt_pending
-        .store(1, Ordering::Relaxed);
+// IRQs are raised from device ticks in whichever task is running, so the
+// target core's execution context is looked up rather than using the current
+// one.
+fn cpu_irq_raise(cpu: usize) {
+    log::debug!("cpu{cpu} irq raise");
+    if let Some(ctx) = GuestExecutionContext::for_core(cpu) {
+        ctx.interrupt_pending.store(1, Ordering::Relaxed);
+    }
 }
 
-fn cpu_irq_rescind() {
-    log::debug!("cpu irq rescind");
-    GuestExecutionContext::current()
-        .interrupt_pending
-        .store(0, Ordering::Relaxed);
+fn cpu_irq_rescind(cpu: usize) {
+    log::debug!("cpu{cpu} irq rescind");
+    if let Some(ctx) = GuestExecutionContext::for_core(cpu) {
+        ctx.interrupt_pending.store(0, Ordering::Relaxed);
+    }
 }
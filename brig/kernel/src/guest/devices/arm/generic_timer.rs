@@ -9,6 +9,7 @@ use {
             irq::IrqController,
             tickable::Tickable,
         },
+        timer::VirtualClock,
     },
     alloc::{borrow::ToOwned, collections::BTreeMap, string::String, sync::Arc},
     bitfields::bitfield,
@@ -21,12 +22,19 @@ use {
 
 #[guest_device_factory(generic_timer)]
 fn create_generic_timer(config: &BTreeMap<InternedString, InternedString>) -> Arc<dyn Device> {
+    // derive the counter from the number of executed guest instructions, each
+    // taking 2^icount_shift nanoseconds
+    let virtual_clock = config
+        .get(&InternedString::from_static("icount_shift"))
+        .map(|shift| VirtualClock::enable(shift.as_ref().parse().unwrap()));
+
     Arc::new(GenericTimer::new(
         *config
             .get(&InternedString::from_static("irq_controller"))
             .unwrap(),
         27,
         Nanoseconds::new(1_000),
+        virtual_clock,
     ))
 }
 
@@ -57,6 +65,8 @@ struct GenericTimer {
     irq: usize,
 
     tick_interval: Nanoseconds<u64>,
+    /// Ticked by guest instructions rather than host time if present
+    virtual_clock: Option<&'static VirtualClock>,
 
     counter: AtomicU64,
     /// Used when registering for a periodic tick from the kernel
@@ -73,13 +83,19 @@ struct GenericTimer {
 }
 
 impl GenericTimer {
-    fn new(controller_name: InternedString, irq: usize, tick_interval: Nanoseconds<u64>) -> Self {
+    fn new(
+        controller_name: InternedString,
+        irq: usize,
+        tick_interval: Nanoseconds<u64>,
+        virtual_clock: Option<&'static VirtualClock>,
+    ) -> Self {
         Self {
             id: ObjectId::new(),
             irq,
             controller_name,
             controller: Once::new(),
             tick_interval,
+            virtual_clock,
             counter: AtomicU64::new(0),
             frequency: AtomicU64::new(10_000_000), //
             virtual_offset: AtomicU64::new(0),
@@ -142,11 +158,16 @@ impl Device for GenericTimer {
         let gic = ObjectStore::global().get_irq_controller(gic_id).unwrap();
         self.controller.call_once(|| gic);
 
-        host::timer::register_tickable(
-            // Nanoseconds(1_000_000_000),
-            self.tick_interval,
-            ObjectStore::global().get_tickable(self.id()).unwrap(),
-        );
+        let tickable = ObjectStore::global().get_tickable(self.id()).unwrap();
+
+        match self.virtual_clock {
+            Some(clock) => clock.register_tickable(self.tick_interval, tickable),
+            None => host::timer::register_tickable(
+                // Nanoseconds(1_000_000_000),
+                self.tick_interval,
+                tickable,
+            ),
+        }
     }
 
     fn stop(&self) {}
@@ -157,7 +178,7 @@ impl RegisterMappedDevice for GenericTimer {
         let value = match sys_reg_id {
             CNTKCTL_EL1 => self.cntkctl_el1.load(Ordering::Relaxed),
             CNTFRQ_EL0 => self.frequency.load(Ordering::Relaxed),
-            CNTPCT_EL0 => todo!("CNTPCT_EL0"),
+            CNTPCT_EL0 => self.physical_count(),
             CNTVCT_EL0 => self.counter.load(Ordering::Relaxed),
             CNTP_TVAL_EL0 => todo!("CNTP_TVAL_EL0"),
             CNTP_CTL_EL0 => todo!("CNTP_CTL_EL0"),
@@ -5,14 +5,30 @@ use {
             memory::{AddressSpace, AddressSpaceRegion},
         },
         host::{
-            dbt::sysreg_helpers::{self, encode_sysreg_id},
+            arch::{
+                CoreStorage, MAX_CORES,
+                x86::{
+                    aarch64_mmu::TranslationRegime, memory::VirtualMemoryArea,
+                    safepoint::SafepointContext,
+                },
+            },
+            dbt::{
+                models::ModelDevice,
+                softmmu,
+                sysreg_helpers::{self, encode_sysreg_id},
+            },
             fs::Filesystem,
             objects::{ObjectStore, device::Device},
         },
     },
     alloc::{boxed::Box, collections::BTreeMap, sync::Arc},
     common::{TestConfig, intern::InternedString},
-    core::{panic, ptr, sync::atomic::AtomicU64},
+    core::{
+        cell::UnsafeCell,
+        panic,
+        ptr::{self, null, null_mut},
+        sync::atomic::{AtomicBool, AtomicI64, AtomicPtr, AtomicU32, AtomicU64, Ordering},
+    },
     spin::Once,
     x86::current::segmentation::{rdfsbase, wrfsbase},
 };
@@ -35,13 +51,60 @@ impl Guest {
     }
 }
 
+/// Maximum number of guest cores
+pub const MAX_GUEST_CORES: usize = 16;
+
+/// Execution contexts of started guest cores, indexed by core ID
+static CORE_EXECUTION_CONTEXTS: [AtomicPtr<GuestExecutionContext>; MAX_GUEST_CORES] =
+    [const { AtomicPtr::new(null_mut()) }; MAX_GUEST_CORES];
+
+/// FS base of the execution context whose guest mappings are currently present
+/// in the lower half of the host address space, for each host core
+static GUEST_MAPPINGS_OWNER: [AtomicU64; MAX_CORES] = [const { AtomicU64::new(0) }; MAX_CORES];
+
 #[repr(C)]
 pub struct GuestExecutionContext {
     pub current_address_space: *mut AddressSpace,
     pub interrupt_pending: AtomicU64,
+    /// Guest instructions translated code may execute before returning to the
+    /// block execution loop, only used when guest time is derived from the
+    /// instruction count
+    pub instruction_budget: AtomicI64,
+    /// Core executing in this context, null before any core has been started
+    pub current_core: *const ModelDevice,
+    /// Block execution loop re-entry point of the current core
+    pub safepoint: UnsafeCell<SafepointContext>,
+    /// Scratch space for translated code moving values to and from `MXCSR`,
+    /// which can only be loaded and stored through memory
+    pub mxcsr: AtomicU32,
+    /// Event register checked by `WFE`, set by `SEV` on any core
+    pub event_register: AtomicBool,
+    /// Guest virtual pages looked up by translated code accessing guest memory
+    /// through the software TLB
+    pub software_tlb: UnsafeCell<softmmu::Table>,
+    /// Opcode of the last TLB maintenance instruction executed, cleared once
+    /// the block execution loop has performed it
+    pub tlbi_opcode: AtomicU32,
+    /// Value of the register operand of `tlbi_opcode`
+    pub tlbi_operand: AtomicU64,
 }
 
 impl GuestExecutionContext {
+    pub fn new(address_space: *mut AddressSpace, core: *const ModelDevice) -> Box<Self> {
+        Box::new(Self {
+            current_address_space: address_space,
+            interrupt_pending: AtomicU64::new(0),
+            instruction_budget: AtomicI64::new(i64::MAX),
+            current_core: core,
+            safepoint: UnsafeCell::new(SafepointContext::empty()),
+            mxcsr: AtomicU32::new(0),
+            event_register: AtomicBool::new(false),
+            software_tlb: UnsafeCell::new(softmmu::Table::new()),
+            tlbi_opcode: AtomicU32::new(0),
+            tlbi_operand: AtomicU64::new(0),
+        })
+    }
+
     pub fn activate(self: Box<Self>) {
         unsafe {
             wrfsbase(Box::into_raw(self) as u64);
@@ -51,6 +114,75 @@ impl GuestExecutionContext {
     pub fn current() -> &'static Self {
         unsafe { &*(rdfsbase() as *const Self) }
     }
+
+    /// Core executing in this context
+    pub fn current_core(&self) -> &'static ModelDevice {
+        unsafe { self.current_core.as_ref() }
+            .expect("no guest core running in current execution context")
+    }
+
+    /// Execution context of the guest core with the supplied ID, if it has
+    /// been started
+    pub fn for_core(core_id: usize) -> Option<&'static Self> {
+        unsafe {
+            CORE_EXECUTION_CONTEXTS
+                .get(core_id)?
+                .load(Ordering::Acquire)
+                .as_ref()
+        }
+    }
+
+    /// Sets the event register of every started guest core
+    pub fn send_event() {
+        CORE_EXECUTION_CONTEXTS
+            .iter()
+            .filter_map(|ctx| unsafe { ctx.load(Ordering::Acquire).as_ref() })
+            .for_each(|ctx| ctx.event_register.store(true, Ordering::Relaxed));
+    }
+
+    /// Publish this execution context as belonging to the supplied core ID,
+    /// returning the raw pointer to be loaded into FS base
+    pub fn register(self: Box<Self>, core_id: usize) -> *mut Self {
+        let ptr = Box::into_raw(self);
+
+        if CORE_EXECUTION_CONTEXTS[core_id]
+            .compare_exchange(null_mut(), ptr, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            panic!("guest core {core_id} already has an execution context");
+        }
+
+        ptr
+    }
+
+    /// Called by the scheduler after FS base has been switched
+    ///
+    /// Guest virtual mappings in the lower half of each host core's page tables
+    /// are faulted in on behalf of a single execution context, so they are
+    /// dropped when a context starts running whose translation regime differs
+    /// from that of the context they were made for. Cores sharing page tables,
+    /// ASID and exception level keep them.
+    pub fn switched_to(fs_base: u64) {
+        if fs_base == 0 {
+            return;
+        }
+
+        let previous = GUEST_MAPPINGS_OWNER[CoreStorage::id()].swap(fs_base, Ordering::Relaxed);
+        if previous == fs_base {
+            return;
+        }
+
+        let regime = |fs_base: u64| {
+            unsafe { (fs_base as *const Self).as_ref() }
+                .and_then(|ctx| unsafe { ctx.current_core.as_ref() })
+                .map(TranslationRegime::current)
+        };
+
+        match (regime(previous), regime(fs_base)) {
+            (Some(previous), Some(next)) if previous == next => {}
+            _ => VirtualMemoryArea::current().invalidate_guest_mappings(),
+        }
+    }
 }
 
 /// Start guest emulation
@@ -135,14 +267,16 @@ pub fn start<FS: Filesystem>(guest_data: &mut FS, test_config: TestConfig) {
         }
     }
 
-    let temp_exec_ctx = Box::new(GuestExecutionContext {
-        current_address_space: guest
+    // boot context used while running tests and loading images, each core
+    // creates its own when started
+    let temp_exec_ctx = GuestExecutionContext::new(
+        guest
             .address_spaces
             .get_mut(&("as0".into()))
             .unwrap()
             .as_mut() as *mut AddressSpace,
-        interrupt_pending: AtomicU64::new(0),
-    });
+        null(),
+    );
 
     log::debug!("activating guest execution context");
     temp_exec_ctx.activate();
@@ -162,19 +296,10 @@ pub fn start<FS: Filesystem>(guest_data: &mut FS, test_config: TestConfig) {
         }
     }
 
-    // go go go (start all devices)
+    // go go go (start all devices, each core spawns its own execution task)
     log::warn!("starting guest");
 
-    for (_, device) in guest
-        .devices
-        .iter()
-        .filter(|(name, _)| **name != InternedString::from_static("core0"))
-    {
+    for (_, device) in guest.devices.iter() {
         device.start();
     }
-    guest
-        .devices
-        .get(&InternedString::from_static("core0"))
-        .unwrap()
-        .start();
 }
@@ -22,13 +22,22 @@ pub fn platform_init(boot_info: &BootInfo) {
     x86::init(boot_info);
 }
 
+/// Starts every other host core, each of which initializes itself and then
+/// calls `entry`
+pub fn start_secondary_cores(entry: fn() -> !) {
+    x86::smp::start(entry);
+}
+
+/// Maximum number of host cores, further cores are left stopped
+pub const MAX_CORES: usize = 4;
+
 #[derive(Default)]
 pub struct CoreStorage {
     state: HashMap<TypeId, Box<dyn Any>>,
 }
 
 static mut NEXT_CORE_ID: AtomicU64 = AtomicU64::new(0);
-static mut CORES: [Once<CoreStorage>; 4] = [Once::INIT; 4];
+static mut CORES: [Once<CoreStorage>; MAX_CORES] = [Once::INIT; MAX_CORES];
 
 fn get_local_pid() -> u64 {
     unsafe { rdmsr(IA32_TSC_AUX) }
@@ -42,6 +51,11 @@ impl CoreStorage {
         unsafe { CORES[get_local_pid() as usize].call_once(Self::default) };
     }
 
+    /// ID of the current core, assigned in the order cores are initialized
+    pub fn id() -> usize {
+        get_local_pid() as usize
+    }
+
     pub fn this_mut() -> &'static mut Self {
         unsafe { CORES[get_local_pid() as usize].get_mut().unwrap() }
     }
@@ -1,5 +1,6 @@
 use {
     crate::{
+        guest::{GuestExecutionContext, memory::AddressSpaceRegionKind},
         host::{
             arch::x86::{
                 irq::exit_with_message, memory::guest_physical_to_host_virt,
@@ -13,143 +14,616 @@ use {
     proc_macro_lib::ktest,
 };
 
-// returns guest physical address
-pub fn guest_translate(device: &ModelDevice, guest_virtual_address: u64) -> Option<u64> {
-    let mmu_enabled = device.register_file.read::<u64>("SCTLR_EL1_bits") & 1 == 1;
-    if !mmu_enabled {
-        return Some(guest_virtual_address);
+/// Valid bit of a descriptor
+const VALID: u64 = 1 << 0;
+/// Set in table descriptors, and in page descriptors at the final level
+const TABLE_OR_PAGE: u64 = 1 << 1;
+/// AP[1], data accesses are permitted from EL0
+const AP_EL0: u64 = 1 << 6;
+/// AP[2], writes are not permitted
+const AP_READ_ONLY: u64 = 1 << 7;
+const ACCESS_FLAG: u64 = 1 << 10;
+/// nG, the translation is specific to the current ASID
+const NOT_GLOBAL: u64 = 1 << 11;
+const DIRTY_BIT_MODIFIER: u64 = 1 << 51;
+const PXN: u64 = 1 << 53;
+const UXN: u64 = 1 << 54;
+const PXN_TABLE: u64 = 1 << 59;
+const UXN_TABLE: u64 = 1 << 60;
+/// APTable[0], data accesses are not permitted from EL0 in the next levels
+const AP_TABLE_NO_EL0: u64 = 1 << 61;
+/// APTable[1], writes are not permitted in the next levels
+const AP_TABLE_READ_ONLY: u64 = 1 << 62;
+
+/// SCTLR_EL1.WXN, writable memory is never executable
+const SCTLR_WXN: u64 = 1 << 19;
+/// TCR_EL1.A1, the ASID is held by TTBR1_EL1 rather than TTBR0_EL1
+const TCR_A1: u64 = 1 << 22;
+/// TCR_EL1.AS, ASIDs are 16 rather than 8 bits
+const TCR_AS: u64 = 1 << 36;
+
+/// Bits of each VA range that can be accessed, as guest virtual addresses are
+/// accessed through their low 40 bits in the host lower half, which holds
+/// this many bits of both ranges without their addresses aliasing
+const HOST_VA_SIZE: u32 = 39;
+
+/// Reason a guest virtual address could not be accessed, reported to the guest
+/// in the fault status code of an abort
+///
+/// Levels are those of the table walk, which starts at level -1 for 52-bit VAs
+/// with 4K granules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Table base or output address exceeds the configured physical address
+    /// size
+    AddressSize { level: i8 },
+    /// Address is outside of both VA ranges or the part of them supported, or
+    /// there is no valid descriptor at the supplied level of the table walk
+    Translation { level: i8 },
+    /// Access flag of the descriptor is clear and not managed by hardware
+    AccessFlag { level: i8 },
+    /// Descriptor does not permit the access from the current exception level
+    Permission { level: i8 },
+    /// Output address is neither RAM nor a device
+    External,
+    /// Table read at the supplied level of the table walk is not in RAM
+    TableWalkExternal { level: i8 },
+}
+
+impl Fault {
+    /// Data or instruction fault status code (DFSC/IFSC)
+    pub fn status_code(&self) -> u32 {
+        match *self {
+            Self::AddressSize { level: -1 } => 0b10_1001,
+            Self::AddressSize { level } => level as u32,
+            Self::Translation { level: -1 } => 0b10_1011,
+            Self::Translation { level } => 0b00_0100 | level as u32,
+            Self::AccessFlag { level } => 0b00_1000 | level as u32,
+            Self::Permission { level } => 0b00_1100 | level as u32,
+            Self::External => 0b01_0000,
+            Self::TableWalkExternal { level: -1 } => 0b01_0011,
+            Self::TableWalkExternal { level } => 0b01_0100 | level as u32,
+        }
     }
-    // let ttbcr = device.register_file.read::<u32>("TTBCR_S_bits");
-    // log::trace!("{ttbcr:032b}");
-    // let ttbcr_n = ttbcr & 0b111;
-    // exit_with_message!("{ttbcr_n:03b}");
-
-    let ttbr0_el1 = device.register_file.read::<u64>("_TTBR0_EL1_bits");
-    let ttbr1_el1 = device.register_file.read::<u64>("_TTBR1_EL1_bits");
-    log::trace!("ttbr0_el1: {ttbr0_el1:x}");
-    log::trace!("ttbr1_el1: {ttbr1_el1:x}");
-
-    // assumes 39-bit VA
-    let top_bit = (guest_virtual_address >> 39) & 1;
-    let translation_table_base_guest_phys = match top_bit {
-        0 => ttbr0_el1,
-        1 => ttbr1_el1,
-        _ => exit_with_message!(""),
-    };
+}
 
-    let ttbgp_masked = translation_table_base_guest_phys & !0xffff000000000fff;
+/// Kind of access a guest virtual address is translated for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    /// Instruction fetch
+    Execute,
+    /// Read on behalf of a debugger or checker rather than the guest, which is
+    /// not permission checked and never updates descriptors
+    Inspect,
+}
 
-    log::trace!("guest_virtual_address: {guest_virtual_address:x?}");
-    log::trace!("translation_table_base_guest_phys: {translation_table_base_guest_phys:x?}");
-    log::trace!("ttbgp_masked: {ttbgp_masked:x?}");
+/// Successful translation of a guest virtual address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub physical_address: u64,
+    /// Whether the current exception level may write to the address without
+    /// the descriptor being updated
+    pub writable: bool,
+    /// Size of the block or page the address was translated through
+    pub size: u64,
+    /// Whether the translation applies to every ASID
+    pub global: bool,
+    /// Whether the translation was made from EL1 and permits accesses that
+    /// EL0 may not make
+    pub privileged: bool,
+}
 
-    let translation_table_base = guest_physical_to_host_virt(ttbgp_masked);
-    log::trace!("translation_table_base: {translation_table_base:x?}");
-    let table = unsafe { &*(translation_table_base.as_ptr::<[Descriptor; 512]>()) };
+/// System registers and exception level the translations of a core depend on,
+/// lower half mappings made for one core are valid for another with an equal
+/// regime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TranslationRegime {
+    sctlr: u64,
+    tcr: u64,
+    ttbr0: u64,
+    ttbr1: u64,
+    el0: bool,
+}
 
-    //log::trace!("table: {table:x?}");
+impl TranslationRegime {
+    pub fn current(device: &ModelDevice) -> Self {
+        Self {
+            sctlr: device.register_file.read::<u64>("SCTLR_EL1_bits"),
+            tcr: device.register_file.read::<u64>("TCR_EL1_bits"),
+            ttbr0: device.register_file.read::<u64>("_TTBR0_EL1_bits"),
+            ttbr1: device.register_file.read::<u64>("_TTBR1_EL1_bits"),
+            el0: device.register_file.read::<u8>("PSTATE_EL") == 0,
+        }
+    }
+}
 
-    // Skip L0, because 3-level page tables.
-    translate_l1(device, table, guest_virtual_address)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Granule {
+    Size4KiB,
+    Size16KiB,
+    Size64KiB,
 }
 
-fn _translate_l0(
-    device: &ModelDevice,
-    table: &[Descriptor; 512],
-    guest_virtual_address: u64,
-) -> Option<u64> {
-    let entry_idx = ((guest_virtual_address >> 39) & 0x1ff) as usize;
+impl Granule {
+    /// Decodes TCR_EL1.TG0, reserved values are treated as 4K
+    fn from_tg0(tg0: u64) -> Self {
+        match tg0 {
+            0b01 => Self::Size64KiB,
+            0b10 => Self::Size16KiB,
+            _ => Self::Size4KiB,
+        }
+    }
 
-    log::trace!("entry_idx: {entry_idx:x?}");
-    let entry = table[entry_idx];
-    log::trace!("entry: {entry:x?}");
+    /// Decodes TCR_EL1.TG1, which is encoded differently to TG0
+    fn from_tg1(tg1: u64) -> Self {
+        match tg1 {
+            0b01 => Self::Size16KiB,
+            0b11 => Self::Size64KiB,
+            _ => Self::Size4KiB,
+        }
+    }
 
-    if entry.is_table_or_page() {
-        translate_l1(device, entry_to_table(&entry), guest_virtual_address)
-    } else {
-        exit_with_message!("entry was not table or page")
+    /// Number of bits of the address resolved by the final level
+    fn shift(self) -> u32 {
+        match self {
+            Self::Size4KiB => 12,
+            Self::Size16KiB => 14,
+            Self::Size64KiB => 16,
+        }
+    }
+
+    /// Number of bits of the address resolved by each table
+    fn stride(self) -> u32 {
+        self.shift() - 3
+    }
+
+    fn block_permitted(self, level: i8, lpa2: bool) -> bool {
+        match self {
+            Self::Size4KiB => level == 1 || level == 2 || (lpa2 && level == 0),
+            Self::Size16KiB => level == 2 || (lpa2 && level == 1),
+            Self::Size64KiB => level == 1 || level == 2,
+        }
     }
 }
 
-fn translate_l1(
-    device: &ModelDevice,
-    table: &[Descriptor; 512],
-    guest_virtual_address: u64,
-) -> Option<u64> {
-    let entry_idx = ((guest_virtual_address >> 30) & 0x1ff) as usize;
-    log::trace!("l1 entry_idx: {entry_idx:x?}");
-    let entry = table[entry_idx];
-    log::trace!("l1 entry: {entry:x?}");
-
-    if !entry.is_valid() {
-        // guest page fault, look up exception vector table (VBAR_EL2)
-        guest_page_fault(device, guest_virtual_address);
-        exit_with_message!("invalid L1")
+/// Walk of the tables of the VA range containing an address, as configured by
+/// TCR_EL1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Walk {
+    /// Guest physical address of the first table
+    base: u64,
+    granule: Granule,
+    /// Size of the VA range in bits
+    input_size: u32,
+    /// Size of guest physical addresses in bits
+    output_size: u32,
+    /// Table descriptors can restrict the permissions of the next levels
+    hierarchical_permissions: bool,
+    /// 52-bit output addresses for 4K and 16K granules (TCR_EL1.DS)
+    lpa2: bool,
+    /// Access flag is set by the walk rather than faulting (TCR_EL1.HA)
+    hardware_access: bool,
+    /// Dirty state is managed by the walk (TCR_EL1.HD)
+    hardware_dirty: bool,
+}
+
+impl Walk {
+    fn new(tcr: u64, ttbr0: u64, ttbr1: u64, guest_virtual_address: u64) -> Result<Self, Fault> {
+        let field = |lsb: u32, width: u32| (tcr >> lsb) & ((1 << width) - 1);
+
+        let upper = (guest_virtual_address >> 55) & 1 == 1;
+        let (ttbr, tsz, granule, disabled, top_byte_ignored, hierarchical_disabled) = if upper {
+            (
+                ttbr1,
+                field(16, 6),
+                Granule::from_tg1(field(30, 2)),
+                field(23, 1),
+                field(38, 1),
+                field(42, 1),
+            )
+        } else {
+            (
+                ttbr0,
+                field(0, 6),
+                Granule::from_tg0(field(14, 2)),
+                field(7, 1),
+                field(37, 1),
+                field(41, 1),
+            )
+        };
+
+        // out of range sizes behave as the nearest supported size
+        let input_size = 64 - (tsz as u32).clamp(12, 39);
+
+        // bits above the VA range must all match bit 55, ignoring the top byte
+        // if it is used for tagging
+        let top = if top_byte_ignored == 1 { 56 } else { 64 };
+        let width = top - input_size;
+        let high_bits = (guest_virtual_address >> input_size) & ((1 << width) - 1);
+        let expected = if upper { (1 << width) - 1 } else { 0 };
+
+        if disabled == 1 || high_bits != expected {
+            return Err(Fault::Translation { level: 0 });
+        }
+
+        // addresses of wider VA ranges can only be used if they would be valid
+        // in a range of the supported size
+        let unsupported = input_size - input_size.min(HOST_VA_SIZE);
+        let supported_bits =
+            (guest_virtual_address >> (input_size - unsupported)) & ((1 << unsupported) - 1);
+        if supported_bits != expected & ((1 << unsupported) - 1) {
+            log::debug!(
+                "{guest_virtual_address:#x} is outside of the {HOST_VA_SIZE}-bit VA ranges \
+                 supported, faulting"
+            );
+            return Err(Fault::Translation { level: 0 });
+        }
+
+        let lpa2 = field(59, 1) == 1;
+        let output_size = match field(32, 3) {
+            0b000 => 32,
+            0b001 => 36,
+            0b010 => 40,
+            0b011 => 42,
+            0b100 => 44,
+            0b101 => 48,
+            _ if lpa2 || granule == Granule::Size64KiB => 52,
+            _ => 48,
+        };
+
+        // bits 51:48 of the table base are held in TTBR bits 5:2 with 52-bit
+        // output addresses
+        let base = if output_size == 52 {
+            (ttbr & 0x0000_ffff_ffff_ffc0) | (((ttbr >> 2) & 0xf) << 48)
+        } else {
+            ttbr & 0x0000_ffff_ffff_fffe
+        };
+
+        Ok(Self {
+            base,
+            granule,
+            input_size,
+            output_size,
+            hierarchical_permissions: hierarchical_disabled == 0,
+            lpa2,
+            hardware_access: field(39, 1) == 1,
+            hardware_dirty: field(40, 1) == 1,
+        })
     }
 
-    if entry.is_table_or_page() {
-        translate_l2(device, entry_to_table(&entry), guest_virtual_address)
-    } else {
-        let mask = (1 << 30) - 1;
-        Some((entry.output_address().0 as u64 & !mask) | (guest_virtual_address & mask))
+    /// Level of the first table, such that the final level resolves the
+    /// granule
+    fn start_level(&self) -> i8 {
+        let levels = (self.input_size - self.granule.shift()).div_ceil(self.granule.stride());
+        4 - levels as i8
+    }
+
+    /// Lowest bit of the address resolved by the supplied level
+    fn level_shift(&self, level: i8) -> u32 {
+        self.granule.shift() + self.granule.stride() * (3 - level) as u32
+    }
+
+    /// Byte offset into the table at the supplied level of the descriptor for
+    /// the address, the first table may have fewer entries than the others
+    fn descriptor_offset(&self, level: i8, guest_virtual_address: u64) -> u64 {
+        let shift = self.level_shift(level);
+        let width = (self.input_size - shift).min(self.granule.stride());
+        ((guest_virtual_address >> shift) & ((1 << width) - 1)) * 8
+    }
+
+    /// Output address of a table, block or page descriptor, aligned to the
+    /// supplied number of bits
+    fn output_address(&self, descriptor: u64, shift: u32) -> u64 {
+        let (mask, high_bits) = if self.lpa2 {
+            (0x0003_ffff_ffff_ffff, ((descriptor >> 8) & 0b11) << 50)
+        } else if self.output_size == 52 {
+            (0x0000_ffff_ffff_ffff, ((descriptor >> 12) & 0xf) << 48)
+        } else {
+            (0x0000_ffff_ffff_ffff, 0)
+        };
+
+        (descriptor & mask & !((1 << shift) - 1)) | high_bits
+    }
+
+    fn exceeds_output_size(&self, address: u64) -> bool {
+        address >> self.output_size != 0
     }
 }
 
-fn translate_l2(
-    device: &ModelDevice,
-    table: &[Descriptor; 512],
-    guest_virtual_address: u64,
-) -> Option<u64> {
-    let entry_idx = ((guest_virtual_address >> 21) & 0x1ff) as usize;
-    log::trace!("l2 entry_idx: {entry_idx:x?}");
-    let entry = table[entry_idx];
-    log::trace!("l2 entry: {entry:x?}");
-
-    if !entry.is_valid() {
-        exit_with_message!("invalid L2")
+/// Permissions removed by the table descriptors of a walk
+#[derive(Debug, Default, Clone, Copy)]
+struct TableRestrictions {
+    no_el0: bool,
+    read_only: bool,
+    unprivileged_execute_never: bool,
+    privileged_execute_never: bool,
+}
+
+impl TableRestrictions {
+    fn descend(self, descriptor: u64) -> Self {
+        Self {
+            no_el0: self.no_el0 || descriptor & AP_TABLE_NO_EL0 != 0,
+            read_only: self.read_only || descriptor & AP_TABLE_READ_ONLY != 0,
+            unprivileged_execute_never: self.unprivileged_execute_never
+                || descriptor & UXN_TABLE != 0,
+            privileged_execute_never: self.privileged_execute_never || descriptor & PXN_TABLE != 0,
+        }
     }
+}
 
-    if entry.is_table_or_page() {
-        translate_l3(device, entry_to_table(&entry), guest_virtual_address)
-    } else {
-        let mask = (1 << 21) - 1;
-        Some((entry.output_address().0 as u64 & !mask) | (guest_virtual_address & mask))
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Permissions {
+    read: bool,
+    write: bool,
+    execute: bool,
+}
+
+impl Permissions {
+    fn new(descriptor: u64, restrictions: TableRestrictions, el0: bool, wxn: bool) -> Self {
+        let el0_accessible = descriptor & AP_EL0 != 0 && !restrictions.no_el0;
+        let read_only = descriptor & AP_READ_ONLY != 0 || restrictions.read_only;
+
+        let read = !el0 || el0_accessible;
+        let write = read && !read_only;
+
+        let execute = if el0 {
+            descriptor & UXN == 0 && !restrictions.unprivileged_execute_never
+        } else {
+            // memory writable from EL0 is never executable at EL1
+            descriptor & PXN == 0
+                && !restrictions.privileged_execute_never
+                && !(el0_accessible && !read_only)
+        };
+
+        Self {
+            read,
+            write,
+            execute: execute && !(wxn && write),
+        }
+    }
+
+    /// Whether any access permitted by these is not permitted by `other`
+    fn exceed(&self, other: &Self) -> bool {
+        (self.read && !other.read)
+            || (self.write && !other.write)
+            || (self.execute && !other.execute)
+    }
+
+    fn permits(&self, access: Access) -> bool {
+        match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Execute => self.execute,
+            Access::Inspect => true,
+        }
     }
 }
 
-fn translate_l3(
+/// Translates a guest virtual address to a guest physical address for the
+/// supplied access from the current exception level
+///
+/// Walks the stage 1 EL1&0 tables configured by TCR_EL1, setting the access
+/// flag and dirty state of the final descriptor if managed by hardware. The
+/// caller decides whether to deliver a fault to the guest as an abort.
+///
+/// Data accesses are only checked when the host maps the page, so mappings
+/// made from EL1 that permit more than EL0 may access are marked privileged,
+/// to be dropped on returning to EL0.
+pub fn guest_translate(
     device: &ModelDevice,
-    table: &[Descriptor; 512],
     guest_virtual_address: u64,
-) -> Option<u64> {
-    let entry_idx = ((guest_virtual_address >> 12) & 0x1ff) as usize;
-    log::trace!("l3 entry_idx: {entry_idx:x?}");
-    let entry = table[entry_idx];
-    log::trace!("l3 entry: {entry:x?}");
-
-    if entry.is_table_or_page() {
-        Some((entry.output_address().0 as u64) | (guest_virtual_address & ((1 << 12) - 1)))
-    } else {
-        log::warn!("invalid L3");
-        guest_page_fault(device, guest_virtual_address);
-        None
+    access: Access,
+) -> Result<Mapping, Fault> {
+    let sctlr = device.register_file.read::<u64>("SCTLR_EL1_bits");
+    let mmu_enabled = sctlr & 1 == 1;
+    if !mmu_enabled {
+        return Ok(Mapping {
+            physical_address: guest_virtual_address,
+            writable: true,
+            size: 0x1000,
+            global: true,
+            privileged: false,
+        });
     }
+
+    let walk = Walk::new(
+        device.register_file.read::<u64>("TCR_EL1_bits"),
+        device.register_file.read::<u64>("_TTBR0_EL1_bits"),
+        device.register_file.read::<u64>("_TTBR1_EL1_bits"),
+        guest_virtual_address,
+    )?;
+    log::trace!("guest_virtual_address: {guest_virtual_address:x}, walk: {walk:x?}");
+
+    let mut level = walk.start_level();
+    let mut table = walk.base;
+    let mut restrictions = TableRestrictions::default();
+
+    if walk.exceeds_output_size(table) {
+        return Err(Fault::AddressSize { level });
+    }
+
+    let (descriptor, descriptor_address) = loop {
+        let descriptor_address = table + walk.descriptor_offset(level, guest_virtual_address);
+        let descriptor =
+            read_descriptor(descriptor_address).ok_or(Fault::TableWalkExternal { level })?;
+        log::trace!("l{level} descriptor @ {descriptor_address:x}: {descriptor:x}");
+
+        if descriptor & VALID == 0 {
+            return Err(Fault::Translation { level });
+        }
+
+        if level == 3 {
+            // the table encoding is used for pages, and the block encoding is reserved
+            if descriptor & TABLE_OR_PAGE == 0 {
+                return Err(Fault::Translation { level });
+            }
+            break (descriptor, descriptor_address);
+        }
+
+        if descriptor & TABLE_OR_PAGE == 0 {
+            if !walk.granule.block_permitted(level, walk.lpa2) {
+                return Err(Fault::Translation { level });
+            }
+            break (descriptor, descriptor_address);
+        }
+
+        if walk.hierarchical_permissions {
+            restrictions = restrictions.descend(descriptor);
+        }
+
+        table = walk.output_address(descriptor, walk.granule.shift());
+        if walk.exceeds_output_size(table) {
+            return Err(Fault::AddressSize { level });
+        }
+
+        level += 1;
+    };
+
+    let shift = walk.level_shift(level);
+    let physical_address =
+        walk.output_address(descriptor, shift) | (guest_virtual_address & ((1 << shift) - 1));
+    if walk.exceeds_output_size(physical_address) {
+        return Err(Fault::AddressSize { level });
+    }
+
+    let el0 = device.register_file.read::<u8>("PSTATE_EL") == 0;
+    let wxn = sctlr & SCTLR_WXN != 0;
+    let mut permissions = Permissions::new(descriptor, restrictions, el0, wxn);
+
+    let size = 1 << shift;
+    let global = descriptor & NOT_GLOBAL == 0;
+
+    if access == Access::Inspect {
+        return Ok(Mapping {
+            physical_address,
+            writable: permissions.write,
+            size,
+            global,
+            privileged: false,
+        });
+    }
+
+    let mut updated = descriptor;
+
+    if descriptor & ACCESS_FLAG == 0 {
+        if !walk.hardware_access {
+            return Err(Fault::AccessFlag { level });
+        }
+        updated |= ACCESS_FLAG;
+    }
+
+    // writes to clean pages whose dirty state is managed by hardware mark them
+    // dirty by making them writable
+    if access == Access::Write
+        && !permissions.write
+        && walk.hardware_dirty
+        && descriptor & DIRTY_BIT_MODIFIER != 0
+    {
+        let dirty = Permissions::new(descriptor & !AP_READ_ONLY, restrictions, el0, wxn);
+        if dirty.write {
+            updated &= !AP_READ_ONLY;
+            permissions = dirty;
+        }
+    }
+
+    if !permissions.permits(access) {
+        return Err(Fault::Permission { level });
+    }
+
+    if updated != descriptor {
+        log::trace!("updating descriptor @ {descriptor_address:x}: {updated:x}");
+        unsafe {
+            guest_physical_to_host_virt(descriptor_address)
+                .as_mut_ptr::<u64>()
+                .write_volatile(updated)
+        };
+    }
+
+    let privileged =
+        !el0 && permissions.exceed(&Permissions::new(updated, restrictions, true, wxn));
+
+    Ok(Mapping {
+        physical_address,
+        writable: permissions.write,
+        size,
+        global,
+        privileged,
+    })
 }
 
-fn entry_to_table(entry: &Descriptor) -> &[Descriptor; 512] {
-    unsafe {
-        &*guest_physical_to_host_virt(entry.output_address().0 as u64).as_ptr::<[Descriptor; 512]>()
+/// ASID of the current translations, held by TTBR1_EL1 or TTBR0_EL1 as selected
+/// by TCR_EL1.A1
+pub fn current_asid(device: &ModelDevice) -> u16 {
+    let tcr = device.register_file.read::<u64>("TCR_EL1_bits");
+    let ttbr = device.register_file.read::<u64>(if tcr & TCR_A1 != 0 {
+        "_TTBR1_EL1_bits"
+    } else {
+        "_TTBR0_EL1_bits"
+    });
+
+    ((ttbr >> 48) as u16) & asid_mask(device)
+}
+
+/// Valid bits of ASIDs, 16 or 8 depending on TCR_EL1.AS
+pub fn asid_mask(device: &ModelDevice) -> u16 {
+    if device.register_file.read::<u64>("TCR_EL1_bits") & TCR_AS != 0 {
+        u16::MAX
+    } else {
+        u16::from(u8::MAX)
     }
 }
 
-fn guest_page_fault(device: &ModelDevice, guest_virtual_address: u64) {
-    log::warn!("guest page fault @ {guest_virtual_address}");
+/// Reads the descriptor at the supplied guest physical address, if it is in
+/// RAM
+fn read_descriptor(address: u64) -> Option<u64> {
+    let address_space = unsafe { &*GuestExecutionContext::current().current_address_space };
+    let region = address_space.find_region(address)?;
+
+    matches!(region.kind(), AddressSpaceRegionKind::Ram).then(|| unsafe {
+        guest_physical_to_host_virt(address)
+            .as_ptr::<u64>()
+            .read_volatile()
+    })
+}
+
+/// Takes a data abort for the access to `guest_virtual_address` made by the
+/// current instruction, resuming the block execution loop at the exception
+/// vector
+///
+/// Must be called while handling the host page fault raised by the access.
+pub fn data_abort(
+    device: &ModelDevice,
+    fault: Fault,
+    guest_virtual_address: u64,
+    write: bool,
+) -> ! {
+    log::debug!("data abort ({fault:?}) @ {guest_virtual_address:#x}");
 
+    // WnR
+    let syndrome = fault.status_code() | (u32::from(write) << 6);
     let retaddr = device.register_file.read::<u64>("_PC");
 
-    take_arm_exception(device, 1, 1, 0, guest_virtual_address, retaddr, 0);
+    take_arm_exception(device, 1, 1, syndrome, guest_virtual_address, retaddr, 0);
 
-    interrupt_restore_safepoint(1);
+    interrupt_restore_safepoint(GuestExecutionContext::current().safepoint.get(), 1);
+}
+
+/// Takes an instruction abort for fetching the instruction at
+/// `guest_virtual_address`, the current PC
+pub fn instruction_abort(device: &ModelDevice, fault: Fault, guest_virtual_address: u64) {
+    log::debug!("instruction abort ({fault:?}) @ {guest_virtual_address:#x}");
+
+    take_arm_exception(
+        device,
+        1,
+        4,
+        fault.status_code(),
+        guest_virtual_address,
+        guest_virtual_address,
+        0,
+    );
 }
 
 pub fn take_arm_exception(
@@ -206,11 +680,12 @@ pub fn take_arm_exception(
     device.register_file.write::<u8>("PSTATE_I", 1);
     device.register_file.write::<u8>("PSTATE_F", 1);
 
-    let vbar = device.register_file.read::<u64>(match current_el {
+    // exceptions from EL0 are taken to the vector table of the target EL
+    let vbar = device.register_file.read::<u64>(match target_el {
         1 => "VBAR_EL1",
         2 => "VBAR_EL2",
         3 => "VBAR_EL3",
-        _ => exit_with_message!("invalid EL \"{current_el}\""),
+        _ => exit_with_message!("invalid EL \"{target_el}\""),
     });
     log::trace!("vbar: {:x}", vbar);
 
@@ -218,7 +693,7 @@ pub fn take_arm_exception(
     device.register_file.write::<u64>("_PC", vbar + voff);
 }
 
-fn get_psr_from_pstate(device: &ModelDevice) -> u32 {
+pub fn get_psr_from_pstate(device: &ModelDevice) -> u32 {
     let n = device.register_file.read::<u8>("PSTATE_N") as u32;
     let z = device.register_file.read::<u8>("PSTATE_Z") as u32;
     let c = device.register_file.read::<u8>("PSTATE_C") as u32;
@@ -233,6 +708,30 @@ fn get_psr_from_pstate(device: &ModelDevice) -> u32 {
     n << 31 | z << 30 | c << 29 | v << 28 | d << 9 | a << 8 | i << 7 | f << 6 | el << 2 | sp
 }
 
+pub fn set_pstate_from_psr(device: &ModelDevice, psr: u32) {
+    [
+        ("PSTATE_N", 31),
+        ("PSTATE_Z", 30),
+        ("PSTATE_C", 29),
+        ("PSTATE_V", 28),
+        ("PSTATE_D", 9),
+        ("PSTATE_A", 8),
+        ("PSTATE_I", 7),
+        ("PSTATE_F", 6),
+        ("PSTATE_SP", 0),
+    ]
+    .into_iter()
+    .for_each(|(field, bit)| {
+        device
+            .register_file
+            .write::<u8>(field, ((psr >> bit) & 1) as u8)
+    });
+
+    device
+        .register_file
+        .write::<u8>("PSTATE_EL", ((psr >> 2) & 0b11) as u8);
+}
+
 fn get_exception_class(current_el: u8, target_el: u8, typ: u8) -> u32 {
     match typ {
         0 => {
@@ -280,3 +779,93 @@ fn l1_entry_suspicious() {
 
     //    log::error!("{desc:?}: {:?}", desc.flags());
 }
+
+#[ktest]
+fn fault_status_codes() {
+    assert_eq!(Fault::Translation { level: 0 }.status_code(), 0b00_0100);
+    assert_eq!(Fault::Translation { level: 3 }.status_code(), 0b00_0111);
+    assert_eq!(Fault::Translation { level: -1 }.status_code(), 0b10_1011);
+    assert_eq!(Fault::AddressSize { level: 1 }.status_code(), 0b00_0001);
+    assert_eq!(Fault::AccessFlag { level: 3 }.status_code(), 0b00_1011);
+    assert_eq!(Fault::Permission { level: 2 }.status_code(), 0b00_1110);
+    assert_eq!(Fault::External.status_code(), 0b01_0000);
+    assert_eq!(
+        Fault::TableWalkExternal { level: 1 }.status_code(),
+        0b01_0101
+    );
+}
+
+#[ktest]
+fn walk_configuration() {
+    // Linux defaults: 48-bit VAs with 4K granules in both ranges, 48-bit PAs, top
+    // byte ignored in the lower range
+    let tcr = 0x25_b510_3510;
+    let walk = Walk::new(tcr, 0x4000_1000, 0x8000_2001, 0xffff_ffc0_1000_0000).unwrap();
+    assert_eq!(walk.base, 0x8000_2000);
+    assert_eq!(walk.granule, Granule::Size4KiB);
+    assert_eq!(walk.input_size, 48);
+    assert_eq!(walk.output_size, 48);
+    assert_eq!(walk.start_level(), 0);
+    assert_eq!(walk.descriptor_offset(0, 0xffff_ffc0_1000_0000), 0x1ff * 8);
+
+    let walk = Walk::new(tcr, 0x4000_1000, 0x8000_2001, 0x5a00_0000_ffff_f000).unwrap();
+    assert_eq!(walk.base, 0x4000_1000);
+
+    // 48-bit addresses beyond the 39 bits of each range accessible through the
+    // host would alias those of the other range
+    assert_eq!(
+        Walk::new(tcr, 0x4000_1000, 0x8000_2001, 0xffff_8000_1000_0000),
+        Err(Fault::Translation { level: 0 })
+    );
+    assert_eq!(
+        Walk::new(tcr, 0x4000_1000, 0x8000_2001, 0x0000_0080_1000_0000),
+        Err(Fault::Translation { level: 0 })
+    );
+
+    // between the two ranges
+    assert_eq!(
+        Walk::new(tcr, 0, 0, 0x0001_0000_0000_0000),
+        Err(Fault::Translation { level: 0 })
+    );
+
+    // 39-bit VAs with 64K granules resolve 13 bits per level from level 2
+    let tcr = (0b01 << 14) | 25;
+    let walk = Walk::new(tcr, 0x1_0000, 0, 0x40_0001_2345).unwrap();
+    assert_eq!(walk.granule, Granule::Size64KiB);
+    assert_eq!(walk.start_level(), 2);
+    assert_eq!(walk.level_shift(2), 29);
+    assert_eq!(walk.descriptor_offset(2, 0x40_0001_2345), 0x200 * 8);
+    assert_eq!(walk.descriptor_offset(3, 0x40_0001_2345), 8);
+
+    // 52-bit VAs with 4K granules need a fifth level
+    let tcr = (1 << 59) | (0b110 << 32) | 12;
+    assert_eq!(Walk::new(tcr, 0, 0, 0).unwrap().start_level(), -1);
+}
+
+#[ktest]
+fn descriptor_permissions() {
+    let none = TableRestrictions::default();
+    let kernel_data = VALID | ACCESS_FLAG | PXN | UXN;
+    let user_code = VALID | ACCESS_FLAG | AP_EL0 | AP_READ_ONLY | PXN;
+
+    let permissions = Permissions::new(kernel_data, none, false, false);
+    assert!(permissions.read && permissions.write && !permissions.execute);
+    let permissions = Permissions::new(kernel_data, none, true, false);
+    assert!(!permissions.read && !permissions.write);
+
+    let permissions = Permissions::new(user_code, none, true, false);
+    assert!(permissions.read && !permissions.write && permissions.execute);
+    let restricted = none.descend(UXN_TABLE | AP_TABLE_NO_EL0);
+    let permissions = Permissions::new(user_code, restricted, true, false);
+    assert!(!permissions.read && !permissions.execute);
+
+    // EL1 may access kernel data that EL0 may not, but not user code
+    let user = Permissions::new(kernel_data, none, true, false);
+    assert!(Permissions::new(kernel_data, none, false, false).exceed(&user));
+    let user = Permissions::new(user_code, none, true, false);
+    assert!(!Permissions::new(user_code, none, false, false).exceed(&user));
+
+    // writable memory is not executable with SCTLR_EL1.WXN set
+    let permissions = Permissions::new(VALID | ACCESS_FLAG, none, false, true);
+    assert!(permissions.write && !permissions.execute);
+}
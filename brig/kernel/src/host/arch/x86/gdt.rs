@@ -1,6 +1,5 @@
 use {
-    core::ptr::addr_of,
-    spin::Once,
+    alloc::{boxed::Box, vec},
     x86_64::{
         VirtAddr,
         instructions::{
@@ -17,8 +16,7 @@ use {
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-static TSS: Once<TaskStateSegment> = Once::INIT;
-static GDT: Once<(GlobalDescriptorTable, Selectors)> = Once::INIT;
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
 
 struct Selectors {
     kernel_code_selector: SegmentSelector,
@@ -30,46 +28,41 @@ struct Selectors {
     tss_selector: SegmentSelector,
 }
 
+/// Loads a descriptor table and TSS for the current core
+///
+/// Each core needs its own, as loading a TSS marks its descriptor busy and a
+/// busy TSS cannot be loaded again by another core.
 pub fn init() {
-    TSS.call_once(|| {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+    let tss = Box::leak(Box::new(TaskStateSegment::new()));
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+        let stack = vec![0u8; DOUBLE_FAULT_STACK_SIZE].leak();
 
-            let stack_start = VirtAddr::from_ptr(addr_of!(STACK));
-            stack_start + u64::try_from(STACK_SIZE).unwrap() // stack end
-        };
-        tss
-    });
+        let stack_start = VirtAddr::from_ptr(stack.as_ptr());
+        stack_start + u64::try_from(DOUBLE_FAULT_STACK_SIZE).unwrap() // stack end
+    };
 
-    GDT.call_once(|| {
-        let mut gdt = GlobalDescriptorTable::new();
-        let kernel_code_selector = gdt.append(Descriptor::kernel_code_segment());
-        let kernel_data_selector = gdt.append(Descriptor::kernel_data_segment());
-        let user_data_selector = gdt.append(Descriptor::user_data_segment());
-        let user_code_selector = gdt.append(Descriptor::user_code_segment());
-        let tss_selector = gdt.append(Descriptor::tss_segment(TSS.get().unwrap()));
-        (
-            gdt,
-            Selectors {
-                kernel_code_selector,
-                kernel_data_selector,
-                user_data_selector,
-                user_code_selector,
-                tss_selector,
-            },
-        )
-    });
+    let mut gdt = GlobalDescriptorTable::new();
+    let kernel_code_selector = gdt.append(Descriptor::kernel_code_segment());
+    let kernel_data_selector = gdt.append(Descriptor::kernel_data_segment());
+    let user_data_selector = gdt.append(Descriptor::user_data_segment());
+    let user_code_selector = gdt.append(Descriptor::user_code_segment());
+    let tss_selector = gdt.append(Descriptor::tss_segment(tss));
+    let selectors = Selectors {
+        kernel_code_selector,
+        kernel_data_selector,
+        user_data_selector,
+        user_code_selector,
+        tss_selector,
+    };
 
-    GDT.get().unwrap().0.load();
+    Box::leak(Box::new(gdt)).load();
     unsafe {
-        CS::set_reg(GDT.get().unwrap().1.kernel_code_selector);
-        DS::set_reg(GDT.get().unwrap().1.kernel_data_selector);
-        ES::set_reg(GDT.get().unwrap().1.kernel_data_selector);
-        FS::set_reg(GDT.get().unwrap().1.kernel_data_selector);
-        GS::set_reg(GDT.get().unwrap().1.kernel_data_selector);
-        SS::set_reg(GDT.get().unwrap().1.kernel_data_selector);
-        load_tss(GDT.get().unwrap().1.tss_selector);
+        CS::set_reg(selectors.kernel_code_selector);
+        DS::set_reg(selectors.kernel_data_selector);
+        ES::set_reg(selectors.kernel_data_selector);
+        FS::set_reg(selectors.kernel_data_selector);
+        GS::set_reg(selectors.kernel_data_selector);
+        SS::set_reg(selectors.kernel_data_selector);
+        load_tss(selectors.tss_selector);
     }
 }
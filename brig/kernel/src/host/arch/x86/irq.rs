@@ -4,29 +4,30 @@ use {
         host::{
             arch::x86::{
                 MachineContext,
-                aarch64_mmu::guest_translate,
+                aarch64_mmu::{Access, Fault, current_asid, data_abort, guest_translate},
                 dbg,
                 memory::{
                     GUEST_PHYSICAL_START, LOW_HALF_CANONICAL_END, VirtAddrExt, VirtualMemoryArea,
                 },
             },
-            dbt::models::ModelDevice,
+            dbt::{replay, smc, tlb},
         },
         qemu_exit,
     },
     alloc::alloc::alloc_zeroed,
     bitset_core::BitSet,
     common::intern::InternedString,
-    core::{alloc::Layout, any::Any},
+    core::alloc::Layout,
     iced_x86::{Code, OpKind, Register},
     proc_macro_lib::irq_handler,
-    spin::Once,
+    spin::{Mutex, Once},
     x86::irq::{
         BREAKPOINT_VECTOR, DEBUG_VECTOR, DIVIDE_ERROR_VECTOR, DOUBLE_FAULT_VECTOR,
         GENERAL_PROTECTION_FAULT_VECTOR, PAGE_FAULT_VECTOR,
     },
     x86_64::{
-        VirtAddr,
+        PhysAddr, VirtAddr,
+        instructions::interrupts::without_interrupts,
         registers::control::Cr2,
         structures::{
             idt::{InterruptDescriptorTable, PageFaultErrorCode},
@@ -51,6 +52,9 @@ pub(crate) use exit_with_message;
 
 static mut IRQ_MANAGER: Once<IrqManager> = Once::INIT;
 
+/// Held while backing guest RAM pages
+static GUEST_RAM_LOCK: Mutex<()> = Mutex::new(());
+
 pub fn init() {
     unsafe {
         IRQ_MANAGER.call_once(|| IrqManager::new());
@@ -60,6 +64,12 @@ pub fn init() {
     };
 }
 
+/// Loads the interrupt descriptor table on a core started after [`init`], which
+/// is shared by every core
+pub fn init_local() {
+    unsafe { IRQ_MANAGER.get_mut() }.unwrap().idt.load();
+}
+
 pub fn assign_irq(nr: u8, handler: IrqHandlerFn) -> Result<(), IrqError> {
     let irqm = unsafe { IRQ_MANAGER.get_mut() }.unwrap();
     irqm.assign_irq(nr, handler)?;
@@ -172,40 +182,39 @@ fn page_fault_exception(machine_context: *mut MachineContext) {
         let exec_ctx = crate::guest::GuestExecutionContext::current();
         let addrspace = unsafe { &*exec_ctx.current_address_space };
 
-        let device = ((&**unsafe {
-            crate::guest::GUEST
-                .get()
-                .unwrap()
-                .devices
-                .get(&("core0".into()))
-                .unwrap()
-        }) as &dyn Any)
-            .downcast_ref::<ModelDevice>()
-            .unwrap();
+        // no core is running in the boot execution context (tests, image loading), so
+        // treat accesses as untranslated
+        let device = unsafe { exec_ctx.current_core.as_ref() };
 
-        let pc = device.register_file.read::<u64>("_PC");
-        log::debug!("PC = {pc:016x}");
+        let mmu_enabled = device.is_some_and(|device| {
+            let pc = device.register_file.read::<u64>("_PC");
+            log::debug!("core {} PC = {pc:016x}", device.core_id());
 
-        let mmu_enabled = device.register_file.read::<u64>("SCTLR_EL1_bits") & 1 == 1;
+            device.register_file.read::<u64>("SCTLR_EL1_bits") & 1 == 1
+        });
 
         // correct the address as it was masked off in emitter.rs:read/write-memory
         let unmasked_address =
             VirtAddr::new((((faulting_address.as_u64() as i64) << 24) >> 24) as u64);
 
-        let guest_physical = if mmu_enabled {
-            // translate:
-            // * walk guest page tables from top level page table translate faulting address
-            // * if it doesnt exist: guest page fault
-            // * if it does exist but is invalid (write to a read only mapped page)
-            // * or it works, we get a guest physical address, we do the next logic on line
-            //   186 and map it as writeable, but if it was a read then map as read only
-            // * map that guest physical address into the correct location in host virtual
-            //   memory
-
-            guest_translate(device, unmasked_address.as_u64()).unwrap()
-        } else {
-            unmasked_address.as_u64()
-        };
+        let write = error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+
+        let tlb_generation = tlb::generation();
+
+        // pages the guest may not write to from the current EL are mapped read-only, so a
+        // later write faults again to be permission checked or to mark the page dirty
+        let mapping = mmu_enabled.then(|| {
+            let device = device.unwrap();
+            let access = if write { Access::Write } else { Access::Read };
+
+            guest_translate(device, unmasked_address.as_u64(), access)
+                .unwrap_or_else(|fault| data_abort(device, fault, unmasked_address.as_u64(), write))
+        });
+
+        let (guest_physical, guest_writable) = mapping
+            .map_or((unmasked_address.as_u64(), true), |mapping| {
+                (mapping.physical_address, mapping.writable)
+            });
 
         log::debug!("guest physical: {guest_physical:x?}");
 
@@ -231,35 +240,13 @@ fn page_fault_exception(machine_context: *mut MachineContext) {
                         AddressSpaceRegionKind::Ram => {
                             // Physical address lies within a RAM-backed region, so allocate a
                             // backing page.
-                            let backing_page = VirtAddr::from_ptr(unsafe {
-                                alloc_zeroed(Layout::from_size_align(0x1000, 0x1000).unwrap())
-                            })
-                            .to_phys();
-
-                            // Map the allocated backing page into the 1-1 guest phyical memory area
-                            VirtualMemoryArea::current().map_page(
-                                Page::<Size4KiB>::from_start_address(
-                                    (GUEST_PHYSICAL_START + guest_physical).align_down(0x1000u64),
-                                )
-                                .unwrap(),
-                                PhysFrame::from_start_address(backing_page).unwrap(),
-                                PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-                            );
-
-                            log::debug!(
-                                "allocated backing page {backing_page:x?} -> {:x?}",
-                                (GUEST_PHYSICAL_START + guest_physical).align_down(0x1000u64)
-                            );
-
-                            backing_page
+                            allocate_guest_ram(guest_physical)
                         }
                         AddressSpaceRegionKind::IO(device) => {
                             log::debug!("guest device page fault at rip {:x}", machine_context.rip);
 
                             let offset = guest_physical - rgn.base();
 
-                            let write = error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
-
                             let data = unsafe { &*(machine_context.rip as *const [u8; 15]) };
 
                             let mut decoder = iced_x86::Decoder::new(64, data, 0);
@@ -368,7 +355,9 @@ fn page_fault_exception(machine_context: *mut MachineContext) {
 
                                 let mut bytes = alloc::vec![0; size ];
 
-                                device.read(offset, &mut bytes);
+                                replay::device_read(guest_physical, &mut bytes, |bytes| {
+                                    device.read(offset, bytes)
+                                });
 
                                 log::debug!("read {bytes:x?} from device, writing to {dest:?}");
 
@@ -405,8 +394,11 @@ fn page_fault_exception(machine_context: *mut MachineContext) {
                             return;
                         }
                     }
+                } else if let Some(device) = device {
+                    // Physical address not in valid guest region, an external abort on real
+                    // hardware
+                    data_abort(device, Fault::External, unmasked_address.as_u64(), write)
                 } else {
-                    // Physical address not in valid guest region -- real fault.
                     exit_with_message!(
                         "GUEST PAGE FAULT code {error_code:?} @ {guest_physical:x?}: no region -- this is a real fault"
                     )
@@ -423,16 +415,84 @@ fn page_fault_exception(machine_context: *mut MachineContext) {
             faulting_address.align_down(0x1000u64)
         );
 
-        VirtualMemoryArea::current().map_page_propagate_invalidation(
-            Page::<Size4KiB>::from_start_address(faulting_address.align_down(0x1000u64)).unwrap(),
-            PhysFrame::from_start_address(backing_page).unwrap(),
-            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        let page =
+            Page::<Size4KiB>::from_start_address(faulting_address.align_down(0x1000u64)).unwrap();
+
+        // code pages are mapped read-only, writing to one invalidates its translations
+        smc::map_guest_virtual(
+            guest_physical,
+            faulting_address,
+            write,
+            guest_writable,
+            |flags| {
+                VirtualMemoryArea::current().map_page_propagate_invalidation(
+                    page,
+                    PhysFrame::from_start_address(backing_page).unwrap(),
+                    flags,
+                )
+            },
         );
+
+        // recorded so that TLB maintenance by the guest only unmaps the pages it affects
+        if let (Some(device), Some(mapping)) = (device, mapping) {
+            tlb::insert(
+                unmasked_address.as_u64(),
+                &mapping,
+                device.core_id(),
+                current_asid(device),
+                Some((page, tlb_generation)),
+            );
+        }
+    } else if faulting_address >= GUEST_PHYSICAL_START
+        && error_code.contains(
+            PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE,
+        )
+        && smc::handle_guest_physical_write(faulting_address - GUEST_PHYSICAL_START)
+    {
+        // host wrote to a write-protected code page in the guest physical mapping,
+        // now writable again so retry
     } else {
         exit_with_message!("HOST PAGE FAULT code {error_code:?} @ {faulting_address:?}");
     }
 }
 
+/// Allocates a zeroed backing page for the guest RAM page containing
+/// `guest_physical` and maps it into the guest physical mapping
+///
+/// Cores may fault on the same page at once, so if another core has backed it
+/// in the meantime its backing page is returned instead.
+pub fn allocate_guest_ram(guest_physical: u64) -> PhysAddr {
+    let page = Page::<Size4KiB>::containing_address(GUEST_PHYSICAL_START + guest_physical);
+
+    without_interrupts(|| {
+        let _guard = GUEST_RAM_LOCK.lock();
+
+        let mut vma = VirtualMemoryArea::current();
+        if let Some(backing_page) = vma.opt.translate_addr(page.start_address()) {
+            return backing_page;
+        }
+
+        let backing_page = VirtAddr::from_ptr(unsafe {
+            alloc_zeroed(Layout::from_size_align(0x1000, 0x1000).unwrap())
+        })
+        .to_phys();
+
+        // Map the allocated backing page into the 1-1 guest phyical memory area
+        vma.map_page(
+            page,
+            PhysFrame::from_start_address(backing_page).unwrap(),
+            smc::guest_physical_flags(guest_physical),
+        );
+
+        log::debug!(
+            "allocated backing page {backing_page:x?} -> {:x?}",
+            page.start_address()
+        );
+
+        backing_page
+    })
+}
+
 #[irq_handler(with_code = false)]
 fn div0_exception() {
     exit_with_message!("EXCEPTION: DIVIDE BY 0");
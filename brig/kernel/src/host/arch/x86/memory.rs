@@ -1,5 +1,8 @@
 use {
-    crate::host::memory::bytes,
+    crate::host::{
+        arch::{CoreStorage, MAX_CORES},
+        memory::bytes,
+    },
     alloc::alloc::{Global, alloc_zeroed},
     bootloader_api::info::{MemoryRegionKind, MemoryRegions},
     buddy_system_allocator::LockedHeap,
@@ -8,9 +11,12 @@ use {
         alloc::{AllocError, Allocator, Layout},
         ops::{Deref, Range},
         ptr::NonNull,
+        sync::atomic::{AtomicU64, Ordering},
     },
+    spin::Mutex,
     x86_64::{
         PhysAddr, VirtAddr,
+        instructions::interrupts::without_interrupts,
         registers::control::{Cr3, Cr3Flags},
         structures::paging::{
             FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags,
@@ -27,6 +33,19 @@ pub const HIGH_HALF_CANONICAL_START: VirtAddr = VirtAddr::new_truncate(0xffff_80
 pub const HIGH_HALF_CANONICAL_END: VirtAddr = VirtAddr::new_truncate(0xffff_ffff_ffff_ffff);
 pub const PHYSICAL_MEMORY_OFFSET: VirtAddr = VirtAddr::new_truncate(0xffff_8180_0000_0000);
 pub const GUEST_PHYSICAL_START: VirtAddr = VirtAddr::new_truncate(0xffff_9000_0000_0000);
+/// Executable aliases of translated code pages, offset by host physical address
+pub const CODE_ALIAS_START: VirtAddr = VirtAddr::new_truncate(0xffff_a000_0000_0000);
+
+/// End of the low memory left out of the heap, which application processors
+/// start executing in
+pub const LOW_MEMORY_END: PhysAddr = PhysAddr::new_truncate(0x10_0000);
+
+/// Page tables of every started host core, indexed by core ID
+static CORE_PAGE_TABLES: [AtomicU64; MAX_CORES] = [const { AtomicU64::new(0) }; MAX_CORES];
+
+/// Held while adding mappings, as the tables of the kernel half are shared by
+/// every core and two cores must not create the same one
+static MAPPING_LOCK: Mutex<()> = Mutex::new(());
 
 pub fn guest_physical_to_host_virt(guest_physical: u64) -> VirtAddr {
     GUEST_PHYSICAL_START + guest_physical
@@ -44,7 +63,13 @@ pub fn heap_init(memory_regions: &MemoryRegions) {
         .iter()
         .filter(|r| matches!(r.kind, MemoryRegionKind::Usable))
     {
-        let region_virt_start = PhysAddr::new(region.start).to_virt();
+        // low memory is kept for the application processor trampoline
+        let start = region.start.max(LOW_MEMORY_END.as_u64());
+        if start >= region.end {
+            continue;
+        }
+
+        let region_virt_start = PhysAddr::new(start).to_virt();
         let region_virt_end = PhysAddr::new(region.end).to_virt();
 
         unsafe {
@@ -104,7 +129,11 @@ impl VirtualMemoryArea {
     }
 
     pub fn current() -> Self {
-        let pml4_base = Self::get_current_cr3();
+        Self::from_pml4(Self::get_current_cr3())
+    }
+
+    /// Page tables rooted at `pml4_base`, which may be those of another core
+    pub fn from_pml4(pml4_base: PhysAddr) -> Self {
         let pml4_virt = pml4_base.to_virt();
         let pml4_table = unsafe { &mut *(pml4_virt.as_mut_ptr()) };
 
@@ -114,7 +143,62 @@ impl VirtualMemoryArea {
         }
     }
 
+    /// Page tables of every started host core
+    pub fn every_core() -> impl Iterator<Item = Self> {
+        CORE_PAGE_TABLES
+            .iter()
+            .map(|pml4_base| pml4_base.load(Ordering::Acquire))
+            .filter(|pml4_base| *pml4_base != 0)
+            .map(|pml4_base| Self::from_pml4(PhysAddr::new(pml4_base)))
+    }
+
+    /// Records the current page tables as those of the current core
+    pub fn register_current_core() {
+        CORE_PAGE_TABLES[CoreStorage::id()]
+            .store(Self::get_current_cr3().as_u64(), Ordering::Release);
+    }
+
+    /// Creates page tables for another core, sharing the kernel half of these
+    /// but with a guest half of its own, so that each core can map the guest
+    /// addresses of whichever translation regime it is executing in
+    pub fn share_kernel_half(&mut self) -> Self {
+        without_interrupts(|| {
+            let _guard = MAPPING_LOCK.lock();
+
+            // every kernel half entry must point to a table before being
+            // copied, so that mappings later added through any core's tables
+            // reach all of them
+            for entry in self.opt.level_4_table_mut().iter_mut().skip(0x100) {
+                if entry.is_unused() {
+                    let frame = HeapStealingFrameAllocator.allocate_frame().unwrap();
+                    entry.set_addr(
+                        frame.start_address(),
+                        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                    );
+                }
+            }
+
+            let frame = HeapStealingFrameAllocator.allocate_frame().unwrap();
+            let table = unsafe { &mut *frame.start_address().to_virt().as_mut_ptr::<PageTable>() };
+
+            table
+                .iter_mut()
+                .zip(self.opt.level_4_table().iter())
+                .skip(0x100)
+                .for_each(|(entry, shared)| *entry = shared.clone());
+
+            Self::from_pml4(frame.start_address())
+        })
+    }
+
     pub fn invalidate_guest_mappings(&mut self) {
+        self.clear_guest_mappings();
+        self.invalidate();
+    }
+
+    /// Unmaps the whole guest half without flushing the TLB, for the page
+    /// tables of another core
+    pub fn clear_guest_mappings(&mut self) {
         self.opt
             .level_4_table_mut()
             .iter_mut()
@@ -124,7 +208,13 @@ impl VirtualMemoryArea {
                 flags.remove(PageTableFlags::PRESENT);
                 e.set_flags(flags)
             });
-        self.invalidate();
+    }
+
+    /// Unmaps a single 4K page of the guest half, if it is mapped
+    pub fn unmap_guest_page(&mut self, page: Page<Size4KiB>) {
+        if let Ok((_, flush)) = self.opt.unmap(page) {
+            flush.flush();
+        }
     }
 
     pub fn invalidate(&self) {
@@ -149,12 +239,16 @@ impl VirtualMemoryArea {
     ) where
         OffsetPageTable<'static>: Mapper<S>,
     {
-        unsafe {
-            let _flush = self
-                .opt
-                .map_to(page, frame, flags, &mut HeapStealingFrameAllocator)
-                .unwrap();
-        }
+        without_interrupts(|| {
+            let _guard = MAPPING_LOCK.lock();
+
+            unsafe {
+                let _flush = self
+                    .opt
+                    .map_to(page, frame, flags, &mut HeapStealingFrameAllocator)
+                    .unwrap();
+            }
+        });
     }
 
     pub fn map_page_propagate_invalidation<S: PageSize + core::fmt::Debug>(
@@ -178,6 +272,18 @@ impl VirtualMemoryArea {
         l1_entry.set_addr(frame.start_address(), flags);
     }
 
+    /// Updates the flags of a single mapped 4K page, returning false if it is
+    /// not currently mapped
+    pub fn update_page_flags(&mut self, page: Page<Size4KiB>, flags: PageTableFlags) -> bool {
+        match unsafe { self.opt.update_flags(page, flags) } {
+            Ok(flush) => {
+                flush.flush();
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
     pub fn translate_address(&self, addr: VirtAddr) -> Option<PhysAddr> {
         let r = self.opt.translate_addr(addr);
 
@@ -1,11 +1,12 @@
 use {
     crate::host::{
-        arch::x86::memory::PHYSICAL_MEMORY_OFFSET,
+        arch::x86::memory::{PHYSICAL_MEMORY_OFFSET, PhysAddrExt},
         devices::{self, Bus, acpi, lapic},
     },
     bootloader_api::BootInfo,
-    core::fmt::Display,
+    core::{fmt::Display, slice},
     log::trace,
+    spin::Once,
     x86::controlregs::{Cr0, Cr4, cr0, cr0_write, cr4, cr4_write},
     x86_64::{
         PhysAddr, VirtAddr,
@@ -20,6 +21,10 @@ mod gdt;
 pub mod irq;
 pub mod memory;
 pub mod safepoint;
+pub mod smp;
+
+/// Kernel ELF as loaded by the bootloader
+static KERNEL_IMAGE: Once<&'static [u8]> = Once::new();
 
 pub fn init(
     BootInfo {
@@ -50,6 +55,13 @@ pub fn init(
         usize::try_from(*kernel_len).unwrap(),
     );
 
+    KERNEL_IMAGE.call_once(|| unsafe {
+        slice::from_raw_parts(
+            PhysAddr::new(*kernel_addr).to_virt().as_ptr(),
+            usize::try_from(*kernel_len).unwrap(),
+        )
+    });
+
     // update control-regs
     update_cregs();
 
@@ -61,6 +73,7 @@ pub fn init(
     gdt::init();
     irq::init();
     dbg::init();
+    smp::init(memory_regions);
 
     // initialize device manager ready to register detected devices
     devices::manager::init();
@@ -71,20 +84,30 @@ pub fn init(
     });
 }
 
+/// Returns the kernel ELF, used to identify the running kernel build
+pub fn kernel_image() -> &'static [u8] {
+    KERNEL_IMAGE.get().expect("kernel image not initialized")
+}
+
 fn update_cregs() {
-    // enable wp
+    // enable wp, and let SSE instructions execute natively
     let mut cr0 = unsafe { cr0() };
-    cr0 |= Cr0::CR0_WRITE_PROTECT;
+    cr0 |= Cr0::CR0_WRITE_PROTECT | Cr0::CR0_MONITOR_COPROCESSOR;
+    cr0 &= !Cr0::CR0_EMULATE_COPROCESSOR;
 
     trace!("cr0={cr0:?}");
     unsafe {
         cr0_write(cr0);
     }
 
-    // enable fsgsbase, pse, pge
+    // enable fsgsbase, pse, pge, and fxsave/sse with unmasked simd exceptions
     let mut cr4 = unsafe { cr4() };
 
-    cr4 |= Cr4::CR4_ENABLE_FSGSBASE | Cr4::CR4_ENABLE_PSE | Cr4::CR4_ENABLE_GLOBAL_PAGES;
+    cr4 |= Cr4::CR4_ENABLE_FSGSBASE
+        | Cr4::CR4_ENABLE_PSE
+        | Cr4::CR4_ENABLE_GLOBAL_PAGES
+        | Cr4::CR4_ENABLE_SSE
+        | Cr4::CR4_UNMASKED_SSE;
     cr4 &= !Cr4::CR4_ENABLE_SMEP;
     trace!("cr4={cr4:?}");
 
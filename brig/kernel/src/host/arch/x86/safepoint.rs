@@ -63,18 +63,6 @@ global_asm!(
     options(att_syntax)
 );
 
-static mut SAFEPOINT: SafepointContext = SafepointContext {
-    rbx: 0,
-    rsp: 0,
-    rbp: 0,
-    r12: 0,
-    r13: 0,
-    r14: 0,
-    r15: 0,
-    rip: 0,
-    rflags: 0,
-};
-
 #[repr(C)]
 pub struct SafepointContext {
     rbx: u64,
@@ -88,6 +76,22 @@ pub struct SafepointContext {
     rflags: u64,
 }
 
+impl SafepointContext {
+    pub const fn empty() -> Self {
+        Self {
+            rbx: 0,
+            rsp: 0,
+            rbp: 0,
+            r12: 0,
+            r13: 0,
+            r14: 0,
+            r15: 0,
+            rip: 0,
+            rflags: 0,
+        }
+    }
+}
+
 unsafe extern "C" {
     fn record_safepoint_raw(safepoint: *mut SafepointContext) -> u32;
     fn restore_safepoint_raw(safepoint: *const SafepointContext, return_value: u32);
@@ -95,25 +99,27 @@ unsafe extern "C" {
 }
 
 #[inline(never)]
-pub fn record_safepoint() -> u32 {
-    unsafe { record_safepoint_raw(&mut SAFEPOINT as *mut _) }
+pub fn record_safepoint(safepoint: *mut SafepointContext) -> u32 {
+    unsafe { record_safepoint_raw(safepoint) }
 }
 
-pub fn restore_safepoint(return_value: u32) -> ! {
-    unsafe { restore_safepoint_raw(&SAFEPOINT as *const _, return_value) }
+pub fn restore_safepoint(safepoint: *const SafepointContext, return_value: u32) -> ! {
+    unsafe { restore_safepoint_raw(safepoint, return_value) }
     unreachable!()
 }
 
-pub fn interrupt_restore_safepoint(return_value: u32) -> ! {
-    unsafe { interrupt_restore_safepoint_raw(&SAFEPOINT as *const _, return_value) }
+pub fn interrupt_restore_safepoint(safepoint: *const SafepointContext, return_value: u32) -> ! {
+    unsafe { interrupt_restore_safepoint_raw(safepoint, return_value) }
     unreachable!()
 }
 
 #[ktest]
 fn safepoint_basic() {
-    let result = record_safepoint();
+    let mut safepoint = SafepointContext::empty();
+
+    let result = record_safepoint(&mut safepoint);
 
     if result == 0 {
-        restore_safepoint(1);
+        restore_safepoint(&safepoint, 1);
     }
 }
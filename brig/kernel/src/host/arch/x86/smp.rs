@@ -0,0 +1,370 @@
+//! Startup of the application processors, and TLB shootdown between cores
+//!
+//! Each application processor is sent INIT and startup IPIs in turn, and starts
+//! executing the trampoline in real mode at [`TRAMPOLINE`]. The trampoline
+//! enters long mode directly, using page tables that identity map low memory
+//! and share the kernel half, then calls [`ap_entry`] on a stack allocated for
+//! the core. That switches to page tables of the core's own, which share the
+//! kernel half with every other core but have a guest half of their own.
+//!
+//! A core that unmaps or write-protects pages in the tables of another core, or
+//! in the shared kernel half, flushes the TLBs of every other core with
+//! [`flush_remote_tlbs`] before relying on the change.
+
+use {
+    crate::host::{
+        arch::{
+            CoreStorage, MAX_CORES,
+            x86::{
+                gdt,
+                irq::{self, assign_irq},
+                memory::{LOW_MEMORY_END, PhysAddrExt, VirtualMemoryArea},
+            },
+        },
+        devices::{
+            acpi,
+            lapic::{self, LAPIC},
+            pit::{self, PIT_FREQUENCY},
+        },
+    },
+    alloc::vec,
+    bootloader_api::info::{MemoryRegionKind, MemoryRegions},
+    core::{
+        arch::global_asm,
+        hint::spin_loop,
+        ptr,
+        sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+    proc_macro_lib::irq_handler,
+    spin::Once,
+    x2apic::lapic::IpiAllShorthand,
+    x86_64::{
+        PhysAddr,
+        instructions::{interrupts::without_interrupts, tlb},
+        structures::paging::{PageTable, PageTableFlags},
+    },
+};
+
+/// Physical address the trampoline is copied to, application processors start
+/// executing it in real mode
+const TRAMPOLINE: u64 = 0x8000;
+/// Physical address of the [`Parameters`] of the core being started
+const PARAMETERS: u64 = 0x9000;
+/// Physical addresses of the page tables the trampoline enters long mode with
+const TRAMPOLINE_PML4: u64 = 0xa000;
+const TRAMPOLINE_PDPT: u64 = 0xb000;
+const TRAMPOLINE_PD: u64 = 0xc000;
+/// End of the low memory used to start application processors
+const TRAMPOLINE_END: u64 = 0xd000;
+
+/// Size of the stack an application processor initializes itself on
+const STACK_SIZE: usize = 0x10_0000;
+
+/// Interrupt vector other cores are asked to flush their TLBs with
+const TLB_SHOOTDOWN_VECTOR: u8 = 0x21;
+
+global_asm!(
+    r#"
+    .global ap_trampoline
+    .global ap_trampoline_end
+
+    .code16
+    ap_trampoline:
+        cli
+        cld
+        xor ax, ax
+        mov ds, ax
+
+        lgdt [{trampoline} + ap_trampoline_gdt_pointer - ap_trampoline]
+
+        // PAE, and SSE as the kernel is compiled to use it
+        mov eax, cr4
+        or eax, (1 << 5) | (1 << 9) | (1 << 10)
+        mov cr4, eax
+
+        mov eax, {pml4}
+        mov cr3, eax
+
+        // long mode and no-execute pages
+        mov ecx, 0xc0000080
+        rdmsr
+        or eax, (1 << 8) | (1 << 11)
+        wrmsr
+
+        // paging and protection, with caching enabled and the FPU present
+        mov eax, cr0
+        and eax, ~((1 << 30) | (1 << 29) | (1 << 2))
+        or eax, (1 << 31) | (1 << 1) | (1 << 0)
+        mov cr0, eax
+
+        // far jump to the 64-bit code segment
+        .byte 0x66, 0xea
+        .long {trampoline} + ap_trampoline_long_mode - ap_trampoline
+        .short 0x08
+
+    .code64
+    ap_trampoline_long_mode:
+        mov ax, 0x10
+        mov ds, ax
+        mov es, ax
+        mov ss, ax
+
+        mov rsp, [{parameters}]
+        mov rax, [{parameters} + 8]
+        call rax
+        ud2
+
+    .balign 8
+    ap_trampoline_gdt:
+        .quad 0
+        .quad 0x00af9a000000ffff
+        .quad 0x00cf92000000ffff
+    ap_trampoline_gdt_pointer:
+        .short ap_trampoline_gdt_pointer - ap_trampoline_gdt - 1
+        .long {trampoline} + ap_trampoline_gdt - ap_trampoline
+    ap_trampoline_end:
+    "#,
+    trampoline = const TRAMPOLINE,
+    pml4 = const TRAMPOLINE_PML4,
+    parameters = const PARAMETERS,
+);
+
+unsafe extern "C" {
+    static ap_trampoline: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// Passed to the application processor being started at [`PARAMETERS`]
+#[repr(C)]
+struct Parameters {
+    stack: u64,
+    entry: u64,
+    page_tables: u64,
+}
+
+/// Whether the memory map leaves the trampoline memory free
+static TRAMPOLINE_USABLE: AtomicBool = AtomicBool::new(false);
+
+/// Called by each application processor once initialized
+static ENTRY: Once<fn() -> !> = Once::INIT;
+
+/// Set by the application processor being started once it no longer needs its
+/// [`Parameters`]
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Number of cores able to take part in TLB shootdowns, which are those with
+/// the lowest IDs
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+/// Number of TLB shootdowns requested, and performed by each core
+static SHOOTDOWNS_REQUESTED: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWNS_PERFORMED: [AtomicU64; MAX_CORES] = [const { AtomicU64::new(0) }; MAX_CORES];
+
+/// Called on the boot core once interrupts are initialized
+pub fn init(memory_regions: &MemoryRegions) {
+    TRAMPOLINE_USABLE.store(
+        memory_regions.iter().any(|region| {
+            matches!(region.kind, MemoryRegionKind::Usable)
+                && region.start <= TRAMPOLINE
+                && region.end >= TRAMPOLINE_END
+        }),
+        Ordering::Relaxed,
+    );
+
+    assign_irq(TLB_SHOOTDOWN_VECTOR, tlb_shootdown).unwrap();
+
+    VirtualMemoryArea::register_current_core();
+}
+
+/// Starts the application processors, up to [`MAX_CORES`] cores in total, one
+/// at a time
+pub fn start(entry: fn() -> !) {
+    const { assert!(TRAMPOLINE_END <= LOW_MEMORY_END.as_u64()) };
+
+    let processors = acpi::application_processors();
+    if processors.is_empty() {
+        return;
+    }
+
+    if !TRAMPOLINE_USABLE.load(Ordering::Relaxed) {
+        log::warn!("trampoline memory in use, not starting application processors");
+        return;
+    }
+
+    ENTRY.call_once(|| entry);
+
+    let mut boot_tables = VirtualMemoryArea::current();
+    let parameters = unsafe {
+        &mut *PhysAddr::new(PARAMETERS)
+            .to_virt()
+            .as_mut_ptr::<Parameters>()
+    };
+
+    for apic_id in processors.iter().take(MAX_CORES - 1) {
+        // made before the trampoline tables, so that they copy the kernel half
+        // entries this populates
+        let page_tables = boot_tables.share_kernel_half();
+        write_trampoline(&mut boot_tables);
+
+        let stack = vec![0u8; STACK_SIZE].leak();
+
+        *parameters = Parameters {
+            stack: stack.as_ptr_range().end as u64,
+            entry: ap_entry as usize as u64,
+            page_tables: page_tables.pml4_base.as_u64(),
+        };
+        STARTED.store(false, Ordering::Release);
+
+        log::trace!("starting application processor {apic_id}");
+
+        // INIT, then two startup IPIs as the first may be missed
+        without_interrupts(|| unsafe { LAPIC.get().unwrap().lock().inner.send_init_ipi(*apic_id) });
+        delay(10_000);
+        for _ in 0..2 {
+            without_interrupts(|| unsafe {
+                LAPIC
+                    .get()
+                    .unwrap()
+                    .lock()
+                    .inner
+                    .send_sipi((TRAMPOLINE >> 12) as u8, *apic_id)
+            });
+            delay(200);
+        }
+
+        // the parameters cannot be reused while the core may yet read them
+        for _ in 0..1000 {
+            if STARTED.load(Ordering::Acquire) {
+                break;
+            }
+            delay(1000);
+        }
+
+        if !STARTED.load(Ordering::Acquire) {
+            log::warn!("application processor {apic_id} did not start");
+            return;
+        }
+    }
+}
+
+/// Copies the trampoline to low memory, and creates the page tables it enters
+/// long mode with, identity mapping the first 2MiB and sharing the kernel half
+fn write_trampoline(boot_tables: &mut VirtualMemoryArea) {
+    unsafe {
+        let start = &raw const ap_trampoline;
+        let length = (&raw const ap_trampoline_end).offset_from(start) as usize;
+        assert!(TRAMPOLINE + length as u64 <= PARAMETERS);
+
+        ptr::copy_nonoverlapping(
+            start,
+            PhysAddr::new(TRAMPOLINE).to_virt().as_mut_ptr(),
+            length,
+        );
+    }
+
+    let table =
+        |address: u64| unsafe { &mut *PhysAddr::new(address).to_virt().as_mut_ptr::<PageTable>() };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    let pml4 = table(TRAMPOLINE_PML4);
+    pml4.zero();
+    pml4[0].set_addr(PhysAddr::new(TRAMPOLINE_PDPT), flags);
+    pml4.iter_mut()
+        .zip(boot_tables.opt.level_4_table().iter())
+        .skip(0x100)
+        .for_each(|(entry, shared)| *entry = shared.clone());
+
+    let pdpt = table(TRAMPOLINE_PDPT);
+    pdpt.zero();
+    pdpt[0].set_addr(PhysAddr::new(TRAMPOLINE_PD), flags);
+
+    let pd = table(TRAMPOLINE_PD);
+    pd.zero();
+    pd[0].set_addr(PhysAddr::new(0), flags | PageTableFlags::HUGE_PAGE);
+}
+
+/// Entry point of each application processor, called by the trampoline
+extern "C" fn ap_entry() -> ! {
+    let parameters = unsafe { &*PhysAddr::new(PARAMETERS).to_virt().as_ptr::<Parameters>() };
+    VirtualMemoryArea::from_pml4(PhysAddr::new(parameters.page_tables)).activate();
+
+    super::update_cregs();
+
+    CoreStorage::init_self();
+    VirtualMemoryArea::register_current_core();
+    gdt::init();
+    irq::init_local();
+    lapic::init_local();
+
+    // its TLB was empty on entry, so earlier shootdowns need not be performed
+    SHOOTDOWNS_PERFORMED[CoreStorage::id()].store(
+        SHOOTDOWNS_REQUESTED.load(Ordering::Acquire),
+        Ordering::Release,
+    );
+    ONLINE.fetch_add(1, Ordering::AcqRel);
+    STARTED.store(true, Ordering::Release);
+
+    (ENTRY.get().unwrap())()
+}
+
+/// Busy waits using the PIT
+fn delay(microseconds: u32) {
+    let ticks = u64::from(PIT_FREQUENCY) * u64::from(microseconds) / 1_000_000;
+
+    pit::init_oneshot(u16::try_from(ticks).unwrap());
+    pit::start();
+
+    while !pit::is_expired() {
+        spin_loop();
+    }
+}
+
+/// Flushes the TLBs of every other core, returning once they have
+///
+/// Must not be called while holding a lock other cores may wait on with
+/// interrupts disabled, as they could not take the shootdown interrupt.
+pub fn flush_remote_tlbs() {
+    let online = ONLINE.load(Ordering::Acquire);
+    if online == 1 {
+        return;
+    }
+
+    let requested = SHOOTDOWNS_REQUESTED.fetch_add(1, Ordering::AcqRel) + 1;
+
+    without_interrupts(|| unsafe {
+        LAPIC
+            .get()
+            .unwrap()
+            .lock()
+            .inner
+            .send_ipi_all(TLB_SHOOTDOWN_VECTOR, IpiAllShorthand::AllExcludingSelf)
+    });
+
+    let this = CoreStorage::id();
+    for core in (0..online).filter(|core| *core != this) {
+        while SHOOTDOWNS_PERFORMED[core].load(Ordering::Acquire) < requested {
+            // the other core may be waiting for this one in turn
+            perform_shootdown();
+            spin_loop();
+        }
+    }
+}
+
+/// Flushes the TLB of the current core if a shootdown has been requested since
+/// it last did
+fn perform_shootdown() {
+    let performed = &SHOOTDOWNS_PERFORMED[CoreStorage::id()];
+    let requested = SHOOTDOWNS_REQUESTED.load(Ordering::Acquire);
+
+    if performed.load(Ordering::Relaxed) < requested {
+        tlb::flush_all();
+        performed.fetch_max(requested, Ordering::Release);
+    }
+}
+
+#[irq_handler(with_code = false)]
+fn tlb_shootdown() {
+    perform_shootdown();
+
+    unsafe { LAPIC.get().unwrap().lock().inner.end_of_interrupt() };
+}
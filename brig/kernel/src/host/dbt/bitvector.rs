@@ -0,0 +1,546 @@
+//! Bitvectors wider than a host word
+//!
+//! Values are held as little-endian 64-bit limbs. Any bits of the most
+//! significant limb above `width` are kept clear, so limbs can be compared
+//! directly.
+
+use {
+    alloc::{vec, vec::Vec},
+    common::mask::mask,
+    core::{
+        cmp::Ordering,
+        ops::{Add, BitAnd, BitOr, BitXor, Mul, Not, Shl, Shr, Sub},
+    },
+    proc_macro_lib::ktest,
+};
+
+const LIMB_BITS: u64 = u64::BITS as u64;
+
+/// Number of 64-bit limbs needed to hold `width` bits
+pub fn limb_count(width: u16) -> usize {
+    usize::from(width.div_ceil(u64::BITS as u16)).max(1)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitvector {
+    limbs: Vec<u64>,
+    width: u16,
+}
+
+impl Bitvector {
+    pub fn new(value: u64, width: u16) -> Self {
+        Self::from_limbs(&[value], width)
+    }
+
+    /// Bitvector made up of the least significant `limbs` first, truncated or
+    /// zero extended to `width` bits
+    pub fn from_limbs(limbs: &[u64], width: u16) -> Self {
+        let mut value = Self {
+            limbs: vec![0; limb_count(width)],
+            width,
+        };
+
+        value
+            .limbs
+            .iter_mut()
+            .zip(limbs)
+            .for_each(|(limb, source)| *limb = *source);
+        value.clear_unused();
+
+        value
+    }
+
+    /// Bitvector of the little-endian `bytes`, as laid out in memory
+    pub fn from_le_bytes(bytes: &[u8]) -> Self {
+        let limbs = bytes
+            .chunks(8)
+            .map(|chunk| {
+                let mut limb = [0; 8];
+                limb[..chunk.len()].copy_from_slice(chunk);
+                u64::from_le_bytes(limb)
+            })
+            .collect::<Vec<_>>();
+
+        Self::from_limbs(&limbs, u16::try_from(bytes.len() * 8).unwrap())
+    }
+
+    /// Little-endian bytes holding every bit of the value
+    pub fn to_le_bytes(&self) -> Vec<u8> {
+        self.limbs
+            .iter()
+            .flat_map(|limb| limb.to_le_bytes())
+            .take(usize::from(self.width.div_ceil(8)))
+            .collect()
+    }
+
+    /// `width` bits, all set
+    pub fn ones(width: u16) -> Self {
+        Self::from_limbs(&vec![u64::MAX; limb_count(width)], width)
+    }
+
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    pub fn limbs(&self) -> &[u64] {
+        &self.limbs
+    }
+
+    /// Least significant 64 bits
+    pub fn low(&self) -> u64 {
+        self.limbs[0]
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.limbs.iter().all(|limb| *limb == 0)
+    }
+
+    /// Most significant bit
+    pub fn sign(&self) -> bool {
+        self.width != 0 && self.bit(u64::from(self.width) - 1)
+    }
+
+    fn bit(&self, index: u64) -> bool {
+        (self.limbs[(index / LIMB_BITS) as usize] >> (index % LIMB_BITS)) & 1 == 1
+    }
+
+    fn clear_unused(&mut self) {
+        let used = self.width % (u64::BITS as u16);
+        if used != 0 {
+            *self.limbs.last_mut().unwrap() &= mask(used);
+        }
+    }
+
+    /// Zero extends or truncates to `width` bits
+    pub fn zero_extend(&self, width: u16) -> Self {
+        Self::from_limbs(&self.limbs, width)
+    }
+
+    pub fn sign_extend(&self, width: u16) -> Self {
+        let extended = self.zero_extend(width);
+
+        if self.sign() && width > self.width {
+            extended | (Self::ones(width) << u64::from(self.width))
+        } else {
+            extended
+        }
+    }
+
+    /// Arithmetic shift right by `amount`, filling with the sign bit
+    pub fn arithmetic_shift_right(&self, amount: u64) -> Self {
+        let shifted = self.clone() >> amount;
+
+        if !self.sign() {
+            return shifted;
+        }
+
+        let width = u64::from(self.width);
+        shifted | (Self::ones(self.width) << width.saturating_sub(amount))
+    }
+
+    /// `length` bits starting at bit `start`
+    pub fn extract(&self, start: u64, length: u16) -> Self {
+        (self.clone() >> start).zero_extend(length)
+    }
+
+    /// Replaces `length` bits starting at bit `start` with the low bits of
+    /// `source`, discarding any beyond the end of this value
+    pub fn insert(&self, source: &Self, start: u64, length: u16) -> Self {
+        let field = Self::ones(length).zero_extend(self.width) << start;
+        let source = source.zero_extend(length).zero_extend(self.width) << start;
+
+        (self.clone() & !field) | source
+    }
+
+    /// Rotates left by `amount` bits within `width`
+    pub fn rotate_left(&self, amount: u64) -> Self {
+        let width = u64::from(self.width);
+        if width == 0 {
+            return self.clone();
+        }
+
+        let amount = amount % width;
+        (self.clone() << amount) | (self.clone() >> (width - amount))
+    }
+
+    /// Rotates right by `amount` bits within `width`
+    pub fn rotate_right(&self, amount: u64) -> Self {
+        let width = u64::from(self.width);
+        if width == 0 {
+            return self.clone();
+        }
+
+        self.rotate_left(width - amount % width)
+    }
+
+    /// Quotient and remainder of unsigned division by `divisor`, one bit at a
+    /// time, or all ones and this value when dividing by zero
+    pub fn div_rem(&self, divisor: &Self) -> (Self, Self) {
+        let width = self.width.max(divisor.width);
+        let (dividend, divisor) = (self.zero_extend(width), divisor.zero_extend(width));
+
+        let mut quotient = Self::new(0, width);
+        let mut remainder = Self::new(0, width);
+
+        for bit in (0..u64::from(width)).rev() {
+            // the bit shifted out of the remainder is set only once it exceeds any divisor
+            let overflow = remainder.sign();
+            remainder = remainder << 1;
+            if dividend.bit(bit) {
+                remainder = remainder | Self::new(1, width);
+            }
+
+            if overflow || remainder.cmp_unsigned(&divisor).is_ge() {
+                remainder = remainder - divisor.clone();
+                quotient = quotient | (Self::new(1, width) << bit);
+            }
+        }
+
+        (quotient, remainder)
+    }
+
+    /// This value to the power of `exponent`, truncated to `width`
+    pub fn pow(&self, exponent: u64) -> Self {
+        let mut base = self.clone();
+        let mut power = Self::new(1, self.width);
+        let mut exponent = exponent;
+
+        while exponent != 0 {
+            if exponent & 1 == 1 {
+                power = power * base.clone();
+            }
+            base = base.clone() * base;
+            exponent >>= 1;
+        }
+
+        power
+    }
+
+    /// Largest integer whose square is at most this value, one bit at a time
+    pub fn square_root(&self) -> Self {
+        let mut remaining = self.clone();
+        let mut root = Self::new(0, self.width);
+
+        // highest even bit position within `width`
+        let top = u64::from(self.width.saturating_sub(1)) & !1;
+
+        for bit in (0..=top).rev().step_by(2) {
+            let candidate = root.clone() | (Self::new(1, self.width) << bit);
+            root = root >> 1;
+
+            if remaining.cmp_unsigned(&candidate).is_ge() {
+                remaining = remaining - candidate;
+                root = root | (Self::new(1, self.width) << bit);
+            }
+        }
+
+        root
+    }
+
+    pub fn cmp_unsigned(&self, other: &Self) -> Ordering {
+        let width = self.width.max(other.width);
+        let (left, right) = (self.zero_extend(width), other.zero_extend(width));
+
+        left.limbs.iter().rev().cmp(right.limbs.iter().rev())
+    }
+
+    pub fn cmp_signed(&self, other: &Self) -> Ordering {
+        let width = self.width.max(other.width);
+        let (left, right) = (self.sign_extend(width), other.sign_extend(width));
+
+        match (left.sign(), right.sign()) {
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            _ => left.cmp_unsigned(&right),
+        }
+    }
+
+    /// Applies `op` to each pair of limbs of the operands zero extended to the
+    /// wider of the two
+    fn zip_limbs(self, rhs: Self, op: impl Fn(u64, u64) -> u64) -> Self {
+        let width = self.width.max(rhs.width);
+        let (left, right) = (self.zero_extend(width), rhs.zero_extend(width));
+
+        let limbs = left
+            .limbs
+            .iter()
+            .zip(&right.limbs)
+            .map(|(left, right)| op(*left, *right))
+            .collect::<Vec<_>>();
+
+        Self::from_limbs(&limbs, width)
+    }
+}
+
+impl Add for Bitvector {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        let width = self.width.max(rhs.width);
+        let (left, right) = (self.zero_extend(width), rhs.zero_extend(width));
+
+        let mut carry = false;
+        let limbs = left
+            .limbs
+            .iter()
+            .zip(&right.limbs)
+            .map(|(left, right)| {
+                let (sum, carry_a) = left.overflowing_add(*right);
+                let (sum, carry_b) = sum.overflowing_add(u64::from(carry));
+                carry = carry_a || carry_b;
+                sum
+            })
+            .collect::<Vec<_>>();
+
+        Self::from_limbs(&limbs, width)
+    }
+}
+
+impl Sub for Bitvector {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        let width = self.width.max(rhs.width);
+        let (left, right) = (self.zero_extend(width), rhs.zero_extend(width));
+
+        let mut borrow = false;
+        let limbs = left
+            .limbs
+            .iter()
+            .zip(&right.limbs)
+            .map(|(left, right)| {
+                let (difference, borrow_a) = left.overflowing_sub(*right);
+                let (difference, borrow_b) = difference.overflowing_sub(u64::from(borrow));
+                borrow = borrow_a || borrow_b;
+                difference
+            })
+            .collect::<Vec<_>>();
+
+        Self::from_limbs(&limbs, width)
+    }
+}
+
+impl Mul for Bitvector {
+    type Output = Self;
+
+    /// Product truncated to the wider of the operands
+    fn mul(self, rhs: Self) -> Self {
+        let width = self.width.max(rhs.width);
+        let (left, right) = (self.zero_extend(width), rhs.zero_extend(width));
+        let count = left.limbs.len();
+
+        let mut limbs = vec![0u64; count];
+        for (i, left) in left.limbs.iter().enumerate() {
+            let mut carry = 0u128;
+            for (j, right) in right.limbs[..count - i].iter().enumerate() {
+                let product =
+                    u128::from(*left) * u128::from(*right) + u128::from(limbs[i + j]) + carry;
+                limbs[i + j] = product as u64;
+                carry = product >> LIMB_BITS;
+            }
+        }
+
+        Self::from_limbs(&limbs, width)
+    }
+}
+
+impl BitAnd for Bitvector {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        self.zip_limbs(rhs, |left, right| left & right)
+    }
+}
+
+impl BitOr for Bitvector {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        self.zip_limbs(rhs, |left, right| left | right)
+    }
+}
+
+impl BitXor for Bitvector {
+    type Output = Self;
+
+    fn bitxor(self, rhs: Self) -> Self {
+        self.zip_limbs(rhs, |left, right| left ^ right)
+    }
+}
+
+impl Not for Bitvector {
+    type Output = Self;
+
+    fn not(self) -> Self {
+        let limbs = self.limbs.iter().map(|limb| !limb).collect::<Vec<_>>();
+        Self::from_limbs(&limbs, self.width)
+    }
+}
+
+impl Shl<u64> for Bitvector {
+    type Output = Self;
+
+    fn shl(self, amount: u64) -> Self {
+        let (words, bits) = (amount / LIMB_BITS, (amount % LIMB_BITS) as u32);
+        let count = self.limbs.len();
+
+        let limbs = (0..count)
+            .map(|index| {
+                let Some(source) = index.checked_sub(words.try_into().unwrap_or(usize::MAX)) else {
+                    return 0;
+                };
+
+                let carried = match (source.checked_sub(1), bits) {
+                    (Some(below), 1..) => self.limbs[below] >> (u64::BITS - bits),
+                    _ => 0,
+                };
+
+                (self.limbs[source] << bits) | carried
+            })
+            .collect::<Vec<_>>();
+
+        Self::from_limbs(&limbs, self.width)
+    }
+}
+
+impl Shr<u64> for Bitvector {
+    type Output = Self;
+
+    fn shr(self, amount: u64) -> Self {
+        let (words, bits) = (amount / LIMB_BITS, (amount % LIMB_BITS) as u32);
+        let count = self.limbs.len();
+
+        let limbs = (0..count)
+            .map(|index| {
+                let source = usize::try_from(words)
+                    .ok()
+                    .and_then(|words| index.checked_add(words))
+                    .filter(|source| *source < count);
+                let Some(source) = source else {
+                    return 0;
+                };
+
+                let carried = match (self.limbs.get(source + 1), bits) {
+                    (Some(above), 1..) => above << (u64::BITS - bits),
+                    _ => 0,
+                };
+
+                (self.limbs[source] >> bits) | carried
+            })
+            .collect::<Vec<_>>();
+
+        Self::from_limbs(&limbs, self.width)
+    }
+}
+
+#[ktest]
+fn bitvector_add_carries_across_limbs() {
+    let sum = Bitvector::from_limbs(&[u64::MAX, 0], 128) + Bitvector::new(1, 128);
+    assert_eq!(sum.limbs(), &[0, 1]);
+
+    let wrapped = Bitvector::ones(130) + Bitvector::new(1, 130);
+    assert!(wrapped.is_zero());
+
+    let difference = Bitvector::from_limbs(&[0, 1], 128) - Bitvector::new(1, 128);
+    assert_eq!(difference.limbs(), &[u64::MAX, 0]);
+}
+
+#[ktest]
+fn bitvector_bytes() {
+    let bytes = (1..=16).collect::<Vec<u8>>();
+    let value = Bitvector::from_le_bytes(&bytes);
+
+    assert_eq!(value.width(), 128);
+    assert_eq!(
+        value.limbs(),
+        &[0x0807_0605_0403_0201, 0x100f_0e0d_0c0b_0a09]
+    );
+    assert_eq!(value.to_le_bytes(), bytes);
+    assert_eq!(Bitvector::new(0x1234, 12).to_le_bytes(), [0x34, 0x02]);
+}
+
+#[ktest]
+fn bitvector_shifts() {
+    let value = Bitvector::from_limbs(&[0x8000_0000_0000_0001, 0], 256);
+
+    assert_eq!((value.clone() << 1).limbs(), &[2, 1, 0, 0]);
+    assert_eq!(
+        (value.clone() << 192).limbs(),
+        &[0, 0, 0, 0x8000_0000_0000_0001]
+    );
+    assert_eq!((value.clone() << 256).limbs(), &[0, 0, 0, 0]);
+    assert_eq!(
+        ((value.clone() << 64) >> 65).limbs(),
+        &[0x4000_0000_0000_0000, 0, 0, 0]
+    );
+
+    let negative = Bitvector::from_limbs(&[0, 1 << 63], 128);
+    assert_eq!(
+        negative.arithmetic_shift_right(64).limbs(),
+        &[1 << 63, u64::MAX]
+    );
+}
+
+#[ktest]
+fn bitvector_extract_insert() {
+    let value = Bitvector::from_limbs(&[0xdead_beef_0000_0000, 0xcafe], 128);
+
+    assert_eq!(value.extract(32, 48).limbs(), &[0xcafe_dead_beef]);
+    assert_eq!(value.extract(64, 16).limbs(), &[0xcafe]);
+    assert!(value.extract(112, 16).is_zero());
+
+    let inserted = value.insert(&Bitvector::new(0xffff, 16), 56, 16);
+    assert_eq!(inserted.limbs(), &[0xffad_beef_0000_0000, 0xcaff]);
+}
+
+#[ktest]
+fn bitvector_compare() {
+    let small = Bitvector::from_limbs(&[u64::MAX, 0], 128);
+    let large = Bitvector::from_limbs(&[0, 1], 128);
+    let negative = Bitvector::from_limbs(&[0, 1 << 63], 128);
+
+    assert_eq!(small.cmp_unsigned(&large), Ordering::Less);
+    assert_eq!(negative.cmp_unsigned(&large), Ordering::Greater);
+    assert_eq!(negative.cmp_signed(&large), Ordering::Less);
+    assert_eq!(
+        Bitvector::new(5, 64).cmp_unsigned(&Bitvector::new(5, 192)),
+        Ordering::Equal
+    );
+}
+
+#[ktest]
+fn bitvector_multiply_divide() {
+    let large = Bitvector::from_limbs(&[u64::MAX, 0], 128);
+
+    let product = large.clone() * large.clone();
+    assert_eq!(product.limbs(), &[1, u64::MAX - 1]);
+    assert_eq!((product.clone() * Bitvector::new(0, 128)).limbs(), &[0, 0]);
+
+    let (quotient, remainder) = (product + Bitvector::new(5, 128)).div_rem(&large);
+    assert_eq!(quotient.limbs(), &[u64::MAX, 0]);
+    assert_eq!(remainder.limbs(), &[5, 0]);
+
+    // divisors with the top bit set overflow the shifted remainder
+    let top = Bitvector::from_limbs(&[0, 1 << 63], 128);
+    let (quotient, remainder) = Bitvector::ones(128).div_rem(&top);
+    assert_eq!(quotient.limbs(), &[1, 0]);
+    assert_eq!(remainder.limbs(), &[u64::MAX, (1 << 63) - 1]);
+
+    assert_eq!(
+        Bitvector::new(3, 128).pow(80).limbs(),
+        &[0x3cea_5978_9c79_d441, 0x6f32_f1ef_8b18_a2bc]
+    );
+}
+
+#[ktest]
+fn bitvector_square_root_rotate() {
+    assert_eq!(
+        Bitvector::from_limbs(&[0, 1], 128).square_root().limbs(),
+        &[1 << 32, 0]
+    );
+    assert_eq!(Bitvector::new(99, 130).square_root().limbs(), &[9, 0, 0]);
+
+    let value = Bitvector::from_limbs(&[1, 1 << 63], 128);
+    assert_eq!(value.rotate_left(1).limbs(), &[3, 0]);
+    assert_eq!(value.rotate_right(1).limbs(), &[0, 1 << 63 | 1 << 62]);
+}
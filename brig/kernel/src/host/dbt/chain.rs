@@ -0,0 +1,165 @@
+//! Direct linking of translated blocks
+//!
+//! Before falling back to the chain cache lookup, every block exit passes
+//! through [`CHAIN_SLOT_COUNT`] patchable slots. Each slot compares the next
+//! guest PC against its tag and on a match jumps straight to the translation
+//! of that PC, so hot loops never leave translated code. Slots start with a tag
+//! that no PC can equal, and are linked by the block execution loop once a
+//! successor has been translated and executed. Unlinking only resets the tag,
+//! leaving a stale displacement that can never be taken.
+
+use {
+    crate::host::{
+        arch::x86::memory::{CODE_ALIAS_START, PhysAddrExt},
+        dbt::x86::encoder::{CHAIN_TAG_OFFSET, CHAIN_TARGET_OFFSET, CHAIN_UNLINKED_TAG},
+    },
+    alloc::vec::Vec,
+    serde::{Deserialize, Serialize},
+    x86_64::PhysAddr,
+};
+
+/// Number of successors each block can be directly linked to, one for each
+/// side of a conditional branch
+pub const CHAIN_SLOT_COUNT: usize = 2;
+
+/// Patchable exit of a translated block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainSlot {
+    /// Address of the 64-bit tag immediate
+    tag: usize,
+    /// Address of the 32-bit jump displacement
+    target: usize,
+}
+
+impl ChainSlot {
+    /// Slot at the supplied offsets of the encoded [`CHAINTAG`] and
+    /// [`CHAINJMP`] instructions within assembled code
+    ///
+    /// [`CHAINTAG`]: crate::host::dbt::x86::encoder::Opcode::CHAINTAG
+    /// [`CHAINJMP`]: crate::host::dbt::x86::encoder::Opcode::CHAINJMP
+    pub fn from_offsets(tag_instruction: usize, jump_instruction: usize) -> Self {
+        Self {
+            tag: tag_instruction + CHAIN_TAG_OFFSET,
+            target: jump_instruction + CHAIN_TARGET_OFFSET,
+        }
+    }
+
+    /// Moves the slot from assembled code to the executable copy of it at `code`
+    pub fn relocate(self, code: *const u8) -> Self {
+        Self {
+            tag: self.tag + code as usize,
+            target: self.target + code as usize,
+        }
+    }
+
+    /// PC this slot is linked to, if any
+    pub fn linked_pc(&self) -> Option<u64> {
+        let tag = unsafe { (self.tag as *const u64).read_unaligned() };
+        (tag != CHAIN_UNLINKED_TAG).then_some(tag)
+    }
+
+    /// Redirects exits to `pc` through this slot to `code`, returning false if
+    /// `code` is out of range of a 32-bit displacement
+    pub fn link(&self, pc: u64, code: *const u8) -> bool {
+        let next_instruction = self.target as i64 + 4;
+        let Ok(displacement) = i32::try_from(code as i64 - next_instruction) else {
+            return false;
+        };
+
+        // the displacement must be in place before the tag can match
+        unsafe {
+            writable(self.target)
+                .cast::<i32>()
+                .write_unaligned(displacement);
+            writable(self.tag).cast::<u64>().write_unaligned(pc);
+        }
+
+        true
+    }
+
+    pub fn unlink(&self) {
+        unsafe {
+            writable(self.tag)
+                .cast::<u64>()
+                .write_unaligned(CHAIN_UNLINKED_TAG)
+        };
+    }
+}
+
+/// Writable view of an address in the executable code alias
+fn writable(executable: usize) -> *mut u8 {
+    PhysAddr::new(executable as u64 - CODE_ALIAS_START.as_u64())
+        .to_virt()
+        .as_mut_ptr()
+}
+
+struct Link {
+    slot: ChainSlot,
+    target: *const u8,
+}
+
+/// Every linked slot of a core, so links can be removed when either end is
+/// invalidated
+#[derive(Default)]
+pub struct ChainLinks {
+    links: Vec<Link>,
+}
+
+// links only point into the code cache of the core that owns them
+unsafe impl Send for ChainLinks {}
+
+impl ChainLinks {
+    /// Links the first free slot in `slots` to the translation of `pc` at
+    /// `code`
+    pub fn link(&mut self, slots: &[ChainSlot], pc: u64, code: *const u8) {
+        if slots.iter().any(|slot| slot.linked_pc() == Some(pc)) {
+            return;
+        }
+
+        let Some(slot) = slots.iter().find(|slot| slot.linked_pc().is_none()) else {
+            return;
+        };
+
+        if slot.link(pc, code) {
+            self.links.push(Link {
+                slot: *slot,
+                target: code,
+            });
+        }
+    }
+
+    pub fn unlink_all(&mut self) {
+        self.links.drain(..).for_each(|link| link.slot.unlink());
+    }
+
+    /// Unlinks every slot linked to a guest virtual PC matching the predicate
+    pub fn unlink_pcs<F: Fn(u64) -> bool>(&mut self, predicate: F) {
+        self.links.retain(|link| {
+            if link.slot.linked_pc().is_some_and(&predicate) {
+                link.slot.unlink();
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    /// Drops the links of every slot in code matching the predicate without
+    /// writing to them, as the memory of that code may already be reused
+    pub fn forget_slots<F: Fn(*const u8) -> bool>(&mut self, predicate: F) {
+        self.links
+            .retain(|link| !predicate(link.slot.tag as *const u8));
+    }
+
+    /// Unlinks every slot linked to code matching the predicate
+    pub fn unlink_targets<F: Fn(*const u8) -> bool>(&mut self, predicate: F) {
+        self.links.retain(|link| {
+            if predicate(link.target) {
+                link.slot.unlink();
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
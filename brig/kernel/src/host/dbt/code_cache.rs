@@ -0,0 +1,263 @@
+//! Executable memory for translated code
+//!
+//! Code is written through the ordinary (writable, non-executable) heap
+//! mapping of page-aligned allocations, and executed through a read-only alias
+//! of the same frames at [`CODE_ALIAS_START`]. The alias uses 4K pages so
+//! permissions can be changed per allocation without touching the huge pages
+//! of the heap mapping.
+
+use {
+    crate::host::{
+        arch::{
+            PAGE_SIZE,
+            x86::memory::{CODE_ALIAS_START, VirtAddrExt, VirtualMemoryArea},
+        },
+        dbt::{
+            Translation,
+            chain::{ChainLinks, ChainSlot},
+            x86::encoder::{CHAIN_TAG_OFFSET, CHAIN_UNLINKED_TAG},
+        },
+    },
+    alloc::{
+        alloc::{alloc_zeroed, dealloc},
+        vec::Vec,
+    },
+    core::{alloc::Layout, ops::Range, ptr::NonNull},
+    proc_macro_lib::ktest,
+    x86_64::{
+        VirtAddr,
+        structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB},
+    },
+};
+
+/// Size in bytes of each code cache region
+pub const REGION_SIZE: usize = 1024 * 1024;
+
+/// Page-aligned memory holding executable code
+pub struct ExecutableMemory {
+    writable: NonNull<u8>,
+    layout: Layout,
+}
+
+impl ExecutableMemory {
+    pub fn new(size: usize) -> Self {
+        let layout = Layout::from_size_align(size.next_multiple_of(PAGE_SIZE), PAGE_SIZE).unwrap();
+        let writable = NonNull::new(unsafe { alloc_zeroed(layout) }).unwrap();
+
+        let memory = Self { writable, layout };
+        memory.set_alias_flags(PageTableFlags::PRESENT);
+        memory
+    }
+
+    /// Writable, non-executable view of the memory
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.writable.as_ptr(), self.layout.size()) }
+    }
+
+    /// Executable, read-only view of the memory
+    pub fn executable_range(&self) -> Range<usize> {
+        let physical = VirtAddr::from_ptr(self.writable.as_ptr()).to_phys();
+        let start = (CODE_ALIAS_START + physical.as_u64()).as_u64() as usize;
+        start..start + self.layout.size()
+    }
+
+    fn set_alias_flags(&self, flags: PageTableFlags) {
+        let mut vma = VirtualMemoryArea::current();
+        let physical_start = VirtAddr::from_ptr(self.writable.as_ptr()).to_phys();
+
+        for offset in (0..self.layout.size() as u64).step_by(PAGE_SIZE) {
+            let frame = PhysFrame::<Size4KiB>::from_start_address(physical_start + offset).unwrap();
+            let page = Page::<Size4KiB>::from_start_address(
+                CODE_ALIAS_START + frame.start_address().as_u64(),
+            )
+            .unwrap();
+
+            // aliases are never unmapped, only made non-executable
+            if !vma.update_page_flags(page, flags) {
+                vma.map_page(page, frame, flags);
+            }
+        }
+    }
+}
+
+impl Drop for ExecutableMemory {
+    fn drop(&mut self) {
+        self.set_alias_flags(PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE);
+        unsafe { dealloc(self.writable.as_ptr(), self.layout) };
+    }
+}
+
+/// How space is reclaimed once the code cache budget is exhausted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Discard every translation
+    Flush,
+    /// Discard the translations in the least recently used region
+    LeastRecentlyUsed,
+}
+
+/// Translations discarded while inserting into the code cache, which must be
+/// removed from the block and chain caches before their memory is reused
+#[derive(Debug, Clone)]
+pub enum Eviction {
+    All,
+    Region(Range<usize>),
+}
+
+impl Eviction {
+    /// Whether the supplied code pointer was evicted
+    pub fn contains(&self, code: *const u8) -> bool {
+        match self {
+            Eviction::All => true,
+            Eviction::Region(range) => range.contains(&(code as usize)),
+        }
+    }
+}
+
+struct Region {
+    memory: ExecutableMemory,
+    used: usize,
+    last_used: u64,
+}
+
+impl Region {
+    fn new() -> Self {
+        Self {
+            memory: ExecutableMemory::new(REGION_SIZE),
+            used: 0,
+            last_used: 0,
+        }
+    }
+
+    fn contains(&self, code: *const u8) -> bool {
+        self.memory.executable_range().contains(&(code as usize))
+    }
+}
+
+/// Bounded store of translated code, split into fixed size regions that are
+/// allocated as needed until the budget is reached
+pub struct CodeCache {
+    regions: Vec<Region>,
+    max_regions: usize,
+    current: usize,
+    policy: EvictionPolicy,
+    clock: u64,
+}
+
+impl CodeCache {
+    pub fn new(budget: usize, policy: EvictionPolicy) -> Self {
+        Self {
+            regions: alloc::vec![Region::new()],
+            max_regions: (budget / REGION_SIZE).max(1),
+            current: 0,
+            policy,
+            clock: 0,
+        }
+    }
+
+    /// Copies `code` into the cache, first passing any translations evicted to
+    /// make room to `discard` so that nothing refers to their memory once it is
+    /// overwritten
+    pub fn insert<F: FnOnce(&Eviction)>(&mut self, code: &[u8], discard: F) -> Translation {
+        assert!(
+            code.len() <= REGION_SIZE,
+            "translation of {} bytes does not fit in a code cache region",
+            code.len()
+        );
+
+        if self.regions[self.current].used + code.len() > REGION_SIZE {
+            if self.regions.len() < self.max_regions {
+                self.regions.push(Region::new());
+                self.current = self.regions.len() - 1;
+            } else {
+                discard(&self.evict());
+            }
+        }
+
+        self.clock += 1;
+
+        let region = &mut self.regions[self.current];
+        let offset = region.used;
+        region.memory.as_mut_slice()[offset..offset + code.len()].copy_from_slice(code);
+        region.used = (offset + code.len()).next_multiple_of(16);
+        region.last_used = self.clock;
+
+        let code_ptr = (region.memory.executable_range().start + offset) as *const u8;
+
+        Translation::from_raw(code_ptr, code.len())
+    }
+
+    /// Records that the supplied translation is being executed
+    pub fn touch(&mut self, translation: &Translation) {
+        if self.policy != EvictionPolicy::LeastRecentlyUsed {
+            return;
+        }
+
+        self.clock += 1;
+
+        if let Some(region) = self
+            .regions
+            .iter_mut()
+            .find(|r| r.contains(translation.as_ptr()))
+        {
+            region.last_used = self.clock;
+        }
+    }
+
+    fn evict(&mut self) -> Eviction {
+        match self.policy {
+            EvictionPolicy::Flush => {
+                log::debug!("code cache full, flushing");
+
+                self.regions.iter_mut().for_each(|r| r.used = 0);
+                self.current = 0;
+
+                Eviction::All
+            }
+            EvictionPolicy::LeastRecentlyUsed => {
+                let (index, region) = self
+                    .regions
+                    .iter_mut()
+                    .enumerate()
+                    .min_by_key(|(_, r)| r.last_used)
+                    .unwrap();
+
+                log::debug!("code cache full, evicting region {index}");
+
+                region.used = 0;
+                self.current = index;
+
+                Eviction::Region(region.memory.executable_range())
+            }
+        }
+    }
+}
+
+#[ktest]
+fn eviction_precedes_reuse() {
+    let mut cache = CodeCache::new(REGION_SIZE, EvictionPolicy::LeastRecentlyUsed);
+
+    // fills the only region, with an unlinked chain slot at its start
+    let mut code = alloc::vec![0xcc; REGION_SIZE];
+    code[CHAIN_TAG_OFFSET..CHAIN_TAG_OFFSET + 8].copy_from_slice(&CHAIN_UNLINKED_TAG.to_le_bytes());
+    let evicted = cache.insert(&code, |_| panic!("nothing to evict"));
+
+    let slot = ChainSlot::from_offsets(0, 16).relocate(evicted.as_ptr());
+    let mut links = ChainLinks::default();
+    links.link(&[slot], 0x4000_0000, evicted.as_ptr());
+
+    let code = [0x90; 64];
+    let mut discarded = false;
+    let translation = cache.insert(&code, |eviction| {
+        discarded = true;
+        links.forget_slots(|code| eviction.contains(code));
+        links.unlink_targets(|code| eviction.contains(code));
+    });
+
+    assert!(discarded);
+    assert_eq!(translation.as_ptr(), evicted.as_ptr());
+    assert_eq!(
+        unsafe { core::slice::from_raw_parts(translation.as_ptr(), translation.len()) },
+        code
+    );
+}
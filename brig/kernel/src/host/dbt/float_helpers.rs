@@ -0,0 +1,637 @@
+//! Floating point operations without an SSE2 instruction, called from
+//! translated code
+//!
+//! Values are passed and returned as bit patterns in the low bits of each
+//! argument, as they are held between nodes, followed by the guest `FPCR` and
+//! a pointer to the guest `FPSR`. Results are computed in software, rounded as
+//! selected by `FPCR.RMode` and flushed to zero under `FPCR.FZ`, and the
+//! exceptions they raise are accumulated into `FPSR`, matching the operations
+//! lowered to SSE2 with `MXCSR` loaded from `FPCR`.
+
+use {core::cmp::Ordering, proc_macro_lib::ktest};
+
+/// Invalid operation cumulative exception bit of `FPSR`
+const FPSR_IOC: u32 = 1 << 0;
+/// Divide by zero cumulative exception bit of `FPSR`
+const FPSR_DZC: u32 = 1 << 1;
+/// Overflow cumulative exception bit of `FPSR`
+const FPSR_OFC: u32 = 1 << 2;
+/// Underflow cumulative exception bit of `FPSR`
+const FPSR_UFC: u32 = 1 << 3;
+/// Inexact cumulative exception bit of `FPSR`
+const FPSR_IXC: u32 = 1 << 4;
+/// Input denormal cumulative exception bit of `FPSR`
+const FPSR_IDC: u32 = 1 << 7;
+
+pub extern "C" fn remainder_f32(left: u64, right: u64, fpcr: u64, fpsr: &mut u32) -> u64 {
+    Environment::new(fpcr, fpsr).remainder(SINGLE, left, right)
+}
+
+pub extern "C" fn remainder_f64(left: u64, right: u64, fpcr: u64, fpsr: &mut u32) -> u64 {
+    Environment::new(fpcr, fpsr).remainder(DOUBLE, left, right)
+}
+
+pub extern "C" fn powi_f32(base: u64, exponent: u64, fpcr: u64, fpsr: &mut u32) -> u64 {
+    Environment::new(fpcr, fpsr).powi(SINGLE, base, exponent as i64)
+}
+
+pub extern "C" fn powi_f64(base: u64, exponent: u64, fpcr: u64, fpsr: &mut u32) -> u64 {
+    Environment::new(fpcr, fpsr).powi(DOUBLE, base, exponent as i64)
+}
+
+pub extern "C" fn exp2_f32(value: u64, fpcr: u64, fpsr: &mut u32) -> u64 {
+    Environment::new(fpcr, fpsr).exp2(SINGLE, value)
+}
+
+pub extern "C" fn exp2_f64(value: u64, fpcr: u64, fpsr: &mut u32) -> u64 {
+    Environment::new(fpcr, fpsr).exp2(DOUBLE, value)
+}
+
+/// IEEE 754 binary interchange format
+#[derive(Debug, Clone, Copy)]
+struct Format {
+    /// Bits of the stored fraction
+    fraction: u32,
+    /// Bits of the biased exponent
+    exponent: u32,
+}
+
+const SINGLE: Format = Format {
+    fraction: 23,
+    exponent: 8,
+};
+
+const DOUBLE: Format = Format {
+    fraction: 52,
+    exponent: 11,
+};
+
+impl Format {
+    fn bias(self) -> i32 {
+        (1 << (self.exponent - 1)) - 1
+    }
+
+    fn sign(self, bits: u64) -> bool {
+        (bits >> (self.fraction + self.exponent)) & 1 == 1
+    }
+
+    fn biased_exponent(self, bits: u64) -> u64 {
+        (bits >> self.fraction) & ((1 << self.exponent) - 1)
+    }
+
+    fn fraction_bits(self, bits: u64) -> u64 {
+        bits & ((1 << self.fraction) - 1)
+    }
+
+    fn is_nan(self, bits: u64) -> bool {
+        self.biased_exponent(bits) == (1 << self.exponent) - 1 && self.fraction_bits(bits) != 0
+    }
+
+    fn is_signalling(self, bits: u64) -> bool {
+        self.is_nan(bits) && bits & self.quiet_bit() == 0
+    }
+
+    fn is_infinite(self, bits: u64) -> bool {
+        self.biased_exponent(bits) == (1 << self.exponent) - 1 && self.fraction_bits(bits) == 0
+    }
+
+    fn is_zero(self, bits: u64) -> bool {
+        self.biased_exponent(bits) == 0 && self.fraction_bits(bits) == 0
+    }
+
+    fn quiet_bit(self) -> u64 {
+        1 << (self.fraction - 1)
+    }
+
+    fn pack(self, sign: bool, biased_exponent: u64, fraction: u64) -> u64 {
+        (u64::from(sign) << (self.fraction + self.exponent))
+            | (biased_exponent << self.fraction)
+            | fraction
+    }
+
+    fn zero(self, sign: bool) -> u64 {
+        self.pack(sign, 0, 0)
+    }
+
+    fn infinity(self, sign: bool) -> u64 {
+        self.pack(sign, (1 << self.exponent) - 1, 0)
+    }
+
+    fn max_finite(self, sign: bool) -> u64 {
+        self.pack(sign, (1 << self.exponent) - 2, (1 << self.fraction) - 1)
+    }
+
+    fn default_nan(self) -> u64 {
+        self.pack(false, (1 << self.exponent) - 1, self.quiet_bit())
+    }
+
+    /// Converts a host double precision value, which must be representable
+    fn from_f64(self, value: f64) -> u64 {
+        match self.fraction {
+            23 => u64::from((value as f32).to_bits()),
+            _ => value.to_bits(),
+        }
+    }
+
+    /// Converts to a host double precision value, exactly
+    fn to_f64(self, bits: u64) -> f64 {
+        match self.fraction {
+            23 => f64::from(f32::from_bits(bits as u32)),
+            _ => f64::from_bits(bits),
+        }
+    }
+}
+
+/// Rounding modes selected by `FPCR.RMode`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RoundingMode {
+    Nearest,
+    PlusInfinity,
+    MinusInfinity,
+    Zero,
+}
+
+/// Finite value `significand * 2^exponent`, computed exactly
+#[derive(Debug, Clone, Copy)]
+struct Exact {
+    sign: bool,
+    significand: u128,
+    exponent: i32,
+}
+
+/// Guest floating point controls of a call, and the `FPSR` its exceptions are
+/// accumulated into
+struct Environment<'fpsr> {
+    rounding: RoundingMode,
+    flush_to_zero: bool,
+    default_nan: bool,
+    fpsr: &'fpsr mut u32,
+}
+
+impl<'fpsr> Environment<'fpsr> {
+    fn new(fpcr: u64, fpsr: &'fpsr mut u32) -> Self {
+        Self {
+            rounding: match (fpcr >> 22) & 0b11 {
+                0b00 => RoundingMode::Nearest,
+                0b01 => RoundingMode::PlusInfinity,
+                0b10 => RoundingMode::MinusInfinity,
+                _ => RoundingMode::Zero,
+            },
+            flush_to_zero: (fpcr >> 24) & 1 == 1,
+            default_nan: (fpcr >> 25) & 1 == 1,
+            fpsr,
+        }
+    }
+
+    fn raise(&mut self, exceptions: u32) {
+        *self.fpsr |= exceptions;
+    }
+
+    /// Result of an operation on `operands` if any is a NaN, preferring
+    /// signalling NaNs, each in turn
+    fn process_nans(&mut self, format: Format, operands: &[u64]) -> Option<u64> {
+        let nan = operands
+            .iter()
+            .find(|operand| format.is_signalling(**operand))
+            .or_else(|| operands.iter().find(|operand| format.is_nan(**operand)))?;
+
+        if format.is_signalling(*nan) {
+            self.raise(FPSR_IOC);
+        }
+
+        Some(if self.default_nan {
+            format.default_nan()
+        } else {
+            nan | format.quiet_bit()
+        })
+    }
+
+    fn invalid(&mut self, format: Format) -> u64 {
+        self.raise(FPSR_IOC);
+        format.default_nan()
+    }
+
+    /// Flushes a denormal input to zero under `FPCR.FZ`
+    fn flush_input(&mut self, format: Format, bits: u64) -> u64 {
+        if self.flush_to_zero && format.biased_exponent(bits) == 0 && !format.is_zero(bits) {
+            self.raise(FPSR_IDC);
+            format.zero(format.sign(bits))
+        } else {
+            bits
+        }
+    }
+
+    /// Exact value of finite `bits`
+    fn unpack(&mut self, format: Format, bits: u64) -> Exact {
+        let bits = self.flush_input(format, bits);
+        let biased_exponent = format.biased_exponent(bits);
+        let fraction = format.fraction_bits(bits);
+
+        // the implicit bit is clear for denormals, with the same exponent as the
+        // smallest normal
+        let (significand, exponent) = if biased_exponent == 0 {
+            (fraction, 1)
+        } else {
+            (fraction | (1 << format.fraction), biased_exponent as i32)
+        };
+
+        Exact {
+            sign: format.sign(bits),
+            significand: u128::from(significand),
+            exponent: exponent - format.bias() - format.fraction as i32,
+        }
+    }
+
+    /// Rounds `value` to `format`, raising the exceptions that causes
+    fn round(&mut self, format: Format, value: Exact) -> u64 {
+        let Exact {
+            sign,
+            significand,
+            exponent,
+        } = value;
+
+        if significand == 0 {
+            return format.zero(sign);
+        }
+
+        let precision = format.fraction as i32 + 1;
+        let min_exponent = 1 - format.bias();
+
+        // exponent of the most significant bit, unbounded
+        let msb = exponent + (128 - significand.leading_zeros() as i32) - 1;
+
+        // tininess is detected before rounding
+        let tiny = msb < min_exponent;
+        if tiny && self.flush_to_zero {
+            self.raise(FPSR_UFC);
+            return format.zero(sign);
+        }
+
+        // exponent of the least significant bit kept, limited by denormals
+        let lsb = msb.max(min_exponent) - (precision - 1);
+        let shift = lsb - exponent;
+
+        let (kept, half, inexact) = match shift {
+            ..=0 => (significand << -shift, Ordering::Less, false),
+            1..=127 => {
+                let dropped = significand & ((1 << shift) - 1);
+                (
+                    significand >> shift,
+                    dropped.cmp(&(1 << (shift - 1))),
+                    dropped != 0,
+                )
+            }
+            128 => (0, significand.cmp(&(1 << 127)), true),
+            _ => (0, Ordering::Less, true),
+        };
+
+        let round_up = inexact
+            && match self.rounding {
+                RoundingMode::Nearest => {
+                    half == Ordering::Greater || (half == Ordering::Equal && kept & 1 == 1)
+                }
+                RoundingMode::PlusInfinity => !sign,
+                RoundingMode::MinusInfinity => sign,
+                RoundingMode::Zero => false,
+            };
+
+        let (mut kept, mut lsb) = (kept + u128::from(round_up), lsb);
+        if kept >> precision != 0 {
+            kept >>= 1;
+            lsb += 1;
+        }
+
+        if inexact {
+            self.raise(FPSR_IXC | if tiny { FPSR_UFC } else { 0 });
+        }
+
+        // denormal, unless rounded up to the smallest normal
+        if kept >> (precision - 1) == 0 {
+            return format.pack(sign, 0, kept as u64);
+        }
+
+        let biased_exponent = lsb + precision - 1 + format.bias();
+        if biased_exponent >= (1 << format.exponent) - 1 {
+            self.raise(FPSR_OFC | FPSR_IXC);
+
+            let to_infinity = match self.rounding {
+                RoundingMode::Nearest => true,
+                RoundingMode::PlusInfinity => !sign,
+                RoundingMode::MinusInfinity => sign,
+                RoundingMode::Zero => false,
+            };
+
+            return if to_infinity {
+                format.infinity(sign)
+            } else {
+                format.max_finite(sign)
+            };
+        }
+
+        format.pack(
+            sign,
+            biased_exponent as u64,
+            format.fraction_bits(kept as u64),
+        )
+    }
+
+    /// Remainder of truncating division, which is always exact
+    fn remainder(&mut self, format: Format, left: u64, right: u64) -> u64 {
+        if let Some(nan) = self.process_nans(format, &[left, right]) {
+            return nan;
+        }
+
+        let left = self.flush_input(format, left);
+        let right = self.flush_input(format, right);
+
+        if format.is_infinite(left) || format.is_zero(right) {
+            return self.invalid(format);
+        }
+
+        let remainder = format.to_f64(left) % format.to_f64(right);
+        let remainder = format.from_f64(remainder);
+
+        // only the output of a denormal remainder is flushed
+        if self.flush_to_zero && format.biased_exponent(remainder) == 0 {
+            if !format.is_zero(remainder) {
+                self.raise(FPSR_UFC);
+            }
+            return format.zero(format.sign(remainder));
+        }
+
+        remainder
+    }
+
+    fn multiply(&mut self, format: Format, left: u64, right: u64) -> u64 {
+        if let Some(nan) = self.process_nans(format, &[left, right]) {
+            return nan;
+        }
+
+        let sign = format.sign(left) != format.sign(right);
+        let left = self.flush_input(format, left);
+        let right = self.flush_input(format, right);
+
+        match (
+            format.is_infinite(left) || format.is_infinite(right),
+            format.is_zero(left) || format.is_zero(right),
+        ) {
+            (true, true) => self.invalid(format),
+            (true, false) => format.infinity(sign),
+            (false, true) => format.zero(sign),
+            (false, false) => {
+                let (left, right) = (self.unpack(format, left), self.unpack(format, right));
+
+                self.round(
+                    format,
+                    Exact {
+                        sign,
+                        significand: left.significand * right.significand,
+                        exponent: left.exponent + right.exponent,
+                    },
+                )
+            }
+        }
+    }
+
+    fn divide(&mut self, format: Format, left: u64, right: u64) -> u64 {
+        if let Some(nan) = self.process_nans(format, &[left, right]) {
+            return nan;
+        }
+
+        let sign = format.sign(left) != format.sign(right);
+        let left = self.flush_input(format, left);
+        let right = self.flush_input(format, right);
+
+        match (
+            format.is_infinite(left),
+            format.is_infinite(right),
+            format.is_zero(left),
+            format.is_zero(right),
+        ) {
+            (true, true, ..) | (_, _, true, true) => self.invalid(format),
+            (true, ..) => format.infinity(sign),
+            (_, true, ..) | (_, _, true, _) => format.zero(sign),
+            (.., true) => {
+                self.raise(FPSR_DZC);
+                format.infinity(sign)
+            }
+            _ => {
+                let (left, right) = (self.unpack(format, left), self.unpack(format, right));
+
+                // the dividend is shifted so the quotient has more bits than the
+                // precision, and the remainder is kept as a sticky bit
+                let shift = 127 - (128 - left.significand.leading_zeros() as i32);
+                let dividend = left.significand << shift;
+                let quotient = dividend / right.significand;
+                let sticky = dividend % right.significand != 0;
+
+                self.round(
+                    format,
+                    Exact {
+                        sign,
+                        significand: (quotient << 1) | u128::from(sticky),
+                        exponent: left.exponent - shift - right.exponent - 1,
+                    },
+                )
+            }
+        }
+    }
+
+    /// `base` to the power of `exponent` by repeated squaring, each product
+    /// rounded, and the reciprocal of that for negative exponents
+    fn powi(&mut self, format: Format, base: u64, exponent: i64) -> u64 {
+        let one = format.pack(false, format.bias() as u64, 0);
+
+        let mut base = base;
+        let mut power = one;
+        let mut remaining = exponent.unsigned_abs();
+
+        while remaining != 0 {
+            if remaining & 1 == 1 {
+                power = self.multiply(format, power, base);
+            }
+            remaining >>= 1;
+            if remaining != 0 {
+                base = self.multiply(format, base, base);
+            }
+        }
+
+        if exponent >= 0 {
+            power
+        } else {
+            self.divide(format, one, power)
+        }
+    }
+
+    /// 2 to the power of `value`, from the power of its integer part and a
+    /// series for its fractional part, rounded once
+    fn exp2(&mut self, format: Format, value: u64) -> u64 {
+        if let Some(nan) = self.process_nans(format, &[value]) {
+            return nan;
+        }
+
+        let value = self.flush_input(format, value);
+
+        if format.is_infinite(value) {
+            return if format.sign(value) {
+                format.zero(false)
+            } else {
+                value
+            };
+        }
+
+        // beyond the range of every finite result, so the integer part fits
+        let value = format.to_f64(value).clamp(-1200.0, 1200.0);
+
+        let integer = value as i32;
+        let fraction = value - f64::from(integer);
+
+        // e^(fraction * ln 2), with |fraction * ln 2| < 0.7
+        let x = fraction * core::f64::consts::LN_2;
+        let mut term = 1.0;
+        let mut sum = 1.0;
+        for n in 1..=24 {
+            term = term * x / f64::from(n);
+            sum += term;
+        }
+
+        let series = self.unpack(DOUBLE, sum.to_bits());
+
+        // unless the fraction is zero the result is irrational, so lies strictly
+        // between the series and the next value below its precision
+        let sticky = fraction != 0.0;
+
+        self.round(
+            format,
+            Exact {
+                sign: false,
+                significand: (series.significand << 64) | u128::from(sticky),
+                exponent: series.exponent - 64 + integer,
+            },
+        )
+    }
+}
+
+/// Calls `helper` with the guest `FPCR` `fpcr`, returning its result and the
+/// exceptions it accumulated into a clear `FPSR`
+fn call(fpcr: u64, helper: impl FnOnce(u64, &mut u32) -> u64) -> (u64, u32) {
+    let mut fpsr = 0;
+    let result = helper(fpcr, &mut fpsr);
+    (result, fpsr)
+}
+
+/// `FPCR` selecting round towards plus infinity
+const FPCR_RP: u64 = 0b01 << 22;
+/// `FPCR` selecting round towards minus infinity
+const FPCR_RM: u64 = 0b10 << 22;
+/// `FPCR` selecting round towards zero
+const FPCR_RZ: u64 = 0b11 << 22;
+/// `FPCR` with flush to zero enabled
+const FPCR_FZ: u64 = 1 << 24;
+
+#[ktest]
+fn remainder() {
+    let f64_remainder = |left: f64, right: f64| {
+        let (result, fpsr) = call(0, |fpcr, fpsr| {
+            remainder_f64(left.to_bits(), right.to_bits(), fpcr, fpsr)
+        });
+        (f64::from_bits(result), fpsr)
+    };
+
+    assert_eq!(f64_remainder(7.5, 2.0), (1.5, 0));
+    assert_eq!(f64_remainder(-7.5, 2.0), (-1.5, 0));
+
+    let (result, fpsr) = f64_remainder(1.0, 0.0);
+    assert!(result.is_nan());
+    assert_eq!(fpsr, FPSR_IOC);
+
+    let (result, fpsr) = call(0, |fpcr, fpsr| {
+        remainder_f32(
+            u64::from(7.5f32.to_bits()),
+            u64::from(2.0f32.to_bits()),
+            fpcr,
+            fpsr,
+        )
+    });
+    assert_eq!((f32::from_bits(result as u32), fpsr), (1.5, 0));
+}
+
+#[ktest]
+fn integer_powers() {
+    let f64_powi = |fpcr: u64, base: f64, exponent: i64| {
+        let (result, fpsr) = call(fpcr, |fpcr, fpsr| {
+            powi_f64(base.to_bits(), exponent as u64, fpcr, fpsr)
+        });
+        (f64::from_bits(result), fpsr)
+    };
+
+    assert_eq!(f64_powi(0, 3.0, 4), (81.0, 0));
+    assert_eq!(f64_powi(0, 2.0, -2), (0.25, 0));
+    assert_eq!(f64_powi(0, 5.0, 0), (1.0, 0));
+
+    let (result, fpsr) = call(0, |fpcr, fpsr| {
+        powi_f32(u64::from(1.5f32.to_bits()), 2, fpcr, fpsr)
+    });
+    assert_eq!((f32::from_bits(result as u32), fpsr), (2.25, 0));
+
+    // a third is just above the nearest double
+    assert_eq!(f64_powi(0, 3.0, -1), (1.0 / 3.0, FPSR_IXC));
+    assert_eq!(f64_powi(FPCR_RM, 3.0, -1), (1.0 / 3.0, FPSR_IXC));
+    assert_eq!(f64_powi(FPCR_RZ, 3.0, -1), (1.0 / 3.0, FPSR_IXC));
+    assert_eq!(
+        f64_powi(FPCR_RP, 3.0, -1).0.to_bits(),
+        (1.0f64 / 3.0).to_bits() + 1
+    );
+
+    let (result, fpsr) = call(0, |fpcr, fpsr| {
+        powi_f32(u64::from(2.0f32.to_bits()), 200, fpcr, fpsr)
+    });
+    assert_eq!(f32::from_bits(result as u32), f32::INFINITY);
+    assert_eq!(fpsr, FPSR_OFC | FPSR_IXC);
+
+    let (result, _) = call(FPCR_RZ, |fpcr, fpsr| {
+        powi_f32(u64::from(2.0f32.to_bits()), 200, fpcr, fpsr)
+    });
+    assert_eq!(f32::from_bits(result as u32), f32::MAX);
+
+    // underflows to zero, or to the smallest denormal when rounding up
+    assert_eq!(
+        f64_powi(0, f64::MIN_POSITIVE, 2),
+        (0.0, FPSR_UFC | FPSR_IXC)
+    );
+    assert_eq!(f64_powi(FPCR_RP, f64::MIN_POSITIVE, 2).0.to_bits(), 1);
+
+    // denormal inputs are flushed to zero
+    assert_eq!(f64_powi(FPCR_FZ, f64::from_bits(1), 1), (0.0, FPSR_IDC));
+}
+
+#[ktest]
+fn powers_of_two() {
+    let f64_exp2 = |fpcr: u64, value: f64| {
+        let (result, fpsr) = call(fpcr, |fpcr, fpsr| exp2_f64(value.to_bits(), fpcr, fpsr));
+        (f64::from_bits(result), fpsr)
+    };
+
+    assert_eq!(f64_exp2(0, 10.0), (1024.0, 0));
+    assert_eq!(f64_exp2(0, -3.0), (0.125, 0));
+    assert_eq!(f64_exp2(0, f64::INFINITY), (f64::INFINITY, 0));
+    assert_eq!(f64_exp2(0, f64::NEG_INFINITY), (0.0, 0));
+    assert_eq!(f64_exp2(0, 1024.0), (f64::INFINITY, FPSR_OFC | FPSR_IXC));
+    assert_eq!(f64_exp2(FPCR_RZ, 1024.0), (f64::MAX, FPSR_OFC | FPSR_IXC));
+
+    let (root, fpsr) = f64_exp2(0, 0.5);
+    assert!(root > core::f64::consts::SQRT_2 - 1e-15);
+    assert!(root < core::f64::consts::SQRT_2 + 1e-15);
+    assert_eq!(fpsr, FPSR_IXC);
+
+    // an irrational result is rounded to either side of it
+    assert_eq!(
+        f64_exp2(FPCR_RP, 0.5).0.to_bits(),
+        f64_exp2(FPCR_RM, 0.5).0.to_bits() + 1
+    );
+
+    let (result, fpsr) = call(0, |fpcr, fpsr| {
+        exp2_f32(u64::from(3.0f32.to_bits()), fpcr, fpsr)
+    });
+    assert_eq!((f32::from_bits(result as u32), fpsr), (8.0, 0));
+}
@@ -0,0 +1,636 @@
+//! GDB remote serial protocol stub for debugging guest cores
+//!
+//! Cores configured with the `gdb` key serve GDB over the second serial port,
+//! which `brig-cli --guest-gdb` exposes as a TCP socket that `gdb-multiarch`
+//! attaches to with `target remote :1235`. Every debugged core shares the one
+//! connection as a GDB thread, whose ID is one more than its core ID.
+//!
+//! Debugged cores stop before their first instruction until GDB attaches.
+//! Their blocks are single instructions and are never chained, so that the
+//! block execution loop can consult the stub before every instruction: to stop
+//! at breakpoints, after a step, or when GDB interrupts execution. The core that
+//! stops serves GDB while the others wait before their next instruction, and a
+//! step only executes an instruction on the core being stepped.
+//!
+//! Registers are those of the `org.gnu.gdb.aarch64.core` feature, and memory is
+//! accessed by guest virtual address, limited to guest RAM so that devices are
+//! never read on behalf of the debugger.
+
+use {
+    crate::{
+        guest::{GuestExecutionContext, MAX_GUEST_CORES, memory::AddressSpaceRegionKind},
+        host::{
+            arch::x86::{
+                aarch64_mmu::{self, Access},
+                memory::guest_physical_to_host_virt,
+            },
+            dbt::models::ModelDevice,
+            devices::serial::UART16550Device,
+        },
+    },
+    alloc::{
+        collections::BTreeSet,
+        format,
+        string::{String, ToString},
+        vec::Vec,
+    },
+    core::{
+        fmt::Write,
+        sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    proc_macro_lib::ktest,
+    spin::{Mutex, Once, RwLock},
+};
+
+/// I/O port of the serial port GDB is attached to, COM2 as COM1 carries the
+/// log
+pub const GDB_SERIAL_IO_PORT: u16 = 0x2F8;
+
+/// Instructions executed between checks for GDB interrupting execution
+const INTERRUPT_POLL_INTERVAL: u64 = 0x1000;
+
+/// Maximum packet size advertised to GDB
+const PACKET_SIZE: usize = 0x1000;
+
+/// Sent by GDB outside of a packet to interrupt execution
+const INTERRUPT: u8 = 0x03;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// Registers of the target description: x0 to x30, then these
+const SP: usize = 31;
+const PC: usize = 32;
+const CPSR: usize = 33;
+const REGISTER_COUNT: usize = 34;
+
+/// [`MODE`] while every debugged core is executing
+const RUNNING: usize = usize::MAX;
+/// [`MODE`] while a core serves GDB and the others wait
+const STOPPED: usize = usize::MAX - 1;
+
+/// Whether debugged cores are running or stopped, otherwise the ID of the only
+/// core executing, which is being stepped
+static MODE: AtomicUsize = AtomicUsize::new(RUNNING);
+
+/// Whether each core is debugged
+static DEBUGGED: [AtomicBool; MAX_GUEST_CORES] =
+    [const { AtomicBool::new(false) }; MAX_GUEST_CORES];
+
+/// Whether each debugged core is waiting before an instruction, so that its
+/// registers can be accessed by the core serving GDB
+static WAITING: [AtomicBool; MAX_GUEST_CORES] = [const { AtomicBool::new(false) }; MAX_GUEST_CORES];
+
+/// Breakpoint addresses, checked by every debugged core before every
+/// instruction
+static BREAKPOINTS: RwLock<BTreeSet<u64>> = RwLock::new(BTreeSet::new());
+
+/// Connection to GDB, held by the core serving it
+static CONNECTION: Once<Mutex<Connection>> = Once::new();
+
+enum Action {
+    Reply(String),
+    Resume(usize),
+}
+
+struct Connection {
+    port: UART16550Device,
+    /// Whether GDB has attached
+    attached: bool,
+    /// Signal reported by the last stop, and the core that stopped
+    stop: (u8, usize),
+    /// Core whose registers and memory are accessed, selected by `Hg`
+    selected: usize,
+    /// Core to step, selected by `Hc`, otherwise the core that stopped
+    stepped: Option<usize>,
+}
+
+/// Debugger state of one core
+pub struct GdbStub {
+    core_id: usize,
+    /// Whether GDB attached while this core was running
+    attached: bool,
+    /// Execution was just resumed, so a breakpoint at the current PC has
+    /// already been reported
+    resumed: bool,
+    /// Whether the instruction this core executes next is a step
+    stepping: bool,
+    /// Instructions executed since execution was resumed
+    instructions: u64,
+}
+
+impl GdbStub {
+    pub fn new(core_id: usize) -> Self {
+        CONNECTION.call_once(|| {
+            log::warn!("waiting for GDB on serial port {GDB_SERIAL_IO_PORT:#x}");
+
+            Mutex::new(Connection {
+                port: UART16550Device::new(GDB_SERIAL_IO_PORT),
+                attached: false,
+                stop: (SIGTRAP, core_id),
+                selected: core_id,
+                stepped: None,
+            })
+        });
+        DEBUGGED[core_id].store(true, Ordering::Release);
+
+        Self {
+            core_id,
+            attached: false,
+            resumed: false,
+            stepping: false,
+            instructions: 0,
+        }
+    }
+
+    /// Stops and serves GDB until execution is resumed if a breakpoint was
+    /// reached, a step completed, or GDB interrupted execution, first waiting
+    /// for any other core serving GDB or being stepped
+    pub fn before_instruction(&mut self, device: &ModelDevice) {
+        // whether the previous instruction was executed by a step
+        let stepped = self.stepping;
+        self.wait();
+
+        let pc = device.well_known_registers.pc().read();
+        let resumed = core::mem::take(&mut self.resumed);
+
+        let signal = if !self.attached {
+            // GDB asks why the target stopped once attached
+            None
+        } else if stepped || (!resumed && BREAKPOINTS.read().contains(&pc)) {
+            Some(SIGTRAP)
+        } else {
+            self.instructions += 1;
+
+            let interrupted = !self.stepping
+                && self.instructions.is_multiple_of(INTERRUPT_POLL_INTERVAL)
+                && CONNECTION
+                    .get()
+                    .unwrap()
+                    .try_lock()
+                    .is_some_and(|mut connection| connection.port.try_receive() == Some(INTERRUPT));
+
+            if !interrupted {
+                return;
+            }
+
+            Some(SIGINT)
+        };
+
+        self.serve(signal);
+        self.wait();
+    }
+
+    /// Reports the stop with `signal`, if any, and serves GDB until it resumes
+    /// execution
+    fn serve(&mut self, signal: Option<u8>) {
+        // another core may be serving GDB already, and accesses this one meanwhile
+        WAITING[self.core_id].store(true, Ordering::Release);
+        let mut connection = CONNECTION.get().unwrap().lock();
+
+        if !core::mem::replace(&mut self.attached, true) {
+            // GDB attached while another core was serving it
+            if connection.attached {
+                WAITING[self.core_id].store(false, Ordering::Release);
+                return;
+            }
+
+            connection.attached = true;
+            connection.stop = (SIGTRAP, self.core_id);
+            connection.selected = self.core_id;
+        }
+
+        MODE.store(STOPPED, Ordering::Release);
+
+        if let Some(signal) = signal {
+            connection.stop = (signal, self.core_id);
+            connection.selected = self.core_id;
+            connection.stepped = None;
+            let reply = connection.stop_reply();
+            connection.send(&reply);
+        }
+
+        let mode = loop {
+            let packet = connection.receive();
+
+            match connection.handle(&packet) {
+                Action::Reply(reply) => connection.send(&reply),
+                Action::Resume(mode) => break mode,
+            }
+        };
+
+        self.resumed = true;
+        self.instructions = 0;
+
+        MODE.store(mode, Ordering::Release);
+        WAITING[self.core_id].store(false, Ordering::Release);
+    }
+
+    /// Waits while another core serves GDB or is being stepped, then records
+    /// whether the next instruction is executed by a step
+    fn wait(&mut self) {
+        let mode = || MODE.load(Ordering::Acquire);
+        let waiting = |mode| mode != RUNNING && mode != self.core_id;
+
+        if waiting(mode()) {
+            WAITING[self.core_id].store(true, Ordering::Release);
+            while waiting(mode()) {
+                core::hint::spin_loop();
+            }
+            WAITING[self.core_id].store(false, Ordering::Release);
+        }
+
+        self.stepping = mode() == self.core_id;
+    }
+}
+
+impl Connection {
+    fn handle(&mut self, packet: &str) -> Action {
+        let mut chars = packet.chars();
+        let command = chars.next();
+        let arguments = chars.as_str();
+        let device = stopped_core(self.selected);
+
+        let reply = match command {
+            Some('?') => self.stop_reply(),
+            Some('g') => (0..REGISTER_COUNT)
+                .map(|n| encode_register(device, n).unwrap())
+                .collect(),
+            Some('G') => {
+                let mut bytes = decode_hex(arguments).unwrap_or_default().into_iter();
+
+                (0..REGISTER_COUNT).for_each(|n| {
+                    let (_, size) = read_register(device, n).unwrap();
+                    let value = bytes.by_ref().take(size).collect::<Vec<_>>();
+                    if value.len() == size {
+                        write_register(device, n, from_le_bytes(&value));
+                    }
+                });
+
+                "OK".to_string()
+            }
+            Some('p') => parse_hex(arguments)
+                .and_then(|n| encode_register(device, n as usize))
+                .unwrap_or_else(|| "E00".to_string()),
+            Some('P') => arguments
+                .split_once('=')
+                .and_then(|(n, value)| {
+                    let n = parse_hex(n)? as usize;
+                    read_register(device, n)?;
+                    write_register(device, n, from_le_bytes(&decode_hex(value)?));
+                    Some("OK".to_string())
+                })
+                .unwrap_or_else(|| "E00".to_string()),
+            Some('m') => match parse_pair(arguments) {
+                Some((address, length)) => {
+                    let bytes = (address..address.saturating_add(length))
+                        .map_while(|address| {
+                            guest_ram(device, address).map(|byte| unsafe { byte.read_volatile() })
+                        })
+                        .collect::<Vec<_>>();
+
+                    if bytes.is_empty() && length != 0 {
+                        "E14".to_string()
+                    } else {
+                        encode_hex(&bytes)
+                    }
+                }
+                None => "E00".to_string(),
+            },
+            Some('M') => arguments
+                .split_once(':')
+                .and_then(|(range, data)| Some((parse_pair(range)?.0, decode_hex(data)?)))
+                .map(|(address, data)| {
+                    let written = (address..).zip(data).all(|(address, value)| {
+                        guest_ram(device, address)
+                            .map(|byte| unsafe { byte.write_volatile(value) })
+                            .is_some()
+                    });
+
+                    (if written { "OK" } else { "E14" }).to_string()
+                })
+                .unwrap_or_else(|| "E00".to_string()),
+            Some(c @ ('Z' | 'z')) => {
+                let mut fields = arguments.split(',');
+
+                // software and hardware breakpoints are equivalent, watchpoints are not supported
+                match (fields.next(), fields.next().and_then(parse_hex)) {
+                    (Some("0" | "1"), Some(address)) => {
+                        if c == 'Z' {
+                            BREAKPOINTS.write().insert(address);
+                        } else {
+                            BREAKPOINTS.write().remove(&address);
+                        }
+                        "OK".to_string()
+                    }
+                    _ => String::new(),
+                }
+            }
+            Some('c') => {
+                if let Some(address) = parse_hex(arguments) {
+                    device.well_known_registers.pc().write(address);
+                }
+
+                return Action::Resume(RUNNING);
+            }
+            Some('s') => {
+                let core_id = self.stepped.unwrap_or(self.stop.1);
+
+                if let Some(address) = parse_hex(arguments) {
+                    stopped_core(core_id)
+                        .well_known_registers
+                        .pc()
+                        .write(address);
+                }
+
+                return Action::Resume(core_id);
+            }
+            Some('D') => {
+                BREAKPOINTS.write().clear();
+                self.send("OK");
+                return Action::Resume(RUNNING);
+            }
+            // the guest cannot be killed, so it is detached from instead
+            Some('k') => {
+                BREAKPOINTS.write().clear();
+                return Action::Resume(RUNNING);
+            }
+            Some('H') => {
+                let (operation, thread) = arguments.split_at(arguments.len().min(1));
+
+                match (operation, parse_thread(thread)) {
+                    ("g", Some(core_id)) => {
+                        self.selected = core_id.unwrap_or(self.stop.1);
+                        "OK".to_string()
+                    }
+                    ("c", Some(core_id)) => {
+                        self.stepped = core_id;
+                        "OK".to_string()
+                    }
+                    _ => "E00".to_string(),
+                }
+            }
+            Some('T') => match parse_thread(arguments) {
+                Some(Some(_)) => "OK".to_string(),
+                _ => "E00".to_string(),
+            },
+            Some('q') => match arguments {
+                "fThreadInfo" => format!(
+                    "m{}",
+                    debugged_cores()
+                        .map(|core_id| format!("{:x}", core_id + 1))
+                        .collect::<Vec<_>>()
+                        .join(",")
+                ),
+                "sThreadInfo" => "l".to_string(),
+                "C" => format!("QC{:x}", self.stop.1 + 1),
+                _ => query(arguments),
+            },
+            _ => String::new(),
+        };
+
+        Action::Reply(reply)
+    }
+
+    /// Reply reporting the last stop, with the thread of the core that stopped
+    fn stop_reply(&self) -> String {
+        let (signal, core_id) = self.stop;
+        format!("T{signal:02x}thread:{:x};", core_id + 1)
+    }
+
+    /// Receives the next packet, acknowledging it once its checksum is verified
+    fn receive(&mut self) -> String {
+        loop {
+            // acknowledgements, and interrupts while already stopped, are ignored
+            while self.port.receive() != b'$' {}
+
+            let mut data = String::new();
+            let mut sum = 0u8;
+            loop {
+                match self.port.receive() {
+                    b'#' => break,
+                    byte => {
+                        sum = sum.wrapping_add(byte);
+                        data.push(char::from(byte));
+                    }
+                }
+            }
+
+            let checksum = [self.port.receive(), self.port.receive()];
+            let checksum = core::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+
+            if checksum == Some(sum) {
+                self.port.send_raw(b"+");
+                return data;
+            }
+
+            self.port.send_raw(b"-");
+        }
+    }
+
+    /// Sends a packet, retransmitting it until GDB acknowledges it
+    fn send(&mut self, data: &str) {
+        let packet = frame(data);
+
+        loop {
+            self.port.send_raw(packet.as_bytes());
+
+            loop {
+                match self.port.receive() {
+                    b'+' => return,
+                    b'-' => break,
+                    _ => (),
+                }
+            }
+        }
+    }
+}
+
+/// IDs of the cores being debugged
+fn debugged_cores() -> impl Iterator<Item = usize> {
+    (0..MAX_GUEST_CORES).filter(|core_id| DEBUGGED[*core_id].load(Ordering::Acquire))
+}
+
+/// Core with the supplied ID once it is waiting for the core serving GDB, so
+/// that its registers are not being modified
+///
+/// A core parked by `WFI` is only waited for once it wakes.
+fn stopped_core(core_id: usize) -> &'static ModelDevice {
+    while !WAITING[core_id].load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+
+    GuestExecutionContext::for_core(core_id)
+        .unwrap()
+        .current_core()
+}
+
+/// Core selected by a thread ID, `None` if any thread is selected, or nothing if
+/// the thread does not exist
+fn parse_thread(thread: &str) -> Option<Option<usize>> {
+    match thread {
+        "-1" | "0" => Some(None),
+        thread => {
+            let core_id = usize::try_from(parse_hex(thread)?.checked_sub(1)?).ok()?;
+            DEBUGGED
+                .get(core_id)?
+                .load(Ordering::Acquire)
+                .then_some(Some(core_id))
+        }
+    }
+}
+
+fn query(query: &str) -> String {
+    if query.starts_with("Supported") {
+        format!("PacketSize={PACKET_SIZE:x};qXfer:features:read+")
+    } else if query == "Attached" {
+        "1".to_string()
+    } else if let Some(annex) = query.strip_prefix("Xfer:features:read:target.xml:") {
+        let Some((offset, length)) = parse_pair(annex) else {
+            return "E00".to_string();
+        };
+
+        let description = target_description();
+        let remaining = description.get(offset as usize..).unwrap_or_default();
+
+        if remaining.len() <= length as usize {
+            format!("l{remaining}")
+        } else {
+            format!("m{}", &remaining[..length as usize])
+        }
+    } else {
+        String::new()
+    }
+}
+
+fn target_description() -> String {
+    let mut xml = String::from(
+        r#"<?xml version="1.0"?><!DOCTYPE target SYSTEM "gdb-target.dtd"><target version="1.0"><architecture>aarch64</architecture><feature name="org.gnu.gdb.aarch64.core">"#,
+    );
+
+    (0..SP).for_each(|n| write!(xml, r#"<reg name="x{n}" bitsize="64"/>"#).unwrap());
+
+    xml.push_str(
+        r#"<reg name="sp" bitsize="64" type="data_ptr"/><reg name="pc" bitsize="64" type="code_ptr"/><reg name="cpsr" bitsize="32"/></feature></target>"#,
+    );
+
+    xml
+}
+
+/// Value and size in bytes of a register of the target description
+fn read_register(device: &ModelDevice, n: usize) -> Option<(u64, usize)> {
+    let register_file = &device.register_file;
+
+    Some(match n {
+        0..SP => (register_file.read::<u64>(format!("R{n}")), 8),
+        SP => (register_file.read::<u64>(stack_pointer(device)), 8),
+        PC => (device.well_known_registers.pc().read(), 8),
+        CPSR => (u64::from(aarch64_mmu::get_psr_from_pstate(device)), 4),
+        _ => return None,
+    })
+}
+
+fn write_register(device: &ModelDevice, n: usize, value: u64) {
+    let register_file = &device.register_file;
+
+    match n {
+        0..SP => register_file.write(format!("R{n}"), value),
+        SP => register_file.write(stack_pointer(device), value),
+        PC => device.well_known_registers.pc().write(value),
+        CPSR => aarch64_mmu::set_pstate_from_psr(device, value as u32),
+        _ => unreachable!(),
+    }
+}
+
+fn encode_register(device: &ModelDevice, n: usize) -> Option<String> {
+    let (value, size) = read_register(device, n)?;
+    Some(encode_hex(&value.to_le_bytes()[..size]))
+}
+
+/// Name of the stack pointer selected by PSTATE
+fn stack_pointer(device: &ModelDevice) -> String {
+    match device.register_file.read::<u8>("PSTATE_SP") {
+        0 => "SP_EL0".to_string(),
+        _ => format!("SP_EL{}", device.register_file.read::<u8>("PSTATE_EL")),
+    }
+}
+
+/// Host pointer to the byte of guest RAM at the supplied guest virtual address
+fn guest_ram(device: &ModelDevice, address: u64) -> Option<*mut u8> {
+    let physical = aarch64_mmu::guest_translate(device, address, Access::Inspect)
+        .ok()?
+        .physical_address;
+
+    let address_space = unsafe { &*GuestExecutionContext::current().current_address_space };
+    let region = address_space.find_region(physical)?;
+
+    matches!(region.kind(), AddressSpaceRegionKind::Ram)
+        .then(|| guest_physical_to_host_virt(physical).as_mut_ptr())
+}
+
+/// Packet with its framing and checksum
+fn frame(data: &str) -> String {
+    let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+    format!("${data}#{checksum:02x}")
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s, 16).ok()
+}
+
+/// Parses an `address,length` pair
+fn parse_pair(s: &str) -> Option<(u64, u64)> {
+    let (address, length) = s.split_once(',')?;
+    Some((parse_hex(address)?, parse_hex(length)?))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Register value from bytes in target (little endian) order
+fn from_le_bytes(bytes: &[u8]) -> u64 {
+    let mut value = [0u8; 8];
+    let len = bytes.len().min(8);
+    value[..len].copy_from_slice(&bytes[..len]);
+    u64::from_le_bytes(value)
+}
+
+#[ktest]
+fn packet_encoding() {
+    assert_eq!(frame("OK"), "$OK#9a");
+    assert_eq!(frame(""), "$#00");
+
+    assert_eq!(encode_hex(&0x1234u64.to_le_bytes()[..4]), "34120000");
+    assert_eq!(decode_hex("34120000"), Some(alloc::vec![0x34, 0x12, 0, 0]));
+    assert_eq!(decode_hex("341"), None);
+    assert_eq!(from_le_bytes(&[0x34, 0x12, 0, 0]), 0x1234);
+
+    assert_eq!(parse_pair("ffff0000,40"), Some((0xffff_0000, 0x40)));
+    assert_eq!(parse_pair("ffff0000"), None);
+
+    // read in chunks until the last is prefixed with 'l'
+    let description = target_description();
+    let first = query("Xfer:features:read:target.xml:0,10");
+    assert_eq!(first, format!("m{}", &description[..0x10]));
+    let last = query(&format!(
+        "Xfer:features:read:target.xml:10,{:x}",
+        description.len()
+    ));
+    assert_eq!(last, format!("l{}", &description[0x10..]));
+
+    // any thread, and threads of cores that are not debugged
+    assert_eq!(parse_thread("-1"), Some(None));
+    assert_eq!(parse_thread("0"), Some(None));
+    assert_eq!(parse_thread(&format!("{:x}", MAX_GUEST_CORES + 1)), None);
+    assert_eq!(parse_thread("g"), None);
+}
@@ -1,6 +1,11 @@
 use {
-    crate::host::dbt::{bit_extract, bit_insert, register_file::RegisterFile},
-    alloc::vec::Vec,
+    crate::host::dbt::{
+        bit_extract, bit_insert,
+        bitvector::{self, Bitvector},
+        register_file::RegisterFile,
+        trampoline::ExecutionResult,
+    },
+    alloc::{collections::BTreeMap, vec::Vec},
     common::{
         arena::Ref,
         hashmap::HashMap,
@@ -23,6 +28,7 @@ use {
         ops::{Add, BitAnd, BitOr, Div, Mul, Sub},
         panic, usize,
     },
+    proc_macro_lib::ktest,
 };
 
 pub fn interpret(
@@ -30,12 +36,74 @@ pub fn interpret(
     function_name: &str,
     arguments: &[Value],
     register_file: &RegisterFile,
+) -> Option<Value> {
+    interpret_with_result(
+        model,
+        function_name,
+        arguments,
+        register_file,
+        &mut ExecutionResult::new(),
+    )
+}
+
+/// Interprets a function, accumulating into `execution_result` the same flags
+/// translated code would return from the trampoline
+pub fn interpret_with_result(
+    model: &Model,
+    function_name: &str,
+    arguments: &[Value],
+    register_file: &RegisterFile,
+    execution_result: &mut ExecutionResult,
+) -> Option<Value> {
+    interpret_function(
+        model,
+        function_name,
+        arguments,
+        register_file,
+        execution_result,
+        None,
+    )
+}
+
+/// Interprets a function without writing to guest memory, writes are instead
+/// held in `overlay` and read back from it
+pub fn interpret_with_overlay(
+    model: &Model,
+    function_name: &str,
+    arguments: &[Value],
+    register_file: &RegisterFile,
+    execution_result: &mut ExecutionResult,
+    overlay: &mut MemoryOverlay,
+) -> Option<Value> {
+    interpret_function(
+        model,
+        function_name,
+        arguments,
+        register_file,
+        execution_result,
+        Some(overlay),
+    )
+}
+
+fn interpret_function(
+    model: &Model,
+    function_name: &str,
+    arguments: &[Value],
+    register_file: &RegisterFile,
+    execution_result: &mut ExecutionResult,
+    overlay: Option<&mut MemoryOverlay>,
 ) -> Option<Value> {
     log::debug!("interpreting {function_name}");
     let function_name = InternedString::from(function_name);
     let function = model.functions().get(&function_name).unwrap();
 
-    let mut interpreter = Interpreter::new(model, function_name, register_file);
+    let mut interpreter = Interpreter::new(
+        model,
+        function_name,
+        register_file,
+        *execution_result,
+        overlay,
+    );
 
     // insert arguments
     interpreter.locals.extend(
@@ -50,12 +118,74 @@ pub fn interpret(
     loop {
         match interpreter.interpret_block(current_block) {
             BlockResult::NextBlock(next) => current_block = next,
-            BlockResult::ReturnValue(value) => return value,
+            BlockResult::ReturnValue(value) => {
+                *execution_result = interpreter.execution_result;
+                return value;
+            }
         }
     }
 }
 
-struct Interpreter<'f, 'r> {
+/// Guest memory writes held back from memory, so that instructions can be
+/// interpreted without side effects
+#[derive(Debug, Default)]
+pub struct MemoryOverlay {
+    /// Value of each written byte, by host address
+    bytes: BTreeMap<usize, u8>,
+    /// Whether the byte at a host address can be read without side effects,
+    /// such as those of reading a device, any other is read as zero
+    readable: Option<fn(usize) -> bool>,
+    /// Whether a read was made from memory that could not be read
+    unreadable_read: bool,
+}
+
+impl MemoryOverlay {
+    /// Overlay only reading memory at the host addresses for which `readable`
+    /// holds
+    pub fn new(readable: fn(usize) -> bool) -> Self {
+        Self {
+            readable: Some(readable),
+            ..Default::default()
+        }
+    }
+
+    /// Whether an access of `size` bytes at `address` can be read, recording
+    /// the read otherwise
+    fn check_read(&mut self, address: usize, size: usize) -> bool {
+        let readable = self
+            .readable
+            .is_none_or(|readable| readable(address) && readable(address + size - 1));
+        self.unreadable_read |= !readable;
+        readable
+    }
+
+    /// Whether any value read came from memory that could not be read, and so
+    /// was not the value in memory
+    pub fn read_unreadable(&self) -> bool {
+        self.unreadable_read
+    }
+
+    fn write(&mut self, address: usize, bytes: &[u8]) {
+        bytes.iter().enumerate().for_each(|(i, byte)| {
+            self.bytes.insert(address + i, *byte);
+        });
+    }
+
+    /// Replaces the bytes read from memory at `address` with any written to
+    /// the overlay
+    fn patch(&self, address: usize, bytes: &mut [u8]) {
+        self.bytes
+            .range(address..address + bytes.len())
+            .for_each(|(byte_address, byte)| bytes[byte_address - address] = *byte);
+    }
+
+    /// Written bytes and their host addresses, in address order
+    pub fn bytes(&self) -> impl Iterator<Item = (usize, u8)> + '_ {
+        self.bytes.iter().map(|(address, byte)| (*address, *byte))
+    }
+}
+
+struct Interpreter<'f, 'r, 'o> {
     model: &'f Model,
     function_name: InternedString,
     // local variables
@@ -65,13 +195,18 @@ struct Interpreter<'f, 'r> {
     register_file: &'r RegisterFile,
     // nzcv
     flags: u8,
+    execution_result: ExecutionResult,
+    // memory writes are made to the overlay if present
+    overlay: Option<&'o mut MemoryOverlay>,
 }
 
-impl<'f, 'r> Interpreter<'f, 'r> {
+impl<'f, 'r, 'o> Interpreter<'f, 'r, 'o> {
     fn new(
         model: &'f Model,
         function_name: InternedString,
         register_file: &'r RegisterFile,
+        execution_result: ExecutionResult,
+        overlay: Option<&'o mut MemoryOverlay>,
     ) -> Self {
         Self {
             model,
@@ -80,6 +215,8 @@ impl<'f, 'r> Interpreter<'f, 'r> {
             statement_values: HashMap::default(),
             register_file,
             flags: 0,
+            execution_result,
+            overlay,
         }
     }
 
@@ -103,10 +240,31 @@ impl<'f, 'r> Interpreter<'f, 'r> {
             Value::SignedInteger { value: i, width: _ } => i
                 .try_into()
                 .unwrap_or_else(|_| panic!("cannot resolve {i} as u64")),
+            Value::Bitvector(bits) => {
+                assert!(
+                    bits.limbs()[1..].iter().all(|limb| *limb == 0),
+                    "cannot resolve {bits:?} as u64"
+                );
+                bits.low()
+            }
             _ => panic!(),
         }
     }
 
+    /// Whether any of `statements` evaluated to a value wider than 64 bits
+    fn any_wide<const N: usize>(&self, statements: [&Ref<Statement>; N]) -> bool {
+        statements
+            .into_iter()
+            .any(|statement| matches!(self.resolve(statement), Value::Bitvector(_)))
+    }
+
+    fn resolve_bitvector<R: Borrow<Ref<Statement>>>(&self, statement_ref: R) -> Bitvector {
+        let value = self.resolve(statement_ref);
+        value
+            .to_bitvector()
+            .unwrap_or_else(|| panic!("{value:?} is not a bitvector"))
+    }
+
     fn interpret_block(&mut self, block_ref: Ref<Block>) -> BlockResult {
         log::trace!("{}: block {block_ref:?}", self.function_name);
         self.statement_values.clear();
@@ -132,7 +290,29 @@ impl<'f, 'r> Interpreter<'f, 'r> {
 
                     Some(self.read_register(typ, offset))
                 }
-                Statement::ReadMemory { .. } => todo!(),
+                Statement::ReadMemory { address, size } => {
+                    let address = guest_address(self.resolve_u64(address));
+                    let size = usize::try_from(self.resolve_u64(size)).unwrap();
+
+                    let readable = match &mut self.overlay {
+                        Some(overlay) => overlay.check_read(address, size),
+                        None => true,
+                    };
+
+                    let mut bytes = alloc::vec![0; size];
+                    if readable {
+                        unsafe { read_memory(address, &mut bytes) };
+                    }
+
+                    if let Some(overlay) = &self.overlay {
+                        overlay.patch(address, &mut bytes);
+                    }
+
+                    Some(Value::from_bitvector(
+                        Bitvector::from_le_bytes(&bytes),
+                        false,
+                    ))
+                }
                 Statement::ReadPc => todo!(),
                 Statement::GetFlags { operation: _ } => {
                     // todo: technically should get the last statement or
@@ -142,6 +322,9 @@ impl<'f, 'r> Interpreter<'f, 'r> {
                         width: 4,
                     })
                 }
+                Statement::UnaryOperation { kind, value } if self.any_wide([value]) => {
+                    Some(wide_unary_operation(kind, self.resolve_bitvector(value)))
+                }
                 Statement::UnaryOperation { kind, value } => {
                     let value = self.resolve(value);
 
@@ -169,6 +352,13 @@ impl<'f, 'r> Interpreter<'f, 'r> {
                         _ => todo!("{kind:?} {value:?}"),
                     }
                 }
+                Statement::BinaryOperation { kind, lhs, rhs } if self.any_wide([lhs, rhs]) => {
+                    Some(wide_binary_operation(
+                        kind,
+                        self.resolve_bitvector(lhs),
+                        self.resolve_bitvector(rhs),
+                    ))
+                }
                 Statement::BinaryOperation { kind, lhs, rhs } => {
                     let left = self.resolve(lhs);
                     let right = self.resolve(rhs);
@@ -254,6 +444,27 @@ impl<'f, 'r> Interpreter<'f, 'r> {
                     }
                 },
 
+                Statement::ShiftOperation {
+                    kind,
+                    value,
+                    amount,
+                } if self.any_wide([value]) => {
+                    let value = self.resolve_bitvector(value);
+                    let amount = self.resolve_u64(amount);
+
+                    Some(Value::from_bitvector(
+                        match kind {
+                            ShiftOperationKind::LogicalShiftLeft => value << amount,
+                            ShiftOperationKind::LogicalShiftRight => value >> amount,
+                            ShiftOperationKind::ArithmeticShiftRight => {
+                                value.arithmetic_shift_right(amount)
+                            }
+                            ShiftOperationKind::RotateLeft => value.rotate_left(amount),
+                            ShiftOperationKind::RotateRight => value.rotate_right(amount),
+                        },
+                        false,
+                    ))
+                }
                 Statement::ShiftOperation {
                     kind,
                     value,
@@ -312,13 +523,44 @@ impl<'f, 'r> Interpreter<'f, 'r> {
                         self.function_name
                     );
 
-                    interpret(
+                    self.execution_result.set_for_call(target.as_ref());
+
+                    let args = args.iter().map(|a| self.resolve(a)).collect::<Vec<_>>();
+
+                    interpret_function(
                         &self.model,
                         target.as_ref(),
-                        &args.iter().map(|a| self.resolve(a)).collect::<Vec<_>>(),
+                        &args,
                         self.register_file,
+                        &mut self.execution_result,
+                        self.overlay.as_deref_mut(),
                     )
                 }
+                Statement::Cast {
+                    kind,
+                    typ: Type::Primitive(target),
+                    value,
+                } if self.any_wide([value]) || target.width() > 64 => {
+                    let signed = matches!(self.resolve(value), Value::SignedInteger { .. });
+                    let value = self.resolve_bitvector(value);
+                    let width = target.width();
+
+                    let cast = match kind {
+                        CastOperationKind::SignExtend => value.sign_extend(width),
+                        // keeps the value, as when folding a constant
+                        CastOperationKind::Convert if signed => value.sign_extend(width),
+                        CastOperationKind::ZeroExtend
+                        | CastOperationKind::Truncate
+                        | CastOperationKind::Reinterpret
+                        | CastOperationKind::Convert
+                        | CastOperationKind::Broadcast => value.zero_extend(width),
+                    };
+
+                    Some(Value::from_bitvector(
+                        cast,
+                        matches!(target, PrimitiveType::SignedInteger(_)),
+                    ))
+                }
                 Statement::Cast {
                     kind,
                     typ: dest_typ,
@@ -420,6 +662,20 @@ impl<'f, 'r> Interpreter<'f, 'r> {
                         (k, t, v) => todo!("{k:?} {t:?} {v:?}"),
                     }
                 }
+                Statement::BitsCast {
+                    kind, value, width, ..
+                } if self.any_wide([value]) || self.resolve_u64(width) > 64 => {
+                    let value = self.resolve_bitvector(value);
+                    let width = u16::try_from(self.resolve_u64(width)).unwrap();
+
+                    Some(Value::from_bitvector(
+                        match kind {
+                            CastOperationKind::SignExtend => value.sign_extend(width),
+                            _ => value.zero_extend(width),
+                        },
+                        false,
+                    ))
+                }
                 Statement::BitsCast {
                     kind,
                     typ,
@@ -473,6 +729,17 @@ impl<'f, 'r> Interpreter<'f, 'r> {
                         false_value
                     }))
                 }
+                Statement::BitExtract {
+                    value,
+                    start,
+                    width,
+                } if self.any_wide([value]) || self.resolve_u64(width) > 64 => {
+                    let value = self.resolve_bitvector(value);
+                    let start = self.resolve_u64(start);
+                    let width = u16::try_from(self.resolve_u64(width)).unwrap();
+
+                    Some(Value::from_bitvector(value.extract(start, width), false))
+                }
                 Statement::BitExtract {
                     value,
                     start,
@@ -495,6 +762,22 @@ impl<'f, 'r> Interpreter<'f, 'r> {
                         _ => todo!("{value:?}"),
                     })
                 }
+                Statement::BitInsert {
+                    target,
+                    source,
+                    start,
+                    width,
+                } if self.any_wide([target, source]) => {
+                    let target = self.resolve_bitvector(target);
+                    let source = self.resolve_bitvector(source);
+                    let start = self.resolve_u64(start);
+                    let width = u16::try_from(self.resolve_u64(width)).unwrap();
+
+                    Some(Value::from_bitvector(
+                        target.insert(&source, start, width),
+                        false,
+                    ))
+                }
                 Statement::BitInsert {
                     target,
                     source,
@@ -540,13 +823,10 @@ impl<'f, 'r> Interpreter<'f, 'r> {
                     Some(Value::Vector(vec))
                 }
                 Statement::CreateBits { value, width } => {
-                    let value = self.resolve_u64(value);
-                    let width = self.resolve_u64(width);
+                    let value = self.resolve_bitvector(value);
+                    let width = u16::try_from(self.resolve_u64(width)).unwrap();
 
-                    Some(Value::UnsignedInteger {
-                        value,
-                        width: u16::try_from(width).unwrap(),
-                    })
+                    Some(Value::from_bitvector(value.zero_extend(width), false))
                 }
                 Statement::SizeOf { value } => {
                     let value = self.resolve(value);
@@ -555,6 +835,10 @@ impl<'f, 'r> Interpreter<'f, 'r> {
                             value: u64::from(width),
                             width: 16,
                         }),
+                        Value::Bitvector(bits) => Some(Value::UnsignedInteger {
+                            value: u64::from(bits.width()),
+                            width: 16,
+                        }),
                         _ => todo!("size-of {value:?}"),
                     }
                 }
@@ -578,6 +862,13 @@ impl<'f, 'r> Interpreter<'f, 'r> {
                     self.locals.insert(symbol.name(), self.resolve(value));
                     None
                 }
+                Statement::WriteRegister { offset, value } if self.any_wide([value]) => {
+                    let offset = usize::try_from(self.resolve_u64(offset)).unwrap();
+
+                    self.write_wide_register(offset, &self.resolve_bitvector(value));
+
+                    None
+                }
                 Statement::WriteRegister { offset, value } => {
                     let (value, width) = match self.resolve(value) {
                         Value::UnsignedInteger { value, width } => (value, width),
@@ -589,6 +880,20 @@ impl<'f, 'r> Interpreter<'f, 'r> {
 
                     let offset = usize::try_from(self.resolve_u64(offset)).unwrap();
 
+                    // translated code reports the same on writes to these registers
+                    if [
+                        "SCTLR_EL1_bits",
+                        "TCR_EL1_bits",
+                        "_TTBR0_EL1_bits",
+                        "_TTBR1_EL1_bits",
+                    ]
+                    .into_iter()
+                    .any(|name| {
+                        self.model.reg_offset(InternedString::from_static(name)) as usize == offset
+                    }) {
+                        self.execution_result.set_need_tlb_invalidate(true);
+                    }
+
                     match width {
                         1..=8 => self
                             .register_file
@@ -600,10 +905,6 @@ impl<'f, 'r> Interpreter<'f, 'r> {
                             .register_file
                             .write_raw(offset, u32::try_from(value).unwrap()),
                         33..=64 => self.register_file.write_raw(offset, value),
-                        65..=128 => {
-                            self.register_file.write_raw(offset, value);
-                            self.register_file.write_raw(offset + 8, 0u64); // todo: hack
-                        }
                         w => {
                             log::trace!(
                                 "tried to write {value} to a {w} bit register offset {offset}, did nothing"
@@ -613,7 +914,19 @@ impl<'f, 'r> Interpreter<'f, 'r> {
 
                     None
                 }
-                Statement::WriteMemory { .. } => todo!(),
+                Statement::WriteMemory { address, value } => {
+                    let address = guest_address(self.resolve_u64(address));
+
+                    let typ = value.get(block.arena()).typ(block.arena()).unwrap();
+                    let bytes = memory_bits(&self.resolve(value), &typ).to_le_bytes();
+
+                    match &mut self.overlay {
+                        Some(overlay) => overlay.write(address, &bytes),
+                        None => unsafe { write_memory(address, &bytes) },
+                    }
+
+                    None
+                }
                 Statement::WritePc { value } => {
                     self.register_file.write_raw(
                         self.model.reg_offset(InternedString::from_static("_PC")) as usize,
@@ -675,13 +988,20 @@ impl<'f, 'r> Interpreter<'f, 'r> {
 
     fn read_register(&self, typ: &Type, offset: usize) -> Value {
         match typ {
+            Type::Primitive(ptyp) if ptyp.width() > 64 => {
+                let width = ptyp.width();
+                let limbs = (0..bitvector::limb_count(width))
+                    .map(|index| self.register_file.read_raw::<u64>(offset + index * 8))
+                    .collect::<Vec<_>>();
+
+                Value::Bitvector(Bitvector::from_limbs(&limbs, width))
+            }
             Type::Primitive(ptyp) => {
                 let value = match ptyp.width() {
                     1..=8 => self.register_file.read_raw::<u8>(offset) as u64,
                     9..=16 => self.register_file.read_raw::<u16>(offset) as u64,
                     17..=32 => self.register_file.read_raw::<u32>(offset) as u64,
                     33..=64 => self.register_file.read_raw::<u64>(offset),
-                    65..=128 => u64::try_from(self.register_file.read_raw::<u128>(offset)).unwrap(),
 
                     w => {
                         log::trace!(
@@ -720,16 +1040,40 @@ impl<'f, 'r> Interpreter<'f, 'r> {
             t => todo!("{t}"),
         }
     }
+
+    /// Writes each limb of `value`, only writing the bytes of the most
+    /// significant limb that hold bits of `value`
+    fn write_wide_register(&self, offset: usize, value: &Bitvector) {
+        for (index, limb) in value.limbs().iter().enumerate() {
+            let offset = offset + index * 8;
+            let remaining = value.width() - u16::try_from(index * 64).unwrap();
+
+            match remaining {
+                1..=8 => self.register_file.write_raw(offset, *limb as u8),
+                9..=16 => self.register_file.write_raw(offset, *limb as u16),
+                17..=32 => self.register_file.write_raw(offset, *limb as u32),
+                _ => self.register_file.write_raw(offset, *limb),
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
-    UnsignedInteger { value: u64, width: u16 },
-    SignedInteger { value: i64, width: u16 },
+    UnsignedInteger {
+        value: u64,
+        width: u16,
+    },
+    SignedInteger {
+        value: i64,
+        width: u16,
+    },
     FloatingPoint(f64),
     String(InternedString),
     Vector(Vec<Value>),
     Tuple(Vec<Value>),
+    /// Unsigned integer wider than 64 bits
+    Bitvector(Bitvector),
 }
 
 impl Value {
@@ -750,6 +1094,82 @@ impl Value {
             Constant::Vector(vec) => Value::Vector(vec.iter().map(Value::from_constant).collect()),
         }
     }
+
+    /// Integer holding `bits`, which is only a `Bitvector` if wider than 64
+    /// bits
+    fn from_bitvector(bits: Bitvector, signed: bool) -> Self {
+        match (bits.width(), signed) {
+            (65.., _) => Value::Bitvector(bits),
+            (width, false) => Value::UnsignedInteger {
+                value: bits.low(),
+                width,
+            },
+            (width, true) => Value::SignedInteger {
+                value: bits.low() as i64,
+                width,
+            },
+        }
+    }
+
+    fn to_bitvector(&self) -> Option<Bitvector> {
+        match self {
+            Value::UnsignedInteger { value, width } => Some(Bitvector::new(*value, *width)),
+            Value::SignedInteger { value, width } => Some(Bitvector::new(*value as u64, *width)),
+            Value::Bitvector(bits) => Some(bits.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// Evaluates `kind` on integers of which at least one is wider than 64 bits,
+/// comparing them as unsigned
+fn wide_binary_operation(kind: &BinaryOperationKind, left: Bitvector, right: Bitvector) -> Value {
+    let compare = |predicate: fn(Ordering) -> bool| Value::UnsignedInteger {
+        value: u64::from(predicate(left.cmp_unsigned(&right))),
+        width: 1,
+    };
+
+    match kind {
+        BinaryOperationKind::CompareEqual => compare(Ordering::is_eq),
+        BinaryOperationKind::CompareNotEqual => compare(Ordering::is_ne),
+        BinaryOperationKind::CompareLessThan => compare(Ordering::is_lt),
+        BinaryOperationKind::CompareLessThanOrEqual => compare(Ordering::is_le),
+        BinaryOperationKind::CompareGreaterThan => compare(Ordering::is_gt),
+        BinaryOperationKind::CompareGreaterThanOrEqual => compare(Ordering::is_ge),
+        BinaryOperationKind::Add => Value::from_bitvector(left + right, false),
+        BinaryOperationKind::Sub => Value::from_bitvector(left - right, false),
+        BinaryOperationKind::And => Value::from_bitvector(left & right, false),
+        BinaryOperationKind::Or => Value::from_bitvector(left | right, false),
+        BinaryOperationKind::Xor => Value::from_bitvector(left ^ right, false),
+        BinaryOperationKind::Multiply => Value::from_bitvector(left * right, false),
+        BinaryOperationKind::Divide => Value::from_bitvector(left.div_rem(&right).0, false),
+        BinaryOperationKind::Modulo => Value::from_bitvector(left.div_rem(&right).1, false),
+        BinaryOperationKind::PowI => Value::from_bitvector(left.pow(right.low()), false),
+    }
+}
+
+/// Evaluates `kind` on an unsigned integer wider than 64 bits
+fn wide_unary_operation(kind: &UnaryOperationKind, value: Bitvector) -> Value {
+    let width = value.width();
+
+    match kind {
+        UnaryOperationKind::Not => Value::UnsignedInteger {
+            value: u64::from(value.is_zero()),
+            width: 1,
+        },
+        UnaryOperationKind::Complement => Value::from_bitvector(!value, false),
+        UnaryOperationKind::Negate => {
+            Value::from_bitvector(Bitvector::new(0, width) - value, false)
+        }
+        UnaryOperationKind::Power2 => {
+            Value::from_bitvector(Bitvector::new(1, width) << value.low(), false)
+        }
+        UnaryOperationKind::SquareRoot => Value::from_bitvector(value.square_root(), false),
+        // unsigned integers are already whole and positive
+        UnaryOperationKind::Absolute | UnaryOperationKind::Ceil | UnaryOperationKind::Floor => {
+            Value::from_bitvector(value, false)
+        }
+    }
 }
 
 impl PartialOrd for Value {
@@ -1001,6 +1421,84 @@ impl Div for Value {
     }
 }
 
+/// Bits of a value of type `typ` as laid out in memory, with the first element
+/// of a vector at the lowest address
+fn memory_bits(value: &Value, typ: &Type) -> Bitvector {
+    match (value, typ) {
+        (Value::FloatingPoint(value), Type::Primitive(PrimitiveType::FloatingPoint(32))) => {
+            Bitvector::new(u64::from((*value as f32).to_bits()), 32)
+        }
+        (Value::FloatingPoint(value), _) => Bitvector::new(value.to_bits(), 64),
+        (Value::Vector(elements), Type::Vector { element_type, .. }) => {
+            let element_width = element_type.width_bytes() * 8;
+            let width = element_width * u16::try_from(elements.len()).unwrap();
+
+            elements
+                .iter()
+                .enumerate()
+                .fold(Bitvector::new(0, width), |bits, (index, element)| {
+                    bits.insert(
+                        &memory_bits(element, element_type),
+                        u64::from(element_width) * index as u64,
+                        element_width,
+                    )
+                })
+        }
+        (value, _) => value
+            .to_bitvector()
+            .unwrap_or_else(|| panic!("cannot write {value:?} to memory")),
+    }
+}
+
+/// Reads the guest memory at host address `address` into `bytes`, in a single
+/// access of up to 8 bytes or else in 8 byte accesses, one for each limb of a
+/// wider value
+unsafe fn read_memory(address: usize, bytes: &mut [u8]) {
+    unsafe {
+        match bytes.len() {
+            1 => bytes[0] = (address as *const u8).read_volatile(),
+            2 => bytes.copy_from_slice(&(address as *const u16).read_volatile().to_le_bytes()),
+            4 => bytes.copy_from_slice(&(address as *const u32).read_volatile().to_le_bytes()),
+            8 => bytes.copy_from_slice(&(address as *const u64).read_volatile().to_le_bytes()),
+            size if size.is_multiple_of(8) => bytes
+                .chunks_mut(8)
+                .enumerate()
+                .for_each(|(i, chunk)| read_memory(address + i * 8, chunk)),
+            size => todo!("read {size} bytes"),
+        }
+    }
+}
+
+/// Writes `bytes` to the guest memory at host address `address`, in a single
+/// access of up to 8 bytes or else in 8 byte accesses, one for each limb of a
+/// wider value
+unsafe fn write_memory(address: usize, bytes: &[u8]) {
+    unsafe {
+        match bytes.len() {
+            1 => (address as *mut u8).write_volatile(bytes[0]),
+            2 => {
+                (address as *mut u16).write_volatile(u16::from_le_bytes(bytes.try_into().unwrap()))
+            }
+            4 => {
+                (address as *mut u32).write_volatile(u32::from_le_bytes(bytes.try_into().unwrap()))
+            }
+            8 => {
+                (address as *mut u64).write_volatile(u64::from_le_bytes(bytes.try_into().unwrap()))
+            }
+            size if size.is_multiple_of(8) => bytes
+                .chunks(8)
+                .enumerate()
+                .for_each(|(i, chunk)| write_memory(address + i * 8, chunk)),
+            size => todo!("write {size} bytes"),
+        }
+    }
+}
+
+/// Host address of guest memory, as accessed by translated code
+fn guest_address(address: u64) -> usize {
+    (address & 0xFF_FFFF_FFFF) as usize
+}
+
 enum BlockResult {
     NextBlock(Ref<Block>),
     ReturnValue(Option<Value>),
@@ -1013,3 +1511,44 @@ fn sign_extend(value: i64, source_width: u16, dest_width: u16) -> i64 {
 
     ((signed_extended as u64) & mask(dest_width)) as i64
 }
+
+#[ktest]
+fn wide_memory_access() {
+    let value = Value::Bitvector(Bitvector::from_limbs(&[1, 2, 3, 4], 256));
+    let typ = Type::Primitive(PrimitiveType::UnsignedInteger(256));
+
+    let mut memory = alloc::boxed::Box::new([0u64; 4]);
+    let address = memory.as_mut_ptr() as usize;
+
+    unsafe { write_memory(address, &memory_bits(&value, &typ).to_le_bytes()) };
+    assert_eq!(*memory, [1, 2, 3, 4]);
+
+    // bytes written to the overlay replace those read from memory
+    let mut overlay = MemoryOverlay::default();
+    overlay.write(address + 8, &[0xff; 8]);
+
+    let mut bytes = alloc::vec![0; 32];
+    unsafe { read_memory(address, &mut bytes) };
+    overlay.patch(address, &mut bytes);
+
+    assert_eq!(
+        Value::from_bitvector(Bitvector::from_le_bytes(&bytes), false),
+        Value::Bitvector(Bitvector::from_limbs(&[1, u64::MAX, 3, 4], 256))
+    );
+
+    // the first element of a vector is at the lowest address
+    let vector = Value::Vector(
+        (0..4)
+            .map(|value| Value::UnsignedInteger { value, width: 32 })
+            .collect(),
+    );
+    let typ = Type::Vector {
+        element_count: 4,
+        element_type: alloc::boxed::Box::new(Type::Primitive(PrimitiveType::UnsignedInteger(32))),
+    };
+
+    assert_eq!(
+        memory_bits(&vector, &typ).limbs(),
+        &[0x0000_0001_0000_0000, 0x0000_0003_0000_0002]
+    );
+}
@@ -1,24 +1,33 @@
 use {
-    crate::host::{
-        arch::x86::memory::VirtualMemoryArea,
-        dbt::{register_file::RegisterFile, trampoline::ExecutionResult},
+    crate::host::dbt::{
+        code_cache::ExecutableMemory, register_file::RegisterFile, trampoline::ExecutionResult,
     },
-    alloc::{string::String, vec::Vec},
+    alloc::string::String,
     common::mask::mask,
     core::{
         alloc::Allocator,
         fmt::{self, Debug},
     },
     iced_x86::{Formatter, Instruction},
-    x86_64::{VirtAddr, structures::paging::PageTableFlags},
 };
 
+pub mod bitvector;
+pub mod chain;
+pub mod code_cache;
 pub mod emitter;
+pub mod float_helpers;
+pub mod gdb;
 pub mod interpret;
 pub mod models;
+pub mod persist;
+pub mod profile;
 pub mod register_file;
+pub mod replay;
+pub mod smc;
+pub mod softmmu;
 pub mod sysreg_helpers;
 mod tests;
+pub mod tlb;
 mod trampoline;
 pub mod translate;
 pub mod x86;
@@ -30,22 +39,39 @@ pub trait Alloc: Allocator + Clone + Copy + Debug {}
 impl<T: Allocator + Clone + Copy + Debug> Alloc for T {}
 
 pub struct Translation {
-    // should be AlignedAllocator<4096> or ExecutableAllocator
-    pub code: Vec<u8>,
+    code: *const u8,
+    len: usize,
+    /// Backing memory if owned by this translation rather than a code cache
+    _memory: Option<ExecutableMemory>,
 }
 
 impl Translation {
-    pub fn new(code: Vec<u8>) -> Self {
-        let start = VirtAddr::from_ptr(code.as_ptr());
-        VirtualMemoryArea::current().update_flags_range(
-            start..start + code.len() as u64,
-            PageTableFlags::PRESENT | PageTableFlags::WRITABLE, // removing  "NOEXECUTE" flag
-        );
-        Self { code }
+    pub fn new(code: &[u8]) -> Self {
+        let mut memory = ExecutableMemory::new(code.len());
+        memory.as_mut_slice()[..code.len()].copy_from_slice(code);
+
+        Self {
+            code: memory.executable_range().start as *const u8,
+            len: code.len(),
+            _memory: Some(memory),
+        }
+    }
+
+    /// Translation of code owned by a code cache region
+    pub fn from_raw(code: *const u8, len: usize) -> Self {
+        Self {
+            code,
+            len,
+            _memory: None,
+        }
     }
 
     pub fn as_ptr(&self) -> *const u8 {
-        self.code.as_ptr()
+        self.code
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
     }
 
     pub fn execute(&self, register_file: &RegisterFile) -> ExecutionResult {
@@ -56,23 +82,10 @@ impl Translation {
     }
 }
 
-/// Disabled until we can validate that `code` is always page-aligned: after the
-/// variable deep clone fix we got isntruction fetch host page faults when
-/// executing cached translations, likely because another translation drop
-/// overlapped?
-// impl Drop for Translation {
-//     fn drop(&mut self) {
-//         let start = VirtAddr::from_ptr(self.code.as_ptr());
-//         VirtualMemoryArea::current().update_flags_range(
-//             start..start + self.code.len() as u64,
-//             PageTableFlags::PRESENT | PageTableFlags::WRITABLE |
-// PageTableFlags::NO_EXECUTE,         );
-//     }
-// }
-
 impl Debug for Translation {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut decoder = iced_x86::Decoder::with_ip(64, &self.code, 0, 0);
+        let code = unsafe { core::slice::from_raw_parts(self.code, self.len) };
+        let mut decoder = iced_x86::Decoder::with_ip(64, code, 0, 0);
 
         let mut formatter = iced_x86::GasFormatter::new();
 
@@ -91,36 +104,24 @@ impl Debug for Translation {
     }
 }
 
+/// Inserts `length` bits of `source` into `target` at `start`
+///
+/// Bits that would land beyond the 64th are discarded, values wider than a
+/// word are handled by [`bitvector::Bitvector`].
 fn bit_insert(target: u64, source: u64, start: u64, length: u64) -> u64 {
-    // todo: hack
-    if start >= 64 {
-        if source == 0 {
-            return 0;
-        } else {
-            panic!("attempting to insert {length} bits of {source} into {target} at {start}");
-        }
-    }
-
+    let start = u32::try_from(start).unwrap_or(u32::MAX);
     let length = u32::try_from(length).unwrap();
 
-    let cleared_target = {
-        let mask = !(mask(length)
-            .checked_shl(u32::try_from(start).unwrap())
-            .unwrap_or_else(|| {
-                panic!("overflow in shl with {target:b} {source:?} {start:?} {length:?}")
-            }));
-        target & mask
-    };
-
-    let shifted_source = {
-        let mask = mask(length);
-        let masked_source = source & mask;
-        masked_source << start
-    };
-
-    cleared_target | shifted_source
+    let field = mask(length).checked_shl(start).unwrap_or(0);
+    let shifted_source = source.checked_shl(start).unwrap_or(0);
+
+    (target & !field) | (shifted_source & field)
 }
 
+/// Extracts `length` bits of `value` from `start`, reading zeros beyond the
+/// 64th bit
 fn bit_extract(value: u64, start: u64, length: u64) -> u64 {
-    (value >> start) & mask(u32::try_from(length).unwrap())
+    let start = u32::try_from(start).unwrap_or(u32::MAX);
+
+    value.checked_shr(start).unwrap_or(0) & mask(u32::try_from(length).unwrap())
 }
@@ -1,19 +1,32 @@
 use {
     crate::{
+        guest::{GUEST, GuestExecutionContext, MAX_GUEST_CORES, memory::AddressSpaceRegionKind},
         host::{
             arch::x86::{
-                aarch64_mmu::{self, take_arm_exception},
-                memory::VirtualMemoryArea,
+                aarch64_mmu::{self, Access, take_arm_exception},
+                irq::allocate_guest_ram,
+                memory::{VirtualMemoryArea, guest_physical_to_host_virt},
                 safepoint::record_safepoint,
             },
             dbt::{
                 Alloc, Translation,
+                chain::{ChainLinks, ChainSlot},
+                code_cache::{CodeCache, Eviction, EvictionPolicy},
                 emitter::{Emitter, Type},
+                gdb::GdbStub,
+                interpret::{MemoryOverlay, Value, interpret_with_overlay, interpret_with_result},
+                persist::{self, PersistedTranslation, TranslationKey},
+                profile::Profiler,
                 register_file::{RegisterFile, WellKnownRegister},
+                replay, smc,
+                softmmu::MemoryAccessKind,
+                tlb::{self, Invalidation},
+                trampoline::ExecutionResult,
                 translate::translate_instruction,
                 x86::{
-                    X86TranslationContext,
+                    AssembledCode, X86TranslationContext,
                     emitter::{BinaryOperationKind, X86Emitter},
+                    register_allocator::RegisterAllocatorKind,
                 },
             },
             devices::manager::SharedDeviceManager,
@@ -26,31 +39,39 @@ use {
                 Object, ObjectId, ObjectStore, ToIrqController, ToMemoryMappedDevice,
                 ToRegisterMappedDevice, ToTickable, device::Device,
             },
+            tasks,
+            timer::VirtualClock,
         },
         util::parse_hex_prefix,
     },
     alloc::{
         alloc::alloc_zeroed,
         borrow::ToOwned,
+        boxed::Box,
         collections::btree_map::BTreeMap,
         string::{String, ToString},
         sync::Arc,
         vec::Vec,
     },
     common::{
-        hashmap::HashMap,
+        hashmap::{HashMap, Hasher},
         intern::InternedString,
         rudder::{Model, RegisterCacheType, RegisterDescriptor},
     },
     core::{
         alloc::Layout,
         fmt::{self, Debug, Write},
-        ptr::NonNull,
+        hash::Hasher as _,
+        ptr::{NonNull, null_mut},
+        sync::atomic::{AtomicI64, AtomicPtr, AtomicU64, AtomicUsize, Ordering},
     },
     itertools::Itertools,
     proc_macro_lib::guest_device_factory,
     spin::Mutex,
-    x86_64::structures::paging::{PageSize, Size4KiB},
+    x86_64::{
+        instructions::{hlt, interrupts::without_interrupts},
+        structures::paging::{PageSize, Size4KiB, Translate},
+    },
 };
 
 /// Size in bytes for the per-translation bump allocator
@@ -62,19 +83,73 @@ const SINGLE_STEP: bool = false;
 /// Write register trace to file
 const PRINT_REGISTERS: bool = false;
 
+/// Check every translated instruction against the interpreter by default
+const LOCKSTEP: bool = false;
+
+/// Serve GDB for every core by default
+const GDB: bool = false;
+
+/// Default budget in bytes for each core's translated code
+const CODE_CACHE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Default policy for reclaiming translated code once over budget
+const CODE_CACHE_EVICTION_POLICY: EvictionPolicy = EvictionPolicy::LeastRecentlyUsed;
+
+/// Default register allocator used to assemble translations
+const REGISTER_ALLOCATOR: RegisterAllocatorKind = RegisterAllocatorKind::Fresh;
+
+/// Default lowering of guest memory accesses in translations
+const MEMORY_ACCESS: MemoryAccessKind = MemoryAccessKind::Direct;
+
 /// Enable the jump table chain cache
 const CHAIN_CACHE_ENABLED: bool = true;
 pub const CHAIN_CACHE_ENTRY_COUNT: usize = 65536;
 const _: () = assert!(CHAIN_CACHE_ENTRY_COUNT.is_power_of_two());
 
+/// Patch direct jumps between translated blocks
+const BLOCK_LINKING_ENABLED: bool = true;
+
+/// Retranslate hot blocks along with their most executed successors, which are
+/// found through block links
+const SUPERBLOCKS_ENABLED: bool = true;
+/// Executions of a block before it becomes the head of a superblock
+const SUPERBLOCK_THRESHOLD: u64 = 10_000;
+/// Maximum number of guest blocks in a superblock
+const SUPERBLOCK_MAX_BLOCKS: usize = 8;
+
+/// Log per-block execution counts every this many guest instructions
+const BLOCK_PROFILE_INTERVAL: Option<usize> = None;
+
+/// Default number of times a block is interpreted before it is translated, 0
+/// to translate every block the first time it runs
+const TRANSLATION_THRESHOLD: u64 = 0;
+
+/// Next guest core ID to be assigned, in order of core creation
+static NEXT_CORE_ID: AtomicUsize = AtomicUsize::new(0);
+
 static MODEL_MANAGER: Mutex<BTreeMap<InternedString, Arc<Model>>> = Mutex::new(BTreeMap::new());
 
+/// Hashes of the serialized models, identifying them in persisted translations
+static MODEL_HASHES: Mutex<BTreeMap<InternedString, u64>> = Mutex::new(BTreeMap::new());
+
+/// Number of guest cores created so far
+pub fn core_count() -> usize {
+    NEXT_CORE_ID.load(Ordering::Relaxed)
+}
+
 pub fn register_model(name: InternedString, model: Model) {
     log::info!("registering {name:?} ISA model");
     let model = Arc::new(model);
     MODEL_MANAGER.lock().insert(name.to_owned(), model.clone());
 }
 
+fn model_hash(name: &str) -> Option<u64> {
+    MODEL_HASHES
+        .lock()
+        .get(&InternedString::from(name))
+        .copied()
+}
+
 pub fn get(name: &str) -> Option<Arc<Model>> {
     MODEL_MANAGER
         .lock()
@@ -95,6 +170,11 @@ pub fn load_all<FS: Filesystem>(fs: &mut FS) {
                 fs.read_to_vec(path).unwrap(),
             )
         })
+        .inspect(|(name, data)| {
+            let mut hasher = Hasher::default();
+            hasher.write(data);
+            MODEL_HASHES.lock().insert(*name, hasher.finish());
+        })
         .map(|(name, data)| (name, postcard::from_bytes::<Model>(&data).unwrap()))
         .for_each(|(name, mut model)| {
             model.registers_mut().iter_mut().for_each(
@@ -113,13 +193,89 @@ fn create_core(config: &BTreeMap<InternedString, InternedString>) -> Arc<dyn Dev
         .map(parse_hex_prefix)
         .unwrap()
         .unwrap();
+    let address_space = config
+        .get(&InternedString::from_static("address_space"))
+        .copied()
+        .unwrap_or(InternedString::from_static("as0"));
 
-    Arc::new(ModelDevice::new(model_name.to_string(), model, initial_pc))
+    let code_cache_size = config
+        .get(&InternedString::from_static("code_cache_size"))
+        .map(parse_hex_prefix)
+        .transpose()
+        .unwrap()
+        .map(|size| usize::try_from(size).unwrap())
+        .unwrap_or(CODE_CACHE_SIZE);
+    let code_cache_eviction = config
+        .get(&InternedString::from_static("code_cache_eviction"))
+        .map(|policy| match policy.as_ref() {
+            "flush" => EvictionPolicy::Flush,
+            "lru" => EvictionPolicy::LeastRecentlyUsed,
+            policy => panic!("unknown code cache eviction policy {policy:?}"),
+        })
+        .unwrap_or(CODE_CACHE_EVICTION_POLICY);
+    let register_allocator = config
+        .get(&InternedString::from_static("register_allocator"))
+        .map(|allocator| match allocator.as_ref() {
+            "fresh" => RegisterAllocatorKind::Fresh,
+            "linear_scan" => RegisterAllocatorKind::LinearScan,
+            allocator => panic!("unknown register allocator {allocator:?}"),
+        })
+        .unwrap_or(REGISTER_ALLOCATOR);
+    let memory_access = config
+        .get(&InternedString::from_static("memory_access"))
+        .map(|access| match access.as_ref() {
+            "direct" => MemoryAccessKind::Direct,
+            "software_tlb" => MemoryAccessKind::SoftwareTlb,
+            access => panic!("unknown memory access {access:?}"),
+        })
+        .unwrap_or(MEMORY_ACCESS);
+    let translation_threshold = config
+        .get(&InternedString::from_static("translation_threshold"))
+        .map(parse_hex_prefix)
+        .transpose()
+        .unwrap()
+        .unwrap_or(TRANSLATION_THRESHOLD);
+    let lockstep = config
+        .get(&InternedString::from_static("lockstep"))
+        .map(|lockstep| lockstep.as_ref().parse().unwrap())
+        .unwrap_or(LOCKSTEP);
+    let gdb = config
+        .get(&InternedString::from_static("gdb"))
+        .map(|gdb| gdb.as_ref().parse().unwrap())
+        .unwrap_or(GDB);
+    let profile_interval = config
+        .get(&InternedString::from_static("profile_interval"))
+        .map(parse_hex_prefix)
+        .transpose()
+        .unwrap();
+
+    let core_id = NEXT_CORE_ID.fetch_add(1, Ordering::Relaxed);
+    assert!(
+        core_id < MAX_GUEST_CORES,
+        "too many guest cores, at most {MAX_GUEST_CORES} are supported"
+    );
+
+    Arc::new(ModelDevice::new(
+        model_name.to_string(),
+        model,
+        initial_pc,
+        core_id,
+        address_space,
+        code_cache_size,
+        code_cache_eviction,
+        register_allocator,
+        memory_access,
+        translation_threshold,
+        lockstep,
+        gdb,
+        profile_interval,
+    ))
 }
 
 pub struct WellKnownRegisters {
     pc: WellKnownRegister<u64>,
     i: WellKnownRegister<bool>,
+    branch_taken: WellKnownRegister<bool>,
 }
 
 impl WellKnownRegisters {
@@ -130,19 +286,54 @@ impl WellKnownRegisters {
     pub fn i(&self) -> WellKnownRegister<bool> {
         self.i
     }
+
+    pub fn branch_taken(&self) -> WellKnownRegister<bool> {
+        self.branch_taken
+    }
 }
 
 pub struct ModelDevice {
     id: ObjectId,
     name: String,
+    core_id: usize,
+    address_space: InternedString,
+    code_cache_size: usize,
+    code_cache_eviction: EvictionPolicy,
+    register_allocator: RegisterAllocatorKind,
+    memory_access: MemoryAccessKind,
+    translation_threshold: u64,
+    /// Execute every instruction with the interpreter as well as its
+    /// translation, and compare the results
+    lockstep: bool,
+    /// Serve a GDB remote stub debugging this core
+    gdb: bool,
+    /// Guest instructions between exports of profiling samples, if profiled
+    profile_interval: Option<u64>,
     model: Arc<Model>,
+    model_hash: u64,
     pub register_file: RegisterFile,
     pub well_known_registers: WellKnownRegisters,
+    /// Guest physical pages written to since code was translated from them,
+    /// drained before the next block is executed
+    invalidated_code_pages: Mutex<Vec<u64>>,
+    /// Guest virtual addresses dropped from the TLB since the last block,
+    /// drained before the next block is executed
+    invalidated_translations: Mutex<Vec<Arc<Invalidation>>>,
+    /// Chain cache table of the running block execution loop
+    chain_cache_table: AtomicPtr<ChainCacheEntry<*const u8>>,
+    /// Direct jumps patched between translated blocks
+    chain_links: Mutex<ChainLinks>,
+    /// Guest instructions executed by the block execution loop, as of the start
+    /// of the current block
+    instruction_count: AtomicU64,
+    /// Instruction budget of the executing translation under the virtual
+    /// clock, zero otherwise
+    instruction_budget: AtomicI64,
 }
 
 impl Debug for ModelDevice {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "ModelDevice({})", self.name)
+        write!(f, "ModelDevice({}, core {})", self.name, self.core_id)
     }
 }
 
@@ -158,9 +349,26 @@ impl ToMemoryMappedDevice for ModelDevice {}
 impl ToIrqController for ModelDevice {}
 
 impl Device for ModelDevice {
+    /// Spawns a task executing this core in its own execution context
     fn start(&self) {
-        self.block_exec(SINGLE_STEP);
-        unreachable!("execution should never terminate here")
+        let address_space = unsafe { GUEST.get_mut() }
+            .unwrap()
+            .address_spaces
+            .get_mut(&self.address_space)
+            .unwrap_or_else(|| {
+                panic!(
+                    "address space {} not configured for core {}",
+                    self.address_space, self.core_id
+                )
+            })
+            .as_mut() as *mut _;
+
+        let exec_ctx =
+            GuestExecutionContext::new(address_space, self as *const Self).register(self.core_id);
+
+        let task = tasks::create_task(core_task);
+        task.set_fs_base(exec_ctx as u64);
+        task.start();
     }
 
     fn stop(&self) {
@@ -168,12 +376,36 @@ impl Device for ModelDevice {
     }
 }
 
+/// Entry point of the task executing a guest core, the core is found via the
+/// execution context loaded into FS base
+fn core_task() {
+    GuestExecutionContext::current()
+        .current_core()
+        .block_exec(SINGLE_STEP);
+    unreachable!("execution should never terminate here")
+}
+
 impl ModelDevice {
-    fn new(name: String, model: Arc<Model>, initial_pc: u64) -> Self {
+    fn new(
+        name: String,
+        model: Arc<Model>,
+        initial_pc: u64,
+        core_id: usize,
+        address_space: InternedString,
+        code_cache_size: usize,
+        code_cache_eviction: EvictionPolicy,
+        register_allocator: RegisterAllocatorKind,
+        memory_access: MemoryAccessKind,
+        translation_threshold: u64,
+        lockstep: bool,
+        gdb: bool,
+        profile_interval: Option<u64>,
+    ) -> Self {
         let register_file = RegisterFile::init(&*model);
         let well_known_registers = WellKnownRegisters {
             pc: register_file.as_wellknown::<u64>("_PC"),
             i: register_file.as_wellknown::<bool>("PSTATE_I"),
+            branch_taken: register_file.as_wellknown::<bool>("__BranchTaken"),
         };
 
         // interpret(
@@ -206,15 +438,76 @@ impl ModelDevice {
 
         register_file.write("_PC", initial_pc);
 
+        // Aff0 identifies the core to the guest
+        let mpidr = register_file.read::<u64>("MPIDR_EL1_bits");
+        register_file.write("MPIDR_EL1_bits", (mpidr & !0xff) | core_id as u64);
+
+        let model_hash = model_hash(&name).expect("model was not loaded from the filesystem");
+
         Self {
             id: ObjectId::new(),
             name,
+            core_id,
+            address_space,
+            code_cache_size,
+            code_cache_eviction,
+            register_allocator,
+            memory_access,
+            translation_threshold,
+            lockstep,
+            gdb,
+            profile_interval,
             model,
+            model_hash,
             register_file,
             well_known_registers,
+            invalidated_code_pages: Mutex::new(Vec::new()),
+            invalidated_translations: Mutex::new(Vec::new()),
+            chain_cache_table: AtomicPtr::new(null_mut()),
+            chain_links: Mutex::new(ChainLinks::default()),
+            instruction_count: AtomicU64::new(0),
+            instruction_budget: AtomicI64::new(0),
+        }
+    }
+
+    pub fn core_id(&self) -> usize {
+        self.core_id
+    }
+
+    pub fn instruction_count(&self) -> u64 {
+        self.instruction_count.load(Ordering::Relaxed)
+    }
+
+    /// Discards translations from the supplied guest physical page before the
+    /// next block is executed
+    ///
+    /// The chain cache and direct links are keyed by guest virtual address so
+    /// entries from the page cannot be found directly, instead the whole cache
+    /// is emptied and every link removed. Another core only does so once it
+    /// next returns to its block execution loop, as its translations may be
+    /// reading them, but the current core does so straight away so that the
+    /// translation that wrote to the page cannot chain into code from it.
+    pub fn invalidate_code_page(&self, page: u64) {
+        without_interrupts(|| self.invalidated_code_pages.lock().push(page));
+
+        if !core::ptr::eq(GuestExecutionContext::current().current_core, self) {
+            return;
+        }
+
+        without_interrupts(|| self.chain_links.lock().unlink_all());
+
+        let table = self.chain_cache_table.load(Ordering::Relaxed);
+        if !table.is_null() {
+            DirectMappedCache::<CHAIN_CACHE_ENTRY_COUNT, *const u8> { table }.fill_keys(1);
         }
     }
 
+    /// Drops translations of guest virtual addresses removed from the TLB
+    /// before the next block is executed
+    pub fn invalidate_translations(&self, invalidation: Arc<Invalidation>) {
+        without_interrupts(|| self.invalidated_translations.lock().push(invalidation));
+    }
+
     fn get_nzcv(&self) -> u8 {
         let n = self.register_file.read::<u8>("PSTATE_N");
         let z = self.register_file.read::<u8>("PSTATE_Z");
@@ -239,26 +532,99 @@ impl ModelDevice {
 
         let mut instructions_executed = 0usize;
 
+        // each instruction is translated on its own to be checked against the interpreter,
+        // which runs on a separate register file
+        let single_step_mode = single_step_mode || self.lockstep;
+        let shadow_register_file = self.lockstep.then(|| RegisterFile::init(&*self.model));
+
+        // the debugger is consulted before every instruction
+        let single_step_mode = single_step_mode || self.gdb;
+        let mut gdb = self.gdb.then(|| GdbStub::new(self.core_id));
+
+        let mut profiler = self
+            .profile_interval
+            .map(|interval| Profiler::new(self.core_id, interval));
+
+        // blocks are not chained while recording or replaying so that asynchronous
+        // events are observed at exact instruction counts, nor in lockstep or while
+        // debugging so that every instruction returns to this loop
+        let unchained = replay::is_enabled() || self.lockstep || self.gdb;
+
+        // guest time advanced by the number of instructions executed
+        let virtual_clock = VirtualClock::get();
+        let chain_cache_enabled = CHAIN_CACHE_ENABLED && !unchained;
+        let block_linking_enabled = BLOCK_LINKING_ENABLED && !unchained;
+        let superblocks_enabled = SUPERBLOCKS_ENABLED && !unchained;
+
         // guest PC to translated block cache
         // todo: should be guest physical address not virtual so we dont need to
         // invalidate
         let mut block_cache = HashMap::<u64, TranslatedBlock>::default();
+        // guest physical PC to number of times the block was interpreted
+        let mut interpreted_executions = HashMap::<u64, u64>::default();
         // guest virtual address
         let mut chain_cache = DirectMappedCache::<CHAIN_CACHE_ENTRY_COUNT, *const u8>::new(1);
-
-        let mut block_freq_hist = HashMap::<u64, (u64, usize)>::default();
+        self.chain_cache_table
+            .store(chain_cache.table, Ordering::Relaxed);
 
         // virtual to physical PCs
         let mut translation_cache = DirectMappedCache::<1024, u64>::new(1);
 
         let mut allocator = BumpAllocator::new(TRANSLATION_ALLOCATOR_SIZE);
 
+        let mut code_cache = CodeCache::new(self.code_cache_size, self.code_cache_eviction);
+
+        let mut mmu_registers = MmuRegisters::current(self);
+
+        // physical PC of the last block to return to this loop normally, whose exits
+        // are linked to the next block executed
+        let mut previous_block = None;
+
         //  log::set_max_level(log::LevelFilter::Error);
 
-        let _status = record_safepoint();
+        if let Some(clock) = virtual_clock {
+            clock.resume(self.core_id);
+        }
+
+        let status = record_safepoint(GuestExecutionContext::current().safepoint.get());
+
+        // a fault returns here before the instructions executed were counted, those
+        // of blocks chained before the faulting one were taken from the budget and
+        // the faulting instruction is counted as one
+        if let Some(clock) = virtual_clock.filter(|_| status != 0) {
+            let budget = self.instruction_budget.swap(0, Ordering::Relaxed);
+            let remaining = GuestExecutionContext::current()
+                .instruction_budget
+                .load(Ordering::Relaxed);
+            clock.advance(
+                self.core_id,
+                u64::try_from(budget - remaining).unwrap_or(0) + 1,
+            );
+        }
 
         // block translation/execution loop
         loop {
+            self.instruction_count
+                .store(instructions_executed as u64, Ordering::Relaxed);
+            replay::flush(transport);
+
+            if let Some(profiler) = profiler
+                .as_mut()
+                .filter(|profiler| profiler.is_due(instructions_executed as u64))
+            {
+                block_cache.values_mut().for_each(|block| {
+                    let executions = block.executions();
+                    profiler.record(block.virtual_pc, 0, executions - block.profiled_executions);
+                    block.profiled_executions = executions;
+                });
+                profiler.export(transport, instructions_executed as u64);
+            }
+
+            // before invalidating caches as the debugger may write to guest code
+            if let Some(gdb) = &mut gdb {
+                gdb.before_instruction(self);
+            }
+
             // if instructions_executed == 389280 {
             //     log::set_max_level(log::LevelFilter::Trace);
             // }
@@ -267,147 +633,389 @@ impl ModelDevice {
             //     panic!();
             // }
 
+            // this or another core dropped TLB entries, drop them from our
+            // virtually-indexed caches
+            let invalidations =
+                without_interrupts(|| core::mem::take(&mut *self.invalidated_translations.lock()));
+            for invalidation in invalidations {
+                if invalidation.is_all() {
+                    chain_cache.fill_keys(1);
+                    translation_cache.fill_keys(1);
+                    without_interrupts(|| self.chain_links.lock().unlink_all());
+                } else {
+                    chain_cache.invalidate_keys(|virtual_pc| invalidation.contains(virtual_pc));
+                    translation_cache
+                        .invalidate_keys(|virtual_pc| invalidation.contains(virtual_pc));
+                    without_interrupts(|| {
+                        self.chain_links
+                            .lock()
+                            .unlink_pcs(|virtual_pc| invalidation.contains(virtual_pc))
+                    });
+                }
+
+                // superblocks span guest virtual pages that may now be mapped elsewhere
+                self.discard_blocks(&mut block_cache, |block| {
+                    block.is_superblock()
+                        && block
+                            .virtual_pcs
+                            .iter()
+                            .any(|virtual_pc| invalidation.contains(*virtual_pc))
+                });
+            }
+
+            // guest wrote to pages we translated code from
+            let invalidated_code_pages =
+                without_interrupts(|| core::mem::take(&mut *self.invalidated_code_pages.lock()));
+            if !invalidated_code_pages.is_empty() {
+                chain_cache.fill_keys(1);
+                without_interrupts(|| self.chain_links.lock().unlink_all());
+                self.discard_blocks(&mut block_cache, |block| {
+                    block
+                        .pages
+                        .iter()
+                        .any(|page| invalidated_code_pages.contains(page))
+                });
+            }
+
             let block_start_virtual_pc = self.well_known_registers.pc().read(); // self.register_file.read::<u64>("_PC");
 
-            let block_start_physical_pc =
-                if let Some(pc) = translation_cache.get(block_start_virtual_pc as usize) {
-                    pc
-                } else {
-                    let pc = aarch64_mmu::guest_translate(self, block_start_virtual_pc).unwrap();
-                    translation_cache.insert(block_start_virtual_pc as usize, pc);
-                    pc
+            let block_start_physical_pc = if let Some(pc) =
+                translation_cache.get(block_start_virtual_pc as usize)
+            {
+                pc
+            } else {
+                match aarch64_mmu::guest_translate(self, block_start_virtual_pc, Access::Execute) {
+                    Ok(mapping) => {
+                        let pc = mapping.physical_address;
+                        translation_cache.insert(block_start_virtual_pc as usize, pc);
+                        tlb::insert(
+                            block_start_virtual_pc,
+                            &mapping,
+                            self.core_id,
+                            aarch64_mmu::current_asid(self),
+                            None,
+                        );
+                        pc
+                    }
+                    Err(fault) => {
+                        // continue from the exception vector
+                        aarch64_mmu::instruction_abort(self, fault, block_start_virtual_pc);
+                        previous_block = None;
+                        continue;
+                    }
+                }
+            };
+
+            // cold blocks are interpreted until they have run often enough to be worth
+            // translating
+            let is_cold = self.translation_threshold != 0
+                && !self.lockstep
+                && !block_cache.contains_key(&block_start_physical_pc)
+                && {
+                    let executions = interpreted_executions
+                        .entry(block_start_physical_pc)
+                        .or_insert(0);
+                    *executions += 1;
+
+                    if *executions <= self.translation_threshold {
+                        true
+                    } else {
+                        interpreted_executions.remove(&block_start_physical_pc);
+                        false
+                    }
                 };
 
-            let translated_block =
-                block_cache
-                    .entry(block_start_physical_pc)
-                    .or_insert_with(|| {
+            let exec_result = if is_cold {
+                log::debug!(
+                    "interpreting {block_start_virtual_pc:#08x} ({block_start_physical_pc:#08x}) (instr {instructions_executed})"
+                );
+
+                let start = profiler.is_some().then(Profiler::now);
+                let (exec_result, instructions) = self.interpret_block(
+                    (block_start_virtual_pc, block_start_physical_pc),
+                    single_step_mode,
+                );
+                instructions_executed += instructions;
+
+                if let (Some(profiler), Some(start)) = (&mut profiler, start) {
+                    profiler.record(block_start_virtual_pc, Profiler::now() - start, 1);
+                }
+
+                if let Some(clock) = virtual_clock {
+                    clock.advance(self.core_id, instructions as u64);
+                }
+
+                // interpreted blocks have no exits to link
+                previous_block = None;
+
+                exec_result
+            } else {
+                if !block_cache.contains_key(&block_start_physical_pc) {
+                    // protect before reading opcodes so that no write is missed
+                    smc::protect(block_start_physical_pc);
+
+                    let chain_cache_table = chain_cache.table as u64;
+                    let mut discard = |eviction: &Eviction| {
+                        self.discard_evicted(eviction, &mut block_cache, &mut chain_cache)
+                    };
+
+                    let persisted = self.load_persisted_block(
+                        &mut code_cache,
+                        chain_cache_table,
+                        (block_start_virtual_pc, block_start_physical_pc),
+                        single_step_mode,
+                        &mut discard,
+                    );
+
+                    let translated_block = persisted.unwrap_or_else(|| {
                         allocator.clear();
                         self.translate_block(
                             BumpAllocatorRef::new(&allocator),
-                            chain_cache.table as u64,
-                            block_start_virtual_pc,
+                            &mut code_cache,
+                            chain_cache_table,
+                            &[(block_start_virtual_pc, block_start_physical_pc)],
                             single_step_mode,
+                            &mut discard,
                         )
                     });
 
-            // block_freq_hist
-            //     .entry(block_start_virtual_pc)
-            //     .and_modify(|(freq, _)| *freq += 1)
-            //     .or_insert_with(|| (1, translated_block.translation.code.len()));
-            // if instructions_executed > 50_000_000 {
-            //     block_freq_hist
-            //         .into_iter()
-            //         .sorted_by_key(|(_, (_, size))| *size)
-            //         .sorted_by_key(|(_, (freq, _))| *freq)
-            //         .for_each(|(addr, (freq, size))| {
-            //             crate::println!("{addr:x}: {freq} ({})", bytes(size))
-            //         });
-            //     panic!()
-            // }
+                    block_cache.insert(block_start_physical_pc, translated_block);
+                }
 
-            if CHAIN_CACHE_ENABLED {
-                chain_cache.insert(
-                    block_start_virtual_pc as usize,
-                    translated_block.translation.as_ptr(),
-                );
-            }
+                let is_hot = block_cache
+                    .get(&block_start_physical_pc)
+                    .is_some_and(|block| {
+                        !block.is_superblock() && block.executions() >= SUPERBLOCK_THRESHOLD
+                    });
 
-            instructions_executed += translated_block.opcodes.len();
+                if superblocks_enabled && !single_step_mode && is_hot {
+                    let trace = form_trace(
+                        &block_cache,
+                        &mut translation_cache,
+                        (block_start_virtual_pc, block_start_physical_pc),
+                    );
 
-            log::debug!(
-                "executing {block_start_virtual_pc:#08x} ({block_start_physical_pc:#08x}): {:08x?} (instr {instructions_executed})",
-                translated_block.opcodes,
-            );
+                    if trace.len() > 1 {
+                        log::debug!("forming superblock {trace:x?}");
 
-            let exec_result = translated_block.translation.execute(&self.register_file);
-
-            // log::trace!(
-            //     "nzcv: {:04b}, sp: {:x}, x0: {:x}, x1: {:x}, x2: {:x}, x3: {:x}, x18:
-            // {:x}",     self.get_nzcv(),
-            //     self.register_file.read::<u64>("SP_EL3"),
-            //     self.register_file.read::<u64>("R0"),
-            //     self.register_file.read::<u64>("R1"),
-            //     self.register_file.read::<u64>("R2"),
-            //     self.register_file.read::<u64>("R3"),
-            //     self.register_file.read::<u64>("R18"),
-            // );
-
-            if PRINT_REGISTERS {
-                write!(transport, "instr = {:08x}\n", translated_block.opcodes[0]).unwrap();
-                write!(
-                    transport,
-                    "PC = {:016x}\n",
-                    self.register_file.read::<u64>("_PC")
-                )
-                .unwrap();
-                write!(transport, "PSTATE:\n").unwrap();
-                for field in [
-                    "A", "ALLINT", //"BTYPE",
-                    "C", "D", "DIT", "E", "EL", "EXLOCK", "F", "GE", "I", "IL", "IT", "J", "M",
-                    "N", "PAN", "PM", "PPEND", "Q", "SM", "SP", "SS", "SSBS", "T", "TCO", "UAO",
-                    "V", "Z", "ZA", "nRW",
-                ] {
-                    write!(
-                        transport,
-                        "\t{field} = {}\n",
-                        self.register_file
-                            .read::<u8>(alloc::format!("PSTATE_{field}"))
-                    )
-                    .unwrap();
+                        trace
+                            .iter()
+                            .for_each(|(_, physical_pc)| smc::protect(*physical_pc));
+
+                        // the old translation of the head is no longer reachable
+                        let head = block_cache[&block_start_physical_pc].translation.as_ptr();
+                        self.discard_blocks(&mut block_cache, |block| {
+                            block.translation.as_ptr() == head
+                        });
+                        chain_cache.invalidate_values(|code| *code == head);
+
+                        allocator.clear();
+                        let chain_cache_table = chain_cache.table as u64;
+                        let superblock = self.translate_block(
+                            BumpAllocatorRef::new(&allocator),
+                            &mut code_cache,
+                            chain_cache_table,
+                            &trace,
+                            single_step_mode,
+                            &mut |eviction: &Eviction| {
+                                self.discard_evicted(eviction, &mut block_cache, &mut chain_cache)
+                            },
+                        );
+
+                        block_cache.insert(block_start_physical_pc, superblock);
+                    }
                 }
-                // write!(
-                //     transport,
-                //     "BTypeNext = {}\n",
-                //     self.register_file.read::<u8>("BTypeNext")
-                // )
-                // .unwrap();
-                for el in 0..=3 {
-                    write!(
-                        transport,
-                        "SP_EL{el} = {:016x}\n",
-                        self.register_file.read::<u64>(alloc::format!("SP_EL{el}"))
-                    )
-                    .unwrap();
+
+                let translated_block = block_cache.get(&block_start_physical_pc).unwrap();
+                code_cache.touch(&translated_block.translation);
+
+                if block_linking_enabled && !single_step_mode {
+                    if let Some(previous) = previous_block.and_then(|pc| block_cache.get(&pc)) {
+                        without_interrupts(|| {
+                            self.chain_links.lock().link(
+                                &previous.chain_slots,
+                                block_start_virtual_pc,
+                                translated_block.translation.as_ptr(),
+                            )
+                        });
+                    }
                 }
-                for el in 1..=3 {
-                    write!(
-                        transport,
-                        "SPSR_EL{el} = {:016x}\n",
-                        self.register_file
-                            .read::<u64>(alloc::format!("SPSR_EL{el}_bits"))
-                    )
-                    .unwrap();
+
+                if chain_cache_enabled {
+                    chain_cache.insert(
+                        block_start_virtual_pc as usize,
+                        translated_block.translation.as_ptr(),
+                    );
                 }
-                for el in 1..=3 {
-                    write!(
-                        transport,
-                        "ELR_EL{el} = {:016x}\n",
-                        self.register_file.read::<u64>(alloc::format!("ELR_EL{el}"))
-                    )
-                    .unwrap();
+
+                let previously_executed = instructions_executed;
+                instructions_executed += translated_block.opcodes.len();
+
+                if let Some(interval) = BLOCK_PROFILE_INTERVAL {
+                    if previously_executed / interval != instructions_executed / interval {
+                        log_block_profile(&block_cache);
+                    }
+                }
+
+                log::debug!(
+                    "executing {block_start_virtual_pc:#08x} ({block_start_physical_pc:#08x}): {:08x?} (instr {instructions_executed})",
+                    translated_block.opcodes,
+                );
+
+                let exec_context = GuestExecutionContext::current();
+
+                // the translation, and any chained to it, leave once the next timer is due
+                let budget = virtual_clock.map(|clock| {
+                    let budget = i64::try_from(clock.instructions_until_deadline(self.core_id))
+                        .unwrap_or(i64::MAX);
+                    exec_context
+                        .instruction_budget
+                        .store(budget, Ordering::Relaxed);
+                    self.instruction_budget.store(budget, Ordering::Relaxed);
+                    budget
+                });
+
+                // interpreted first so that memory is seen as it was before the instruction
+                let overlay = shadow_register_file
+                    .as_ref()
+                    .map(|shadow| self.interpret_shadow(shadow, translated_block.opcodes[0]));
+
+                let start = profiler.is_some().then(Profiler::now);
+                let exec_result = translated_block.translation.execute(&self.register_file);
+
+                // executions are counted by the translation
+                if let (Some(profiler), Some(start)) = (&mut profiler, start) {
+                    profiler.record(block_start_virtual_pc, Profiler::now() - start, 0);
+                }
+
+                if let (Some(shadow), Some(overlay)) = (&shadow_register_file, &overlay) {
+                    self.check_lockstep(
+                        shadow,
+                        overlay,
+                        block_start_virtual_pc,
+                        translated_block.opcodes[0],
+                    );
+                }
+
+                if let (Some(clock), Some(budget)) = (virtual_clock, budget) {
+                    self.instruction_budget.store(0, Ordering::Relaxed);
+                    let remaining = exec_context.instruction_budget.load(Ordering::Relaxed);
+                    clock.advance(self.core_id, u64::try_from(budget - remaining).unwrap());
                 }
-                for reg in 0..=30 {
+
+                previous_block = (exec_result.as_u32() == 0).then_some(block_start_physical_pc);
+
+                // log::trace!(
+                //     "nzcv: {:04b}, sp: {:x}, x0: {:x}, x1: {:x}, x2: {:x}, x3: {:x}, x18:
+                // {:x}",     self.get_nzcv(),
+                //     self.register_file.read::<u64>("SP_EL3"),
+                //     self.register_file.read::<u64>("R0"),
+                //     self.register_file.read::<u64>("R1"),
+                //     self.register_file.read::<u64>("R2"),
+                //     self.register_file.read::<u64>("R3"),
+                //     self.register_file.read::<u64>("R18"),
+                // );
+
+                if PRINT_REGISTERS {
+                    write!(transport, "instr = {:08x}\n", translated_block.opcodes[0]).unwrap();
                     write!(
                         transport,
-                        "R{reg:02} = {:016x}\n",
-                        self.register_file.read::<u64>(alloc::format!("R{reg}"))
+                        "PC = {:016x}\n",
+                        self.register_file.read::<u64>("_PC")
                     )
                     .unwrap();
+                    write!(transport, "PSTATE:\n").unwrap();
+                    for field in [
+                        "A", "ALLINT", //"BTYPE",
+                        "C", "D", "DIT", "E", "EL", "EXLOCK", "F", "GE", "I", "IL", "IT", "J", "M",
+                        "N", "PAN", "PM", "PPEND", "Q", "SM", "SP", "SS", "SSBS", "T", "TCO",
+                        "UAO", "V", "Z", "ZA", "nRW",
+                    ] {
+                        write!(
+                            transport,
+                            "\t{field} = {}\n",
+                            self.register_file
+                                .read::<u8>(alloc::format!("PSTATE_{field}"))
+                        )
+                        .unwrap();
+                    }
+                    // write!(
+                    //     transport,
+                    //     "BTypeNext = {}\n",
+                    //     self.register_file.read::<u8>("BTypeNext")
+                    // )
+                    // .unwrap();
+                    for el in 0..=3 {
+                        write!(
+                            transport,
+                            "SP_EL{el} = {:016x}\n",
+                            self.register_file.read::<u64>(alloc::format!("SP_EL{el}"))
+                        )
+                        .unwrap();
+                    }
+                    for el in 1..=3 {
+                        write!(
+                            transport,
+                            "SPSR_EL{el} = {:016x}\n",
+                            self.register_file
+                                .read::<u64>(alloc::format!("SPSR_EL{el}_bits"))
+                        )
+                        .unwrap();
+                    }
+                    for el in 1..=3 {
+                        write!(
+                            transport,
+                            "ELR_EL{el} = {:016x}\n",
+                            self.register_file.read::<u64>(alloc::format!("ELR_EL{el}"))
+                        )
+                        .unwrap();
+                    }
+                    for reg in 0..=30 {
+                        write!(
+                            transport,
+                            "R{reg:02} = {:016x}\n",
+                            self.register_file.read::<u64>(alloc::format!("R{reg}"))
+                        )
+                        .unwrap();
+                    }
+                    write!(transport, "\n\n").unwrap();
+                    if !single_step_mode {
+                        write!(transport, "skip {}\n", translated_block.opcodes.len()).unwrap();
+                    }
                 }
-                write!(transport, "\n\n").unwrap();
-                if !single_step_mode {
-                    write!(transport, "skip {}\n", translated_block.opcodes.len()).unwrap();
-                }
-            }
+
+                exec_result
+            };
 
             if exec_result.need_tlb_invalidate() {
-                chain_cache.fill_keys(1);
-                translation_cache.fill_keys(1);
-                VirtualMemoryArea::current().invalidate_guest_mappings();
+                tlb::invalidate(self.tlb_operation(&mut mmu_registers));
+            }
+
+            if exec_result.exception_return() && self.register_file.read::<u8>("PSTATE_EL") == 0 {
+                tlb::invalidate(tlb::Operation::Privileged);
+            }
+
+            if exec_result.send_event() {
+                GuestExecutionContext::send_event();
             }
 
-            if exec_result.interrupt_pending() {
+            if exec_result.send_event_local() {
+                GuestExecutionContext::current()
+                    .event_register
+                    .store(true, Ordering::Relaxed);
+            }
+
+            let woken_by_interrupt = (exec_result.wait_for_interrupt()
+                || exec_result.wait_for_event())
+                && self.park(exec_result.wait_for_event());
+
+            let interrupt_pending = replay::interrupt_pending(
+                self.core_id,
+                instructions_executed as u64,
+                exec_result.interrupt_pending() || woken_by_interrupt,
+            );
+
+            if interrupt_pending {
                 let masked = self.well_known_registers.i().read(); //self.register_file.read::<bool>("PSTATE_I");
 
                 if !masked {
@@ -423,12 +1031,351 @@ impl ModelDevice {
         }
     }
 
-    fn translate_block<A: Alloc>(
+    /// Parks the core after a `WFI`, or a `WFE` if `for_event`, returning
+    /// whether it was woken by a pending interrupt
+    ///
+    /// Interrupts wake the core whether or not they are masked, and `WFE` also
+    /// consumes a set event register. Guest time only passes while instructions
+    /// execute under the virtual clock, so a parked core does not hold back the
+    /// others, and once every core is parked time is advanced straight to the
+    /// next deadline rather than waited for. Both instructions are allowed to
+    /// complete immediately, which they do while recording or replaying as
+    /// time spent parked is not deterministic.
+    fn park(&self, for_event: bool) -> bool {
+        let exec_context = GuestExecutionContext::current();
+
+        if replay::is_enabled() {
+            return false;
+        }
+
+        let virtual_clock = VirtualClock::get();
+        if let Some(clock) = virtual_clock {
+            clock.suspend(self.core_id);
+        }
+
+        let interrupted = loop {
+            if exec_context.interrupt_pending.load(Ordering::Relaxed) != 0 {
+                break true;
+            }
+
+            if for_event && exec_context.event_register.swap(false, Ordering::Relaxed) {
+                break false;
+            }
+
+            match virtual_clock {
+                Some(clock) if clock.skip_to_deadline() => (),
+                // the host timer interrupt, which also ticks guest devices, wakes the
+                // core at the latest
+                _ => hlt(),
+            }
+        };
+
+        if let Some(clock) = virtual_clock {
+            clock.resume(self.core_id);
+        }
+
+        interrupted
+    }
+
+    /// Executes the block at `block_start_pc` with the interpreter, one
+    /// instruction at a time, returning the number of instructions executed
+    ///
+    /// Unlike a translation, the block only ends once the PC is not advanced to
+    /// the next instruction, as whether an instruction can write to the PC is
+    /// not known until it is translated.
+    fn interpret_block(
+        &self,
+        (block_start_pc, block_start_physical_pc): (u64, u64),
+        single_step_mode: bool,
+    ) -> (ExecutionResult, usize) {
+        let mut execution_result = ExecutionResult::new();
+        let mut current_pc = block_start_pc;
+        let mut instructions = 0;
+
+        loop {
+            let opcode =
+                unsafe { *fetch_pointer(block_start_physical_pc + (current_pc - block_start_pc)) };
+
+            log::debug!("interpreting {opcode:#08x} @ {current_pc:#08x}");
+
+            self.well_known_registers.branch_taken().write(false);
+
+            interpret_with_result(
+                &self.model,
+                "__DecodeA64",
+                &[Value::UnsignedInteger {
+                    value: u64::from(opcode),
+                    width: 32,
+                }],
+                &self.register_file,
+                &mut execution_result,
+            );
+            instructions += 1;
+
+            // recorded as translated code does, the block ends after it
+            if execution_result.tlbi() {
+                let exec_ctx = GuestExecutionContext::current();
+                let operand = match opcode & 0b1_1111 {
+                    31 => 0,
+                    rt => self.register_file.read::<u64>(alloc::format!("R{rt}")),
+                };
+                exec_ctx.tlbi_operand.store(operand, Ordering::Relaxed);
+                exec_ctx.tlbi_opcode.store(opcode, Ordering::Relaxed);
+            }
+
+            if !self.well_known_registers.branch_taken().read() {
+                let pc = self.well_known_registers.pc().read();
+                self.well_known_registers.pc().write(pc + 4);
+            }
+
+            let next_pc = self.well_known_registers.pc().read();
+
+            if next_pc != current_pc + 4
+                || next_pc & !0xFFF != block_start_pc & !0xFFF
+                || execution_result.as_u32() != 0
+                || single_step_mode
+            {
+                break;
+            }
+
+            current_pc = next_pc;
+        }
+
+        // translated code reports pending interrupts whenever it leaves
+        let interrupt_pending = GuestExecutionContext::current()
+            .interrupt_pending
+            .load(Ordering::Relaxed);
+        execution_result.set_interrupt_pending(interrupt_pending != 0);
+
+        (execution_result, instructions)
+    }
+
+    /// Executes `opcode` with the interpreter on `shadow`, a copy of the
+    /// register file, returning the memory it wrote to rather than modifying
+    /// guest memory
+    fn interpret_shadow(&self, shadow: &RegisterFile, opcode: u32) -> MemoryOverlay {
+        shadow.copy_from(&self.register_file);
+        shadow.write("__BranchTaken", false);
+
+        // devices are never read as their values would only be seen by the interpreter
+        let mut overlay = MemoryOverlay::new(is_guest_ram);
+        interpret_with_overlay(
+            &self.model,
+            "__DecodeA64",
+            &[Value::UnsignedInteger {
+                value: u64::from(opcode),
+                width: 32,
+            }],
+            shadow,
+            &mut ExecutionResult::new(),
+            &mut overlay,
+        );
+
+        if !shadow.read::<bool>("__BranchTaken") {
+            let pc = shadow.read::<u64>("_PC");
+            shadow.write("_PC", pc + 4);
+        }
+
+        overlay
+    }
+
+    /// Compares the state left by the translation of `opcode` with that left by
+    /// the interpreter, reporting every register and byte of memory that
+    /// differs on the first divergence
+    ///
+    /// Only memory backed by RAM is compared as accessing devices has side
+    /// effects. Instructions that read from a device are not compared at all,
+    /// as the interpreter never saw the value the translation read.
+    fn check_lockstep(&self, shadow: &RegisterFile, overlay: &MemoryOverlay, pc: u64, opcode: u32) {
+        if overlay.read_unreadable() {
+            log::trace!("not comparing {opcode:08x} @ {pc:#x}, which read from a device");
+            return;
+        }
+
+        let registers = self.register_file.differences(shadow);
+
+        let memory = overlay
+            .bytes()
+            .filter(|(address, _)| is_guest_ram(*address))
+            .map(|(address, interpreted)| {
+                let translated = unsafe { (address as *const u8).read_volatile() };
+                (address, translated, interpreted)
+            })
+            .filter(|(_, translated, interpreted)| translated != interpreted)
+            .collect::<Vec<_>>();
+
+        if registers.is_empty() && memory.is_empty() {
+            return;
+        }
+
+        let hex = |bytes: &[u8]| {
+            bytes
+                .iter()
+                .rev()
+                .map(|byte| alloc::format!("{byte:02x}"))
+                .collect::<String>()
+        };
+
+        log::error!("lockstep divergence executing {opcode:08x} @ {pc:#x}");
+        registers
+            .iter()
+            .for_each(|(name, translated, interpreted)| {
+                log::error!(
+                    "\t{name}: translated {}, interpreted {}",
+                    hex(translated),
+                    hex(interpreted)
+                )
+            });
+        memory
+            .iter()
+            .for_each(|(address, translated, interpreted)| {
+                log::error!(
+                    "\t{address:#x}: translated {translated:02x}, interpreted {interpreted:02x}"
+                )
+            });
+
+        panic!("translation of {opcode:08x} @ {pc:#x} diverged from the interpreter");
+    }
+
+    /// TLB maintenance performed by the instruction that ended the last block
+    ///
+    /// A TLBI records its opcode and operand in the execution context once it
+    /// has completed, so nothing is recorded if it took an exception and
+    /// nothing needs to be dropped. Other instructions only switch ASID, unless
+    /// they changed the other registers controlling translation.
+    fn tlb_operation(&self, mmu_registers: &mut MmuRegisters) -> tlb::Operation {
+        let current = MmuRegisters::current(self);
+        let previous = core::mem::replace(mmu_registers, current);
+
+        let exec_ctx = GuestExecutionContext::current();
+        let opcode = exec_ctx.tlbi_opcode.swap(0, Ordering::Relaxed);
+        let operand = exec_ctx.tlbi_operand.load(Ordering::Relaxed);
+
+        let tlbi = tlb::Operation::decode_tlbi(opcode, aarch64_mmu::asid_mask(self), |_| operand);
+
+        match tlbi {
+            Some(operation) => operation,
+            None if current.sctlr == previous.sctlr && current.tcr == previous.tcr => {
+                tlb::Operation::Switch {
+                    core_id: self.core_id,
+                    asid: current.asid,
+                }
+            }
+            None => tlb::Operation::All,
+        }
+    }
+
+    /// Removes translated blocks matching the predicate, along with every link
+    /// to or from them
+    fn discard_blocks<F: Fn(&TranslatedBlock) -> bool>(
+        &self,
+        block_cache: &mut HashMap<u64, TranslatedBlock>,
+        predicate: F,
+    ) {
+        let mut removed = Vec::new();
+        block_cache.retain(|_, block| {
+            let discard = predicate(block);
+            if discard {
+                let start = block.translation.as_ptr();
+                removed.push(start..start.wrapping_add(block.translation.len()));
+            }
+            !discard
+        });
+
+        if !removed.is_empty() {
+            let is_removed = |code| removed.iter().any(|range| range.contains(&code));
+            without_interrupts(|| {
+                let mut chain_links = self.chain_links.lock();
+                chain_links.forget_slots(is_removed);
+                chain_links.unlink_targets(is_removed);
+            });
+        }
+    }
+
+    /// Drops everything referring to code evicted from an over budget code
+    /// cache
+    fn discard_evicted(
+        &self,
+        eviction: &Eviction,
+        block_cache: &mut HashMap<u64, TranslatedBlock>,
+        chain_cache: &mut DirectMappedCache<CHAIN_CACHE_ENTRY_COUNT, *const u8>,
+    ) {
+        block_cache.retain(|_, block| !eviction.contains(block.translation.as_ptr()));
+        chain_cache.invalidate_values(|code| eviction.contains(*code));
+        // slots in the evicted code are dropped before patching the surviving
+        // slots, so nothing is written to the memory being reused
+        without_interrupts(|| {
+            let mut chain_links = self.chain_links.lock();
+            chain_links.forget_slots(|code| eviction.contains(code));
+            chain_links.unlink_targets(|code| eviction.contains(code));
+        });
+    }
+
+    /// Loads a translation of the block at the supplied (virtual, physical) PC
+    /// saved by a previous boot, if there is one for the current guest state
+    ///
+    /// Translations evicted from the code cache to make room are passed to
+    /// `discard` before their memory is reused.
+    fn load_persisted_block<F: FnMut(&Eviction)>(
+        &self,
+        code_cache: &mut CodeCache,
+        chain_cache: u64,
+        (block_start_pc, block_start_physical_pc): (u64, u64),
+        single_step_mode: bool,
+        discard: &mut F,
+    ) -> Option<TranslatedBlock> {
+        // translations deducting from the instruction budget are not persisted
+        if single_step_mode || !persist::is_enabled() || VirtualClock::get().is_some() {
+            return None;
+        }
+
+        let key = TranslationKey::new(
+            &self.model,
+            self.model_hash,
+            &self.register_file,
+            block_start_physical_pc,
+            self.register_allocator,
+            self.memory_access,
+        );
+        let (persisted, opcodes) = persist::lookup(&key, fetch_pointer(block_start_physical_pc))?;
+
+        log::debug!("loaded persisted translation of {block_start_pc:#08x}");
+
+        let executions = Box::new(AtomicU64::new(0));
+        let code = persisted.relocated_code(chain_cache, executions.as_ptr() as u64);
+        let translation = code_cache.insert(&code, discard);
+        let chain_slots = persisted
+            .chain_slots()
+            .iter()
+            .map(|slot| slot.relocate(translation.as_ptr()))
+            .collect();
+
+        Some(TranslatedBlock {
+            translation,
+            opcodes,
+            chain_slots,
+            pages: alloc::vec![block_start_physical_pc & !0xfff],
+            executions,
+            profiled_executions: 0,
+            virtual_pc: block_start_pc,
+            virtual_pcs: alloc::vec![block_start_pc],
+        })
+    }
+
+    /// Translates a trace of (virtual, physical) block start PCs into a single
+    /// block, guarding the entry to each block after the first and leaving
+    /// through a side exit if the guest went elsewhere
+    ///
+    /// Translations evicted from the code cache to make room are passed to
+    /// `discard` before their memory is reused.
+    fn translate_block<A: Alloc, F: FnMut(&Eviction)>(
         &self,
         allocator: A,
+        code_cache: &mut CodeCache,
         chain_cache: u64,
-        block_start_pc: u64,
+        trace: &[(u64, u64)],
         single_step_mode: bool,
+        discard: &mut F,
     ) -> TranslatedBlock {
         let mut ctx = X86TranslationContext::new_with_allocator(
             allocator,
@@ -436,96 +1383,184 @@ impl ModelDevice {
             true,
             self.register_file.global_register_offset(),
         );
+        ctx.set_register_allocator(self.register_allocator);
+        ctx.set_memory_access(self.memory_access);
         let mut emitter = X86Emitter::new(&mut ctx);
 
-        let mut current_pc = block_start_pc;
+        // superblocks depend on the path taken through them so are not persisted
+        let persistent_key = match trace {
+            [(_, physical_pc)]
+                if !single_step_mode && persist::is_enabled() && VirtualClock::get().is_none() =>
+            {
+                Some(TranslationKey::new(
+                    &self.model,
+                    self.model_hash,
+                    &self.register_file,
+                    *physical_pc,
+                    self.register_allocator,
+                    self.memory_access,
+                ))
+            }
+            _ => None,
+        };
+
+        let executions = Box::new(AtomicU64::new(0));
 
         let mut opcodes = Vec::new();
+        let mut pages = Vec::new();
+        let mut blocks = 0;
 
         // block prologue
         emitter.prologue();
 
-        // reset BranchTaken
-        let _false = emitter.constant(0 as u64, Type::Unsigned(1));
-        emitter.write_register(self.model.reg_offset("__BranchTaken") as u64, _false);
+        emitter.count_execution(executions.as_ptr() as u64);
 
-        // instruction translation loop
-        let was_end_of_block = loop {
-            // read opcode
-            let opcode = unsafe { *((current_pc & 0xFF_FFFF_FFFF) as *const u32) };
+        for (index, &(block_start_pc, block_start_physical_pc)) in trace.iter().enumerate() {
+            blocks += 1;
 
-            log::debug!("translating {opcode:#08x} @ {current_pc:#08x}");
-            log::debug!("{}", disarm64::decoder::decode(opcode).unwrap());
+            let page = block_start_physical_pc & !0xfff;
+            if !pages.contains(&page) {
+                pages.push(page);
+            }
 
-            //#[cfg(feature = "debug_translation")]
-            opcodes.push(opcode);
+            let mut current_pc = block_start_pc;
 
-            let _return_value = translate_instruction(
-                allocator,
-                &*self.model,
-                "__DecodeA64",
-                &mut emitter,
-                &self.register_file,
-                opcode,
-            )
-            .unwrap();
+            // reset BranchTaken
+            let _false = emitter.constant(0 as u64, Type::Unsigned(1));
+            emitter.write_register(self.model.reg_offset("__BranchTaken") as u64, _false);
 
-            // hit a maybe-PC modifying instruction
-            if emitter.ctx().get_pc_write_flag() {
-                // end of block
-                break true;
-            } else {
-                // emit code to increment PC register by 4
-                let pc_offset = self.model.reg_offset("_PC");
-                let pc = emitter.read_register(pc_offset as u64, Type::Unsigned(64));
-                let _4 = emitter.constant(4, Type::Unsigned(64));
-                let pc_inc = emitter.binary_operation(BinaryOperationKind::Add(pc, _4));
-                emitter.write_register(pc_offset as u64, pc_inc);
+            // instruction translation loop
+            let was_end_of_block = loop {
+                // read opcode
+                let opcode = unsafe {
+                    *fetch_pointer(block_start_physical_pc + (current_pc - block_start_pc))
+                };
 
-                // increase our local pc by 4
-                current_pc += 4;
+                log::debug!("translating {opcode:#08x} @ {current_pc:#08x}");
+                log::debug!("{}", disarm64::decoder::decode(opcode).unwrap());
 
-                // did we cross a page boundary?
-                if current_pc & !0xFFF != block_start_pc & !0xFFF {
+                //#[cfg(feature = "debug_translation")]
+                opcodes.push(opcode);
+
+                let _return_value = translate_instruction(
+                    allocator,
+                    &*self.model,
+                    "__DecodeA64",
+                    &mut emitter,
+                    &self.register_file,
+                    opcode,
+                )
+                .unwrap();
+
+                // hit a maybe-PC modifying instruction
+                if emitter.ctx().get_pc_write_flag() {
+                    // end of block
+                    break true;
+                } else {
+                    // emit code to increment PC register by 4
+                    let pc_offset = self.model.reg_offset("_PC");
+                    let pc = emitter.read_register(pc_offset as u64, Type::Unsigned(64));
+                    let _4 = emitter.constant(4, Type::Unsigned(64));
+                    let pc_inc = emitter.binary_operation(BinaryOperationKind::Add(pc, _4));
+                    emitter.write_register(pc_offset as u64, pc_inc);
+
+                    // increase our local pc by 4
+                    current_pc += 4;
+
+                    // did we cross a page boundary?
+                    if current_pc & !0xFFF != block_start_pc & !0xFFF {
+                        break false;
+                    }
+                }
+
+                // if we have a TLB invalidation or other non-zero status in that
+                // instruction, do not translate the rest of the block
+                if emitter.execution_result.as_u32() != 0 {
                     break false;
                 }
-            }
 
-            // if we have a TLB invalidation or other non-zero status in that instruction,
-            // do not translate the rest of the block
-            if emitter.execution_result.need_tlb_invalidate() {
-                break false;
+                // only translate single instruction in single_step_mode
+                if single_step_mode {
+                    break false;
+                }
+            };
+
+            // if we didn't jump anywhere at the end of the block (IE. branch was not
+            // taken), increment PC by 4 bytes
+            if was_end_of_block {
+                let branch_taken = emitter.read_register(
+                    self.model.reg_offset("__BranchTaken") as u64,
+                    Type::Unsigned(1),
+                );
+
+                let _0 = emitter.constant(0, Type::Unsigned(64));
+                let _4 = emitter.constant(4, Type::Unsigned(64));
+                let addend = emitter.select(branch_taken, _0, _4);
+
+                let pc_offset = self.model.reg_offset("_PC");
+                let pc = emitter.read_register(pc_offset, Type::Unsigned(64));
+                let new_pc = emitter.binary_operation(BinaryOperationKind::Add(pc, addend));
+                emitter.write_register(pc_offset, new_pc);
             }
 
-            // only translate single instruction in single_step_mode
-            if single_step_mode {
-                break false;
+            let Some(&(next_pc, _)) = trace.get(index + 1) else {
+                break;
+            };
+
+            // the TLB must be invalidated, or the core parked, before anything else
+            // is executed
+            if emitter.execution_result.as_u32() != 0 {
+                break;
             }
-        };
 
-        // if we didn't jump anywhere at the end of the block (IE. branch was not
-        // taken), increment PC by 4 bytes
-        if was_end_of_block {
-            let branch_taken = emitter.read_register(
-                self.model.reg_offset("__BranchTaken") as u64,
-                Type::Unsigned(1),
-            );
+            let on_trace = emitter.ctx_mut().create_block();
+            let side_exit = emitter.ctx_mut().create_block();
+            emitter.guard_pc(next_pc, on_trace, side_exit);
 
-            let _0 = emitter.constant(0, Type::Unsigned(64));
-            let _4 = emitter.constant(4, Type::Unsigned(64));
-            let addend = emitter.select(branch_taken, _0, _4);
+            emitter.set_current_block(side_exit);
+            if VirtualClock::get().is_some() {
+                emitter.consume_instruction_budget(opcodes.len() as u64);
+            }
+            emitter.leave_with_cache(chain_cache);
+
+            // leaving a translation is the only other place interrupts are checked,
+            // so without this they would wait for the whole superblock
+            emitter.set_current_block(on_trace);
+            let next_block = emitter.ctx_mut().create_block();
+            let interrupted = emitter.ctx_mut().create_block();
+            emitter.poll_interrupts(next_block, interrupted);
+
+            emitter.set_current_block(interrupted);
+            if VirtualClock::get().is_some() {
+                emitter.consume_instruction_budget(opcodes.len() as u64);
+            }
+            emitter.leave();
 
-            let pc_offset = self.model.reg_offset("_PC");
-            let pc = emitter.read_register(pc_offset, Type::Unsigned(64));
-            let new_pc = emitter.binary_operation(BinaryOperationKind::Add(pc, addend));
-            emitter.write_register(pc_offset, new_pc);
+            emitter.set_current_block(next_block);
+            emitter.ctx_mut().clear_pc_write_flag();
         }
 
         log::trace!("compiling");
+        if VirtualClock::get().is_some() {
+            emitter.consume_instruction_budget(opcodes.len() as u64);
+        }
         emitter.leave_with_cache(chain_cache);
         let num_regs = emitter.next_vreg();
 
-        let translation = ctx.compile(num_regs);
+        let assembled = ctx.assemble(num_regs);
+
+        if let Some(key) = persistent_key {
+            persist::store(PersistedTranslation::new(key, &opcodes, &assembled));
+        }
+
+        let AssembledCode {
+            code, chain_slots, ..
+        } = assembled;
+        let translation = code_cache.insert(&code, discard);
+        let chain_slots = chain_slots
+            .into_iter()
+            .map(|slot| slot.relocate(translation.as_ptr()))
+            .collect();
 
         // if block_start_pc == 0xffffffc00811c584 {
         //     log::error!("WARNING! Large block @ {block_start_pc:x}");
@@ -545,6 +1580,33 @@ impl ModelDevice {
         TranslatedBlock {
             translation,
             opcodes,
+            chain_slots,
+            pages,
+            executions,
+            profiled_executions: 0,
+            virtual_pc: trace[0].0,
+            virtual_pcs: trace[..blocks]
+                .iter()
+                .map(|(virtual_pc, _)| *virtual_pc)
+                .collect(),
+        }
+    }
+}
+
+/// Guest registers controlling translation other than the table bases
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct MmuRegisters {
+    sctlr: u64,
+    tcr: u64,
+    asid: u16,
+}
+
+impl MmuRegisters {
+    fn current(device: &ModelDevice) -> Self {
+        Self {
+            sctlr: device.register_file.read::<u64>("SCTLR_EL1_bits"),
+            tcr: device.register_file.read::<u64>("TCR_EL1_bits"),
+            asid: aarch64_mmu::current_asid(device),
         }
     }
 }
@@ -552,6 +1614,124 @@ impl ModelDevice {
 pub struct TranslatedBlock {
     translation: Translation,
     opcodes: Vec<u32>,
+    chain_slots: Vec<ChainSlot>,
+    /// Guest physical pages the block was translated from
+    pages: Vec<u64>,
+    /// Incremented by the translated code every time it is entered
+    executions: Box<AtomicU64>,
+    /// Executions already reported to the profiler
+    profiled_executions: u64,
+    /// Guest virtual PC the block was translated at
+    virtual_pc: u64,
+    /// Guest virtual PCs of the guest blocks translated, more than one for
+    /// superblocks
+    virtual_pcs: Vec<u64>,
+}
+
+impl TranslatedBlock {
+    fn executions(&self) -> u64 {
+        self.executions.load(Ordering::Relaxed)
+    }
+
+    fn is_superblock(&self) -> bool {
+        self.virtual_pcs.len() > 1
+    }
+}
+
+/// Follows the most executed linked successor of each block from `head`,
+/// returning the (virtual, physical) start PCs of the blocks of a superblock
+///
+/// Block counters do not record which exit was taken, but a successor that
+/// runs more often is the likely direction of the branch.
+fn form_trace(
+    block_cache: &HashMap<u64, TranslatedBlock>,
+    translation_cache: &mut DirectMappedCache<1024, u64>,
+    head: (u64, u64),
+) -> Vec<(u64, u64)> {
+    let mut trace = alloc::vec![head];
+
+    while trace.len() < SUPERBLOCK_MAX_BLOCKS {
+        let (_, physical_pc) = *trace.last().unwrap();
+        let Some(block) = block_cache.get(&physical_pc) else {
+            break;
+        };
+
+        let likely = block
+            .chain_slots
+            .iter()
+            .filter_map(ChainSlot::linked_pc)
+            .filter_map(|virtual_pc| {
+                let physical_pc = translation_cache.get(virtual_pc as usize)?;
+                let successor = block_cache.get(&physical_pc)?;
+                Some(((virtual_pc, physical_pc), successor.executions()))
+            })
+            .max_by_key(|(_, executions)| *executions);
+
+        let Some((next, _)) = likely else {
+            break;
+        };
+
+        // stop at loops rather than unrolling them
+        if trace.iter().any(|(virtual_pc, _)| *virtual_pc == next.0) {
+            break;
+        }
+
+        trace.push(next);
+    }
+
+    trace
+}
+
+/// Host address of the instruction at `guest_physical`
+///
+/// Instructions are fetched through the guest physical mapping rather than the
+/// lower half, where a fault would be checked as a data read, as the page was
+/// checked for an instruction fetch when the block start was translated.
+fn fetch_pointer(guest_physical: u64) -> *const u32 {
+    let host = guest_physical_to_host_virt(guest_physical);
+
+    // code written by the loader is backed, but zeroed RAM may not be yet
+    if VirtualMemoryArea::current()
+        .opt
+        .translate_addr(host)
+        .is_none()
+    {
+        allocate_guest_ram(guest_physical);
+    }
+
+    host.as_ptr()
+}
+
+/// Whether the guest memory accessed at the host address `address` by the
+/// current core is backed by RAM rather than a device
+fn is_guest_ram(address: usize) -> bool {
+    let exec_context = GuestExecutionContext::current();
+    let core = unsafe { &*exec_context.current_core };
+    let address_space = unsafe { &*exec_context.current_address_space };
+
+    // guest virtual addresses are truncated to 40 bits when accessed
+    let virtual_address = (((address as i64) << 24) >> 24) as u64;
+
+    aarch64_mmu::guest_translate(core, virtual_address, Access::Inspect)
+        .ok()
+        .and_then(|mapping| address_space.find_region(mapping.physical_address))
+        .is_some_and(|region| matches!(region.kind(), AddressSpaceRegionKind::Ram))
+}
+
+/// Logs the execution count and code size of every translated block, hottest
+/// last
+fn log_block_profile(block_cache: &HashMap<u64, TranslatedBlock>) {
+    block_cache
+        .iter()
+        .sorted_by_key(|(_, block)| block.executions())
+        .for_each(|(physical_pc, block)| {
+            log::info!(
+                "{physical_pc:x}: {} ({}, {} blocks)",
+                block.executions(),
+                bytes(block.translation.len()),
+                block.virtual_pcs.len()
+            )
+        });
 }
 
 fn register_cache_type(name: InternedString) -> RegisterCacheType {
@@ -638,4 +1818,20 @@ impl<const N: usize, V: Copy> DirectMappedCache<N, V> {
     pub fn fill_keys(&mut self, key: usize) {
         self.table().iter_mut().for_each(|e| e.key = key);
     }
+
+    /// Invalidates all entries whose key matches the predicate
+    pub fn invalidate_keys<F: Fn(u64) -> bool>(&mut self, predicate: F) {
+        self.table()
+            .iter_mut()
+            .filter(|e| predicate(e.key as u64))
+            .for_each(|e| e.key = 1);
+    }
+
+    /// Invalidates all entries whose value matches the predicate
+    pub fn invalidate_values<F: Fn(&V) -> bool>(&mut self, predicate: F) {
+        self.table()
+            .iter_mut()
+            .filter(|e| predicate(&e.value))
+            .for_each(|e| e.key = 1);
+    }
 }
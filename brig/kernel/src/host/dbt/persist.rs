@@ -0,0 +1,377 @@
+//! Translations saved to the guest data disk and reused by later boots
+//!
+//! Translated code only depends on where it is placed through a few 64-bit
+//! immediates holding host addresses: the chain cache of the translating core,
+//! the execution counter of the block, and host helper functions. Each of
+//! these is recorded as a [`Relocation`] when the code is assembled and patched
+//! when a persisted translation is loaded into a code cache, so the rest of the
+//! code can be copied as-is.
+//!
+//! Translations are stored in a file reserved in the guest data tarfile by
+//! `brig-cli`, which is overwritten in place on the disk periodically and when
+//! the kernel is terminated. The file is discarded when the kernel image
+//! changes, as the translator may have too.
+
+use {
+    crate::host::{
+        arch::x86::kernel_image,
+        dbt::{
+            chain::ChainSlot,
+            float_helpers,
+            register_file::RegisterFile,
+            softmmu::{self, MemoryAccessKind},
+            sysreg_helpers::{sys_reg_read, sys_reg_write},
+            x86::{
+                AssembledCode, encoder::RELOCATION_OFFSET,
+                register_allocator::RegisterAllocatorKind,
+            },
+        },
+        devices::manager::SharedDeviceManager,
+        fs::{Error, Filesystem},
+    },
+    alloc::{
+        string::{String, ToString},
+        vec::Vec,
+    },
+    common::{
+        hashmap::{HashMap, Hasher},
+        rudder::{Model, RegisterCacheType, RegisterDescriptor},
+    },
+    core::{hash::Hasher as _, ops::Range},
+    displaydoc::Display,
+    serde::{Deserialize, Serialize},
+    spin::{Mutex, Once},
+    x86_64::instructions::interrupts::without_interrupts,
+};
+
+/// Path of the translation cache within the guest data filesystem
+pub const TRANSLATION_CACHE_PATH: &str = "translation_cache.bin";
+
+/// Incremented whenever the layout of the translation cache file changes
+const FORMAT_VERSION: u32 = 2;
+
+/// Number of new translations after which the translation cache is written
+/// back to the disk
+const FLUSH_INTERVAL: usize = 4096;
+
+static CACHE: Once<Mutex<PersistentCache>> = Once::new();
+
+/// Host address loaded by translated code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, Serialize, Deserialize)]
+pub enum Symbol {
+    /// chain cache
+    ChainCache,
+    /// execution counter
+    ExecutionCounter,
+    /// host function {0}
+    Function(u8),
+}
+
+impl Symbol {
+    /// Symbol of a host function called from translated code
+    pub fn function(address: u64) -> Self {
+        let index = host_functions()
+            .iter()
+            .position(|function| *function == address)
+            .unwrap_or_else(|| panic!("{address:#x} is not a known host function"));
+
+        Self::Function(u8::try_from(index).unwrap())
+    }
+}
+
+/// Host functions that can be called from translated code
+fn host_functions() -> [u64; 10] {
+    [
+        sys_reg_read as u64,
+        sys_reg_write as u64,
+        softmmu::read_miss as u64,
+        softmmu::write_miss as u64,
+        float_helpers::remainder_f32 as u64,
+        float_helpers::remainder_f64 as u64,
+        float_helpers::powi_f32 as u64,
+        float_helpers::powi_f64 as u64,
+        float_helpers::exp2_f32 as u64,
+        float_helpers::exp2_f64 as u64,
+    ]
+}
+
+/// Location of a symbol's address within assembled code
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Relocation {
+    offset: usize,
+    symbol: Symbol,
+}
+
+impl Relocation {
+    /// Relocation of the encoded [`MOVREL`] at the supplied offset
+    ///
+    /// [`MOVREL`]: crate::host::dbt::x86::encoder::Opcode::MOVREL
+    pub fn from_offset(instruction: usize, symbol: Symbol) -> Self {
+        Self {
+            offset: instruction + RELOCATION_OFFSET,
+            symbol,
+        }
+    }
+
+    fn apply(&self, code: &mut [u8], chain_cache: u64, execution_counter: u64) {
+        let address = match self.symbol {
+            Symbol::ChainCache => chain_cache,
+            Symbol::ExecutionCounter => execution_counter,
+            Symbol::Function(index) => host_functions()[usize::from(index)],
+        };
+
+        code[self.offset..self.offset + 8].copy_from_slice(&address.to_le_bytes());
+    }
+}
+
+/// Identifies the guest state a block was translated in, and how it was
+/// translated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TranslationKey {
+    physical_pc: u64,
+    model_hash: u64,
+    /// Hash of the registers whose values were emitted as constants
+    context_hash: u64,
+    register_allocator: RegisterAllocatorKind,
+    memory_access: MemoryAccessKind,
+}
+
+impl TranslationKey {
+    pub fn new(
+        model: &Model,
+        model_hash: u64,
+        register_file: &RegisterFile,
+        physical_pc: u64,
+        register_allocator: RegisterAllocatorKind,
+        memory_access: MemoryAccessKind,
+    ) -> Self {
+        // combined with xor as register iteration order is not stable
+        let context_hash = model
+            .registers()
+            .values()
+            .filter(|register| register.cache != RegisterCacheType::None)
+            .map(|RegisterDescriptor { typ, offset, .. }| {
+                let mut hasher = Hasher::default();
+                hasher.write_u64(*offset);
+                (0..usize::from(typ.width_bytes())).for_each(|i| {
+                    hasher.write_u8(register_file.read_raw::<u8>(*offset as usize + i))
+                });
+                hasher.finish()
+            })
+            .fold(0, |acc, hash| acc ^ hash);
+
+        Self {
+            physical_pc,
+            model_hash,
+            context_hash,
+            register_allocator,
+            memory_access,
+        }
+    }
+}
+
+/// Translated block as stored on disk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PersistedTranslation {
+    key: TranslationKey,
+    opcode_count: usize,
+    opcode_hash: u64,
+    code: Vec<u8>,
+    chain_slots: Vec<ChainSlot>,
+    relocations: Vec<Relocation>,
+}
+
+impl PersistedTranslation {
+    pub fn new(key: TranslationKey, opcodes: &[u32], assembled: &AssembledCode) -> Self {
+        Self {
+            key,
+            opcode_count: opcodes.len(),
+            opcode_hash: hash_opcodes(opcodes),
+            code: assembled.code.clone(),
+            chain_slots: assembled.chain_slots.clone(),
+            relocations: assembled.relocations.clone(),
+        }
+    }
+
+    /// Copy of the code with every relocation applied
+    pub fn relocated_code(&self, chain_cache: u64, execution_counter: u64) -> Vec<u8> {
+        let mut code = self.code.clone();
+        self.relocations
+            .iter()
+            .for_each(|relocation| relocation.apply(&mut code, chain_cache, execution_counter));
+        code
+    }
+
+    /// Offsets of the chain slots within the code
+    pub fn chain_slots(&self) -> &[ChainSlot] {
+        &self.chain_slots
+    }
+}
+
+fn hash_opcodes(opcodes: &[u32]) -> u64 {
+    let mut hasher = Hasher::default();
+    opcodes.iter().for_each(|opcode| hasher.write_u32(*opcode));
+    hasher.finish()
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheFile<T> {
+    version: u32,
+    kernel_hash: u64,
+    translations: Vec<T>,
+}
+
+struct PersistentCache {
+    /// Alias of the block device containing the guest data filesystem
+    disk: String,
+    /// Bytes of the disk reserved for the translation cache file
+    region: Range<usize>,
+    kernel_hash: u64,
+    translations: HashMap<TranslationKey, Vec<PersistedTranslation>>,
+    /// Translations stored since the cache was last written back
+    unsaved: usize,
+}
+
+impl PersistentCache {
+    fn flush(&mut self) {
+        let mut data = postcard::to_allocvec(&CacheFile {
+            version: FORMAT_VERSION,
+            kernel_hash: self.kernel_hash,
+            translations: self.translations.values().flatten().collect::<Vec<_>>(),
+        })
+        .unwrap();
+
+        if data.len() > self.region.len() {
+            log::warn!(
+                "translation cache ({} bytes) exceeds its {} byte file, not saving",
+                data.len(),
+                self.region.len()
+            );
+            return;
+        }
+
+        let device = SharedDeviceManager::get()
+            .get_device_by_alias(&self.disk)
+            .expect("disk not found");
+        let mut device = device.lock();
+        let disk = device.as_block();
+
+        let block_size = disk.block_size();
+        assert_eq!(self.region.start % block_size, 0);
+        data.resize(data.len().next_multiple_of(block_size), 0);
+
+        disk.write(&data, self.region.start / block_size).unwrap();
+        self.unsaved = 0;
+
+        log::info!("saved translation cache ({} bytes)", data.len());
+    }
+}
+
+/// Loads the translation cache from the guest data filesystem on the supplied
+/// disk, persistence is disabled if the filesystem does not contain one
+pub fn init<FS: Filesystem>(fs: &mut FS, disk: &str) {
+    let region = match fs.device_range(TRANSLATION_CACHE_PATH) {
+        Ok(region) => region,
+        Err(Error::NotFound(_)) => {
+            log::info!("no translation cache file found, translations will not be persisted");
+            return;
+        }
+        Err(e) => panic!("failed to locate translation cache: {e}"),
+    };
+
+    let kernel_hash = {
+        let mut hasher = Hasher::default();
+        hasher.write(kernel_image());
+        hasher.finish()
+    };
+
+    let data = fs.read_to_vec(TRANSLATION_CACHE_PATH).unwrap();
+    let translations = match postcard::take_from_bytes::<CacheFile<PersistedTranslation>>(&data) {
+        Ok((file, _)) if file.version == FORMAT_VERSION && file.kernel_hash == kernel_hash => {
+            file.translations
+        }
+        _ => {
+            log::info!("translation cache is empty or was created by a different kernel");
+            Vec::new()
+        }
+    };
+
+    log::info!("loaded {} persisted translations", translations.len());
+
+    let mut map = HashMap::<TranslationKey, Vec<PersistedTranslation>>::default();
+    translations
+        .into_iter()
+        .for_each(|translation| map.entry(translation.key).or_default().push(translation));
+
+    CACHE.call_once(|| {
+        Mutex::new(PersistentCache {
+            disk: disk.to_string(),
+            region,
+            kernel_hash,
+            translations: map,
+            unsaved: 0,
+        })
+    });
+}
+
+pub fn is_enabled() -> bool {
+    CACHE.get().is_some()
+}
+
+/// Finds a persisted translation of the block at `key` whose guest code,
+/// starting at `guest_code`, is unchanged, returning it along with the opcodes
+pub fn lookup(
+    key: &TranslationKey,
+    guest_code: *const u32,
+) -> Option<(PersistedTranslation, Vec<u32>)> {
+    let cache = CACHE.get()?;
+
+    // guest memory is read outside of the lock as it may fault
+    let candidates =
+        without_interrupts(|| cache.lock().translations.get(key).cloned()).unwrap_or_default();
+
+    candidates.into_iter().find_map(|translation| {
+        let opcodes = (0..translation.opcode_count)
+            .map(|i| unsafe { guest_code.add(i).read_volatile() })
+            .collect::<Vec<_>>();
+
+        (hash_opcodes(&opcodes) == translation.opcode_hash).then_some((translation, opcodes))
+    })
+}
+
+/// Writes any translations stored since the cache was last written back to
+/// the disk, before the kernel exits
+pub fn flush() {
+    let Some(cache) = CACHE.get() else {
+        return;
+    };
+
+    without_interrupts(|| {
+        let mut cache = cache.lock();
+
+        if cache.unsaved != 0 {
+            cache.flush();
+        }
+    });
+}
+
+/// Adds a translation to the cache, writing the cache back to disk every
+/// [`FLUSH_INTERVAL`] translations
+pub fn store(translation: PersistedTranslation) {
+    let Some(cache) = CACHE.get() else {
+        return;
+    };
+
+    without_interrupts(|| {
+        let mut cache = cache.lock();
+
+        let entries = cache.translations.entry(translation.key).or_default();
+        entries.retain(|existing| existing.opcode_hash != translation.opcode_hash);
+        entries.push(translation);
+
+        cache.unsaved += 1;
+        if cache.unsaved >= FLUSH_INTERVAL {
+            cache.flush();
+        }
+    });
+}
@@ -0,0 +1,127 @@
+//! Guest execution profiling
+//!
+//! Cores configured with the `profile_interval` key measure the host cycles
+//! (TSC ticks) spent in each block entered by the block execution loop, which
+//! includes any blocks chained to it, and count block executions. Translated
+//! blocks count their own executions, so chained entries are included there.
+//!
+//! Every interval guest instructions, the samples accrued since the last export
+//! are written to the hyperport, one line per guest virtual PC, for
+//! `brig-cli profile` to sum and symbolise.
+
+use {
+    common::hashmap::HashMap,
+    core::fmt::{self, Display, Write},
+    proc_macro_lib::ktest,
+};
+
+/// Prefix of every sample line written to the hyperport
+const SAMPLE_PREFIX: &str = "profile";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Sample {
+    executions: u64,
+    cycles: u64,
+}
+
+/// Sample of a block as written to the hyperport
+struct SampleLine {
+    core_id: usize,
+    virtual_pc: u64,
+    sample: Sample,
+}
+
+impl Display for SampleLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{SAMPLE_PREFIX} {} {:#x} {} {}",
+            self.core_id, self.virtual_pc, self.sample.executions, self.sample.cycles
+        )
+    }
+}
+
+pub struct Profiler {
+    core_id: usize,
+    /// Guest instructions between exports
+    interval: u64,
+    /// Instruction count after which the next export is due
+    next_export: u64,
+    /// Samples since the last export, by the guest virtual PC of the block
+    samples: HashMap<u64, Sample>,
+}
+
+impl Profiler {
+    pub fn new(core_id: usize, interval: u64) -> Self {
+        log::warn!("profiling core {core_id} every {interval} instructions");
+
+        Self {
+            core_id,
+            interval,
+            next_export: interval,
+            samples: HashMap::default(),
+        }
+    }
+
+    /// Current value of the TSC
+    pub fn now() -> u64 {
+        unsafe { x86::time::rdtsc() }
+    }
+
+    /// Attributes cycles spent in the block at `virtual_pc`, along with
+    /// executions of it not counted by a translation
+    pub fn record(&mut self, virtual_pc: u64, cycles: u64, executions: u64) {
+        let sample = self.samples.entry(virtual_pc).or_default();
+        sample.cycles += cycles;
+        sample.executions += executions;
+    }
+
+    pub fn is_due(&self, instruction_count: u64) -> bool {
+        instruction_count >= self.next_export
+    }
+
+    /// Writes the samples accrued since the last export to the supplied
+    /// writer, the hyperport
+    pub fn export<W: Write>(&mut self, writer: &mut W, instruction_count: u64) {
+        self.samples
+            .drain()
+            .filter(|(_, sample)| *sample != Sample::default())
+            .for_each(|(virtual_pc, sample)| {
+                writeln!(
+                    writer,
+                    "{}",
+                    SampleLine {
+                        core_id: self.core_id,
+                        virtual_pc,
+                        sample,
+                    }
+                )
+                .unwrap()
+            });
+
+        self.next_export = instruction_count + self.interval;
+    }
+}
+
+#[ktest]
+fn profile_export() {
+    let mut profiler = Profiler::new(1, 100);
+    assert!(!profiler.is_due(99));
+    assert!(profiler.is_due(100));
+
+    profiler.record(0xffff_0000_8000_0000, 20, 1);
+    profiler.record(0xffff_0000_8000_0000, 30, 0);
+    profiler.record(0x8000_1000, 0, 0);
+
+    let mut output = alloc::string::String::new();
+    profiler.export(&mut output, 150);
+
+    // blocks with nothing to report are omitted
+    assert_eq!(output, "profile 1 0xffff000080000000 1 50\n");
+    assert!(!profiler.is_due(249));
+    assert!(profiler.is_due(250));
+
+    output.clear();
+    profiler.export(&mut output, 250);
+    assert!(output.is_empty());
+}
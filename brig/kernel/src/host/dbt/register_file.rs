@@ -119,6 +119,34 @@ impl RegisterFile {
         V::read(&unsafe { self.inner.as_ref_unchecked() }[offset..offset + V::SIZE])
     }
 
+    /// Overwrites every register with its value in `other`, which must have
+    /// been initialised from the same model
+    pub fn copy_from(&self, other: &Self) {
+        let len = self.global_register_offset;
+        unsafe { self.inner.as_mut_unchecked() }
+        [..len].copy_from_slice(&unsafe { other.inner.as_ref_unchecked() }[..len]);
+    }
+
+    /// Registers whose value differs from that in `other`, sorted by name, along
+    /// with both values
+    pub fn differences<'a>(&'a self, other: &'a Self) -> Vec<(InternedString, &'a [u8], &'a [u8])> {
+        let ours = unsafe { self.inner.as_ref_unchecked() };
+        let theirs = unsafe { other.inner.as_ref_unchecked() };
+
+        self.registers
+            .iter()
+            .map(|(name, &(offset, size))| {
+                (
+                    *name,
+                    &ours[offset..offset + size],
+                    &theirs[offset..offset + size],
+                )
+            })
+            .filter(|(_, ours, theirs)| ours != theirs)
+            .sorted_by(|(a, ..), (b, ..)| a.as_ref().cmp(b.as_ref()))
+            .collect()
+    }
+
     pub fn as_wellknown<V: RegisterValue>(
         &self,
         name: impl Into<InternedString>,
@@ -0,0 +1,388 @@
+//! Deterministic record and replay of guest execution
+//!
+//! Everything the guest can observe that does not follow from its own
+//! execution is an asynchronous event: the pending interrupt line, reads of
+//! system register mapped devices (such as the generic timer counter) and reads
+//! of memory mapped devices. When recording, each event is logged along with
+//! the core that observed it and that core's instruction count. When replaying,
+//! the logged values are returned in place of the real ones, so that the guest
+//! follows exactly the same path.
+//!
+//! Instruction counts are only updated between blocks, so while recording or
+//! replaying every block returns to the block execution loop rather than being
+//! chained to the next.
+//!
+//! Events are written to the hyperport one per line, interleaved with any
+//! register trace, and `brig-cli` extracts them into the log file it later
+//! replays.
+
+use {
+    crate::guest::{GuestExecutionContext, MAX_GUEST_CORES},
+    alloc::{collections::VecDeque, string::String, vec::Vec},
+    common::ReplayConfig,
+    core::{
+        fmt::{self, Display, Write},
+        num::ParseIntError,
+        str::FromStr,
+    },
+    proc_macro_lib::ktest,
+    spin::{Mutex, Once},
+    x86_64::instructions::interrupts::without_interrupts,
+};
+
+/// Prefix of every event line written to the hyperport
+const EVENT_PREFIX: &str = "replay";
+
+static REPLAYER: Once<Mutex<Replayer>> = Once::new();
+
+/// Failed to parse replay log
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum ParseError {
+    /// Line {0:?} is not a replay event
+    NotAnEvent(String),
+    /// Unknown event kind {0:?}
+    UnknownKind(String),
+    /// Missing field in event
+    MissingField,
+    /// Invalid number: {0}
+    Number(#[from] ParseIntError),
+    /// Invalid bytes {0:?}
+    Bytes(String),
+}
+
+/// Asynchronous event observed by the guest
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Event {
+    /// Pending interrupt line changed
+    Interrupt(bool),
+    /// System register mapped device was read
+    RegisterRead { id: u64, value: u64 },
+    /// Memory mapped device was read, of any size
+    DeviceRead { address: u64, bytes: Vec<u8> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LoggedEvent {
+    core_id: usize,
+    instruction_count: u64,
+    event: Event,
+}
+
+impl Display for LoggedEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{EVENT_PREFIX} {} {} ",
+            self.core_id, self.instruction_count
+        )?;
+
+        match &self.event {
+            Event::Interrupt(pending) => write!(f, "irq {}", u8::from(*pending)),
+            Event::RegisterRead { id, value } => write!(f, "sysreg {id:#x} {value:#x}"),
+            // in address order
+            Event::DeviceRead { address, bytes } => {
+                write!(f, "mmio {address:#x} ")?;
+                bytes.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+            }
+        }
+    }
+}
+
+impl FromStr for LoggedEvent {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split_whitespace();
+
+        if fields.next() != Some(EVENT_PREFIX) {
+            return Err(ParseError::NotAnEvent(s.into()));
+        }
+
+        let mut next = || fields.next().ok_or(ParseError::MissingField);
+        let hex = |s: &str| u64::from_str_radix(s.trim_start_matches("0x"), 16);
+
+        let core_id = next()?.parse()?;
+        let instruction_count = next()?.parse()?;
+
+        let event = match next()? {
+            "irq" => Event::Interrupt(next()? != "0"),
+            "sysreg" => Event::RegisterRead {
+                id: hex(next()?)?,
+                value: hex(next()?)?,
+            },
+            "mmio" => Event::DeviceRead {
+                address: hex(next()?)?,
+                bytes: parse_bytes(next()?)?,
+            },
+            kind => return Err(ParseError::UnknownKind(kind.into())),
+        };
+
+        Ok(Self {
+            core_id,
+            instruction_count,
+            event,
+        })
+    }
+}
+
+struct Replayer {
+    /// Events still to be replayed for each core, empty when recording
+    events: Option<Vec<VecDeque<LoggedEvent>>>,
+    /// Interrupt line last observed by each core
+    interrupts: [bool; MAX_GUEST_CORES],
+    /// Event lines not yet written to the hyperport
+    output: String,
+}
+
+impl Replayer {
+    fn log(&mut self, event: LoggedEvent) {
+        writeln!(self.output, "{event}").unwrap();
+    }
+
+    /// Takes the next event of the core, which must match the supplied
+    /// predicate
+    fn take<F: Fn(&Event) -> bool>(
+        &mut self,
+        core_id: usize,
+        instruction_count: u64,
+        predicate: F,
+    ) -> Event {
+        let event = self.events.as_mut().unwrap()[core_id]
+            .pop_front()
+            .unwrap_or_else(|| panic!("replay log of core {core_id} exhausted"));
+
+        if event.instruction_count != instruction_count || !predicate(&event.event) {
+            panic!(
+                "replay diverged on core {core_id} at instruction {instruction_count}, expected {event}"
+            );
+        }
+
+        self.log(event.clone());
+        event.event
+    }
+}
+
+/// Starts recording or replaying according to the supplied configuration
+pub fn init(config: ReplayConfig) {
+    let events = match config {
+        ReplayConfig::None => return,
+        ReplayConfig::Record => {
+            log::warn!("recording asynchronous events");
+            None
+        }
+        ReplayConfig::Replay(log) => {
+            let mut events = (0..MAX_GUEST_CORES)
+                .map(|_| VecDeque::new())
+                .collect::<Vec<_>>();
+
+            log.lines()
+                .filter(|line| line.starts_with(EVENT_PREFIX))
+                .map(|line| line.parse::<LoggedEvent>().unwrap())
+                .for_each(|event| events[event.core_id].push_back(event));
+
+            log::warn!(
+                "replaying {} asynchronous events",
+                events.iter().map(VecDeque::len).sum::<usize>()
+            );
+
+            Some(events)
+        }
+    };
+
+    REPLAYER.call_once(|| {
+        Mutex::new(Replayer {
+            events,
+            interrupts: [false; MAX_GUEST_CORES],
+            output: String::new(),
+        })
+    });
+}
+
+/// Whether execution is being recorded or replayed
+pub fn is_enabled() -> bool {
+    REPLAYER.get().is_some()
+}
+
+/// Whether an interrupt is pending for the core between blocks, given the
+/// interrupt line observed by the last block
+pub fn interrupt_pending(core_id: usize, instruction_count: u64, observed: bool) -> bool {
+    let Some(replayer) = REPLAYER.get() else {
+        return observed;
+    };
+
+    without_interrupts(|| {
+        let mut replayer = replayer.lock();
+
+        if replayer.events.is_some() {
+            let changed = replayer.events.as_ref().unwrap()[core_id]
+                .front()
+                .is_some_and(|event| {
+                    event.instruction_count == instruction_count
+                        && matches!(event.event, Event::Interrupt(_))
+                });
+
+            if changed {
+                let Event::Interrupt(pending) = replayer.take(core_id, instruction_count, |_| true)
+                else {
+                    unreachable!()
+                };
+                replayer.interrupts[core_id] = pending;
+            }
+        } else if replayer.interrupts[core_id] != observed {
+            replayer.interrupts[core_id] = observed;
+            replayer.log(LoggedEvent {
+                core_id,
+                instruction_count,
+                event: Event::Interrupt(observed),
+            });
+        }
+
+        replayer.interrupts[core_id]
+    })
+}
+
+/// Reads a system register mapped device, the value is logged or replayed if
+/// read by a running core
+pub fn register_read<F: FnOnce() -> u64>(id: u64, read: F) -> u64 {
+    let (Some(replayer), Some((core_id, instruction_count))) = (REPLAYER.get(), current_core())
+    else {
+        return read();
+    };
+
+    let replayed = without_interrupts(|| {
+        let mut replayer = replayer.lock();
+
+        replayer.events.is_some().then(|| {
+            let Event::RegisterRead { value, .. } = replayer.take(
+                core_id,
+                instruction_count,
+                |event| matches!(event, Event::RegisterRead { id: logged, .. } if *logged == id),
+            ) else {
+                unreachable!()
+            };
+            value
+        })
+    });
+
+    // the device is not read at all when replaying as reads may have side effects
+    replayed.unwrap_or_else(|| {
+        let value = read();
+        without_interrupts(|| {
+            replayer.lock().log(LoggedEvent {
+                core_id,
+                instruction_count,
+                event: Event::RegisterRead { id, value },
+            })
+        });
+        value
+    })
+}
+
+/// Reads a memory mapped device at the supplied guest physical address into
+/// `buf`, the value is logged or replayed if read by a running core
+pub fn device_read<F: FnOnce(&mut [u8])>(address: u64, buf: &mut [u8], read: F) {
+    let (Some(replayer), Some((core_id, instruction_count))) = (REPLAYER.get(), current_core())
+    else {
+        return read(buf);
+    };
+
+    let replayed = without_interrupts(|| {
+        let mut replayer = replayer.lock();
+
+        replayer.events.is_some().then(|| {
+            let Event::DeviceRead { bytes, .. } =
+                replayer.take(core_id, instruction_count, |event| {
+                    matches!(
+                        event,
+                        Event::DeviceRead { address: logged, bytes }
+                            if *logged == address && bytes.len() == buf.len()
+                    )
+                })
+            else {
+                unreachable!()
+            };
+            bytes
+        })
+    });
+
+    match replayed {
+        Some(bytes) => buf.copy_from_slice(&bytes),
+        None => {
+            read(buf);
+
+            without_interrupts(|| {
+                replayer.lock().log(LoggedEvent {
+                    core_id,
+                    instruction_count,
+                    event: Event::DeviceRead {
+                        address,
+                        bytes: buf.to_vec(),
+                    },
+                })
+            });
+        }
+    }
+}
+
+/// Writes logged and replayed events to the supplied writer, the hyperport
+pub fn flush<W: Write>(writer: &mut W) {
+    let Some(replayer) = REPLAYER.get() else {
+        return;
+    };
+
+    let output = without_interrupts(|| core::mem::take(&mut replayer.lock().output));
+    if !output.is_empty() {
+        writer.write_str(&output).unwrap();
+    }
+}
+
+/// Bytes written as pairs of hex digits, in address order
+fn parse_bytes(s: &str) -> Result<Vec<u8>, ParseError> {
+    if !s.is_ascii() || !s.len().is_multiple_of(2) {
+        return Err(ParseError::Bytes(s.into()));
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&s[i..i + 2], 16)?))
+        .collect()
+}
+
+/// ID and instruction count of the core running in the current execution
+/// context, if any
+fn current_core() -> Option<(usize, u64)> {
+    let core = unsafe { GuestExecutionContext::current().current_core.as_ref() }?;
+    Some((core.core_id(), core.instruction_count()))
+}
+
+#[ktest]
+fn event_line_roundtrip() {
+    [
+        Event::Interrupt(true),
+        Event::RegisterRead {
+            id: 0x1be040,
+            value: 0x1234_5678,
+        },
+        Event::DeviceRead {
+            address: 0x800_0000,
+            bytes: alloc::vec![0xef, 0xbe, 0xad, 0xde],
+        },
+        Event::DeviceRead {
+            address: 0x900_0010,
+            bytes: (0..16).collect(),
+        },
+    ]
+    .into_iter()
+    .map(|event| LoggedEvent {
+        core_id: 1,
+        instruction_count: 52590,
+        event,
+    })
+    .for_each(|event| {
+        let line = alloc::format!("{event}");
+        assert_eq!(line.parse::<LoggedEvent>().unwrap(), event);
+    });
+
+    assert!("skip 4".parse::<LoggedEvent>().is_err());
+    assert!("replay 0 1 mmio 0x1000 abc".parse::<LoggedEvent>().is_err());
+}
@@ -0,0 +1,228 @@
+//! Self-modifying code detection
+//!
+//! Guest physical pages that blocks have been translated from are
+//! write-protected, both in the guest physical mapping and in every guest
+//! virtual alias of them in the lower half of each host core's page tables,
+//! and flushed from the TLBs of every host core. A write to such a page faults,
+//! the page is made writable again, and every core discards the translations
+//! that came from it before executing its next block.
+
+use {
+    crate::{
+        guest::{GuestExecutionContext, MAX_GUEST_CORES},
+        host::arch::x86::{
+            memory::{GUEST_PHYSICAL_START, VirtualMemoryArea},
+            smp,
+        },
+    },
+    alloc::vec::Vec,
+    common::hashmap::{HashMap, HashSet},
+    spin::{Lazy, Mutex},
+    x86_64::{
+        PhysAddr, VirtAddr,
+        instructions::interrupts::without_interrupts,
+        structures::paging::{Page, PageTableFlags, Size4KiB, Translate, mapper::TranslateResult},
+    },
+};
+
+const PAGE_MASK: u64 = !0xfff;
+
+static STATE: Lazy<Mutex<State>> = Lazy::new(|| Mutex::new(State::default()));
+
+#[derive(Default)]
+struct State {
+    /// Guest physical pages containing translated code
+    code_pages: HashSet<u64>,
+    /// Lower half pages that have been mapped to each guest physical page, along
+    /// with the root of the host page tables they were mapped in and whether
+    /// the guest permits writes through them, entries may be stale and are
+    /// checked before use
+    aliases: HashMap<u64, Vec<(PhysAddr, Page<Size4KiB>, bool)>>,
+}
+
+/// Write-protects the guest physical page containing `guest_physical`, to be
+/// called before translating code from it
+pub fn protect(guest_physical: u64) {
+    let page = guest_physical & PAGE_MASK;
+
+    // the lock is also taken in the page fault handler, so the holder must not be
+    // preempted
+    let protected = without_interrupts(|| {
+        let mut state = STATE.lock();
+
+        if !state.code_pages.insert(page) {
+            return false;
+        }
+
+        log::debug!("write-protecting code page {page:#x}");
+
+        state.set_writable(page, false);
+        true
+    });
+
+    // the lock must be released first, as other cores may wait for it with
+    // interrupts disabled
+    if protected {
+        smp::flush_remote_tlbs();
+    }
+}
+
+/// Maps `alias` to `guest_physical` in the lower half with `map`, passing the
+/// flags the mapping should use, invalidating translated code if `write`
+/// targets a protected page
+///
+/// The mapping is made while holding the lock, so that another core cannot
+/// protect the page between its flags being chosen and it being mapped.
+/// Mappings the guest does not permit writes through are never made writable.
+pub fn map_guest_virtual<F: FnOnce(PageTableFlags)>(
+    guest_physical: u64,
+    alias: VirtAddr,
+    write: bool,
+    guest_writable: bool,
+    map: F,
+) {
+    let page = guest_physical & PAGE_MASK;
+    let alias = Page::<Size4KiB>::containing_address(alias);
+    let pml4_base = VirtualMemoryArea::current().pml4_base;
+
+    let was_code_page = without_interrupts(|| {
+        let mut state = STATE.lock();
+
+        let aliases = state.aliases.entry(page).or_default();
+        match aliases
+            .iter_mut()
+            .find(|(table, existing, _)| *table == pml4_base && *existing == alias)
+        {
+            Some((_, _, writable)) => *writable = guest_writable,
+            None => aliases.push((pml4_base, alias, guest_writable)),
+        }
+
+        let (is_code_page, was_code_page) = if write && state.code_pages.remove(&page) {
+            state.set_writable(page, true);
+            (false, true)
+        } else {
+            (state.code_pages.contains(&page), false)
+        };
+
+        map(if is_code_page || !guest_writable {
+            PageTableFlags::PRESENT
+        } else {
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+        });
+
+        was_code_page
+    });
+
+    if was_code_page {
+        notify_cores(page);
+    }
+}
+
+/// Returns the flags a new backing page for `guest_physical` should be mapped
+/// with in the guest physical mapping
+pub fn guest_physical_flags(guest_physical: u64) -> PageTableFlags {
+    if is_protected(guest_physical) {
+        PageTableFlags::PRESENT
+    } else {
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+    }
+}
+
+/// Whether the guest physical page containing `guest_physical` holds
+/// translated code, and so must not be written to without being unprotected
+pub fn is_protected(guest_physical: u64) -> bool {
+    without_interrupts(|| {
+        STATE
+            .lock()
+            .code_pages
+            .contains(&(guest_physical & PAGE_MASK))
+    })
+}
+
+/// Handles a write protection fault in the guest physical mapping, returning
+/// false if `guest_physical` is not a protected code page
+///
+/// The fault may also come from a TLB entry cached while the page was
+/// protected, after another core has made it writable again, in which case it
+/// is retried.
+pub fn handle_guest_physical_write(guest_physical: u64) -> bool {
+    let page = guest_physical & PAGE_MASK;
+
+    let was_code_page = without_interrupts(|| {
+        let mut state = STATE.lock();
+
+        if state.code_pages.remove(&page) {
+            state.set_writable(page, true);
+            true
+        } else {
+            false
+        }
+    });
+
+    if was_code_page {
+        notify_cores(page);
+        return true;
+    }
+
+    // the faulting TLB entry has been dropped, so if the page is now writable
+    // the write can be retried
+    matches!(
+        VirtualMemoryArea::current()
+            .opt
+            .translate(GUEST_PHYSICAL_START + page),
+        TranslateResult::Mapped { flags, .. } if flags.contains(PageTableFlags::WRITABLE)
+    )
+}
+
+impl State {
+    /// Updates the guest physical mapping of `page` and all of its live lower
+    /// half aliases
+    fn set_writable(&mut self, page: u64, writable: bool) {
+        let flags = if writable {
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+        } else {
+            PageTableFlags::PRESENT
+        };
+
+        let mut vma = VirtualMemoryArea::current();
+
+        let guest_physical_page = Page::<Size4KiB>::containing_address(GUEST_PHYSICAL_START + page);
+
+        // not yet backed, will be mapped with the right flags when it is
+        let Some(backing_frame) = vma.opt.translate_addr(guest_physical_page.start_address())
+        else {
+            return;
+        };
+
+        vma.update_page_flags(guest_physical_page, flags);
+
+        if let Some(aliases) = self.aliases.get_mut(&page) {
+            // drop aliases that have since been unmapped or remapped elsewhere
+            aliases.retain(|(table, alias, _)| {
+                VirtualMemoryArea::from_pml4(*table)
+                    .opt
+                    .translate_addr(alias.start_address())
+                    == Some(backing_frame)
+            });
+
+            for (table, alias, guest_writable) in aliases.iter() {
+                let flags = if *guest_writable {
+                    flags
+                } else {
+                    PageTableFlags::PRESENT
+                };
+                VirtualMemoryArea::from_pml4(*table).update_page_flags(*alias, flags);
+            }
+        }
+    }
+}
+
+/// Tells every running core to discard translations from `page` before it
+/// executes its next block
+fn notify_cores(page: u64) {
+    log::debug!("code page {page:#x} written, invalidating translations");
+
+    (0..MAX_GUEST_CORES)
+        .filter_map(GuestExecutionContext::for_core)
+        .for_each(|ctx| ctx.current_core().invalidate_code_page(page));
+}
@@ -0,0 +1,384 @@
+//! Software TLB consulted inline by translated guest memory accesses
+//!
+//! With [`MemoryAccessKind::SoftwareTlb`], each guest load and store looks up
+//! the page of its guest virtual address in a direct-mapped table held in the
+//! execution context. On a hit it adds the entry's addend to reach the backing
+//! page in the guest physical mapping. Misses, unaligned accesses and device
+//! accesses call [`read_miss`] or [`write_miss`] instead. These walk the guest
+//! page tables, take data aborts and access devices without going through the
+//! host page fault handler.
+//!
+//! Filled entries are recorded in the model of the guest TLB in [`tlb`], so
+//! guest TLB maintenance drops them along with the host mappings, emptying them
+//! from the table of every core affected before the maintenance completes.
+//! Code pages are not made writable through the table. An entry filled before
+//! code was translated from its page is still caught by the write protection of
+//! the guest physical mapping.
+
+use {
+    crate::{
+        guest::{GuestExecutionContext, memory::AddressSpaceRegionKind},
+        host::{
+            arch::x86::{
+                aarch64_mmu::{Access, Fault, current_asid, data_abort, guest_translate},
+                irq::{allocate_guest_ram, exit_with_message},
+                memory::{VirtualMemoryArea, guest_physical_to_host_virt},
+            },
+            dbt::{
+                replay, smc,
+                tlb::{self, Invalidation},
+            },
+            objects::device::MemoryMappedDevice,
+        },
+    },
+    alloc::sync::Arc,
+    core::mem::offset_of,
+    proc_macro_lib::ktest,
+    serde::{Deserialize, Serialize},
+};
+
+/// Number of entries in the table, a power of two
+pub const ENTRY_COUNT: usize = 256;
+/// log2 of the size of an [`Entry`], by which translated code scales indices
+pub const ENTRY_SHIFT: u32 = 5;
+pub const PAGE_SHIFT: u32 = 12;
+const PAGE_SIZE: u64 = 1 << PAGE_SHIFT;
+const PAGE_MASK: u64 = !(PAGE_SIZE - 1);
+
+/// How translated code accesses guest memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum MemoryAccessKind {
+    /// Masked host accesses into the lower half, with first touches, device
+    /// accesses and MMU misses handled by the host page fault handler
+    Direct,
+    /// Inline lookups in the software TLB, with misses handled by a call to
+    /// [`read_miss`] or [`write_miss`]
+    SoftwareTlb,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Entry {
+    /// Guest virtual page that may be read through the entry
+    read_tag: u64,
+    /// Guest virtual page that may be written through the entry
+    write_tag: u64,
+    /// Added to a guest virtual address in the page to give its host address
+    addend: u64,
+    _padding: u64,
+}
+
+const _: () = assert!(size_of::<Entry>() == 1 << ENTRY_SHIFT);
+
+/// Direct-mapped table of guest virtual pages, indexed by the low bits of the
+/// page number
+#[repr(C)]
+pub struct Table {
+    entries: [Entry; ENTRY_COUNT],
+    /// Data of accesses made by the slow path rather than through a backing
+    /// page, which translated code then accesses in their place
+    bounce: [u8; 16],
+}
+
+impl Table {
+    pub fn new() -> Self {
+        let mut table = Self {
+            entries: [Entry {
+                read_tag: 0,
+                write_tag: 0,
+                addend: 0,
+                _padding: 0,
+            }; ENTRY_COUNT],
+            bounce: [0; 16],
+        };
+
+        table.flush();
+        table
+    }
+
+    /// Table of the current execution context
+    pub fn current() -> &'static mut Self {
+        unsafe { &mut *GuestExecutionContext::current().software_tlb.get() }
+    }
+
+    /// Makes the guest virtual page containing `guest_virtual` accessible
+    /// through the host page containing `host`, for writes as well as reads if
+    /// `writable`
+    pub fn insert(&mut self, guest_virtual: u64, host: u64, writable: bool) {
+        let page = guest_virtual & PAGE_MASK;
+        let index = index(page);
+
+        self.entries[index] = Entry {
+            read_tag: page,
+            write_tag: if writable { page } else { empty_tag(index) },
+            addend: (host & PAGE_MASK).wrapping_sub(page),
+            _padding: 0,
+        };
+    }
+
+    /// Empties every entry
+    pub fn flush(&mut self) {
+        (0..ENTRY_COUNT).for_each(|index| self.empty(index));
+    }
+
+    /// Empties the entries dropped from the guest TLB by the invalidation
+    pub fn apply(&mut self, invalidation: &Invalidation) {
+        if invalidation.is_all() {
+            self.flush();
+        } else {
+            self.invalidate(|page| invalidation.contains(page));
+        }
+    }
+
+    /// Empties the entries of guest virtual pages matching the predicate
+    pub fn invalidate<F: Fn(u64) -> bool>(&mut self, predicate: F) {
+        for index in 0..ENTRY_COUNT {
+            let page = self.entries[index].read_tag;
+
+            if page != empty_tag(index) && predicate(page) {
+                self.empty(index);
+            }
+        }
+    }
+
+    /// Empties an entry, keeping its addend as translated code on another core
+    /// may have matched its tag already
+    fn empty(&mut self, index: usize) {
+        let entry = &mut self.entries[index];
+        entry.read_tag = empty_tag(index);
+        entry.write_tag = empty_tag(index);
+    }
+}
+
+/// Index of the entry of the guest virtual `page`
+fn index(page: u64) -> usize {
+    (page >> PAGE_SHIFT) as usize & (ENTRY_COUNT - 1)
+}
+
+/// Tag of the entry at `index` when empty, a page that is never looked up in it
+fn empty_tag(index: usize) -> u64 {
+    ((index ^ 1) as u64) << PAGE_SHIFT
+}
+
+/// Bits of the guest virtual address of a `size` byte access that must match
+/// a tag, so that accesses which are not naturally aligned always miss
+pub fn tag_mask(size: u64) -> u64 {
+    PAGE_MASK | (size - 1)
+}
+
+/// Displacement from the FS base of the tag of the first entry compared
+/// against by reads or writes
+pub fn tag_displacement(write: bool) -> i32 {
+    let tag = if write {
+        offset_of!(Entry, write_tag)
+    } else {
+        offset_of!(Entry, read_tag)
+    };
+
+    i32::try_from(
+        offset_of!(GuestExecutionContext, software_tlb) + offset_of!(Table, entries) + tag,
+    )
+    .unwrap()
+}
+
+/// Displacement from the FS base of the addend of the first entry
+pub fn addend_displacement() -> i32 {
+    i32::try_from(
+        offset_of!(GuestExecutionContext, software_tlb)
+            + offset_of!(Table, entries)
+            + offset_of!(Entry, addend),
+    )
+    .unwrap()
+}
+
+/// Displacement from the FS base of the bounce buffer
+pub fn bounce_displacement() -> i32 {
+    i32::try_from(offset_of!(GuestExecutionContext, software_tlb) + offset_of!(Table, bounce))
+        .unwrap()
+}
+
+/// Called by translated code when a read of `size` bytes from `guest_virtual`
+/// misses, returning the host address to read from instead
+pub extern "C" fn read_miss(guest_virtual: u64, size: u64) -> u64 {
+    miss(guest_virtual, size, false)
+}
+
+/// Called by translated code when a write of `size` bytes to `guest_virtual`
+/// misses, once the value has been stored to the bounce buffer, returning the
+/// host address to write to instead
+pub extern "C" fn write_miss(guest_virtual: u64, size: u64) -> u64 {
+    miss(guest_virtual, size, true)
+}
+
+fn miss(guest_virtual: u64, size: u64, write: bool) -> u64 {
+    let exec_ctx = GuestExecutionContext::current();
+    let table = unsafe { &mut *exec_ctx.software_tlb.get() };
+
+    let first = resolve(exec_ctx, guest_virtual, write);
+
+    // accesses within a single RAM page go through its backing page, filling the
+    // entry so that later accesses to the page hit
+    match first {
+        Target::Ram { host, writable } if (guest_virtual & !PAGE_MASK) + size <= PAGE_SIZE => {
+            table.insert(guest_virtual, host, writable);
+            return host;
+        }
+        _ => (),
+    }
+
+    // otherwise each page of the access is made here, through the bounce buffer
+    let mut next = Some(first);
+    let mut done = 0;
+
+    while done < size {
+        let address = guest_virtual.wrapping_add(done);
+        let len = (size - done).min(PAGE_SIZE - (address & !PAGE_MASK));
+        let target = next
+            .take()
+            .unwrap_or_else(|| resolve(exec_ctx, address, write));
+        let bytes = &mut table.bounce[done as usize..(done + len) as usize];
+
+        match target {
+            Target::Ram { host, .. } => {
+                let host = host as *mut u8;
+                unsafe {
+                    if write {
+                        host.copy_from_nonoverlapping(bytes.as_ptr(), bytes.len());
+                    } else {
+                        host.copy_to_nonoverlapping(bytes.as_mut_ptr(), bytes.len());
+                    }
+                }
+            }
+            Target::Device {
+                device,
+                offset,
+                guest_physical,
+            } => {
+                if bytes.len() > 8 {
+                    exit_with_message!(
+                        "{}-byte device access @ {guest_physical:#x} is not supported",
+                        bytes.len()
+                    )
+                }
+
+                if write {
+                    device.write(offset, bytes);
+                } else {
+                    replay::device_read(guest_physical, bytes, |bytes| device.read(offset, bytes));
+                }
+            }
+        }
+
+        done += len;
+    }
+
+    table.bounce.as_ptr() as u64
+}
+
+/// Where a guest virtual address is backed
+enum Target {
+    /// Guest RAM at host virtual address `host` in the guest physical mapping,
+    /// which may also be written through the table if `writable`
+    Ram { host: u64, writable: bool },
+    /// Registers of a device at `offset` within it
+    Device {
+        device: Arc<dyn MemoryMappedDevice>,
+        offset: u64,
+        guest_physical: u64,
+    },
+}
+
+/// Translates `guest_virtual` for a read or write, taking a data abort if the
+/// guest does not permit the access
+fn resolve(exec_ctx: &GuestExecutionContext, guest_virtual: u64, write: bool) -> Target {
+    // no core is running in the boot execution context (tests, image loading), so
+    // treat accesses as untranslated
+    let device = unsafe { exec_ctx.current_core.as_ref() };
+
+    let (guest_physical, guest_writable) = match device {
+        Some(device) => {
+            let access = if write { Access::Write } else { Access::Read };
+            let mapping = guest_translate(device, guest_virtual, access)
+                .unwrap_or_else(|fault| data_abort(device, fault, guest_virtual, write));
+
+            tlb::insert(
+                guest_virtual,
+                &mapping,
+                device.core_id(),
+                current_asid(device),
+                None,
+            );
+
+            (mapping.physical_address, mapping.writable)
+        }
+        None => (guest_virtual, true),
+    };
+
+    let address_space = unsafe { &*exec_ctx.current_address_space };
+    let region = match (address_space.find_region(guest_physical), device) {
+        (Some(region), _) => region,
+        // an external abort on real hardware
+        (None, Some(device)) => data_abort(device, Fault::External, guest_virtual, write),
+        (None, None) => exit_with_message!("guest access @ {guest_physical:#x}: no region"),
+    };
+
+    match region.kind() {
+        AddressSpaceRegionKind::Ram => {
+            let host = guest_physical_to_host_virt(guest_physical);
+
+            if VirtualMemoryArea::current()
+                .translate_address(host.align_down(PAGE_SIZE))
+                .is_none()
+            {
+                allocate_guest_ram(guest_physical);
+            }
+
+            // writing to a code page invalidates the translations from it
+            if write {
+                smc::handle_guest_physical_write(guest_physical);
+            }
+
+            Target::Ram {
+                host: host.as_u64(),
+                writable: guest_writable && !smc::is_protected(guest_physical),
+            }
+        }
+        AddressSpaceRegionKind::IO(device) => Target::Device {
+            device: device.clone(),
+            offset: guest_physical - region.base(),
+            guest_physical,
+        },
+    }
+}
+
+#[ktest]
+fn software_tlb_invalidation() {
+    let mut table = Table::new();
+
+    table.insert(0xffff_8000_1234_5678, 0xffff_9000_0000_1000, true);
+    table.insert(0x4000_6000, 0xffff_9000_0000_2000, false);
+
+    let entry = table.entries[index(0xffff_8000_1234_5000)];
+    assert_eq!(entry.read_tag, 0xffff_8000_1234_5000);
+    assert_eq!(entry.write_tag, 0xffff_8000_1234_5000);
+    assert_eq!(
+        0xffff_8000_1234_5678u64.wrapping_add(entry.addend),
+        0xffff_9000_0000_1678
+    );
+
+    // read-only entries never match a write
+    let entry = table.entries[index(0x4000_6000)];
+    assert_eq!(entry.read_tag, 0x4000_6000);
+    assert_ne!(entry.write_tag & PAGE_MASK, 0x4000_6000);
+
+    table.invalidate(|page| page == 0x4000_6000);
+    assert_ne!(table.entries[index(0x4000_6000)].read_tag, 0x4000_6000);
+    assert_eq!(
+        table.entries[index(0xffff_8000_1234_5000)].read_tag,
+        0xffff_8000_1234_5000
+    );
+
+    // empty entries match no page that is looked up in them
+    table.flush();
+    (0..ENTRY_COUNT).for_each(|i| assert_ne!(index(table.entries[i].read_tag), i));
+}
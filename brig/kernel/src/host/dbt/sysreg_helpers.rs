@@ -1,5 +1,5 @@
 use {
-    crate::host::objects::device::RegisterMappedDevice,
+    crate::host::{dbt::replay, objects::device::RegisterMappedDevice},
     alloc::sync::Arc,
     common::hashmap::HashMap,
     spin::{Lazy, Mutex},
@@ -29,13 +29,15 @@ pub fn sys_reg_read(reg: u64) -> u64 {
     let guard = SYSREG_HANDLERS.lock();
     let handler = guard.get(&reg).unwrap();
 
-    let mut result = [0u8; 8];
+    replay::register_read(reg, || {
+        let mut result = [0u8; 8];
 
-    match handler {
-        Handler::Device(dev) => dev.read(reg, &mut result),
-    }
+        match handler {
+            Handler::Device(dev) => dev.read(reg, &mut result),
+        }
 
-    u64::from_le_bytes(result)
+        u64::from_le_bytes(result)
+    })
 }
 
 pub fn sys_reg_write(reg: u64, value: u64, len: u8) {
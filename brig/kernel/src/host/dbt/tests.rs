@@ -4,18 +4,24 @@ use {
         host::{
             dbt::{
                 Translation, bit_insert,
+                chain::{CHAIN_SLOT_COUNT, ChainLinks},
                 emitter::{Emitter, Type},
-                interpret::{Value, interpret},
+                interpret::{MemoryOverlay, Value, interpret, interpret_with_overlay},
                 models::{self},
+                persist::{PersistedTranslation, TranslationKey},
                 register_file::RegisterFile,
+                softmmu::{self, MemoryAccessKind},
                 sysreg_helpers,
+                trampoline::ExecutionResult,
                 translate::{translate, translate_instruction},
                 x86::{
-                    X86TranslationContext,
+                    AssembledCode, X86TranslationContext,
                     emitter::{
                         BinaryOperationKind, CastOperationKind, NodeKind, ShiftOperationKind,
                         UnaryOperationKind, X86Emitter, X86Node,
                     },
+                    encoder::{Opcode, OperandKind},
+                    register_allocator::RegisterAllocatorKind,
                 },
             },
             memory::bump::{BumpAllocator, BumpAllocatorRef},
@@ -24,9 +30,9 @@ use {
         },
         timer::Measurement,
     },
-    alloc::{alloc::Global, boxed::Box, collections::BTreeMap},
+    alloc::{alloc::Global, boxed::Box, collections::BTreeMap, vec::Vec},
     common::{hashmap::HashMap, mask::mask},
-    core::panic,
+    core::{panic, sync::atomic::Ordering},
     proc_macro_lib::ktest,
 };
 
@@ -374,6 +380,138 @@ fn decodea64_addsub_interpret() {
     // of SEE/cacheable registers work
 }
 
+#[ktest]
+fn lockstep_shadow_interpret() {
+    let model = models::get("aarch64").unwrap();
+
+    let register_file = RegisterFile::init(&*model);
+    register_file.write("SEE", -1i64);
+    register_file.write::<u64>("R0", 0x1000);
+    register_file.write::<u64>("R1", 0xdead_beef);
+    register_file.write::<u64>("R2", 10);
+
+    let shadow = RegisterFile::init(&*model);
+    shadow.copy_from(&register_file);
+    assert!(register_file.differences(&shadow).is_empty());
+
+    // str x1, [x0], held in the overlay rather than written to memory
+    let mut overlay = MemoryOverlay::default();
+    interpret_with_overlay(
+        &*model,
+        "__DecodeA64",
+        &[Value::UnsignedInteger {
+            value: 0xf9000001,
+            width: 32,
+        }],
+        &shadow,
+        &mut ExecutionResult::new(),
+        &mut overlay,
+    );
+    assert_eq!(
+        overlay.bytes().collect::<Vec<_>>(),
+        (0x1000..)
+            .zip(0xdead_beefu64.to_le_bytes())
+            .collect::<Vec<_>>()
+    );
+
+    // add x0, x1, x2
+    interpret_with_overlay(
+        &*model,
+        "__DecodeA64",
+        &[Value::UnsignedInteger {
+            value: 0x8b020020,
+            width: 32,
+        }],
+        &shadow,
+        &mut ExecutionResult::new(),
+        &mut overlay,
+    );
+    let differences = register_file.differences(&shadow);
+    let (_, ours, theirs) = differences
+        .iter()
+        .find(|(name, _, _)| name.as_ref() == "R0")
+        .unwrap();
+    assert_eq!(*ours, 0x1000u64.to_le_bytes());
+    assert_eq!(*theirs, 0xdead_bef9u64.to_le_bytes());
+}
+
+#[ktest]
+fn lockstep_shadow_unreadable() {
+    let model = models::get("aarch64").unwrap();
+
+    let register_file = RegisterFile::init(&*model);
+    register_file.write("SEE", -1i64);
+    register_file.write::<u64>("R0", 0x1000);
+    register_file.write::<u64>("R3", 0xdead_beef);
+
+    // ldr x3, [x0], from memory standing in for a device
+    let mut overlay = MemoryOverlay::new(|_| false);
+    interpret_with_overlay(
+        &*model,
+        "__DecodeA64",
+        &[Value::UnsignedInteger {
+            value: 0xf9400003,
+            width: 32,
+        }],
+        &register_file,
+        &mut ExecutionResult::new(),
+        &mut overlay,
+    );
+
+    assert!(overlay.read_unreadable());
+    assert_eq!(0, register_file.read::<u64>("R3"));
+}
+
+#[ktest]
+fn interpret_128_bit_load_store() {
+    let model = models::get("aarch64").unwrap();
+
+    let register_file = RegisterFile::init(&*model);
+    register_file.write("SEE", -1i64);
+
+    let source = Box::new(0x0f0e_0d0c_0b0a_0908_0706_0504_0302_0100u128);
+    let destination = Box::new(0u128);
+    register_file.write::<u64>("R0", &*source as *const u128 as u64);
+    register_file.write::<u64>("R1", &*destination as *const u128 as u64);
+
+    // ldr q0, [x0]
+    // str q0, [x1]
+    for opcode in [0x3dc00000, 0x3d800020] {
+        interpret(
+            &*model,
+            "__DecodeA64",
+            &[Value::UnsignedInteger {
+                value: opcode,
+                width: 32,
+            }],
+            &register_file,
+        );
+    }
+
+    assert_eq!(*destination, *source);
+
+    // str q0, [x1], held in the overlay
+    let mut overlay = MemoryOverlay::default();
+    interpret_with_overlay(
+        &*model,
+        "__DecodeA64",
+        &[Value::UnsignedInteger {
+            value: 0x3d800020,
+            width: 32,
+        }],
+        &register_file,
+        &mut ExecutionResult::new(),
+        &mut overlay,
+    );
+
+    assert_eq!(
+        overlay.bytes().collect::<Vec<_>>(),
+        (&*destination as *const u128 as usize..)
+            .zip(source.to_le_bytes())
+            .collect::<Vec<_>>()
+    );
+}
+
 #[ktest]
 fn decodea64_mov() {
     let model = models::get("aarch64").unwrap();
@@ -846,6 +984,59 @@ fn mem_load() {
     assert_eq!(register_file.read::<u64>("R0"), VALUE);
 }
 
+///  str x0, [x1]
+///  ldr x2, [x1]
+#[ktest]
+fn mem_software_tlb() {
+    #[repr(align(4096))]
+    struct Page([u64; 512]);
+
+    let model = models::get("aarch64").unwrap();
+
+    let register_file = RegisterFile::init(&*model);
+
+    let mut ctx = X86TranslationContext::new(&model, false, register_file.global_register_offset());
+    ctx.set_memory_access(MemoryAccessKind::SoftwareTlb);
+    let mut emitter = X86Emitter::new(&mut ctx);
+
+    for opcode in [0xf9000020, 0xf9400022] {
+        let opcode = emitter.constant(opcode, Type::Unsigned(32));
+        translate(
+            Global,
+            &*model,
+            "__DecodeA64",
+            &[opcode],
+            &mut emitter,
+            &register_file,
+        )
+        .unwrap();
+    }
+
+    emitter.leave();
+
+    let num_regs = emitter.next_vreg();
+    let translation = ctx.compile(num_regs);
+
+    const VALUE: u64 = 0xdead_c0de_0000_0000;
+    const GUEST_VIRTUAL: u64 = 0x4000_1008;
+    let mut page = Box::new(Page([0; 512]));
+
+    // hits the entry, so never reaches the slow path
+    softmmu::Table::current().insert(GUEST_VIRTUAL, page.0.as_mut_ptr() as u64, true);
+
+    register_file.write("SEE", -1i64);
+    register_file.write::<u64>("R0", VALUE);
+    register_file.write::<u64>("R1", GUEST_VIRTUAL);
+    register_file.write::<u64>("R2", 0xdeadcafe); // will be overwritten
+
+    translation.execute(&register_file);
+
+    softmmu::Table::current().flush();
+
+    assert_eq!(page.0[1], VALUE);
+    assert_eq!(register_file.read::<u64>("R2"), VALUE);
+}
+
 /// failing due to cached SEE
 #[ktest]
 fn fibonacci_block() {
@@ -1161,6 +1352,198 @@ fn decodea64_cmp_harness(x: u64, y: u64) -> u8 {
         | register_file.read::<u8>("PSTATE_V")
 }
 
+#[ktest]
+fn decodea64_cmp_branch() {
+    // b.<cond> #8
+    let eq = 0x54000040;
+    let ne = 0x54000041;
+    let mi = 0x54000044;
+    let ge = 0x5400004a;
+    let lt = 0x5400004b;
+    let gt = 0x5400004c;
+    let le = 0x5400004d;
+
+    for (x, y, branch, taken) in [
+        (5, 5, eq, true),
+        (5, 5, ne, false),
+        (5, 5, ge, true),
+        (5, 5, gt, false),
+        (5, 5, le, true),
+        (5, 10, eq, false),
+        (5, 10, mi, true),
+        (5, 10, lt, true),
+        (10, 5, gt, true),
+        (10, 5, le, false),
+        (0x8000000000000000, 1, lt, true),
+        (0x8000000000000000, 1, mi, false),
+    ] {
+        assert_eq!(
+            decodea64_cmp_branch_harness(x, y, branch),
+            (taken, decodea64_cmp_harness(x, y)),
+            "{x:#x} {y:#x} {branch:#x}"
+        );
+    }
+}
+
+/// Compares `x` and `y` then executes the conditional `branch`, returning
+/// whether it was taken and the flags
+fn decodea64_cmp_branch_harness(x: u64, y: u64, branch: u32) -> (bool, u8) {
+    let model = models::get("aarch64").unwrap();
+
+    let register_file = RegisterFile::init(&*model);
+
+    let mut ctx = X86TranslationContext::new(&model, false, register_file.global_register_offset());
+    let mut emitter = X86Emitter::new(&mut ctx);
+
+    register_file.write::<u64>("R0", x);
+    register_file.write::<u64>("R1", y);
+
+    register_file.write("SEE", -1i64);
+
+    // cmp    x0, x1
+    // b.<cond> #8
+    for opcode in [0xeb01001f, branch] {
+        let opcode = emitter.constant(u64::from(opcode), Type::Unsigned(32));
+        translate(
+            Global,
+            &*model,
+            "__DecodeA64",
+            &[opcode],
+            &mut emitter,
+            &register_file,
+        )
+        .unwrap();
+    }
+
+    emitter.leave();
+
+    let num_regs = emitter.next_vreg();
+    let translation = ctx.compile(num_regs);
+    translation.execute(&register_file);
+
+    (
+        register_file.read::<bool>("__BranchTaken"),
+        register_file.read::<u8>("PSTATE_N") << 3
+            | register_file.read::<u8>("PSTATE_Z") << 2
+            | register_file.read::<u8>("PSTATE_C") << 1
+            | register_file.read::<u8>("PSTATE_V"),
+    )
+}
+
+#[ktest]
+fn register_cache_write_back() {
+    let model = models::get("aarch64").unwrap();
+
+    let register_file = RegisterFile::init(&*model);
+
+    let mut ctx = X86TranslationContext::new(&model, false, register_file.global_register_offset());
+    let mut emitter = X86Emitter::new(&mut ctx);
+
+    register_file.write("SEE", -1i64);
+
+    // more registers are written than can be cached at once
+    let program = [
+        0xd2800020, // mov     x0, #0x1
+        0xd2800041, // mov     x1, #0x2
+        0xd2800062, // mov     x2, #0x3
+        0xd2800083, // mov     x3, #0x4
+        0xd28000a4, // mov     x4, #0x5
+        0xd28000c5, // mov     x5, #0x6
+        0x8b050006, // add     x6, x0, x5
+        0x8b000000, // add     x0, x0, x0
+    ];
+
+    for opcode in program {
+        let opcode = emitter.constant(opcode, Type::Unsigned(32));
+        translate(
+            Global,
+            &*model,
+            "__DecodeA64",
+            &[opcode],
+            &mut emitter,
+            &register_file,
+        )
+        .unwrap();
+    }
+
+    emitter.leave();
+
+    let num_regs = emitter.next_vreg();
+    let translation = ctx.compile(num_regs);
+    translation.execute(&register_file);
+
+    assert_eq!(register_file.read::<u64>("R0"), 2);
+    for n in 1..6 {
+        assert_eq!(register_file.read::<u64>(alloc::format!("R{n}")), n + 1);
+    }
+    assert_eq!(register_file.read::<u64>("R6"), 7);
+}
+
+#[ktest]
+fn register_cache_loads_once() {
+    let model = models::get("aarch64").unwrap();
+
+    let register_file = RegisterFile::init(&*model);
+
+    let mut ctx = X86TranslationContext::new(&model, false, register_file.global_register_offset());
+    let mut emitter = X86Emitter::new(&mut ctx);
+    let block = emitter.get_current_block();
+
+    register_file.write("SEE", -1i64);
+    register_file.write("R0", 5u64);
+
+    let program = [
+        0x8b000001, // add     x1, x0, x0
+        0x8b010002, // add     x2, x0, x1
+        0x8b020023, // add     x3, x1, x2
+    ];
+
+    for opcode in program {
+        let opcode = emitter.constant(opcode, Type::Unsigned(32));
+        translate(
+            Global,
+            &*model,
+            "__DecodeA64",
+            &[opcode],
+            &mut emitter,
+            &register_file,
+        )
+        .unwrap();
+    }
+
+    emitter.leave();
+    let num_regs = emitter.next_vreg();
+
+    // reads of X0 reuse the host register it was first loaded into, and reads of
+    // X1 the value written to it
+    let loads = |offset: u64| {
+        block
+            .get(ctx.arena())
+            .instructions()
+            .iter()
+            .filter(|instruction| {
+                matches!(
+                    instruction.0,
+                    Opcode::MOV(src, _) if matches!(
+                        src.kind(),
+                        OperandKind::Memory { displacement, .. }
+                            if u64::try_from(*displacement) == Ok(offset)
+                    )
+                )
+            })
+            .count()
+    };
+    assert_eq!(loads(model.reg_offset("R0")), 1);
+    assert_eq!(loads(model.reg_offset("R1")), 0);
+
+    let translation = ctx.compile(num_regs);
+    translation.execute(&register_file);
+
+    assert_eq!(register_file.read::<u64>("R1"), 10);
+    assert_eq!(register_file.read::<u64>("R2"), 15);
+    assert_eq!(register_file.read::<u64>("R3"), 25);
+}
+
 #[ktest]
 fn shiftreg() {
     let model = models::get("aarch64").unwrap();
@@ -4439,6 +4822,229 @@ fn leave_with_cache() {
     let _translation = ctx.compile(num_regs);
 }
 
+#[ktest]
+fn chain_slots_link_and_unlink() {
+    let model = models::get("aarch64").unwrap();
+
+    let register_file = RegisterFile::init(&*model);
+
+    let mut ctx = X86TranslationContext::new(&model, false, register_file.global_register_offset());
+    let mut emitter = X86Emitter::new(&mut ctx);
+
+    let aaaa = emitter.constant(0xAAAA, Type::Unsigned(64));
+    emitter.write_register(model.reg_offset("_PC"), aaaa);
+
+    emitter.leave_with_cache(0x1234);
+
+    let num_regs = emitter.next_vreg();
+    let AssembledCode {
+        code, chain_slots, ..
+    } = ctx.assemble(num_regs);
+    assert_eq!(chain_slots.len(), CHAIN_SLOT_COUNT);
+
+    let translation = Translation::new(&code);
+    let slots = chain_slots
+        .into_iter()
+        .map(|slot| slot.relocate(translation.as_ptr()))
+        .collect::<Vec<_>>();
+    assert!(slots.iter().all(|slot| slot.linked_pc().is_none()));
+
+    let mut links = ChainLinks::default();
+    links.link(&slots, 0xAAAA, translation.as_ptr());
+    links.link(&slots, 0xAAAA, translation.as_ptr());
+    assert_eq!(slots[0].linked_pc(), Some(0xAAAA));
+    assert_eq!(slots[1].linked_pc(), None);
+
+    links.unlink_targets(|code| code == translation.as_ptr());
+    assert_eq!(slots[0].linked_pc(), None);
+
+    // slots in discarded code are forgotten without being written to
+    let range = translation.as_ptr()..translation.as_ptr().wrapping_add(translation.len());
+    links.link(&slots, 0xAAAA, translation.as_ptr());
+    links.forget_slots(|code| range.contains(&code));
+    links.unlink_targets(|code| range.contains(&code));
+    assert_eq!(slots[0].linked_pc(), Some(0xAAAA));
+}
+
+#[ktest]
+fn persisted_translation_relocation() {
+    let model = models::get("aarch64").unwrap();
+
+    let register_file = RegisterFile::init(&*model);
+
+    let mut ctx = X86TranslationContext::new(&model, false, register_file.global_register_offset());
+    let mut emitter = X86Emitter::new(&mut ctx);
+
+    emitter.count_execution(0x1111_2222_3333);
+
+    let aaaa = emitter.constant(0xAAAA, Type::Unsigned(64));
+    emitter.write_register(model.reg_offset("_PC"), aaaa);
+
+    emitter.leave_with_cache(0x4444_5555_6666);
+
+    let num_regs = emitter.next_vreg();
+    let assembled = ctx.assemble(num_regs);
+    assert_eq!(assembled.chain_slots.len(), CHAIN_SLOT_COUNT);
+    assert_eq!(assembled.relocations.len(), 2);
+
+    let key = TranslationKey::new(
+        &model,
+        0,
+        &register_file,
+        0xAAAA,
+        RegisterAllocatorKind::LinearScan,
+        MemoryAccessKind::Direct,
+    );
+    // translations made differently are never mistaken for one another
+    assert_ne!(
+        key,
+        TranslationKey::new(
+            &model,
+            0,
+            &register_file,
+            0xAAAA,
+            RegisterAllocatorKind::Fresh,
+            MemoryAccessKind::Direct,
+        )
+    );
+    assert_ne!(
+        key,
+        TranslationKey::new(
+            &model,
+            0,
+            &register_file,
+            0xAAAA,
+            RegisterAllocatorKind::LinearScan,
+            MemoryAccessKind::SoftwareTlb,
+        )
+    );
+
+    let translation = PersistedTranslation::new(key, &[0xd503201f], &assembled);
+
+    let contains = |code: &[u8], value: u64| {
+        code.windows(8)
+            .any(|window| window == value.to_le_bytes().as_slice())
+    };
+
+    let code = translation.relocated_code(0x7777_8888_9999, 0xAAAA_BBBB_CCCC);
+    assert_eq!(code.len(), assembled.code.len());
+    assert!(contains(&code, 0x7777_8888_9999));
+    assert!(contains(&code, 0xAAAA_BBBB_CCCC));
+    assert!(!contains(&code, 0x1111_2222_3333));
+    assert!(!contains(&code, 0x4444_5555_6666));
+}
+
+#[ktest]
+fn guard_pc() {
+    let model = models::get("aarch64").unwrap();
+
+    let register_file = RegisterFile::init(&*model);
+
+    let mut ctx = X86TranslationContext::new(&model, false, register_file.global_register_offset());
+    let mut emitter = X86Emitter::new(&mut ctx);
+
+    let on_trace = emitter.ctx_mut().create_block();
+    let side_exit = emitter.ctx_mut().create_block();
+    emitter.guard_pc(0xffff_ffc0_0800_1000, on_trace, side_exit);
+
+    emitter.set_current_block(side_exit);
+    let _1 = emitter.constant(1, Type::Unsigned(64));
+    emitter.write_register(model.reg_offset("R0"), _1);
+    emitter.leave();
+
+    emitter.set_current_block(on_trace);
+    let _2 = emitter.constant(2, Type::Unsigned(64));
+    emitter.write_register(model.reg_offset("R0"), _2);
+    emitter.leave();
+
+    let num_regs = emitter.next_vreg();
+    let translation = ctx.compile(num_regs);
+
+    register_file.write::<u64>("_PC", 0xffff_ffc0_0800_1000);
+    translation.execute(&register_file);
+    assert_eq!(register_file.read::<u64>("R0"), 2);
+
+    register_file.write::<u64>("_PC", 0x0800_1000);
+    translation.execute(&register_file);
+    assert_eq!(register_file.read::<u64>("R0"), 1);
+}
+
+#[ktest]
+fn poll_interrupts() {
+    let model = models::get("aarch64").unwrap();
+
+    let register_file = RegisterFile::init(&*model);
+
+    let mut ctx = X86TranslationContext::new(&model, false, register_file.global_register_offset());
+    let mut emitter = X86Emitter::new(&mut ctx);
+
+    let next = emitter.ctx_mut().create_block();
+    let interrupted = emitter.ctx_mut().create_block();
+    emitter.poll_interrupts(next, interrupted);
+
+    emitter.set_current_block(interrupted);
+    let _1 = emitter.constant(1, Type::Unsigned(64));
+    emitter.write_register(model.reg_offset("R0"), _1);
+    emitter.leave();
+
+    emitter.set_current_block(next);
+    let _2 = emitter.constant(2, Type::Unsigned(64));
+    emitter.write_register(model.reg_offset("R0"), _2);
+    emitter.leave();
+
+    let num_regs = emitter.next_vreg();
+    let translation = ctx.compile(num_regs);
+
+    let pending = &GuestExecutionContext::current().interrupt_pending;
+
+    translation.execute(&register_file);
+    assert_eq!(register_file.read::<u64>("R0"), 2);
+
+    pending.store(1, Ordering::Relaxed);
+    let result = translation.execute(&register_file);
+    assert_eq!(register_file.read::<u64>("R0"), 1);
+    assert!(result.interrupt_pending());
+
+    pending.store(0, Ordering::Relaxed);
+}
+
+#[ktest]
+fn instruction_budget() {
+    let model = models::get("aarch64").unwrap();
+
+    let register_file = RegisterFile::init(&*model);
+
+    let mut ctx = X86TranslationContext::new(&model, false, register_file.global_register_offset());
+    let mut emitter = X86Emitter::new(&mut ctx);
+
+    emitter.consume_instruction_budget(3);
+
+    let aaaa = emitter.constant(0xAAAA, Type::Unsigned(64));
+    emitter.write_register(model.reg_offset("_PC"), aaaa);
+    emitter.leave();
+
+    let num_regs = emitter.next_vreg();
+    let translation = ctx.compile(num_regs);
+
+    let budget = &GuestExecutionContext::current().instruction_budget;
+
+    // enough budget to run to the end
+    register_file.write::<u64>("_PC", 0);
+    budget.store(10, Ordering::Relaxed);
+    translation.execute(&register_file);
+    assert_eq!(budget.load(Ordering::Relaxed), 7);
+    assert_eq!(register_file.read::<u64>("_PC"), 0xAAAA);
+
+    // leaves as soon as the budget is used up
+    register_file.write::<u64>("_PC", 0);
+    budget.store(3, Ordering::Relaxed);
+    translation.execute(&register_file);
+    assert_eq!(budget.load(Ordering::Relaxed), 0);
+    assert_eq!(register_file.read::<u64>("_PC"), 0);
+
+    budget.store(i64::MAX, Ordering::Relaxed);
+}
+
 #[ktest]
 fn end_cycle() {
     let model = models::get("aarch64").unwrap();
@@ -0,0 +1,449 @@
+//! Software model of the guest TLB
+//!
+//! Guest translations are cached in the lower half host mappings made by the
+//! page fault handler, and in the virtual to physical PC caches of the block
+//! execution loops. Each of these is recorded here against the guest block or
+//! page it was translated through, tagged with the ASID it was made under unless
+//! it is global, so that TLB maintenance instructions and ASID switches only
+//! drop the entries they affect rather than every guest mapping.
+//!
+//! The entries are shared by every core, each recording which host core's page
+//! tables its pages were mapped in, and every core is told which guest virtual
+//! addresses to drop from its own caches, but for its software TLB, which is
+//! emptied immediately. Pages unmapped from the page tables of other host cores
+//! are flushed from their TLBs before the maintenance completes. An ASID switch
+//! is local to the switching core, so only its caches are told, though the host
+//! pages of other ASIDs are unmapped for every core.
+
+use {
+    crate::{
+        guest::{GuestExecutionContext, MAX_GUEST_CORES},
+        host::arch::x86::{aarch64_mmu::Mapping, memory::VirtualMemoryArea, smp},
+    },
+    alloc::{sync::Arc, vec::Vec},
+    common::hashmap::{HashMap, HashSet},
+    core::sync::atomic::{AtomicU64, Ordering},
+    proc_macro_lib::ktest,
+    spin::{Lazy, Mutex},
+    x86_64::{
+        PhysAddr,
+        instructions::interrupts::without_interrupts,
+        structures::paging::{Page, Size4KiB},
+    },
+};
+
+/// Guest virtual addresses are accessed through this many low bits in the lower
+/// half
+const LOWER_HALF_MASK: u64 = 0xFF_FFFF_FFFF;
+
+/// Fixed bits of the SYS instruction, with CRn of the TLB maintenance
+/// instructions
+const TLBI_MASK: u32 = 0xfff8_f000;
+const TLBI: u32 = 0xd508_8000;
+
+static STATE: Lazy<Mutex<State>> = Lazy::new(|| Mutex::new(State::default()));
+
+/// Incremented by every maintenance operation, so that a host page mapped
+/// through a translation made while another core performed one is not kept
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Guest virtual address range translated by a single block or page
+/// descriptor, within the lower half
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Leaf {
+    base: u64,
+    size: u64,
+}
+
+impl Leaf {
+    fn containing(guest_virtual_address: u64, size: u64) -> Self {
+        Self {
+            base: guest_virtual_address & LOWER_HALF_MASK & !(size - 1),
+            size,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// Whether every translation of the leaf was global
+    global: bool,
+    /// ASIDs the leaf was translated under
+    asids: Vec<u16>,
+    /// Whether any translation of the leaf was made from EL1 and permits
+    /// accesses EL0 may not make
+    privileged: bool,
+    /// Lower half pages mapped through the leaf, with the root of the host page
+    /// tables each was mapped in
+    host_pages: HashSet<(PhysAddr, Page<Size4KiB>)>,
+    /// Bitmap of the cores whose caches hold translations of the leaf
+    cores: u16,
+}
+
+const _: () = assert!(MAX_GUEST_CORES <= u16::BITS as usize);
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<Leaf, Entry>,
+    /// Sizes of every leaf ever recorded, an address may be in a leaf of each
+    sizes: Vec<u64>,
+}
+
+impl State {
+    fn remove<I: IntoIterator<Item = Leaf>>(&mut self, leaves: I) -> Invalidation {
+        let mut invalidation = Invalidation::default();
+
+        for leaf in leaves {
+            if let Some(entry) = self.entries.remove(&leaf) {
+                entry
+                    .host_pages
+                    .into_iter()
+                    .for_each(|page| invalidation.unmap(page));
+                invalidation.insert(leaf);
+            }
+        }
+
+        invalidation
+    }
+
+    /// Unmaps the host pages of the leaves, dropping them from the caches of
+    /// `core_id` only and keeping the entries other cores still hold
+    fn remove_for_core<I: IntoIterator<Item = Leaf>>(
+        &mut self,
+        leaves: I,
+        core_id: usize,
+    ) -> Invalidation {
+        let mut invalidation = Invalidation::default();
+
+        for leaf in leaves {
+            let Some(entry) = self.entries.get_mut(&leaf) else {
+                continue;
+            };
+
+            entry
+                .host_pages
+                .drain()
+                .for_each(|page| invalidation.unmap(page));
+
+            if entry.cores & (1 << core_id) != 0 {
+                entry.cores &= !(1 << core_id);
+                invalidation.insert(leaf);
+            }
+
+            if entry.cores == 0 {
+                self.entries.remove(&leaf);
+            }
+        }
+
+        invalidation
+    }
+
+    /// Leaves whose entries match the predicate
+    fn matching<F: Fn(&Entry) -> bool>(&self, predicate: F) -> Vec<Leaf> {
+        self.entries
+            .iter()
+            .filter(|(_, entry)| predicate(entry))
+            .map(|(leaf, _)| *leaf)
+            .collect()
+    }
+}
+
+/// TLB maintenance performed by a guest instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    /// Every entry
+    All,
+    /// Entries translating the address, of the ASID if supplied or else of
+    /// every ASID
+    Address { address: u64, asid: Option<u16> },
+    /// Non-global entries of the ASID
+    Asid(u16),
+    /// The current ASID of a core changed, non-global entries of every other
+    /// ASID are dropped from its caches and unmapped as the host mappings are
+    /// not tagged
+    Switch { core_id: usize, asid: u16 },
+    /// An exception return to EL0, privileged entries are dropped as the host
+    /// mappings do not distinguish exception levels
+    Privileged,
+}
+
+impl Operation {
+    /// Decodes a TLBI instruction, reading its register operand with the
+    /// supplied function
+    ///
+    /// The outer shareable, inner shareable and non-shareable forms of each
+    /// operation are treated alike. Operations by range, or on the EL2, EL3
+    /// or stage 2 translation regimes, drop every entry.
+    pub fn decode_tlbi<F: FnOnce(u32) -> u64>(
+        opcode: u32,
+        asid_mask: u16,
+        read_register: F,
+    ) -> Option<Self> {
+        if opcode & TLBI_MASK != TLBI {
+            return None;
+        }
+
+        let op1 = (opcode >> 16) & 0b111;
+        let crm = (opcode >> 8) & 0b1111;
+        let op2 = (opcode >> 5) & 0b111;
+
+        let operand = match opcode & 0b1_1111 {
+            31 => 0,
+            rt => read_register(rt),
+        };
+
+        // VA[55:12] in the low 44 bits, sign extended from bit 55
+        let address = ((((operand & 0xfff_ffff_ffff) << 20) as i64) >> 8) as u64;
+        let asid = (operand >> 48) as u16 & asid_mask;
+
+        Some(match (op1, crm, op2) {
+            (0, 1 | 3 | 7, 1 | 5) => Self::Address {
+                address,
+                asid: Some(asid),
+            },
+            (0, 1 | 3 | 7, 3 | 7) => Self::Address {
+                address,
+                asid: None,
+            },
+            (0, 1 | 3 | 7, 2) => Self::Asid(asid),
+            _ => Self::All,
+        })
+    }
+}
+
+/// Guest virtual addresses dropped from the TLB
+#[derive(Debug, Default)]
+pub struct Invalidation {
+    all: bool,
+    leaves: HashSet<Leaf>,
+    sizes: Vec<u64>,
+    /// Whether pages were unmapped from the page tables of other host cores
+    remote: bool,
+}
+
+impl Invalidation {
+    /// Unmaps a lower half page from the host page tables it was mapped in
+    fn unmap(&mut self, (pml4_base, page): (PhysAddr, Page<Size4KiB>)) {
+        let mut vma = VirtualMemoryArea::from_pml4(pml4_base);
+        vma.unmap_guest_page(page);
+
+        self.remote |= pml4_base != VirtualMemoryArea::current().pml4_base;
+    }
+
+    fn insert(&mut self, leaf: Leaf) {
+        if !self.sizes.contains(&leaf.size) {
+            self.sizes.push(leaf.size);
+        }
+        self.leaves.insert(leaf);
+    }
+
+    pub fn is_all(&self) -> bool {
+        self.all
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.all && self.leaves.is_empty()
+    }
+
+    pub fn contains(&self, guest_virtual_address: u64) -> bool {
+        self.all
+            || self.sizes.iter().any(|size| {
+                self.leaves
+                    .contains(&Leaf::containing(guest_virtual_address, *size))
+            })
+    }
+}
+
+/// Current generation, to be read before translating an address through which
+/// a host page is mapped and passed to [`insert`]
+pub fn generation() -> u64 {
+    GENERATION.load(Ordering::Acquire)
+}
+
+/// Records a translation of `guest_virtual_address` made by a core under the
+/// ASID, through which the lower half page `host_page` is mapped if supplied
+/// along with the [`generation`] read before translating
+///
+/// If maintenance has been performed since, the translation may be stale, so
+/// the page is unmapped instead, to be faulted in again.
+pub fn insert(
+    guest_virtual_address: u64,
+    mapping: &Mapping,
+    core_id: usize,
+    asid: u16,
+    host_page: Option<(Page<Size4KiB>, u64)>,
+) {
+    let leaf = Leaf::containing(guest_virtual_address, mapping.size);
+
+    // the lock is also taken in the page fault handler, so the holder must not be
+    // preempted
+    without_interrupts(|| {
+        let mut state = STATE.lock();
+
+        match host_page {
+            Some((page, generation)) if generation != GENERATION.load(Ordering::Acquire) => {
+                VirtualMemoryArea::current().unmap_guest_page(page);
+                return;
+            }
+            _ => {}
+        }
+
+        if !state.sizes.contains(&leaf.size) {
+            state.sizes.push(leaf.size);
+        }
+
+        let entry = state.entries.entry(leaf).or_insert_with(|| Entry {
+            global: true,
+            asids: Vec::new(),
+            privileged: false,
+            host_pages: HashSet::default(),
+            cores: 0,
+        });
+
+        entry.cores |= 1 << core_id;
+
+        entry.global &= mapping.global;
+        entry.privileged |= mapping.privileged;
+        if !entry.asids.contains(&asid) {
+            entry.asids.push(asid);
+        }
+        if let Some((page, _)) = host_page {
+            entry
+                .host_pages
+                .insert((VirtualMemoryArea::current().pml4_base, page));
+        }
+    });
+}
+
+/// Drops the entries affected by the operation, unmapping their host pages and
+/// telling every core affected to drop them from its caches
+pub fn invalidate(operation: Operation) {
+    log::debug!("TLB maintenance: {operation:x?}");
+
+    let core_id = match operation {
+        Operation::Switch { core_id, .. } => Some(core_id),
+        _ => None,
+    };
+
+    let invalidation = without_interrupts(|| {
+        let mut state = STATE.lock();
+        GENERATION.fetch_add(1, Ordering::AcqRel);
+
+        match operation {
+            Operation::All => {
+                state.entries.clear();
+                VirtualMemoryArea::every_core().for_each(|mut vma| vma.clear_guest_mappings());
+                VirtualMemoryArea::current().invalidate();
+
+                Invalidation {
+                    all: true,
+                    remote: true,
+                    ..Default::default()
+                }
+            }
+            Operation::Address { address, asid } => {
+                let leaves = state
+                    .sizes
+                    .iter()
+                    .map(|size| Leaf::containing(address, *size))
+                    .filter(|leaf| {
+                        state.entries.get(leaf).is_some_and(|entry| {
+                            entry.global || asid.is_none_or(|asid| entry.asids.contains(&asid))
+                        })
+                    })
+                    .collect::<Vec<_>>();
+
+                state.remove(leaves)
+            }
+            Operation::Asid(asid) => {
+                let leaves = state.matching(|entry| !entry.global && entry.asids.contains(&asid));
+                state.remove(leaves)
+            }
+            Operation::Switch { core_id, asid } => {
+                let leaves = state.matching(|entry| {
+                    !entry.global && entry.asids.iter().any(|tagged| *tagged != asid)
+                });
+                state.remove_for_core(leaves, core_id)
+            }
+            Operation::Privileged => {
+                let leaves = state.matching(|entry| entry.privileged);
+                state.remove(leaves)
+            }
+        }
+    });
+
+    // the lock must be released first, as other cores may wait for it with
+    // interrupts disabled
+    if invalidation.remote {
+        smp::flush_remote_tlbs();
+    }
+
+    if invalidation.is_empty() {
+        return;
+    }
+
+    let invalidation = Arc::new(invalidation);
+    (0..MAX_GUEST_CORES)
+        .filter(|core| core_id.is_none_or(|core_id| core_id == *core))
+        .filter_map(GuestExecutionContext::for_core)
+        .for_each(|ctx| {
+            // translated code looks up the software TLB without returning to the
+            // block execution loop, so it is emptied here rather than by the core
+            unsafe { &mut *ctx.software_tlb.get() }.apply(&invalidation);
+
+            ctx.current_core()
+                .invalidate_translations(invalidation.clone())
+        });
+}
+
+#[ktest]
+fn tlbi_decoding() {
+    let registers = |rt| [0x5_0000_0000_0000 | 0xff8_0001_2345, 0x3_0000_0000_0000][rt as usize];
+    let decode = |opcode| Operation::decode_tlbi(opcode, u16::MAX, registers);
+
+    // tlbi vmalle1is
+    assert_eq!(decode(0xd508_831f), Some(Operation::All));
+    // tlbi vae1is, x0
+    assert_eq!(
+        decode(0xd508_8320),
+        Some(Operation::Address {
+            address: 0xffff_8000_1234_5000,
+            asid: Some(5)
+        })
+    );
+    // tlbi vaale1, x0
+    assert_eq!(
+        decode(0xd508_87e0),
+        Some(Operation::Address {
+            address: 0xffff_8000_1234_5000,
+            asid: None
+        })
+    );
+    // tlbi aside1is, x1
+    assert_eq!(decode(0xd508_8341), Some(Operation::Asid(3)));
+    // tlbi rvae1is, x0
+    assert_eq!(decode(0xd508_8220), Some(Operation::All));
+    // dc civac, x1
+    assert_eq!(decode(0xd50b_7e21), None);
+
+    // 8-bit ASIDs ignore the top byte
+    assert_eq!(
+        Operation::decode_tlbi(0xd508_8340, 0xff, |_| 0x1203_0000_0000_0000),
+        Some(Operation::Asid(3))
+    );
+}
+
+#[ktest]
+fn invalidation_lookup() {
+    let mut invalidation = Invalidation::default();
+    invalidation.insert(Leaf::containing(0xffff_8000_0020_1000, 0x20_0000));
+    invalidation.insert(Leaf::containing(0x4000_3000, 0x1000));
+
+    assert!(invalidation.contains(0xffff_8000_0020_0000));
+    assert!(invalidation.contains(0xffff_8000_003f_fffc));
+    assert!(!invalidation.contains(0xffff_8000_0040_0000));
+    assert!(invalidation.contains(0x4000_3ffc));
+    assert!(!invalidation.contains(0x4000_4000));
+    assert!(!invalidation.is_empty());
+    assert!(Invalidation::default().is_empty());
+}
@@ -5,7 +5,21 @@ use {bitfields::bitfield, core::arch::asm};
 pub struct ExecutionResult {
     need_tlb_invalidate: bool,
     interrupt_pending: bool,
-    #[bits(30)]
+    /// `WFI` executed, the core should be parked until an interrupt is pending
+    wait_for_interrupt: bool,
+    /// `WFE` executed, the core should be parked until an event or interrupt
+    wait_for_event: bool,
+    /// `SEV` executed, all cores waiting for an event should be woken
+    send_event: bool,
+    /// `SEVL` executed, only this core's event register should be set
+    send_event_local: bool,
+    /// `TLBI` executed, its opcode and operand are recorded in the execution
+    /// context
+    tlbi: bool,
+    /// `ERET` executed, mappings only EL1 may access are dropped if it returned
+    /// to EL0
+    exception_return: bool,
+    #[bits(24)]
     _reserved: u32,
 }
 
@@ -13,6 +27,23 @@ impl ExecutionResult {
     pub fn as_u32(&self) -> u32 {
         self.into_bits()
     }
+
+    /// Sets the status corresponding to a call to `function` in the model, if
+    /// it is one the block execution loop handles
+    pub fn set_for_call(&mut self, function: &str) {
+        match function {
+            "sail_tlbi" => {
+                self.set_need_tlb_invalidate(true);
+                self.set_tlbi(true);
+            }
+            "WaitForInterrupt" => self.set_wait_for_interrupt(true),
+            "WaitForEvent" => self.set_wait_for_event(true),
+            "SendEvent" => self.set_send_event(true),
+            "SendEventLocal" => self.set_send_event_local(true),
+            "AArch64_ExceptionReturn" => self.set_exception_return(true),
+            _ => (),
+        }
+    }
 }
 
 #[inline(never)] // only disabled to make debugging easier
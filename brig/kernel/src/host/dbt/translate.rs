@@ -1,6 +1,7 @@
 use {
     crate::host::dbt::{
         Alloc,
+        bitvector::limb_count,
         emitter::{self, Emitter, Type},
         register_file::{GLOBAL_REGISTER_SIZE, RegisterFile},
         sysreg_helpers::{self, encode_sysreg_id, sys_reg_read, sys_reg_write},
@@ -96,6 +97,7 @@ pub fn translate_instruction<A: Alloc>(
     register_file.write("SEE", -1i64);
 
     let initial_block = emitter.get_current_block();
+    let tlbi = emitter.execution_result.tlbi();
 
     let mut attempts_remaining = NUM_TRANSLATE_ATTEMPTS;
 
@@ -141,6 +143,18 @@ pub fn translate_instruction<A: Alloc>(
 
     emitter.set_current_block(end_block);
 
+    // the block execution loop performs the maintenance once the translation leaves
+    if !tlbi && emitter.execution_result.tlbi() {
+        let operand = match opcode & 0b1_1111 {
+            31 => emitter.constant(0, Type::Unsigned(64)),
+            rt => emitter.read_register(
+                model.reg_offset(alloc::format!("R{rt}")),
+                Type::Unsigned(64),
+            ),
+        };
+        emitter.record_tlbi(opcode, operand);
+    }
+
     result
 }
 
@@ -418,15 +432,13 @@ impl<'m, 'r, 'e, 'c, A: Alloc> FunctionTranslator<'m, 'r, 'e, 'c, A> {
                         let prev_value =
                             value.expect("no previous value written to local variable");
 
-                        let id = self.allocate_variable_id();
+                        let typ = emit_rudder_type(&typ);
+                        let id = self.allocate_variable_id(&typ);
 
                         // fix up the previous write
                         self.emitter.write_stack_variable(id, prev_value);
 
-                        LocalVariable::Stack {
-                            typ: emit_rudder_type(&typ),
-                            id,
-                        }
+                        LocalVariable::Stack { typ, id }
                     })
                     .collect_into(&mut stack_variables);
 
@@ -726,9 +738,13 @@ impl<'m, 'r, 'e, 'c, A: Alloc> FunctionTranslator<'m, 'r, 'e, 'c, A> {
                     Constant::SignedInteger { value, .. } => {
                         self.emitter.constant(*value as u64, typ)
                     }
-                    Constant::FloatingPoint { value, .. } => {
-                        self.emitter.constant(*value as u64, typ)
-                    }
+                    // floating point values are represented by their bit patterns
+                    Constant::FloatingPoint { value, .. } => match typ.width() {
+                        32 => self
+                            .emitter
+                            .constant(u64::from((*value as f32).to_bits()), typ),
+                        _ => self.emitter.constant(value.to_bits(), typ),
+                    },
 
                     Constant::String(_) => self
                         .emitter
@@ -806,6 +822,8 @@ impl<'m, 'r, 'e, 'c, A: Alloc> FunctionTranslator<'m, 'r, 'e, 'c, A> {
                                 self.function.name(),
                             );
 
+                            let typ = emit_rudder_type(&symbol.typ());
+
                             let id = if let Some(id) = self.promoted_locations.get(&symbol.name()) {
                                 log::trace!(
                                     "variable {:?} already promoted to stack @ {id:#x}",
@@ -813,7 +831,7 @@ impl<'m, 'r, 'e, 'c, A: Alloc> FunctionTranslator<'m, 'r, 'e, 'c, A> {
                                 );
                                 *id
                             } else {
-                                let id = self.allocate_variable_id();
+                                let id = self.allocate_variable_id(&typ);
                                 self.promoted_locations.insert(symbol.name(), id);
 
                                 log::trace!(
@@ -824,10 +842,7 @@ impl<'m, 'r, 'e, 'c, A: Alloc> FunctionTranslator<'m, 'r, 'e, 'c, A> {
                                 id
                             };
 
-                            *variable = LocalVariable::Stack {
-                                typ: emit_rudder_type(&symbol.typ()),
-                                id,
-                            };
+                            *variable = LocalVariable::Stack { typ, id };
 
                             // clears operands??? todo: understand this
                             let current_block = self.emitter.get_current_block();
@@ -1080,9 +1095,7 @@ impl<'m, 'r, 'e, 'c, A: Alloc> FunctionTranslator<'m, 'r, 'e, 'c, A> {
             Statement::Call { target, args, .. } => {
                 let args = args.iter().map(|a| value_store.get(*a)).collect::<Vec<_>>();
 
-                if target.as_ref() == "sail_tlbi" {
-                    self.emitter.execution_result.set_need_tlb_invalidate(true);
-                }
+                self.emitter.execution_result.set_for_call(target.as_ref());
 
                 StatementResult::Data(translate_with_variable_ids(
                     self.allocator.clone(),
@@ -1364,10 +1377,17 @@ impl<'m, 'r, 'e, 'c, A: Alloc> FunctionTranslator<'m, 'r, 'e, 'c, A> {
         }
     }
 
-    fn allocate_variable_id(&self) -> usize {
-        let id = self.current_variable_id.fetch_add(1, Ordering::Relaxed);
+    /// Allocates stack slots for a variable of type `typ`, with integers wider
+    /// than 64 bits taking one consecutive 8 byte slot per limb
+    fn allocate_variable_id(&self, typ: &Type) -> usize {
+        let slots = match typ {
+            Type::Unsigned(width) | Type::Signed(width) => limb_count(*width),
+            _ => 1,
+        };
+
+        let id = self.current_variable_id.fetch_add(slots, Ordering::Relaxed);
 
-        if id == GLOBAL_REGISTER_SIZE / 8 {
+        if id + slots > GLOBAL_REGISTER_SIZE / 8 {
             panic!("variable number {id:#x} exceeded MAX_STACK_SIZE ({GLOBAL_REGISTER_SIZE:#x})")
         }
 
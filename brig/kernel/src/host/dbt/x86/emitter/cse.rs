@@ -0,0 +1,123 @@
+//! Common subexpression elimination over the node graph
+//!
+//! Nodes are lowered at most once per block, so a node built again from the
+//! same operands can be replaced with the earlier one and share its lowered
+//! operand. Only nodes whose value does not depend on where they are lowered
+//! qualify: reads of registers, memory and stack variables, flags and call
+//! results do not, and neither do floating point operations, which depend on
+//! the rounding mode loaded from `FPCR`.
+
+use {
+    crate::host::dbt::{
+        Alloc,
+        emitter::Type,
+        x86::emitter::{
+            BinaryOperationKind, NodeKind, TernaryOperationKind, UnaryOperationKind, X86Node,
+            X86NodeRef,
+        },
+    },
+    alloc::vec::Vec,
+    common::hashmap::HashMap,
+    core::cell::RefCell,
+};
+
+/// Nodes eligible for elimination built since the start of the current block
+pub(super) struct CommonNodes<A: Alloc> {
+    nodes: RefCell<HashMap<(Type, NodeKind<A>), X86NodeRef<A>>>,
+}
+
+impl<A: Alloc> CommonNodes<A> {
+    pub fn new() -> Self {
+        Self {
+            nodes: RefCell::new(HashMap::default()),
+        }
+    }
+
+    /// Returns an earlier node equivalent to `node` if there is one, otherwise
+    /// the result of `create`
+    pub fn get_or_insert(
+        &self,
+        node: X86Node<A>,
+        create: impl FnOnce(X86Node<A>) -> X86NodeRef<A>,
+    ) -> X86NodeRef<A> {
+        if !is_common(&node) {
+            return create(node);
+        }
+
+        self.nodes
+            .borrow_mut()
+            .entry((node.typ, node.kind.clone()))
+            .or_insert_with(|| create(node))
+            .clone()
+    }
+
+    /// Forgets every node, as nodes are lowered again in each block
+    pub fn clear(&mut self) {
+        self.nodes.get_mut().clear();
+    }
+}
+
+fn is_common<A: Alloc>(node: &X86Node<A>) -> bool {
+    use {BinaryOperationKind::*, UnaryOperationKind::*};
+
+    let is_float = |node: &X86NodeRef<A>| matches!(node.typ(), Type::Floating(_));
+
+    let operands = match &node.kind {
+        NodeKind::Constant { .. } | NodeKind::FunctionPointer(_) => Vec::new(),
+        NodeKind::UnaryOperation(
+            Not(value) | Negate(value) | Complement(value) | Power2(value) | Absolute(value)
+            | Ceil(value) | Floor(value) | SquareRoot(value),
+        ) => alloc::vec![value],
+        NodeKind::BinaryOperation(
+            Add(left, right)
+            | Sub(left, right)
+            | Multiply(left, right)
+            | Divide(left, right)
+            | Modulo(left, right)
+            | And(left, right)
+            | Or(left, right)
+            | Xor(left, right)
+            | PowI(left, right)
+            | CompareEqual(left, right)
+            | CompareNotEqual(left, right)
+            | CompareLessThan(left, right)
+            | CompareLessThanOrEqual(left, right)
+            | CompareGreaterThan(left, right)
+            | CompareGreaterThanOrEqual(left, right),
+        ) => alloc::vec![left, right],
+        NodeKind::Cast { value, .. } => alloc::vec![value],
+        NodeKind::Shift { value, amount, .. } => alloc::vec![value, amount],
+        NodeKind::BitExtract {
+            value,
+            start,
+            length,
+        } => alloc::vec![value, start, length],
+        NodeKind::BitInsert {
+            target,
+            source,
+            start,
+            length,
+        } => alloc::vec![target, source, start, length],
+        NodeKind::BitReplicate { pattern, count } => alloc::vec![pattern, count],
+        NodeKind::Select {
+            condition,
+            true_value,
+            false_value,
+        } => alloc::vec![condition, true_value, false_value],
+        NodeKind::Tuple(values) | NodeKind::Limbs(values) => values.iter().collect(),
+        NodeKind::VectorLane { vector, .. } => alloc::vec![vector],
+        NodeKind::VectorInsertLane { vector, value, .. } => alloc::vec![vector, value],
+        NodeKind::VectorOperation { lhs, rhs, .. } => alloc::vec![lhs, rhs],
+        // flags of an `AddWithCarry` are read back from the instruction that
+        // computed it
+        NodeKind::TernaryOperation(TernaryOperationKind::AddWithCarry(..))
+        | NodeKind::GuestRegister { .. }
+        | NodeKind::ReadMemory { .. }
+        | NodeKind::ReadStackVariable { .. }
+        | NodeKind::GetFlags { .. }
+        | NodeKind::HostFlag { .. }
+        | NodeKind::CallReturnValue => return false,
+    };
+
+    !matches!(node.typ, Type::Floating(_)) && !operands.into_iter().any(is_float)
+}
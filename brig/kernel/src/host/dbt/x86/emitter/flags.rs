@@ -0,0 +1,223 @@
+//! Lazy evaluation of the guest NZCV flags
+//!
+//! Writes of the flags of an operation to `PSTATE_N/Z/C/V` are deferred while
+//! the host `EFLAGS` still hold them, so that conditions read back in the same
+//! block are evaluated from `EFLAGS` directly. The deferred bytes are stored to
+//! the register file before anything overwrites `EFLAGS` or the block is left.
+
+use crate::host::dbt::{
+    Alloc,
+    x86::{
+        emitter::{BinaryOperationKind, NodeKind, UnaryOperationKind, X86NodeRef},
+        encoder::{Instruction, Opcode, Operand},
+        peephole::{self, Flags},
+    },
+};
+
+/// Guest condition flag
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Flag {
+    N,
+    Z,
+    C,
+    V,
+}
+
+impl Flag {
+    pub const ALL: [Flag; 4] = [Flag::N, Flag::Z, Flag::C, Flag::V];
+
+    /// Instruction setting the byte `dest` to the flag held in `EFLAGS`
+    pub fn set<A: Alloc>(self, dest: Operand<A>) -> Instruction<A> {
+        match self {
+            Flag::N => Instruction::sets(dest),
+            Flag::Z => Instruction::sete(dest),
+            Flag::C => Instruction::setc(dest),
+            Flag::V => Instruction::seto(dest),
+        }
+    }
+}
+
+/// Guest condition that can be evaluated from `EFLAGS`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    Set(Flag),
+    Clear(Flag),
+    /// `N == V`
+    GreaterOrEqual,
+    /// `N != V`
+    Less,
+    /// `Z == 0 && N == V`
+    Greater,
+    /// `Z == 1 || N != V`
+    LessOrEqual,
+}
+
+impl Condition {
+    pub fn negate(self) -> Self {
+        match self {
+            Condition::Set(flag) => Condition::Clear(flag),
+            Condition::Clear(flag) => Condition::Set(flag),
+            Condition::GreaterOrEqual => Condition::Less,
+            Condition::Less => Condition::GreaterOrEqual,
+            Condition::Greater => Condition::LessOrEqual,
+            Condition::LessOrEqual => Condition::Greater,
+        }
+    }
+
+    /// Instruction setting the byte `dest` to the condition, or to its negation
+    /// if there is no `setcc` for it, along with whether it was negated
+    pub fn set<A: Alloc>(self, dest: Operand<A>) -> (Instruction<A>, bool) {
+        let instruction = match self {
+            Condition::Set(flag) => flag.set(dest),
+            Condition::Clear(Flag::Z) => Instruction::setne(dest),
+            Condition::Clear(Flag::C) => Instruction::setae(dest),
+            Condition::Clear(flag @ (Flag::N | Flag::V)) => return (flag.set(dest), true),
+            Condition::GreaterOrEqual => Instruction::setge(dest),
+            Condition::Less => Instruction::setl(dest),
+            Condition::Greater => Instruction::setg(dest),
+            Condition::LessOrEqual => Instruction::setle(dest),
+        };
+
+        (instruction, false)
+    }
+}
+
+/// Flags held in the host `EFLAGS` on behalf of the guest
+pub(super) struct LazyFlags<A: Alloc> {
+    /// Operation whose flags `EFLAGS` currently hold
+    operation: Option<X86NodeRef<A>>,
+    /// Flags of `operation` written to the guest but not yet to the register
+    /// file
+    pending: [bool; 4],
+}
+
+impl<A: Alloc> LazyFlags<A> {
+    pub fn new() -> Self {
+        Self {
+            operation: None,
+            pending: [false; 4],
+        }
+    }
+
+    /// Whether `EFLAGS` hold the flags of `operation`
+    pub fn holds(&self, operation: &X86NodeRef<A>) -> bool {
+        self.operation.as_ref() == Some(operation)
+    }
+
+    /// Operation whose flags `EFLAGS` hold, if any
+    pub fn operation(&self) -> Option<&X86NodeRef<A>> {
+        self.operation.as_ref()
+    }
+
+    /// Records that `EFLAGS` now hold the flags of `operation`
+    pub fn produced(&mut self, operation: X86NodeRef<A>) {
+        assert!(!self.is_pending(), "flags overwritten before being stored");
+        self.operation = Some(operation);
+    }
+
+    /// Defers writing `flag` of the held operation to the register file
+    pub fn defer(&mut self, flag: Flag) {
+        assert!(self.operation.is_some());
+        self.pending[flag as usize] = true;
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending.contains(&true)
+    }
+
+    pub fn is_flag_pending(&self, flag: Flag) -> bool {
+        self.pending[flag as usize]
+    }
+
+    /// Forgets a deferred write of `flag`, as the register was written since
+    pub fn discard(&mut self, flag: Flag) {
+        self.pending[flag as usize] = false;
+    }
+
+    /// Returns the flags whose writes were deferred, which must now be stored
+    pub fn take_pending(&mut self) -> impl Iterator<Item = Flag> + use<A> {
+        let pending = core::mem::replace(&mut self.pending, [false; 4]);
+        Flag::ALL
+            .into_iter()
+            .filter(move |flag| pending[*flag as usize])
+    }
+
+    /// Forgets the held operation once `EFLAGS` are overwritten
+    pub fn clear(&mut self) {
+        assert!(!self.is_pending(), "flags overwritten before being stored");
+        self.operation = None;
+    }
+
+    /// Condition computed by `node` from flags read while still held in
+    /// `EFLAGS`, if it can be evaluated from them
+    pub fn condition(&self, node: &X86NodeRef<A>) -> Option<Condition> {
+        use BinaryOperationKind::*;
+
+        match node.kind() {
+            NodeKind::HostFlag { .. } => self.held_flag(node).map(Condition::Set),
+            NodeKind::UnaryOperation(UnaryOperationKind::Not(value)) => {
+                self.condition(value).map(Condition::negate)
+            }
+            NodeKind::BinaryOperation(CompareEqual(left, right)) => self.equality(left, right),
+            NodeKind::BinaryOperation(CompareNotEqual(left, right)) => {
+                self.equality(left, right).map(Condition::negate)
+            }
+            NodeKind::BinaryOperation(And(left, right)) => {
+                match (self.condition(left)?, self.condition(right)?) {
+                    (Condition::GreaterOrEqual, Condition::Clear(Flag::Z))
+                    | (Condition::Clear(Flag::Z), Condition::GreaterOrEqual) => {
+                        Some(Condition::Greater)
+                    }
+                    _ => None,
+                }
+            }
+            NodeKind::BinaryOperation(Or(left, right)) => {
+                match (self.condition(left)?, self.condition(right)?) {
+                    (Condition::Less, Condition::Set(Flag::Z))
+                    | (Condition::Set(Flag::Z), Condition::Less) => Some(Condition::LessOrEqual),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Condition under which `left` equals `right`
+    fn equality(&self, left: &X86NodeRef<A>, right: &X86NodeRef<A>) -> Option<Condition> {
+        match (self.held_flag(left), self.held_flag(right), right.kind()) {
+            (Some(flag), _, NodeKind::Constant { value: 1, .. }) => Some(Condition::Set(flag)),
+            (Some(flag), _, NodeKind::Constant { value: 0, .. }) => Some(Condition::Clear(flag)),
+            (Some(Flag::N), Some(Flag::V), _) | (Some(Flag::V), Some(Flag::N), _) => {
+                Some(Condition::GreaterOrEqual)
+            }
+            _ => None,
+        }
+    }
+
+    /// Flag read by `node` while `EFLAGS` still hold it
+    fn held_flag(&self, node: &X86NodeRef<A>) -> Option<Flag> {
+        match node.kind() {
+            NodeKind::HostFlag { operation, flag } if self.holds(operation) => Some(*flag),
+            _ => None,
+        }
+    }
+}
+
+/// Whether deferred flags can stay in `EFLAGS` past `instruction`, which must
+/// neither overwrite them nor leave the block
+pub fn preserved_by<A: Alloc>(instruction: &Instruction<A>) -> bool {
+    matches!(peephole::flags(instruction), Flags::Unaffected)
+        || matches!(
+            instruction.0,
+            Opcode::SETS(_)
+                | Opcode::SETE(_)
+                | Opcode::SETC(_)
+                | Opcode::SETO(_)
+                | Opcode::SETNE(_)
+                | Opcode::SETAE(_)
+                | Opcode::SETGE(_)
+                | Opcode::SETL(_)
+                | Opcode::SETG(_)
+                | Opcode::SETLE(_)
+        )
+}
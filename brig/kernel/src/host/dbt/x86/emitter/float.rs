@@ -0,0 +1,778 @@
+//! Lowering of scalar floating point operations to SSE2
+//!
+//! Floating point values are held as bit patterns in general purpose registers
+//! between nodes, and only moved into `xmm` registers for the duration of the
+//! instruction operating on them, so SSE registers are never live across calls
+//! or blocks.
+//!
+//! `MXCSR` is loaded from the guest `FPCR` before the first floating point
+//! operation of a block, and exception flags raised by each operation are
+//! accumulated into the guest `FPSR`.
+//!
+//! Bitwise operations act on the bit patterns directly, and operations without
+//! an SSE2 instruction call the software implementations in
+//! [`float_helpers`].
+
+use {
+    crate::{
+        guest::GuestExecutionContext,
+        host::dbt::{
+            Alloc,
+            emitter::Type,
+            float_helpers,
+            persist::Symbol,
+            x86::{
+                emitter::{
+                    ARG_REGS, BinaryOperationKind, CastOperationKind, UnaryOperationKind,
+                    X86Emitter, X86NodeRef,
+                },
+                encoder::{
+                    Instruction, Operand, PhysicalRegister, Register, SegmentRegister, width::Width,
+                },
+            },
+        },
+    },
+    alloc::vec::Vec,
+    core::mem::offset_of,
+    proc_macro_lib::ktest,
+};
+
+/// `MXCSR` with all exceptions masked, no flags raised and round to nearest
+const MXCSR_DEFAULT: u64 = 0x1f80;
+
+/// Shift of the rounding control field in `MXCSR`
+const MXCSR_RC_SHIFT: u64 = 13;
+
+/// Rounding control value rounding towards negative infinity
+const MXCSR_RC_DOWN: u64 = 0b01;
+
+/// Rounding control value rounding towards positive infinity
+const MXCSR_RC_UP: u64 = 0b10;
+
+/// Exception flags in the low bits of `MXCSR`
+const MXCSR_FLAGS_MASK: u64 = 0x3f;
+
+/// 2^63 in single precision
+const LIMIT_F32: u32 = 0x5f00_0000;
+
+/// 2^63 in double precision
+const LIMIT_F64: u64 = 0x43e0_0000_0000_0000;
+
+/// How a cast involving a floating point type is lowered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum FloatCast {
+    /// Between an integer and a floating point value
+    Numeric,
+    /// Between single and double precision
+    Precision,
+}
+
+impl FloatCast {
+    /// Classifies a cast from `source` to `target`, returning `None` if it only
+    /// moves bits around and can be lowered like an integer cast
+    pub(super) fn classify(source: &Type, target: &Type, kind: &CastOperationKind) -> Option<Self> {
+        match (source, target) {
+            (Type::Floating(source), Type::Floating(target)) => {
+                (source != target).then_some(Self::Precision)
+            }
+            (Type::Floating(_), _) | (_, Type::Floating(_)) => {
+                (*kind != CastOperationKind::Reinterpret).then_some(Self::Numeric)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Type of the result of a binary operation on floating point values, or
+/// `None` if the operands are not floating point
+pub(super) fn binary_operation_type<A: Alloc>(op: &BinaryOperationKind<A>) -> Option<Type> {
+    use BinaryOperationKind::*;
+
+    let (Add(left, _)
+    | Sub(left, _)
+    | Multiply(left, _)
+    | Divide(left, _)
+    | Modulo(left, _)
+    | And(left, _)
+    | Or(left, _)
+    | Xor(left, _)
+    | PowI(left, _)
+    | CompareEqual(left, _)
+    | CompareNotEqual(left, _)
+    | CompareLessThan(left, _)
+    | CompareLessThanOrEqual(left, _)
+    | CompareGreaterThan(left, _)
+    | CompareGreaterThanOrEqual(left, _)) = op;
+
+    if !matches!(left.typ(), Type::Floating(_)) {
+        return None;
+    }
+
+    Some(match op {
+        CompareEqual(..)
+        | CompareNotEqual(..)
+        | CompareLessThan(..)
+        | CompareLessThanOrEqual(..)
+        | CompareGreaterThan(..)
+        | CompareGreaterThanOrEqual(..) => Type::Unsigned(1),
+        _ => *left.typ(),
+    })
+}
+
+/// Operand of a unary operation if it is a floating point value
+pub(super) fn unary_operand<A: Alloc>(op: &UnaryOperationKind<A>) -> Option<&X86NodeRef<A>> {
+    use UnaryOperationKind::*;
+
+    let (Not(value) | Negate(value) | Complement(value) | Power2(value) | Absolute(value)
+    | Ceil(value) | Floor(value) | SquareRoot(value)) = op;
+
+    matches!(value.typ(), Type::Floating(_)).then_some(value)
+}
+
+/// Converts the `FPCR` value in the low bits of `fpcr` to the equivalent
+/// `MXCSR`
+fn fpcr_to_mxcsr(fpcr: u64) -> u64 {
+    // RMode is RN, RP, RM, RZ where rounding control is RN, RM, RP, RZ
+    let rmode = (fpcr >> 22) & 0b11;
+    let rc = ((rmode & 1) << 1) | (rmode >> 1);
+
+    // FZ flushes both denormal outputs (FTZ) and inputs (DAZ)
+    let fz = (fpcr >> 24) & 1;
+
+    MXCSR_DEFAULT | (rc << MXCSR_RC_SHIFT) | (fz << 15) | (fz << 6)
+}
+
+/// Converts the exception flags of `mxcsr` to the cumulative exception bits of
+/// `FPSR`
+fn mxcsr_to_fpsr(mxcsr: u64) -> u64 {
+    // IE -> IOC, ZE -> DZC, OE -> OFC, UE -> UFC, PE -> IXC, DE -> IDC
+    (mxcsr & 1) | ((mxcsr >> 1) & 0x1e) | ((mxcsr & 0b10) << 6)
+}
+
+impl<'a, 'ctx, A: Alloc> X86Emitter<'ctx, A> {
+    fn mxcsr_scratch() -> Operand<A> {
+        Operand::mem_seg_displ(
+            32,
+            SegmentRegister::FS,
+            i32::try_from(offset_of!(GuestExecutionContext, mxcsr)).unwrap(),
+        )
+    }
+
+    fn guest_register_operand(offset: u64) -> Operand<A> {
+        Operand::mem_base_displ(
+            Width::_32,
+            Register::PhysicalRegister(PhysicalRegister::RBP),
+            offset.try_into().unwrap(),
+        )
+    }
+
+    /// Loads `MXCSR` from the guest `FPCR` if it has not been since the start
+    /// of the current block or the last write to `FPCR`
+    fn load_fp_mode(&mut self) {
+        if self.fp_mode_loaded {
+            return;
+        }
+
+        let fpcr = Operand::vreg(Width::_32, self.next_vreg());
+        self.push_instruction(
+            Instruction::mov(Self::guest_register_operand(self.ctx().fpcr_offset), fpcr).unwrap(),
+        );
+
+        // same as `fpcr_to_mxcsr`
+        let rmode = Operand::vreg(Width::_32, self.next_vreg());
+        self.push_instruction(Instruction::mov(fpcr, rmode).unwrap());
+        self.push_instruction(Instruction::shr(Operand::imm(Width::_8, 22), rmode));
+        self.push_instruction(Instruction::and(Operand::imm(Width::_32, 0b11), rmode));
+
+        let rc = Operand::vreg(Width::_32, self.next_vreg());
+        self.push_instruction(Instruction::mov(rmode, rc).unwrap());
+        self.push_instruction(Instruction::and(Operand::imm(Width::_32, 1), rc));
+        self.push_instruction(Instruction::shl(Operand::imm(Width::_8, 1), rc));
+        self.push_instruction(Instruction::shr(Operand::imm(Width::_8, 1), rmode));
+        self.push_instruction(Instruction::or(rmode, rc));
+        self.push_instruction(Instruction::shl(
+            Operand::imm(Width::_8, MXCSR_RC_SHIFT),
+            rc,
+        ));
+        self.push_instruction(Instruction::or(Operand::imm(Width::_32, MXCSR_DEFAULT), rc));
+
+        let fz = Operand::vreg(Width::_32, self.next_vreg());
+        self.push_instruction(Instruction::mov(fpcr, fz).unwrap());
+        self.push_instruction(Instruction::shr(Operand::imm(Width::_8, 24), fz));
+        self.push_instruction(Instruction::and(Operand::imm(Width::_32, 1), fz));
+
+        let ftz = Operand::vreg(Width::_32, self.next_vreg());
+        self.push_instruction(Instruction::mov(fz, ftz).unwrap());
+        self.push_instruction(Instruction::shl(Operand::imm(Width::_8, 15), ftz));
+        self.push_instruction(Instruction::or(ftz, rc));
+        self.push_instruction(Instruction::shl(Operand::imm(Width::_8, 6), fz));
+        self.push_instruction(Instruction::or(fz, rc));
+
+        self.push_instruction(Instruction::mov(rc, Self::mxcsr_scratch()).unwrap());
+        self.push_instruction(Instruction::ldmxcsr(Self::mxcsr_scratch()));
+
+        self.fp_mode_loaded = true;
+    }
+
+    /// Loads `MXCSR` with a fixed rounding mode, ignoring `FPCR`
+    fn load_rounding_mode(&mut self, rc: u64) {
+        let mxcsr = Operand::vreg(Width::_32, self.next_vreg());
+        self.push_instruction(
+            Instruction::mov(
+                Operand::imm(Width::_32, MXCSR_DEFAULT | (rc << MXCSR_RC_SHIFT)),
+                mxcsr,
+            )
+            .unwrap(),
+        );
+        self.push_instruction(Instruction::mov(mxcsr, Self::mxcsr_scratch()).unwrap());
+        self.push_instruction(Instruction::ldmxcsr(Self::mxcsr_scratch()));
+
+        // FPCR needs to be reloaded before the next operation
+        self.fp_mode_loaded = false;
+    }
+
+    /// ORs the exception flags raised since `MXCSR` was last loaded into the
+    /// guest `FPSR`, then clears them so they are not accumulated again after
+    /// the guest clears `FPSR`
+    fn accumulate_fp_exceptions(&mut self) {
+        self.push_instruction(Instruction::stmxcsr(Self::mxcsr_scratch()));
+
+        let mxcsr = Operand::vreg(Width::_32, self.next_vreg());
+        self.push_instruction(Instruction::mov(Self::mxcsr_scratch(), mxcsr).unwrap());
+
+        // same as `mxcsr_to_fpsr`
+        let flags = Operand::vreg(Width::_32, self.next_vreg());
+        self.push_instruction(Instruction::mov(mxcsr, flags).unwrap());
+        self.push_instruction(Instruction::and(Operand::imm(Width::_32, 1), flags));
+
+        let arithmetic = Operand::vreg(Width::_32, self.next_vreg());
+        self.push_instruction(Instruction::mov(mxcsr, arithmetic).unwrap());
+        self.push_instruction(Instruction::shr(Operand::imm(Width::_8, 1), arithmetic));
+        self.push_instruction(Instruction::and(Operand::imm(Width::_32, 0x1e), arithmetic));
+        self.push_instruction(Instruction::or(arithmetic, flags));
+
+        let denormal = Operand::vreg(Width::_32, self.next_vreg());
+        self.push_instruction(Instruction::mov(mxcsr, denormal).unwrap());
+        self.push_instruction(Instruction::and(Operand::imm(Width::_32, 0b10), denormal));
+        self.push_instruction(Instruction::shl(Operand::imm(Width::_8, 6), denormal));
+        self.push_instruction(Instruction::or(denormal, flags));
+
+        let fpsr = Operand::vreg(Width::_32, self.next_vreg());
+        let fpsr_register = Self::guest_register_operand(self.ctx().fpsr_offset);
+        self.push_instruction(Instruction::mov(fpsr_register, fpsr).unwrap());
+        self.push_instruction(Instruction::or(flags, fpsr));
+        self.push_instruction(Instruction::mov(fpsr, fpsr_register).unwrap());
+
+        self.push_instruction(Instruction::and(
+            Operand::imm(Width::_32, 0xffff & !MXCSR_FLAGS_MASK),
+            mxcsr,
+        ));
+        self.push_instruction(Instruction::mov(mxcsr, Self::mxcsr_scratch()).unwrap());
+        self.push_instruction(Instruction::ldmxcsr(Self::mxcsr_scratch()));
+    }
+
+    /// Moves a floating point value into a fresh SSE register
+    fn to_xmm(&mut self, node: &X86NodeRef<A>) -> Operand<A> {
+        let value = self.to_operand_reg_promote(node);
+        let xmm = Operand::vxmm(value.width(), self.next_vreg());
+        self.push_instruction(Instruction::movq(value, xmm));
+        xmm
+    }
+
+    /// Moves a floating point value out of an SSE register
+    fn from_xmm(&mut self, xmm: Operand<A>) -> Operand<A> {
+        let dst = Operand::vreg(xmm.width(), self.next_vreg());
+        self.push_instruction(Instruction::movq(xmm, dst));
+        dst
+    }
+
+    /// Copies a value held in an SSE register into a fresh one
+    fn copy_xmm(&mut self, xmm: Operand<A>) -> Operand<A> {
+        let value = self.from_xmm(xmm);
+        let copy = Operand::vxmm(xmm.width(), self.next_vreg());
+        self.push_instruction(Instruction::movq(value, copy));
+        copy
+    }
+
+    pub(super) fn float_binary_operation_to_operand(
+        &mut self,
+        kind: &BinaryOperationKind<A>,
+    ) -> Operand<A> {
+        use BinaryOperationKind::*;
+
+        match kind {
+            Add(left, right) | Sub(left, right) | Multiply(left, right) | Divide(left, right) => {
+                assert_eq!(left.typ(), right.typ());
+
+                let dst = self.to_xmm(left);
+                let right = self.to_xmm(right);
+
+                self.load_fp_mode();
+                self.push_instruction(match kind {
+                    Add(..) => Instruction::fadd(right, dst),
+                    Sub(..) => Instruction::fsub(right, dst),
+                    Multiply(..) => Instruction::fmul(right, dst),
+                    Divide(..) => Instruction::fdiv(right, dst),
+                    _ => unreachable!(),
+                });
+                self.accumulate_fp_exceptions();
+
+                self.from_xmm(dst)
+            }
+            CompareEqual(left, right)
+            | CompareNotEqual(left, right)
+            | CompareGreaterThan(left, right)
+            | CompareGreaterThanOrEqual(left, right) => self.float_compare(kind, left, right),
+            // swapped so only the above (unordered is false) conditions are needed
+            CompareLessThan(left, right) | CompareLessThanOrEqual(left, right) => {
+                self.float_compare(kind, right, left)
+            }
+            // bit patterns are held in general purpose registers
+            And(left, right) | Or(left, right) | Xor(left, right) => {
+                assert_eq!(left.typ(), right.typ());
+
+                let width = Width::from_uncanonicalized(left.typ().width()).unwrap();
+                let left = self.to_operand(left);
+                let right = self.to_operand_reg_promote(right);
+                let dst = Operand::vreg(width, self.next_vreg());
+                self.push_instruction(Instruction::mov(left, dst).unwrap());
+                self.push_instruction(match kind {
+                    And(..) => Instruction::and(right, dst),
+                    Or(..) => Instruction::or(right, dst),
+                    Xor(..) => Instruction::xor(right, dst),
+                    _ => unreachable!(),
+                });
+
+                dst
+            }
+            Modulo(left, right) => {
+                assert_eq!(left.typ(), right.typ());
+
+                let helper = if left.typ().width() == 32 {
+                    float_helpers::remainder_f32
+                } else {
+                    float_helpers::remainder_f64
+                };
+                self.float_helper_call(helper as u64, &[left, right], left.typ())
+            }
+            PowI(base, exponent) => {
+                let helper = if base.typ().width() == 32 {
+                    float_helpers::powi_f32
+                } else {
+                    float_helpers::powi_f64
+                };
+                self.float_helper_call(helper as u64, &[base, exponent], base.typ())
+            }
+        }
+    }
+
+    /// Calls the host `helper` with the bit patterns of `arguments` extended to
+    /// 64 bits and the guest floating point environment, returning the bit
+    /// pattern of its result of type `typ`
+    fn float_helper_call(
+        &mut self,
+        helper: u64,
+        arguments: &[&X86NodeRef<A>],
+        typ: &Type,
+    ) -> Operand<A> {
+        let arguments = arguments
+            .iter()
+            .map(|argument| {
+                let value = self.to_operand_reg_promote(argument);
+                if value.width() == Width::_64 {
+                    return value;
+                }
+
+                let wide = Operand::vreg(Width::_64, self.next_vreg());
+                self.push_instruction(if let Type::Signed(_) = argument.typ() {
+                    Instruction::movsx(value, wide)
+                } else {
+                    Instruction::movzx(value, wide)
+                });
+                wide
+            })
+            .collect::<Vec<_>>();
+
+        // followed by the guest `FPCR` and a pointer to the guest `FPSR`
+        let fpcr_register = Self::guest_register_operand(self.ctx().fpcr_offset);
+        let fpcr_value = Operand::vreg(Width::_32, self.next_vreg());
+        self.push_instruction(Instruction::mov(fpcr_register, fpcr_value).unwrap());
+        let fpcr = Operand::vreg(Width::_64, self.next_vreg());
+        self.push_instruction(Instruction::movzx(fpcr_value, fpcr));
+
+        let fpsr = Operand::vreg(Width::_64, self.next_vreg());
+        self.push_instruction(Instruction::lea(
+            Self::guest_register_operand(self.ctx().fpsr_offset).with_width(Width::_64),
+            fpsr,
+        ));
+
+        let arguments = arguments
+            .into_iter()
+            .chain([fpcr, fpsr])
+            .collect::<Vec<_>>();
+
+        let function = Operand::vreg(Width::_64, self.next_vreg());
+        self.push_instruction(Instruction::movrel(
+            Symbol::function(helper),
+            helper,
+            function,
+        ));
+
+        for (argument, register) in arguments.iter().zip(ARG_REGS) {
+            self.push_instruction(
+                Instruction::mov(*argument, Operand::preg(Width::_64, *register)).unwrap(),
+            );
+        }
+        self.push_instruction(Instruction::call(function, arguments.len(), 1));
+
+        let width = Width::from_uncanonicalized(typ.width()).unwrap();
+        let dst = Operand::vreg(width, self.next_vreg());
+        self.push_instruction(
+            Instruction::mov(Operand::preg(width, PhysicalRegister::RAX), dst).unwrap(),
+        );
+
+        dst
+    }
+
+    /// Compares `left` with `right`, with less than comparisons already
+    /// swapped to greater than
+    fn float_compare(
+        &mut self,
+        kind: &BinaryOperationKind<A>,
+        left: &X86NodeRef<A>,
+        right: &X86NodeRef<A>,
+    ) -> Operand<A> {
+        use BinaryOperationKind::*;
+
+        assert_eq!(left.typ(), right.typ());
+
+        let left = self.to_xmm(left);
+        let right = self.to_xmm(right);
+
+        self.load_fp_mode();
+        self.push_instruction(Instruction::ucomis(right, left));
+
+        // unordered sets ZF, PF and CF
+        let dst = Operand::vreg(Width::_8, self.next_vreg());
+        match kind {
+            CompareEqual(..) => {
+                let ordered = Operand::vreg(Width::_8, self.next_vreg());
+                self.push_instruction(Instruction::sete(dst));
+                self.push_instruction(Instruction::setnp(ordered));
+                self.push_instruction(Instruction::and(ordered, dst));
+            }
+            CompareNotEqual(..) => {
+                let unordered = Operand::vreg(Width::_8, self.next_vreg());
+                self.push_instruction(Instruction::setne(dst));
+                self.push_instruction(Instruction::setp(unordered));
+                self.push_instruction(Instruction::or(unordered, dst));
+            }
+            CompareGreaterThan(..) | CompareLessThan(..) => {
+                self.push_instruction(Instruction::seta(dst))
+            }
+            CompareGreaterThanOrEqual(..) | CompareLessThanOrEqual(..) => {
+                self.push_instruction(Instruction::setae(dst))
+            }
+            _ => panic!("{kind:?} is not a compare"),
+        }
+
+        self.accumulate_fp_exceptions();
+
+        dst
+    }
+
+    pub(super) fn float_unary_operation_to_operand(
+        &mut self,
+        kind: &UnaryOperationKind<A>,
+    ) -> Operand<A> {
+        use UnaryOperationKind::*;
+
+        match kind {
+            SquareRoot(value) => {
+                let value = self.to_xmm(value);
+                let dst = Operand::vxmm(value.width(), self.next_vreg());
+
+                self.load_fp_mode();
+                self.push_instruction(Instruction::fsqrt(value, dst));
+                self.accumulate_fp_exceptions();
+
+                self.from_xmm(dst)
+            }
+            // only the sign bit changes, without raising any exceptions
+            Negate(value) | Absolute(value) => {
+                let width = Width::from_uncanonicalized(value.typ().width()).unwrap();
+                let sign = 1u64 << (value.typ().width() - 1);
+
+                let value = self.to_operand(value);
+                let dst = Operand::vreg(width, self.next_vreg());
+                self.push_instruction(Instruction::mov(value, dst).unwrap());
+
+                let mask = Operand::vreg(width, self.next_vreg());
+                if let Negate(_) = kind {
+                    self.push_instruction(
+                        Instruction::mov(Operand::imm(width, sign), mask).unwrap(),
+                    );
+                    self.push_instruction(Instruction::xor(mask, dst));
+                } else {
+                    self.push_instruction(
+                        Instruction::mov(Operand::imm(width, sign - 1), mask).unwrap(),
+                    );
+                    self.push_instruction(Instruction::and(mask, dst));
+                }
+
+                dst
+            }
+            Floor(value) | Ceil(value) => {
+                let value = self.to_xmm(value);
+                let dst = Operand::vreg(Width::_64, self.next_vreg());
+
+                self.load_rounding_mode(if let Floor(_) = kind {
+                    MXCSR_RC_DOWN
+                } else {
+                    MXCSR_RC_UP
+                });
+                self.push_instruction(Instruction::cvtf2si(value, dst));
+                self.accumulate_fp_exceptions();
+
+                dst
+            }
+            // bit patterns are held in general purpose registers
+            Complement(value) => {
+                let width = Width::from_uncanonicalized(value.typ().width()).unwrap();
+                let value = self.to_operand(value);
+                let dst = Operand::vreg(width, self.next_vreg());
+                self.push_instruction(Instruction::mov(value, dst).unwrap());
+                self.push_instruction(Instruction::not(dst));
+
+                dst
+            }
+            Not(value) => {
+                let width = Width::from_uncanonicalized(value.typ().width()).unwrap();
+                let value = self.to_operand_reg_promote(value);
+                let dst = Operand::vreg(width, self.next_vreg());
+
+                self.push_instruction(Instruction::cmp(Operand::imm(width, 0), value));
+                self.push_instruction(Instruction::sete(dst));
+                self.push_instruction(Instruction::and(Operand::imm(width, 1), dst));
+
+                dst
+            }
+            Power2(value) => {
+                let helper = if value.typ().width() == 32 {
+                    float_helpers::exp2_f32
+                } else {
+                    float_helpers::exp2_f64
+                };
+                self.float_helper_call(helper as u64, &[value], value.typ())
+            }
+        }
+    }
+
+    pub(super) fn float_cast_to_operand(
+        &mut self,
+        value: &X86NodeRef<A>,
+        target: Type,
+        kind: &CastOperationKind,
+    ) -> Operand<A> {
+        let cast = FloatCast::classify(value.typ(), &target, kind);
+
+        match (cast, *value.typ(), target) {
+            (Some(FloatCast::Precision), Type::Floating(_), Type::Floating(width)) => {
+                let value = self.to_xmm(value);
+                let dst = Operand::vxmm(
+                    Width::from_uncanonicalized(width).unwrap(),
+                    self.next_vreg(),
+                );
+
+                self.load_fp_mode();
+                self.push_instruction(Instruction::cvtf2f(value, dst));
+                self.accumulate_fp_exceptions();
+
+                self.from_xmm(dst)
+            }
+            (
+                Some(FloatCast::Numeric),
+                Type::Signed(_) | Type::Unsigned(_) | Type::Bits,
+                Type::Floating(width),
+            ) => self.int_to_float(value, Width::from_uncanonicalized(width).unwrap()),
+            // bitvectors are unsigned and held in 64 bits
+            (Some(FloatCast::Numeric), Type::Floating(_), Type::Bits) => {
+                self.float_to_int(value, false)
+            }
+            (
+                Some(FloatCast::Numeric),
+                Type::Floating(_),
+                Type::Signed(width) | Type::Unsigned(width),
+            ) => {
+                let converted = self.float_to_int(value, matches!(target, Type::Signed(_)));
+
+                // truncate to the width of the target
+                let mut src = converted;
+                src.width_in_bits = Width::from_uncanonicalized(width).unwrap();
+                if src.width() == converted.width() {
+                    converted
+                } else {
+                    let dst = Operand::vreg(src.width(), self.next_vreg());
+                    self.push_instruction(Instruction::mov(src, dst).unwrap());
+                    dst
+                }
+            }
+            (_, source, target) => {
+                panic!("{kind:?} from {source:?} to {target:?} is not a floating point cast")
+            }
+        }
+    }
+
+    fn int_to_float(&mut self, value: &X86NodeRef<A>, width: Width) -> Operand<A> {
+        let typ = match value.typ() {
+            Type::Bits => Type::Unsigned(64),
+            typ => *typ,
+        };
+        let src = self.to_operand_reg_promote(value);
+
+        // widen to a register size the conversion accepts, unsigned values below 64
+        // bits always fit in a signed 64-bit integer
+        let src = match (typ, src.width()) {
+            (Type::Signed(_), Width::_32 | Width::_64) | (Type::Unsigned(64), _) => src,
+            (Type::Signed(_), _) => {
+                let wide = Operand::vreg(Width::_32, self.next_vreg());
+                self.push_instruction(Instruction::movsx(src, wide));
+                wide
+            }
+            (_, Width::_64) => src,
+            _ => {
+                let wide = Operand::vreg(Width::_64, self.next_vreg());
+                self.push_instruction(Instruction::movzx(src, wide));
+                wide
+            }
+        };
+
+        if typ != Type::Unsigned(64) {
+            let dst = Operand::vxmm(width, self.next_vreg());
+            self.load_fp_mode();
+            self.push_instruction(Instruction::cvtsi2f(src, dst));
+            self.accumulate_fp_exceptions();
+            return self.from_xmm(dst);
+        }
+
+        // values with the top bit set are halved, keeping the lowest bit so the result
+        // rounds the same way, converted, then doubled
+        let is_large = Operand::vreg(Width::_64, self.next_vreg());
+        self.push_instruction(Instruction::mov(src, is_large).unwrap());
+        self.push_instruction(Instruction::shr(Operand::imm(Width::_8, 63), is_large));
+
+        let halved = Operand::vreg(Width::_64, self.next_vreg());
+        let lowest = Operand::vreg(Width::_64, self.next_vreg());
+        self.push_instruction(Instruction::mov(src, halved).unwrap());
+        self.push_instruction(Instruction::shr(Operand::imm(Width::_8, 1), halved));
+        self.push_instruction(Instruction::mov(src, lowest).unwrap());
+        self.push_instruction(Instruction::and(Operand::imm(Width::_64, 1), lowest));
+        self.push_instruction(Instruction::or(lowest, halved));
+
+        let input = Operand::vreg(Width::_64, self.next_vreg());
+        self.push_instruction(Instruction::mov(src, input).unwrap());
+        self.push_instruction(Instruction::test(is_large, is_large));
+        self.push_instruction(Instruction::cmovne(halved, input));
+
+        let converted = Operand::vxmm(width, self.next_vreg());
+        self.load_fp_mode();
+        self.push_instruction(Instruction::cvtsi2f(input, converted));
+        self.accumulate_fp_exceptions();
+
+        // doubling is exact so raises no exceptions
+        let doubled = self.copy_xmm(converted);
+        self.push_instruction(Instruction::fadd(doubled, doubled));
+
+        let dst = self.from_xmm(converted);
+        let doubled = self.from_xmm(doubled);
+        self.push_instruction(Instruction::test(is_large, is_large));
+        self.push_instruction(Instruction::cmovne(doubled, dst));
+
+        dst
+    }
+
+    /// Converts a floating point value to a 64-bit integer, rounding according
+    /// to `FPCR`
+    fn float_to_int(&mut self, value: &X86NodeRef<A>, signed: bool) -> Operand<A> {
+        let value_width = Width::from_uncanonicalized(value.typ().width()).unwrap();
+        let value = self.to_xmm(value);
+        let dst = Operand::vreg(Width::_64, self.next_vreg());
+
+        if signed {
+            self.load_fp_mode();
+            self.push_instruction(Instruction::cvtf2si(value, dst));
+            self.accumulate_fp_exceptions();
+            return dst;
+        }
+
+        // values of at least 2^63 have 2^63 subtracted (exactly) before the signed
+        // conversion, and the top bit set afterwards
+        let limit_bits = if value_width == Width::_32 {
+            u64::from(LIMIT_F32)
+        } else {
+            LIMIT_F64
+        };
+        let limit = Operand::vreg(value_width, self.next_vreg());
+        self.push_instruction(
+            Instruction::mov(Operand::imm(value_width, limit_bits), limit).unwrap(),
+        );
+        let limit_xmm = Operand::vxmm(value_width, self.next_vreg());
+        self.push_instruction(Instruction::movq(limit, limit_xmm));
+
+        self.load_fp_mode();
+
+        // unordered clears this, leaving NaNs to the signed conversion
+        let is_large = Operand::vreg(Width::_8, self.next_vreg());
+        self.push_instruction(Instruction::ucomis(limit_xmm, value));
+        self.push_instruction(Instruction::setae(is_large));
+
+        let offset = Operand::vreg(value_width, self.next_vreg());
+        self.push_instruction(Instruction::mov(Operand::imm(value_width, 0), offset).unwrap());
+        let is_large_wide = Operand::vreg(Width::_64, self.next_vreg());
+        self.push_instruction(Instruction::movzx(is_large, is_large_wide));
+        self.push_instruction(Instruction::test(is_large_wide, is_large_wide));
+        self.push_instruction(Instruction::cmovne(limit, offset));
+
+        let offset_xmm = Operand::vxmm(value_width, self.next_vreg());
+        self.push_instruction(Instruction::movq(offset, offset_xmm));
+        let value = self.copy_xmm(value);
+        self.push_instruction(Instruction::fsub(offset_xmm, value));
+        self.push_instruction(Instruction::cvtf2si(value, dst));
+        self.accumulate_fp_exceptions();
+
+        self.push_instruction(Instruction::shl(Operand::imm(Width::_8, 63), is_large_wide));
+        self.push_instruction(Instruction::xor(is_large_wide, dst));
+
+        dst
+    }
+}
+
+#[ktest]
+fn fpcr_rounding_modes() {
+    assert_eq!(fpcr_to_mxcsr(0), MXCSR_DEFAULT);
+    assert_eq!(
+        fpcr_to_mxcsr(0b01 << 22),
+        MXCSR_DEFAULT | (MXCSR_RC_UP << MXCSR_RC_SHIFT)
+    );
+    assert_eq!(
+        fpcr_to_mxcsr(0b10 << 22),
+        MXCSR_DEFAULT | (MXCSR_RC_DOWN << MXCSR_RC_SHIFT)
+    );
+    assert_eq!(
+        fpcr_to_mxcsr(0b11 << 22),
+        MXCSR_DEFAULT | (0b11 << MXCSR_RC_SHIFT)
+    );
+}
+
+#[ktest]
+fn fpcr_flush_to_zero() {
+    assert_eq!(fpcr_to_mxcsr(1 << 24), MXCSR_DEFAULT | (1 << 15) | (1 << 6));
+}
+
+#[ktest]
+fn mxcsr_exception_flags() {
+    assert_eq!(mxcsr_to_fpsr(MXCSR_DEFAULT), 0);
+    assert_eq!(mxcsr_to_fpsr(1 << 2), 1 << 1);
+    assert_eq!(mxcsr_to_fpsr(1 << 1), 1 << 7);
+    assert_eq!(mxcsr_to_fpsr(MXCSR_FLAGS_MASK), 0x9f);
+}
@@ -0,0 +1,56 @@
+//! Store-to-load forwarding of guest registers
+//!
+//! Within a block, the node last written to or read from each guest register is
+//! remembered, so that later reads of the register reuse it rather than loading
+//! from the register file again.
+
+use {
+    crate::host::dbt::{Alloc, emitter::Type, x86::emitter::X86NodeRef},
+    common::hashmap::HashMap,
+};
+
+/// Guest register values known since the start of the current block, by offset
+pub(super) struct KnownRegisters<A: Alloc> {
+    values: HashMap<u64, X86NodeRef<A>>,
+}
+
+impl<A: Alloc> KnownRegisters<A> {
+    pub fn new() -> Self {
+        Self {
+            values: HashMap::default(),
+        }
+    }
+
+    /// Node holding the `typ` register at `offset`, if known
+    pub fn get(&self, offset: u64, typ: &Type) -> Option<X86NodeRef<A>> {
+        self.values
+            .get(&offset)
+            .filter(|value| value.typ() == typ)
+            .cloned()
+    }
+
+    /// Records `value` as held by the register at `offset`, forgetting any
+    /// registers it overlaps
+    pub fn insert(&mut self, offset: u64, value: X86NodeRef<A>) {
+        self.forget(offset, value.typ().width());
+        self.values.insert(offset, value);
+    }
+
+    /// Forgets any registers overlapping the `width`-bit register at `offset`
+    pub fn forget(&mut self, offset: u64, width: u16) {
+        let end = offset + size(width);
+
+        self.values.retain(|known_offset, value| {
+            known_offset + size(value.typ().width()) <= offset || *known_offset >= end
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+}
+
+/// Bytes of the register file occupied by a `width`-bit register
+fn size(width: u16) -> u64 {
+    u64::from(width.div_ceil(u8::BITS as u16)).next_power_of_two()
+}
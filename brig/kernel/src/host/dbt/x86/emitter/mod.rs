@@ -3,8 +3,11 @@ use {
         guest::GuestExecutionContext,
         host::dbt::{
             Alloc, bit_extract, bit_insert,
+            chain::CHAIN_SLOT_COUNT,
             emitter::Type,
             models::CHAIN_CACHE_ENTRY_COUNT,
+            persist::Symbol,
+            softmmu::MemoryAccessKind,
             trampoline::ExecutionResult,
             x86::{
                 Emitter, X86TranslationContext,
@@ -29,14 +32,42 @@ use {
     proc_macro_lib::ktest,
 };
 
+mod cse;
+mod flags;
+mod float;
+mod forwarding;
+mod register_cache;
 mod to_operand;
+mod vector;
+mod wide;
+
+pub use vector::VectorOperationKind;
+
+use flags::{Condition, Flag};
 
 const INVALID_OFFSET: i32 = 0xDEAD00F;
 
+/// Replace pure nodes with equivalent ones built earlier in the same block
+const COMMON_SUBEXPRESSION_ELIMINATION: bool = true;
+
+/// Reuse the value last written to or read from a guest register in the same
+/// block rather than reading it again
+const STORE_TO_LOAD_FORWARDING: bool = true;
+
+/// Keep the guest NZCV flags in the host `EFLAGS` until they are needed, rather
+/// than storing them to the register file as soon as they are written
+const LAZY_FLAGS: bool = true;
+
+/// Keep the general purpose registers, stack pointers and the PC in host
+/// registers once read or written until the block is left, rather than loading
+/// and storing them on every access
+const REGISTER_CACHE: bool = true;
+
 pub const ARG_REGS: &[PhysicalRegister] = &[
     PhysicalRegister::RDI,
     PhysicalRegister::RSI,
     PhysicalRegister::RDX,
+    PhysicalRegister::RCX,
 ];
 
 /// X86 emitter error
@@ -53,6 +84,16 @@ pub struct X86Emitter<'ctx, A: Alloc> {
     current_block_operands: HashMap<X86NodeRef<A>, Operand<A>>,
     panic_block: Ref<X86Block<A>>,
     next_vreg: usize,
+    /// Whether `MXCSR` has been loaded from `FPCR` earlier in the current block
+    fp_mode_loaded: bool,
+    /// Elements of 64 and 128-bit values produced by `bit_extract`s
+    elements: HashMap<X86NodeRef<A>, vector::Element<A>>,
+    /// Results of `bit_insert`s that began a lane-wise operation
+    partial_lanewise: HashMap<X86NodeRef<A>, vector::Lanewise<A>>,
+    common_nodes: cse::CommonNodes<A>,
+    known_registers: forwarding::KnownRegisters<A>,
+    flags: flags::LazyFlags<A>,
+    register_cache: register_cache::RegisterCache<A>,
     pub execution_result: ExecutionResult,
     ctx: &'ctx mut X86TranslationContext<A>,
 }
@@ -64,6 +105,13 @@ impl<'a, 'ctx, A: Alloc> X86Emitter<'ctx, A> {
             current_block_operands: HashMap::default(),
             panic_block: ctx.panic_block(),
             next_vreg: 0,
+            fp_mode_loaded: false,
+            elements: HashMap::default(),
+            partial_lanewise: HashMap::default(),
+            common_nodes: cse::CommonNodes::new(),
+            known_registers: forwarding::KnownRegisters::new(),
+            flags: flags::LazyFlags::new(),
+            register_cache: register_cache::RegisterCache::new(),
             execution_result: ExecutionResult::new(),
             ctx,
         }
@@ -78,7 +126,13 @@ impl<'a, 'ctx, A: Alloc> X86Emitter<'ctx, A> {
     }
 
     pub fn node(&self, node: X86Node<A>) -> X86NodeRef<A> {
-        X86NodeRef(Rc::new_in(node, self.ctx().allocator.clone()))
+        let create = |node: X86Node<A>| X86NodeRef(Rc::new_in(node, self.ctx().allocator.clone()));
+
+        if COMMON_SUBEXPRESSION_ELIMINATION {
+            self.common_nodes.get_or_insert(node, create)
+        } else {
+            create(node)
+        }
     }
 
     pub fn next_vreg(&mut self) -> usize {
@@ -88,6 +142,16 @@ impl<'a, 'ctx, A: Alloc> X86Emitter<'ctx, A> {
     }
 
     pub fn push_instruction(&mut self, instr: Instruction<A>) {
+        if self.flags.operation().is_some() && !flags::preserved_by(&instr) {
+            // store the deferred flags while EFLAGS still hold them
+            self.materialise_flags();
+            self.flags.clear();
+        }
+
+        if register_cache::written_back_before(&instr) {
+            self.write_back_registers();
+        }
+
         self.current_block
             .get_mut(self.ctx.arena_mut())
             .append(instr);
@@ -100,6 +164,269 @@ impl<'a, 'ctx, A: Alloc> X86Emitter<'ctx, A> {
             .push_next(target);
     }
 
+    /// Increments the 64-bit counter at `address` every time the code runs
+    pub fn count_execution(&mut self, address: u64) {
+        let address_reg = Operand::vreg(Width::_64, self.next_vreg());
+        self.push_instruction(Instruction::movrel(
+            Symbol::ExecutionCounter,
+            address,
+            address_reg,
+        ));
+        self.push_instruction(Instruction::add(
+            Operand::imm(Width::_64, 1),
+            Operand::mem_base_displ(Width::_64, address_reg.as_register().unwrap(), 0),
+        ));
+    }
+
+    /// Deducts `count` executed guest instructions from the instruction budget
+    /// of the execution context, leaving the translation once it has run out
+    pub fn consume_instruction_budget(&mut self, count: u64) {
+        let budget = Operand::mem_seg_displ(
+            64,
+            super::encoder::SegmentRegister::FS,
+            i32::try_from(offset_of!(GuestExecutionContext, instruction_budget)).unwrap(),
+        );
+
+        self.push_instruction(Instruction::sub(Operand::imm(Width::_64, count), budget));
+
+        // out of budget once it is no longer positive, so test the sign of one less
+        let remaining = Operand::vreg(Width::_64, self.next_vreg());
+        self.push_instruction(Instruction::mov(budget, remaining).unwrap());
+        self.push_instruction(Instruction::sub(Operand::imm(Width::_64, 1), remaining));
+        self.push_instruction(Instruction::shr(Operand::imm(Width::_8, 63), remaining));
+        self.push_instruction(Instruction::test(remaining, remaining));
+
+        let exhausted = self.ctx_mut().create_block();
+        self.push_instruction(Instruction::jne(exhausted));
+        self.push_target(exhausted);
+
+        let current = self.get_current_block();
+        self.set_current_block(exhausted);
+        self.leave();
+        self.set_current_block(current);
+    }
+
+    /// Records the TLB maintenance instruction `opcode` and the value of its
+    /// register operand in the execution context
+    pub fn record_tlbi(&mut self, opcode: u32, operand: X86NodeRef<A>) {
+        let operand = self.to_operand_reg_promote(&operand);
+
+        let opcode_reg = Operand::vreg(Width::_32, self.next_vreg());
+        self.push_instruction(
+            Instruction::mov(Operand::imm(Width::_32, u64::from(opcode)), opcode_reg).unwrap(),
+        );
+        self.push_instruction(
+            Instruction::mov(
+                opcode_reg,
+                Operand::mem_seg_displ(
+                    32,
+                    super::encoder::SegmentRegister::FS,
+                    i32::try_from(offset_of!(GuestExecutionContext, tlbi_opcode)).unwrap(),
+                ),
+            )
+            .unwrap(),
+        );
+        self.push_instruction(
+            Instruction::mov(
+                operand,
+                Operand::mem_seg_displ(
+                    64,
+                    super::encoder::SegmentRegister::FS,
+                    i32::try_from(offset_of!(GuestExecutionContext, tlbi_operand)).unwrap(),
+                ),
+            )
+            .unwrap(),
+        );
+    }
+
+    /// Continues to `next` unless an interrupt is pending for the execution
+    /// context, in which case to `interrupted`
+    pub fn poll_interrupts(&mut self, next: Ref<X86Block<A>>, interrupted: Ref<X86Block<A>>) {
+        let pending = Operand::vreg(Width::_32, self.next_vreg());
+        self.push_instruction(
+            Instruction::mov(
+                Operand::mem_seg_displ(
+                    32,
+                    super::encoder::SegmentRegister::FS,
+                    i32::try_from(offset_of!(GuestExecutionContext, interrupt_pending)).unwrap(),
+                ),
+                pending,
+            )
+            .unwrap(),
+        );
+        self.push_instruction(Instruction::test(pending, pending));
+
+        self.push_instruction(Instruction::jne(interrupted));
+        self.push_target(interrupted);
+
+        self.push_instruction(Instruction::jmp(next));
+        self.push_target(next);
+    }
+
+    /// Continues to `on_trace` if the guest PC is `expected_pc`, otherwise to
+    /// `side_exit`
+    pub fn guard_pc(
+        &mut self,
+        expected_pc: u64,
+        on_trace: Ref<X86Block<A>>,
+        side_exit: Ref<X86Block<A>>,
+    ) {
+        // the PC is read from the register file
+        self.write_back_registers();
+
+        let pc = Operand::vreg(Width::_64, self.next_vreg());
+        self.push_instruction(
+            Instruction::mov(
+                Operand::mem_base_displ(
+                    Width::_64,
+                    Register::PhysicalRegister(PhysicalRegister::RBP),
+                    self.ctx().pc_offset() as i32,
+                ),
+                pc,
+            )
+            .unwrap(),
+        );
+
+        // guest PCs do not fit in a sign-extended 32-bit immediate
+        let expected = Operand::vreg(Width::_64, self.next_vreg());
+        self.push_instruction(
+            Instruction::mov(Operand::imm(Width::_64, expected_pc), expected).unwrap(),
+        );
+
+        self.push_instruction(Instruction::cmp(expected, pc));
+
+        self.push_instruction(Instruction::jne(side_exit));
+        self.push_target(side_exit);
+
+        self.push_instruction(Instruction::jmp(on_trace));
+        self.push_target(on_trace);
+    }
+
+    /// Whether reads of the register at `offset` can reuse a known value
+    fn forwards(&self, offset: u64) -> bool {
+        // FPSR is also written when floating point operations are lowered
+        STORE_TO_LOAD_FORWARDING && offset != self.ctx().fpsr_offset
+    }
+
+    /// Whether the `width`-bit register at `offset` can be held in a host
+    /// register until the block is left
+    fn caches(&self, offset: u64, width: Width) -> bool {
+        REGISTER_CACHE
+            && width == Width::_64
+            && self.forwards(offset)
+            && self.ctx().cached_registers.contains(&offset)
+    }
+
+    /// Stores the cached registers written since they were last stored
+    fn write_back_registers(&mut self) {
+        for (offset, value) in self.register_cache.write_back() {
+            self.store_register(offset, value);
+        }
+    }
+
+    /// Reads the cached register at `offset`, loading it into a host register
+    /// on first use
+    fn read_cached_register(&mut self, offset: u64, typ: Type) -> X86NodeRef<A> {
+        let operand = match self.register_cache.get(offset) {
+            Some(operand) if matches!(operand.kind(), OperandKind::Register(_)) => operand,
+            cached => {
+                // an immediate written to the register is moved into one too
+                let src = cached.unwrap_or_else(|| {
+                    Operand::mem_base_displ(
+                        Width::_64,
+                        Register::PhysicalRegister(PhysicalRegister::RBP),
+                        offset.try_into().unwrap(),
+                    )
+                });
+                let dst = Operand::vreg(Width::_64, self.next_vreg());
+                self.push_instruction(Instruction::mov(src, dst).unwrap());
+
+                if let Some((evicted_offset, evicted)) = self.register_cache.hold(offset, dst) {
+                    self.store_register(evicted_offset, evicted);
+                }
+
+                dst
+            }
+        };
+
+        let value = self.node(X86Node {
+            typ,
+            kind: NodeKind::GuestRegister { offset },
+        });
+
+        self.current_block_operands.insert(value.clone(), operand);
+        self.known_registers.insert(offset, value.clone());
+
+        value
+    }
+
+    fn store_register(&mut self, offset: u64, value: Operand<A>) {
+        self.push_instruction(
+            Instruction::mov(
+                value,
+                Operand::mem_base_displ(
+                    value.width(),
+                    Register::PhysicalRegister(PhysicalRegister::RBP),
+                    offset.try_into().unwrap(),
+                ),
+            )
+            .unwrap(),
+        );
+    }
+
+    /// Guest flag held by the register at `offset`, if any
+    fn flag_at(&self, offset: u64) -> Option<Flag> {
+        Flag::ALL
+            .into_iter()
+            .find(|flag| self.flag_offset(*flag) == offset)
+    }
+
+    fn flag_offset(&self, flag: Flag) -> u64 {
+        match flag {
+            Flag::N => self.ctx().n_offset,
+            Flag::Z => self.ctx().z_offset,
+            Flag::C => self.ctx().c_offset,
+            Flag::V => self.ctx().v_offset,
+        }
+    }
+
+    /// Flags within the `width`-bit register at `offset`
+    fn flags_within(&self, offset: u64, width: u16) -> impl Iterator<Item = Flag> {
+        let end = offset + u64::from(width.div_ceil(u8::BITS as u16));
+
+        Flag::ALL
+            .into_iter()
+            .filter(move |flag| (offset..end).contains(&self.flag_offset(*flag)))
+    }
+
+    /// Stores the flags whose writes were deferred to the register file
+    fn materialise_flags(&mut self) {
+        for flag in self.flags.take_pending() {
+            let dest = Operand::mem_base_displ(
+                Width::_8,
+                Register::PhysicalRegister(PhysicalRegister::RBP),
+                self.flag_offset(flag).try_into().unwrap(),
+            );
+
+            self.push_instruction(flag.set(dest));
+        }
+    }
+
+    /// Makes `EFLAGS` hold the flags of `operation`, lowering it again if they
+    /// have been overwritten since
+    fn hold_flags(&mut self, operation: &X86NodeRef<A>) {
+        if self.flags.holds(operation) {
+            return;
+        }
+
+        self.materialise_flags();
+
+        self.current_block_operands.remove(operation);
+        let _ = self.to_operand(operation);
+
+        self.flags.produced(operation.clone());
+    }
+
     fn emit_call(
         &mut self,
         function: X86NodeRef<A>,
@@ -108,6 +435,9 @@ impl<'a, 'ctx, A: Alloc> X86Emitter<'ctx, A> {
     ) {
         let function = self.to_operand_reg_promote(&function);
 
+        // the callee may access the register file
+        self.known_registers.clear();
+
         let arg_count = arguments.len();
 
         arguments
@@ -135,8 +465,16 @@ impl<'ctx, A: Alloc> Emitter<A> for X86Emitter<'ctx, A> {
     type BlockRef = Ref<X86Block<A>>;
 
     fn set_current_block(&mut self, block: Self::BlockRef) {
+        self.materialise_flags();
+        self.flags.clear();
+        self.write_back_registers();
+        self.register_cache.clear();
+
         self.current_block = block;
         self.current_block_operands = HashMap::default();
+        self.fp_mode_loaded = false;
+        self.common_nodes.clear();
+        self.known_registers.clear();
     }
 
     fn get_current_block(&self) -> Self::BlockRef {
@@ -183,15 +521,83 @@ impl<'ctx, A: Alloc> Emitter<A> for X86Emitter<'ctx, A> {
     }
 
     fn read_register(&mut self, offset: u64, typ: Type) -> Self::NodeRef {
-        self.node(X86Node {
+        if let Some(flag) = self
+            .flag_at(offset)
+            .filter(|flag| self.flags.is_flag_pending(*flag))
+        {
+            let node = self.node(X86Node {
+                typ,
+                kind: NodeKind::HostFlag {
+                    operation: self.flags.operation().unwrap().clone(),
+                    flag,
+                },
+            });
+
+            // lowered straight away, while EFLAGS still hold the flag
+            let _ = self.to_operand(&node);
+
+            return node;
+        }
+
+        if self
+            .flags_within(offset, typ.width())
+            .any(|flag| self.flags.is_flag_pending(flag))
+        {
+            self.materialise_flags();
+        }
+
+        if wide::is_wide(&typ) {
+            return self.read_wide_register(offset, typ);
+        }
+
+        if let Some(value) = self.known_registers.get(offset, &typ) {
+            return value;
+        }
+
+        if Width::from_uncanonicalized(typ.width()).is_ok_and(|width| self.caches(offset, width)) {
+            return self.read_cached_register(offset, typ);
+        }
+
+        // read from the register file, which must hold any cached value
+        if self.register_cache.overlaps_dirty(offset, typ.width()) {
+            self.write_back_registers();
+        }
+
+        let value = self.node(X86Node {
             typ,
             kind: NodeKind::GuestRegister { offset },
-        })
+        });
+
+        if self.forwards(offset) {
+            self.known_registers.insert(offset, value.clone());
+        }
+
+        value
     }
 
     fn unary_operation(&mut self, op: UnaryOperationKind<A>) -> Self::NodeRef {
         use UnaryOperationKind::*;
 
+        if let Some(value) = float::unary_operand(&op) {
+            let typ = match &op {
+                Ceil(_) | Floor(_) => Type::Signed(64),
+                _ => *value.typ(),
+            };
+
+            return self.node(X86Node {
+                typ,
+                kind: NodeKind::UnaryOperation(op),
+            });
+        }
+
+        if wide::is_wide_unary_operation(&op) {
+            return self.wide_unary_operation(op);
+        }
+
+        if vector::is_vector_unary_operation(&op) {
+            return self.vector_unary_operation(op);
+        }
+
         match &op {
             Not(value) => match value.kind() {
                 NodeKind::Constant {
@@ -321,6 +727,23 @@ impl<'ctx, A: Alloc> Emitter<A> for X86Emitter<'ctx, A> {
         //     }
         // }
 
+        // floating point operations are not folded, as the result depends on the guest
+        // rounding mode and raises exceptions
+        if let Some(typ) = float::binary_operation_type(&op) {
+            return self.node(X86Node {
+                typ,
+                kind: NodeKind::BinaryOperation(op),
+            });
+        }
+
+        if wide::is_wide_operation(&op) {
+            return self.wide_binary_operation(op);
+        }
+
+        if vector::is_vector_operation(&op) {
+            return self.vector_binary_operation(op);
+        }
+
         match &op {
             Add(lhs, rhs) => match (lhs.kind(), rhs.kind()) {
                 (
@@ -646,7 +1069,27 @@ impl<'ctx, A: Alloc> Emitter<A> for X86Emitter<'ctx, A> {
         target_type: Type,
         cast_kind: CastOperationKind,
     ) -> Self::NodeRef {
+        if float::FloatCast::classify(value.typ(), &target_type, &cast_kind).is_some() {
+            return self.node(X86Node {
+                typ: target_type,
+                kind: NodeKind::Cast {
+                    value,
+                    kind: cast_kind,
+                },
+            });
+        }
+
         match value.kind() {
+            _ if wide::is_wide(value.typ()) || wide::is_wide(&target_type) => {
+                self.wide_cast(value, target_type, cast_kind)
+            }
+            // constants only hold the low 64 bits of a 128-bit value
+            NodeKind::Constant { .. }
+                if cast_kind == CastOperationKind::SignExtend
+                    && vector::is_vector(&target_type) =>
+            {
+                self.vector_cast(value, target_type, cast_kind)
+            }
             NodeKind::Constant {
                 value: constant_value,
                 ..
@@ -684,6 +1127,9 @@ impl<'ctx, A: Alloc> Emitter<A> for X86Emitter<'ctx, A> {
 
                 self.constant(casted_value, target_type)
             }
+            _ if vector::is_vector(value.typ()) || vector::is_vector(&target_type) => {
+                self.vector_cast(value, target_type, cast_kind)
+            }
             _ => match cast_kind {
                 CastOperationKind::Reinterpret | CastOperationKind::Truncate => {
                     if *value.typ() == target_type {
@@ -715,6 +1161,14 @@ impl<'ctx, A: Alloc> Emitter<A> for X86Emitter<'ctx, A> {
         amount: Self::NodeRef,
         kind: ShiftOperationKind,
     ) -> Self::NodeRef {
+        if wide::is_wide(value.typ()) {
+            return self.wide_shift(value, amount, kind);
+        }
+
+        if vector::is_vector(value.typ()) {
+            return self.vector_shift(value, amount, kind);
+        }
+
         let typ = value.typ().clone();
         match (value.kind(), amount.kind(), kind.clone()) {
             (
@@ -797,18 +1251,30 @@ impl<'ctx, A: Alloc> Emitter<A> for X86Emitter<'ctx, A> {
         start: Self::NodeRef,
         length: Self::NodeRef,
     ) -> Self::NodeRef {
+        let element = vector::Element::of(&value, &start, &length);
+
         let typ = value.typ().clone();
-        match (value.kind(), start.kind(), length.kind()) {
+        let extracted = match (value.kind(), start.kind(), length.kind()) {
             // total constant
             (
                 NodeKind::Constant { value, .. },
                 NodeKind::Constant { value: start, .. },
                 NodeKind::Constant { value: length, .. },
-            ) => self.constant(
+            ) if !vector::is_vector(&typ) => self.constant(
                 bit_extract(*value, *start, *length),
                 Type::Unsigned(u16::try_from(*length).unwrap()),
             ),
 
+            (
+                _,
+                NodeKind::Constant { value: start, .. },
+                NodeKind::Constant { value: length, .. },
+            ) if vector::is_vector(&typ) => self.vector_bit_extract(value.clone(), *start, *length),
+
+            _ if wide::is_multiword(&typ) => {
+                self.wide_bit_extract(value.clone(), start.clone(), length.clone())
+            }
+
             // known start and length
             (
                 _,
@@ -850,7 +1316,13 @@ impl<'ctx, A: Alloc> Emitter<A> for X86Emitter<'ctx, A> {
                     length,
                 },
             }),
+        };
+
+        if let Some(element) = element {
+            self.elements.insert(extracted.clone(), element);
         }
+
+        extracted
     }
 
     fn bit_insert(
@@ -860,8 +1332,24 @@ impl<'ctx, A: Alloc> Emitter<A> for X86Emitter<'ctx, A> {
         start: Self::NodeRef,
         length: Self::NodeRef,
     ) -> Self::NodeRef {
+        let lanewise = self.lanewise_insert(&target, &source, &start, &length);
+
         let typ = target.typ().clone();
-        match (target.kind(), source.kind(), start.kind(), length.kind()) {
+        let inserted = match (target.kind(), source.kind(), start.kind(), length.kind()) {
+            (
+                _,
+                _,
+                NodeKind::Constant { value: start, .. },
+                NodeKind::Constant { value: length, .. },
+            ) if vector::is_vector(&typ) => {
+                self.vector_bit_insert(target.clone(), source.clone(), *start, *length)
+            }
+            _ if wide::is_multiword(&typ) => self.wide_bit_insert(
+                target.clone(),
+                source.clone(),
+                start.clone(),
+                length.clone(),
+            ),
             (
                 NodeKind::Constant {
                     value: target,
@@ -920,6 +1408,11 @@ impl<'ctx, A: Alloc> Emitter<A> for X86Emitter<'ctx, A> {
                     length,
                 },
             }),
+        };
+
+        match lanewise {
+            Some(lanewise) => self.complete_lanewise(inserted, lanewise),
+            None => inserted,
         }
     }
 
@@ -969,6 +1462,12 @@ impl<'ctx, A: Alloc> Emitter<A> for X86Emitter<'ctx, A> {
                     true_value
                 }
             }
+            _ if wide::is_wide(true_value.typ()) => {
+                self.wide_select(condition, true_value, false_value)
+            }
+            _ if vector::is_vector(true_value.typ()) => {
+                self.vector_select(condition, true_value, false_value)
+            }
             _ => self.node(X86Node {
                 typ: true_value.typ().clone(),
                 kind: NodeKind::Select {
@@ -981,40 +1480,40 @@ impl<'ctx, A: Alloc> Emitter<A> for X86Emitter<'ctx, A> {
     }
 
     fn write_register(&mut self, offset: u64, value: Self::NodeRef) {
+        // deferred writes of flags are superseded by this one
+        self.flags_within(offset, value.typ().width())
+            .collect::<Vec<_>>()
+            .into_iter()
+            .for_each(|flag| self.flags.discard(flag));
+
+        if wide::is_wide(value.typ()) {
+            self.write_wide_register(offset, value);
+            return;
+        }
+
         // todo: validate offset + width is within register file
 
         // potential issue: read nodes that refer to this regster, which are live past
         // this write how can we detect this?
 
-        // if offset == flags register
-        if offset == self.ctx().n_offset
-            || offset == self.ctx().z_offset
-            || offset == self.ctx().c_offset
-            || offset == self.ctx().v_offset
-        {
+        if let Some(flag) = self.flag_at(offset) {
             // look back to see if we're extracting a bit out of get_flags
             if let Some(op) = contains_get_flags(&value) {
-                // emit the setCC to the memory location directly
-
-                let _value = self.to_operand(&op);
+                // the flags are not kept once set, so the value can not be reused
+                self.known_registers.forget(offset, value.typ().width());
 
-                let dest = Operand::mem_base_displ(
-                    Width::_8,
-                    Register::PhysicalRegister(PhysicalRegister::RBP),
-                    offset.try_into().unwrap(),
-                );
+                self.hold_flags(&op);
 
-                self.push_instruction(if offset == self.ctx().n_offset {
-                    Instruction::sets(dest)
-                } else if offset == self.ctx().z_offset {
-                    Instruction::sete(dest)
-                } else if offset == self.ctx().c_offset {
-                    Instruction::setc(dest)
-                } else if offset == self.ctx().v_offset {
-                    Instruction::seto(dest)
+                if LAZY_FLAGS {
+                    self.flags.defer(flag);
                 } else {
-                    unreachable!()
-                });
+                    // emit the setCC to the memory location directly
+                    self.push_instruction(flag.set(Operand::mem_base_displ(
+                        Width::_8,
+                        Register::PhysicalRegister(PhysicalRegister::RBP),
+                        offset.try_into().unwrap(),
+                    )));
+                }
 
                 return;
             }
@@ -1093,30 +1592,52 @@ impl<'ctx, A: Alloc> Emitter<A> for X86Emitter<'ctx, A> {
         let optimised = false;
 
         if !optimised {
-            let value = self.to_operand(&value);
-            let width = value.width();
+            let operand = self.to_operand(&value);
 
-            self.push_instruction(
-                Instruction::mov(
-                    value,
-                    Operand::mem_base_displ(
-                        width,
-                        Register::PhysicalRegister(PhysicalRegister::RBP),
-                        offset.try_into().unwrap(),
-                    ),
-                )
-                .unwrap(),
+            let cacheable = matches!(
+                operand.kind(),
+                OperandKind::Register(Register::VirtualRegister(_)) | OperandKind::Immediate(_)
             );
+
+            if cacheable && self.caches(offset, operand.width()) {
+                // stored once the block is left, unless written again before then
+                if let Some((evicted_offset, evicted)) = self.register_cache.insert(offset, operand)
+                {
+                    self.store_register(evicted_offset, evicted);
+                }
+            } else {
+                if self
+                    .register_cache
+                    .overlaps_dirty(offset, value.typ().width())
+                {
+                    self.write_back_registers();
+                }
+
+                self.store_register(offset, operand);
+                self.register_cache.forget(offset, value.typ().width());
+            }
+
+            if self.forwards(offset) {
+                self.known_registers.insert(offset, value);
+            } else {
+                self.known_registers.forget(offset, value.typ().width());
+            }
         }
 
         // TODO: Arch-specific hack
         if offset == self.ctx().sctlr_el1_offset
+            || offset == self.ctx().tcr_el1_offset
             || offset == self.ctx().ttbr0_el1_offset
             || offset == self.ctx().ttbr1_el1_offset
         {
             // return with invalidate code
             self.execution_result.set_need_tlb_invalidate(true);
         }
+
+        if offset == self.ctx().fpcr_offset {
+            // rounding mode may have changed
+            self.fp_mode_loaded = false;
+        }
     }
 
     fn read_memory(&mut self, address: Self::NodeRef, typ: Type) -> Self::NodeRef {
@@ -1127,12 +1648,20 @@ impl<'ctx, A: Alloc> Emitter<A> for X86Emitter<'ctx, A> {
     }
 
     fn write_memory(&mut self, address: Self::NodeRef, value: Self::NodeRef) {
+        // exceptions taken on the access are raised from the register file
+        self.materialise_flags();
+        self.write_back_registers();
+
         let address = self.to_operand(&address);
         let OperandKind::Register(address_reg) = address.kind() else {
             panic!()
         };
 
-        let value = self.to_operand(&value);
+        // the slow path of the software TLB is passed the value in a register
+        let value = match self.ctx().memory_access {
+            MemoryAccessKind::Direct => self.to_operand(&value),
+            MemoryAccessKind::SoftwareTlb => self.to_operand_reg_promote(&value),
+        };
         let width = value.width();
 
         // It occurs to me that the Arm distribution we're running is a 39-bit address
@@ -1163,17 +1692,23 @@ impl<'ctx, A: Alloc> Emitter<A> for X86Emitter<'ctx, A> {
 
         // if we mask highest 6 nibbles we get a contiguous address space
 
-        if self.ctx().memory_mask {
-            let mask = Operand::vreg(Width::_64, self.next_vreg());
-            self.push_instruction(
-                Instruction::mov(Operand::imm(Width::_64, 0x0000_00FF_FFFF_FFFF), mask).unwrap(),
-            );
-            self.push_instruction(Instruction::and(mask, address));
-        }
+        let destination = match self.ctx().memory_access {
+            MemoryAccessKind::Direct => {
+                if self.ctx().memory_mask {
+                    let mask = Operand::vreg(Width::_64, self.next_vreg());
+                    self.push_instruction(
+                        Instruction::mov(Operand::imm(Width::_64, 0x0000_00FF_FFFF_FFFF), mask)
+                            .unwrap(),
+                    );
+                    self.push_instruction(Instruction::and(mask, address));
+                }
 
-        self.push_instruction(
-            Instruction::mov(value, Operand::mem_base_displ(width, *address_reg, 0)).unwrap(),
-        );
+                Operand::mem_base_displ(width, *address_reg, 0)
+            }
+            MemoryAccessKind::SoftwareTlb => self.software_tlb_operand(address, width, Some(value)),
+        };
+
+        self.push_instruction(Instruction::mov(value, destination).unwrap());
     }
 
     fn branch(
@@ -1182,6 +1717,42 @@ impl<'ctx, A: Alloc> Emitter<A> for X86Emitter<'ctx, A> {
         true_target: Self::BlockRef,
         false_target: Self::BlockRef,
     ) {
+        if let Some(held) = self.flags.condition(&condition) {
+            // stored before leaving the block, which leaves EFLAGS intact
+            self.materialise_flags();
+
+            let (jump, target, fallthrough) = match held {
+                Condition::Set(Flag::Z) => {
+                    (Instruction::je(true_target), true_target, false_target)
+                }
+                Condition::Clear(Flag::Z) => {
+                    (Instruction::jne(true_target), true_target, false_target)
+                }
+                _ => {
+                    let value = Operand::vreg(Width::_8, self.next_vreg());
+                    let (set, negated) = held.set(value);
+                    self.push_instruction(set);
+                    self.push_instruction(Instruction::test(value, value));
+
+                    let (target, fallthrough) = if negated {
+                        (false_target, true_target)
+                    } else {
+                        (true_target, false_target)
+                    };
+
+                    (Instruction::jne(target), target, fallthrough)
+                }
+            };
+
+            self.push_instruction(jump);
+            self.push_target(target);
+
+            self.push_instruction(Instruction::jmp(fallthrough));
+            self.push_target(fallthrough);
+
+            return;
+        }
+
         match condition.kind() {
             NodeKind::Constant { .. } => {
                 todo!("this was handled in models.rs")
@@ -1228,6 +1799,9 @@ impl<'ctx, A: Alloc> Emitter<A> for X86Emitter<'ctx, A> {
     fn prologue(&mut self) {}
 
     fn leave(&mut self) {
+        // Store the guest registers still cached in host registers
+        self.write_back_registers();
+
         // Read the interrupt pending field of the guest execution context
         self.push_instruction(
             Instruction::mov(
@@ -1260,6 +1834,9 @@ impl<'ctx, A: Alloc> Emitter<A> for X86Emitter<'ctx, A> {
     }
 
     fn leave_with_cache(&mut self, chain_cache: u64) {
+        // linked translations read the guest registers from the register file
+        self.write_back_registers();
+
         let return_block = self.ctx_mut().create_block();
 
         self.push_instruction(
@@ -1306,6 +1883,14 @@ impl<'ctx, A: Alloc> Emitter<A> for X86Emitter<'ctx, A> {
             .unwrap(),
         );
 
+        // direct links to successors, patched once they have been translated
+        for _ in 0..CHAIN_SLOT_COUNT {
+            let tag = Operand::vreg(Width::_64, self.next_vreg());
+            self.push_instruction(Instruction::chaintag(tag));
+            self.push_instruction(Instruction::cmp(tag, pc_vreg));
+            self.push_instruction(Instruction::chainjmp());
+        }
+
         let shifted_pc_vreg = self.next_vreg();
         let shifted_pc_op = Operand::vreg(Width::_64, shifted_pc_vreg);
         self.push_instruction(Instruction::mov(pc_vreg, shifted_pc_op).unwrap());
@@ -1322,9 +1907,11 @@ impl<'ctx, A: Alloc> Emitter<A> for X86Emitter<'ctx, A> {
 
         let tag = Operand::vreg(Width::_64, self.next_vreg());
         let chain_cache_reg = Operand::vreg(Width::_64, self.next_vreg());
-        self.push_instruction(
-            Instruction::mov(Operand::imm(Width::_64, chain_cache), chain_cache_reg).unwrap(),
-        );
+        self.push_instruction(Instruction::movrel(
+            Symbol::ChainCache,
+            chain_cache,
+            chain_cache_reg,
+        ));
 
         self.push_instruction(
             Instruction::mov(
@@ -1368,6 +1955,10 @@ impl<'ctx, A: Alloc> Emitter<A> for X86Emitter<'ctx, A> {
     }
 
     fn read_stack_variable(&mut self, id: usize, typ: Type) -> Self::NodeRef {
+        if wide::is_wide(&typ) {
+            return self.read_wide_stack_variable(id, typ);
+        }
+
         let width = typ.width();
 
         self.node(X86Node {
@@ -1377,6 +1968,11 @@ impl<'ctx, A: Alloc> Emitter<A> for X86Emitter<'ctx, A> {
     }
 
     fn write_stack_variable(&mut self, id: usize, value: Self::NodeRef) {
+        if wide::is_wide(value.typ()) {
+            self.write_wide_stack_variable(id, value);
+            return;
+        }
+
         let value = self.to_operand(&value);
 
         // let mem = Operand::mem_base_displ(
@@ -1677,7 +2273,7 @@ pub struct X86Node<A: Alloc> {
 }
 
 #[derive(Clone)]
-#[derive_where(Debug, PartialEq, Eq)]
+#[derive_where(Debug, PartialEq, Eq, Hash)]
 pub enum NodeKind<A: Alloc> {
     Constant {
         value: u64,
@@ -1725,6 +2321,11 @@ pub enum NodeKind<A: Alloc> {
     GetFlags {
         operation: X86NodeRef<A>,
     },
+    /// Guest flag read while still held in `EFLAGS` after `operation`
+    HostFlag {
+        operation: X86NodeRef<A>,
+        flag: Flag,
+    },
     Tuple(Vec<X86NodeRef<A>, A>),
     Select {
         condition: X86NodeRef<A>,
@@ -1732,10 +2333,32 @@ pub enum NodeKind<A: Alloc> {
         false_value: X86NodeRef<A>,
     },
     CallReturnValue,
+    /// Integer wider than 128 bits, split into 64-bit limbs from the least
+    /// significant
+    Limbs(Vec<X86NodeRef<A>, A>),
+    /// 64-bit lane of a 128-bit value
+    VectorLane {
+        vector: X86NodeRef<A>,
+        lane: u8,
+    },
+    /// 128-bit value with one 64-bit lane replaced by `value`
+    VectorInsertLane {
+        vector: X86NodeRef<A>,
+        value: X86NodeRef<A>,
+        lane: u8,
+    },
+    /// Operation on each `element_width`-bit element of two 64 or 128-bit
+    /// values
+    VectorOperation {
+        kind: VectorOperationKind,
+        lhs: X86NodeRef<A>,
+        rhs: X86NodeRef<A>,
+        element_width: u16,
+    },
 }
 
 #[derive(Clone)]
-#[derive_where(Debug, PartialEq, Eq)]
+#[derive_where(Debug, PartialEq, Eq, Hash)]
 pub enum BinaryOperationKind<A: Alloc> {
     Add(X86NodeRef<A>, X86NodeRef<A>),
     Sub(X86NodeRef<A>, X86NodeRef<A>),
@@ -1755,7 +2378,7 @@ pub enum BinaryOperationKind<A: Alloc> {
 }
 
 #[derive(Clone)]
-#[derive_where(Debug, PartialEq, Eq)]
+#[derive_where(Debug, PartialEq, Eq, Hash)]
 pub enum UnaryOperationKind<A: Alloc> {
     Not(X86NodeRef<A>),
     Negate(X86NodeRef<A>),
@@ -1768,12 +2391,12 @@ pub enum UnaryOperationKind<A: Alloc> {
 }
 
 #[derive(Clone)]
-#[derive_where(Debug, PartialEq, Eq)]
+#[derive_where(Debug, PartialEq, Eq, Hash)]
 pub enum TernaryOperationKind<A: Alloc> {
     AddWithCarry(X86NodeRef<A>, X86NodeRef<A>, X86NodeRef<A>),
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum CastOperationKind {
     ZeroExtend,
     SignExtend,
@@ -1783,7 +2406,7 @@ pub enum CastOperationKind {
     Broadcast,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum ShiftOperationKind {
     LogicalShiftLeft,
     LogicalShiftRight,
@@ -2052,6 +2675,7 @@ fn contains_get_flags<A: Alloc>(value: &X86NodeRef<A>) -> Option<X86NodeRef<A>>
 
         NodeKind::Constant { .. }
         | NodeKind::GuestRegister { .. }
+        | NodeKind::HostFlag { .. }
         | NodeKind::ReadMemory { .. }
         | NodeKind::ReadStackVariable { .. } => None,
 
@@ -2091,7 +2715,17 @@ fn contains_get_flags<A: Alloc>(value: &X86NodeRef<A>) -> Option<X86NodeRef<A>>
             value: a,
             amount: b,
             ..
-        } => contains_get_flags(a).or_else(|| contains_get_flags(b)),
+        }
+        | NodeKind::VectorInsertLane {
+            vector: a,
+            value: b,
+            ..
+        }
+        | NodeKind::VectorOperation { lhs: a, rhs: b, .. } => {
+            contains_get_flags(a).or_else(|| contains_get_flags(b))
+        }
+
+        NodeKind::VectorLane { vector, .. } => contains_get_flags(vector),
 
         NodeKind::BitExtract {
             value: a,
@@ -2100,7 +2734,7 @@ fn contains_get_flags<A: Alloc>(value: &X86NodeRef<A>) -> Option<X86NodeRef<A>>
         } => contains_get_flags(a),
         // .or_else(|| contains_get_flags(b))
         // .or_else(|| contains_get_flags(c)),
-        NodeKind::Tuple(x86_node_refs) => {
+        NodeKind::Tuple(x86_node_refs) | NodeKind::Limbs(x86_node_refs) => {
             x86_node_refs.iter().filter_map(contains_get_flags).next()
         }
 
@@ -0,0 +1,153 @@
+//! Caching of hot guest registers in host registers
+//!
+//! The general purpose registers, stack pointers and PC are loaded into a host
+//! register the first time they are read in a block, and later reads reuse it.
+//! Values written to them stay in the host registers holding them rather than
+//! being stored to the register file straight away, and are written back when
+//! the block is left, before helper calls and before anything that may raise an
+//! exception. Written back registers stay cached, as helpers never write them,
+//! so each of them is loaded at most once and stored at most once per block
+//! unless it is evicted.
+
+use {
+    crate::host::dbt::{
+        Alloc,
+        x86::encoder::{Instruction, Opcode, Operand},
+    },
+    alloc::vec::Vec,
+};
+
+/// Guest registers held at once, as many as the host registers preserved across
+/// calls that the register allocator hands out (RBX and R12 to R14), so that
+/// each can stay in one across helper calls
+const CAPACITY: usize = 4;
+
+/// 64-bit guest register held in a host register
+struct Entry<A: Alloc> {
+    offset: u64,
+    value: Operand<A>,
+    /// Written since it was last stored to the register file
+    dirty: bool,
+}
+
+/// Guest registers held in host registers, least recently used first
+pub(super) struct RegisterCache<A: Alloc> {
+    entries: Vec<Entry<A>>,
+}
+
+impl<A: Alloc> RegisterCache<A> {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Operand holding the register at `offset`, if cached
+    pub fn get(&mut self, offset: u64) -> Option<Operand<A>> {
+        let index = self.position(offset)?;
+
+        let entry = self.entries.remove(index);
+        let value = entry.value;
+        self.entries.push(entry);
+
+        Some(value)
+    }
+
+    /// Records that the register at `offset` was loaded into `value`, returning
+    /// the least recently used register if it must be stored to make room
+    pub fn hold(&mut self, offset: u64, value: Operand<A>) -> Option<(u64, Operand<A>)> {
+        // a written value moved into a register is still to be stored
+        let dirty = self
+            .position(offset)
+            .is_some_and(|index| self.entries[index].dirty);
+
+        self.push(Entry {
+            offset,
+            value,
+            dirty,
+        })
+    }
+
+    /// Records that the register at `offset` was written with `value`, returning
+    /// the least recently used register if it must be stored to make room
+    pub fn insert(&mut self, offset: u64, value: Operand<A>) -> Option<(u64, Operand<A>)> {
+        self.push(Entry {
+            offset,
+            value,
+            dirty: true,
+        })
+    }
+
+    /// Whether any dirty register overlaps the `width`-bit register at `offset`
+    pub fn overlaps_dirty(&self, offset: u64, width: u16) -> bool {
+        self.entries
+            .iter()
+            .any(|entry| entry.dirty && overlaps(entry.offset, offset, width))
+    }
+
+    /// Forgets any registers overlapping the `width`-bit register at `offset`,
+    /// which must not be dirty
+    pub fn forget(&mut self, offset: u64, width: u16) {
+        self.entries
+            .retain(|entry| !overlaps(entry.offset, offset, width));
+    }
+
+    /// Returns every dirty register, which must now be stored, keeping them
+    /// cached
+    pub fn write_back(&mut self) -> Vec<(u64, Operand<A>)> {
+        self.entries
+            .iter_mut()
+            .filter(|entry| entry.dirty)
+            .map(|entry| {
+                entry.dirty = false;
+                (entry.offset, entry.value)
+            })
+            .collect()
+    }
+
+    /// Forgets every register once the block is left, after they have been
+    /// written back
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    fn position(&self, offset: u64) -> Option<usize> {
+        self.entries.iter().position(|entry| entry.offset == offset)
+    }
+
+    fn push(&mut self, entry: Entry<A>) -> Option<(u64, Operand<A>)> {
+        self.entries.retain(|cached| cached.offset != entry.offset);
+        self.entries.push(entry);
+
+        if self.entries.len() > CAPACITY {
+            let evicted = self.entries.remove(0);
+            evicted.dirty.then_some((evicted.offset, evicted.value))
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether the 64-bit register at `cached` overlaps the `width`-bit register at
+/// `offset`
+fn overlaps(cached: u64, offset: u64, width: u16) -> bool {
+    let end = offset + u64::from(width.div_ceil(u8::BITS as u16));
+
+    cached < end && cached + 8 > offset
+}
+
+/// Whether the register file must be up to date before `instruction`, which
+/// leaves the block or calls a helper
+pub fn written_back_before<A: Alloc>(instruction: &Instruction<A>) -> bool {
+    matches!(
+        instruction.0,
+        Opcode::JMP(_)
+            | Opcode::JE(_)
+            | Opcode::JNE(_)
+            | Opcode::CHAINJMP
+            | Opcode::CALL { .. }
+            | Opcode::TLBLOOKUP { .. }
+            | Opcode::RET
+            | Opcode::INT(_)
+    )
+}
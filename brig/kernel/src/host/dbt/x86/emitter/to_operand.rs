@@ -2,14 +2,18 @@ use {
     crate::host::dbt::{
         Alloc,
         emitter::Type,
+        persist::Symbol,
+        softmmu::MemoryAccessKind,
         x86::{
             Emitter,
             emitter::{
                 BinaryOperationKind, CastOperationKind, NodeKind, ShiftOperationKind,
                 TernaryOperationKind, UnaryOperationKind, X86Emitter, X86NodeRef,
+                float::{self, FloatCast},
+                vector,
             },
             encoder::{
-                Instruction, Opcode, Operand, OperandKind, PhysicalRegister,
+                Instruction, Operand, OperandKind, PhysicalRegister,
                 Register::{self},
                 width::Width,
             },
@@ -22,7 +26,7 @@ impl<'a, 'ctx, A: Alloc> X86Emitter<'ctx, A> {
     /// Same as `to_operand` but if the value is a constant, move it to a
     /// register
     pub fn to_operand_reg_promote(&mut self, node: &X86NodeRef<A>) -> Operand<A> {
-        if let NodeKind::Constant { .. } | NodeKind::FunctionPointer(_) = node.kind() {
+        if let NodeKind::Constant { .. } = node.kind() {
             let width = Width::from_uncanonicalized(node.typ().width()).unwrap();
             let value_reg = Operand::vreg(width, self.next_vreg());
             let value_imm = self.to_operand(node);
@@ -49,6 +53,24 @@ impl<'a, 'ctx, A: Alloc> X86Emitter<'ctx, A> {
         op
     }
 
+    /// Looks up `address` in the software TLB, returning the host memory operand
+    /// of a `width` access to it and passing the `value` of a write to the slow
+    /// path
+    pub(super) fn software_tlb_operand(
+        &mut self,
+        address: Operand<A>,
+        width: Width,
+        value: Option<Operand<A>>,
+    ) -> Operand<A> {
+        let host = Operand::vreg(Width::_64, self.next_vreg());
+        let index = Operand::vreg(Width::_64, self.next_vreg());
+        self.push_instruction(Instruction::mov(address, host).unwrap());
+        self.push_instruction(Instruction::mov(address, index).unwrap());
+        self.push_instruction(Instruction::tlblookup(host, index, width, value));
+
+        Operand::mem_base_displ(width, host.as_register().unwrap(), 0)
+    }
+
     pub(super) fn to_operand(&mut self, node: &X86NodeRef<A>) -> Operand<A> {
         if let Some(operand) = self.current_block_operands.get(node) {
             return *operand;
@@ -58,12 +80,19 @@ impl<'a, 'ctx, A: Alloc> X86Emitter<'ctx, A> {
         // before a side-effecty node.
 
         let op = match node.kind() {
+            NodeKind::Constant { value, width } if *width > 64 => {
+                self.vector_constant_to_operand(*value)
+            }
             NodeKind::Constant { value, width } => Operand::imm(
                 Width::from_uncanonicalized(*width)
                     .unwrap_or_else(|e| panic!("failed to canonicalize width of {node:?}: {e}")),
                 *value,
             ),
-            NodeKind::FunctionPointer(target) => Operand::imm(Width::_64, *target),
+            NodeKind::FunctionPointer(target) => {
+                let dst = Operand::vreg(Width::_64, self.next_vreg());
+                self.push_instruction(Instruction::movrel(Symbol::function(*target), *target, dst));
+                dst
+            }
             NodeKind::CallReturnValue => Operand::preg(Width::_64, PhysicalRegister::RAX),
             NodeKind::GuestRegister { offset } => {
                 let width = Width::from_uncanonicalized(node.typ().width()).unwrap_or_else(|e| {
@@ -125,6 +154,9 @@ impl<'a, 'ctx, A: Alloc> X86Emitter<'ctx, A> {
                     dst
                 }
             },
+            NodeKind::UnaryOperation(kind) if float::unary_operand(kind).is_some() => {
+                self.float_unary_operation_to_operand(kind)
+            }
             NodeKind::UnaryOperation(kind) => match &kind {
                 UnaryOperationKind::Complement(value) => {
                     let width = Width::from_uncanonicalized(value.typ().width()).unwrap();
@@ -293,6 +325,14 @@ impl<'a, 'ctx, A: Alloc> X86Emitter<'ctx, A> {
 
                 dst
             }
+            NodeKind::Cast { value, kind }
+                if FloatCast::classify(value.typ(), node.typ(), kind).is_some() =>
+            {
+                self.float_cast_to_operand(value, *node.typ(), kind)
+            }
+            NodeKind::Cast { value, .. } if vector::is_vector(node.typ()) => {
+                self.vector_cast_to_operand(value)
+            }
             NodeKind::Cast { value, kind } => {
                 let target_width = Width::from_uncanonicalized(node.typ().width()).unwrap();
                 let dst = Operand::vreg(target_width, self.next_vreg());
@@ -482,6 +522,15 @@ impl<'a, 'ctx, A: Alloc> X86Emitter<'ctx, A> {
 
                 dest
             }
+            NodeKind::HostFlag { operation, flag } => {
+                // lowered again if EFLAGS no longer hold its flags, as in a later block
+                self.hold_flags(operation);
+
+                let dest = Operand::vreg(Width::_8, self.next_vreg());
+                self.push_instruction(flag.set(dest));
+
+                dest
+            }
             NodeKind::GetFlags { operation } => {
                 let n = Operand::vreg(Width::_8, self.next_vreg());
                 let z = Operand::vreg(Width::_8, self.next_vreg());
@@ -504,57 +553,22 @@ impl<'a, 'ctx, A: Alloc> X86Emitter<'ctx, A> {
                     Instruction::or(v, dest),
                 ];
 
-                match self.current_block_operands.get(operation).copied() {
-                    Some(operation_operand) => {
-                        let block_instructions = &mut self
-                            .current_block
-                            .clone()
-                            .get_mut(self.ctx_mut().arena_mut())
-                            .instructions;
-
-                        let (index, adc) = block_instructions
-                            .iter()
-                            .enumerate()
-                            .rev()
-                            .find(|(_, i)| matches!(i.0, Opcode::ADC(_, _, _)))
-                            .unwrap();
-
-                        if let Opcode::ADC(_, dst, _) = &adc.0 {
-                            assert_eq!(*dst, operation_operand)
-                        } else {
-                            panic!()
-                        };
+                // lowered again if EFLAGS no longer hold its flags
+                self.hold_flags(operation);
 
-                        for instr in instrs.into_iter().rev() {
-                            block_instructions.insert(index + 1, instr);
-                        }
-                    }
-                    None => {
-                        let _target = self.to_operand(operation);
-
-                        self.current_block
-                            .clone()
-                            .get_mut(self.ctx_mut().arena_mut())
-                            .instructions
-                            .extend_from_slice(&instrs);
-                    }
-                }
-                // if the last instruction wasn't an ADC, emit one? todo:
-                if !matches!(
-                    self.current_block
-                        .get(self.ctx.arena())
-                        .instructions()
-                        .last()
-                        .map(|i| &i.0),
-                    Some(Opcode::ADC(_, _, _))
-                ) {
-                    let _op = self.to_operand(operation);
+                for instr in instrs {
+                    self.push_instruction(instr);
                 }
 
                 // nzcv
                 dest
             }
             NodeKind::Tuple(vec) => panic!("cannot convert to operand: {vec:#?}"),
+            NodeKind::Limbs(limbs) => panic!(
+                "{}-bit value must be split into its {} limbs before lowering",
+                node.typ().width(),
+                limbs.len()
+            ),
             NodeKind::Select {
                 condition,
                 true_value,
@@ -563,7 +577,22 @@ impl<'a, 'ctx, A: Alloc> X86Emitter<'ctx, A> {
                 let width = Width::from_uncanonicalized(true_value.typ().width()).unwrap();
                 let dest = Operand::vreg(width, self.next_vreg());
 
-                let condition = self.to_operand(condition);
+                // conditions on flags still held in EFLAGS are set from them directly
+                let (condition, true_value, false_value) = match self.flags.condition(condition) {
+                    Some(held) => {
+                        let value = Operand::vreg(Width::_8, self.next_vreg());
+                        let (set, negated) = held.set(value);
+                        self.push_instruction(set);
+
+                        if negated {
+                            (value, false_value, true_value)
+                        } else {
+                            (value, true_value, false_value)
+                        }
+                    }
+                    None => (self.to_operand(condition), true_value, false_value),
+                };
+
                 let true_value = self.to_operand_reg_promote(true_value);
                 let false_value = self.to_operand(false_value);
 
@@ -577,6 +606,10 @@ impl<'a, 'ctx, A: Alloc> X86Emitter<'ctx, A> {
             NodeKind::ReadMemory { address } => {
                 let width = Width::from_uncanonicalized(node.typ().width()).unwrap();
 
+                // exceptions taken on the access are raised from the register file
+                self.materialise_flags();
+                self.write_back_registers();
+
                 let address = self.to_operand(address);
                 let OperandKind::Register(address_reg) = address.kind() else {
                     panic!()
@@ -584,22 +617,43 @@ impl<'a, 'ctx, A: Alloc> X86Emitter<'ctx, A> {
 
                 let dest = Operand::vreg(width, self.next_vreg());
 
-                if self.ctx().memory_mask {
-                    let mask = Operand::vreg(Width::_64, self.next_vreg());
-                    self.push_instruction(
-                        Instruction::mov(Operand::imm(Width::_64, 0x0000_00FF_FFFF_FFFF), mask)
-                            .unwrap(),
-                    );
-                    self.push_instruction(Instruction::and(mask, address));
-                }
+                let source = match self.ctx().memory_access {
+                    MemoryAccessKind::Direct => {
+                        if self.ctx().memory_mask {
+                            let mask = Operand::vreg(Width::_64, self.next_vreg());
+                            self.push_instruction(
+                                Instruction::mov(
+                                    Operand::imm(Width::_64, 0x0000_00FF_FFFF_FFFF),
+                                    mask,
+                                )
+                                .unwrap(),
+                            );
+                            self.push_instruction(Instruction::and(mask, address));
+                        }
 
-                self.push_instruction(
-                    Instruction::mov(Operand::mem_base_displ(width, *address_reg, 0), dest)
-                        .unwrap(),
-                );
+                        Operand::mem_base_displ(width, *address_reg, 0)
+                    }
+                    MemoryAccessKind::SoftwareTlb => {
+                        self.software_tlb_operand(address, width, None)
+                    }
+                };
+
+                self.push_instruction(Instruction::mov(source, dest).unwrap());
 
                 dest
             }
+            NodeKind::VectorLane { vector, lane } => self.vector_lane_to_operand(vector, *lane),
+            NodeKind::VectorInsertLane {
+                vector,
+                value,
+                lane,
+            } => self.vector_insert_lane_to_operand(vector, value, *lane),
+            NodeKind::VectorOperation {
+                kind,
+                lhs,
+                rhs,
+                element_width,
+            } => self.vector_operation_to_operand(*kind, lhs, rhs, *element_width, node.typ()),
         };
 
         self.current_block_operands.insert(node.clone(), op);
@@ -625,6 +679,10 @@ impl<'a, 'ctx, A: Alloc> X86Emitter<'ctx, A> {
         | CompareGreaterThan(left, right)
         | CompareGreaterThanOrEqual(left, right)) = kind;
 
+        if float::binary_operation_type(kind).is_some() {
+            return self.float_binary_operation_to_operand(kind);
+        }
+
         // do this first to avoid tuple issues
         if let BinaryOperationKind::CompareEqual(left, right)
         | BinaryOperationKind::CompareNotEqual(left, right)
@@ -695,7 +753,7 @@ impl<'a, 'ctx, A: Alloc> X86Emitter<'ctx, A> {
                 }
             },
 
-            (Type::Floating(_), Type::Floating(_)) => todo!(),
+            (Type::Floating(_), Type::Floating(_)) => unreachable!(),
 
             (Type::Tuple, Type::Tuple) => {
                 todo!()
@@ -0,0 +1,821 @@
+//! Lowering of 128-bit values to SSE
+//!
+//! 128-bit values, such as the AdvSIMD `V` registers, are held in `xmm`
+//! registers. Most operations on them are split into operations on their two
+//! 64-bit lanes, which fold away where a lane is already known, for example
+//! after zero extending a 64-bit value.
+//!
+//! AdvSIMD instructions are described as loops over their elements, which
+//! translate to a chain of `bit_insert`s of the same operation on `bit_extract`s
+//! of two values. The emitter records which element each `bit_extract` produced,
+//! and once the chain covers every element of a 64 or 128-bit value it is
+//! replaced by a single packed SSE instruction. Chains that stop early are
+//! lowered element by element as before.
+
+use {
+    crate::host::dbt::{
+        Alloc,
+        emitter::Type,
+        x86::{
+            Emitter,
+            emitter::{
+                BinaryOperationKind, CastOperationKind, NodeKind, ShiftOperationKind,
+                UnaryOperationKind, X86Emitter, X86Node, X86NodeRef,
+            },
+            encoder::{Instruction, Operand, width::Width},
+        },
+    },
+    common::mask::mask,
+    proc_macro_lib::ktest,
+};
+
+/// Type of each 64-bit half of a 128-bit value
+const LANE: Type = Type::Unsigned(64);
+
+/// Operation applied to every element of two values by a single SSE
+/// instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VectorOperationKind {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+    /// Sets each element to all ones if equal, otherwise zero
+    CompareEqual,
+    /// Sets each element to all ones if the left is greater as a signed
+    /// integer, otherwise zero
+    CompareGreaterThan,
+}
+
+/// Element of `vector` produced by a `bit_extract`
+#[derive(Clone)]
+pub(super) struct Element<A: Alloc> {
+    vector: X86NodeRef<A>,
+    start: u64,
+    length: u64,
+}
+
+impl<A: Alloc> Element<A> {
+    /// Recognises an extract of a whole, aligned 8, 16, 32 or 64-bit element
+    /// from a 64 or 128-bit integer
+    pub(super) fn of(
+        value: &X86NodeRef<A>,
+        start: &X86NodeRef<A>,
+        length: &X86NodeRef<A>,
+    ) -> Option<Self> {
+        let (NodeKind::Constant { value: start, .. }, NodeKind::Constant { value: length, .. }) =
+            (start.kind(), length.kind())
+        else {
+            return None;
+        };
+
+        (has_elements(value.typ()) && matches!(length, 8 | 16 | 32 | 64) && start % length == 0)
+            .then(|| Self {
+                vector: value.clone(),
+                start: *start,
+                length: *length,
+            })
+    }
+}
+
+/// Elements of an operation inserted so far by a chain of `bit_insert`s
+#[derive(Clone)]
+pub(super) struct Lanewise<A: Alloc> {
+    kind: VectorOperationKind,
+    lhs: X86NodeRef<A>,
+    rhs: X86NodeRef<A>,
+    element_width: u64,
+    elements: u64,
+}
+
+/// Whether `typ` is a 128-bit integer
+pub(super) fn is_vector(typ: &Type) -> bool {
+    matches!(typ, Type::Unsigned(65..=128) | Type::Signed(65..=128))
+}
+
+/// Whether values of type `typ` can be operated on element-wise
+fn has_elements(typ: &Type) -> bool {
+    matches!(typ, Type::Unsigned(64 | 128) | Type::Signed(64 | 128))
+}
+
+/// Whether the operand of `op` is a 128-bit integer
+pub(super) fn is_vector_unary_operation<A: Alloc>(op: &UnaryOperationKind<A>) -> bool {
+    use UnaryOperationKind::*;
+
+    let (Not(value) | Negate(value) | Complement(value) | Power2(value) | Absolute(value)
+    | Ceil(value) | Floor(value) | SquareRoot(value)) = op;
+
+    is_vector(value.typ())
+}
+
+/// Whether either operand of `op` is a 128-bit integer
+pub(super) fn is_vector_operation<A: Alloc>(op: &BinaryOperationKind<A>) -> bool {
+    use BinaryOperationKind::*;
+
+    let (Add(left, right)
+    | Sub(left, right)
+    | Multiply(left, right)
+    | Divide(left, right)
+    | Modulo(left, right)
+    | And(left, right)
+    | Or(left, right)
+    | Xor(left, right)
+    | PowI(left, right)
+    | CompareEqual(left, right)
+    | CompareNotEqual(left, right)
+    | CompareLessThan(left, right)
+    | CompareLessThanOrEqual(left, right)
+    | CompareGreaterThan(left, right)
+    | CompareGreaterThanOrEqual(left, right)) = op;
+
+    is_vector(left.typ()) || is_vector(right.typ())
+}
+
+impl<'a, 'ctx, A: Alloc> X86Emitter<'ctx, A> {
+    /// 64-bit lane `lane` of `vector`
+    fn vector_lane(&mut self, vector: X86NodeRef<A>, lane: u8) -> X86NodeRef<A> {
+        match vector.kind() {
+            NodeKind::Constant { value, .. } => {
+                self.constant(if lane == 0 { *value } else { 0 }, LANE)
+            }
+            NodeKind::VectorInsertLane {
+                vector,
+                value,
+                lane: inserted,
+            } => {
+                if *inserted == lane {
+                    value.clone()
+                } else {
+                    self.vector_lane(vector.clone(), lane)
+                }
+            }
+            NodeKind::Cast {
+                value,
+                kind: CastOperationKind::ZeroExtend | CastOperationKind::Reinterpret,
+            } if !is_vector(value.typ()) => {
+                if lane == 0 {
+                    self.cast(value.clone(), LANE, CastOperationKind::ZeroExtend)
+                } else {
+                    self.constant(0, LANE)
+                }
+            }
+            NodeKind::Cast { value, .. } if is_vector(value.typ()) => {
+                self.vector_lane(value.clone(), lane)
+            }
+            _ => self.node(X86Node {
+                typ: LANE,
+                kind: NodeKind::VectorLane {
+                    vector: vector.clone(),
+                    lane,
+                },
+            }),
+        }
+    }
+
+    /// Low and high lanes of `value`, which may be narrower than 128 bits
+    pub(super) fn vector_lanes(&mut self, value: &X86NodeRef<A>) -> (X86NodeRef<A>, X86NodeRef<A>) {
+        if is_vector(value.typ()) {
+            (
+                self.vector_lane(value.clone(), 0),
+                self.vector_lane(value.clone(), 1),
+            )
+        } else {
+            (
+                self.cast(value.clone(), LANE, CastOperationKind::ZeroExtend),
+                self.constant(0, LANE),
+            )
+        }
+    }
+
+    /// 128-bit value of type `typ` made up of the lanes `low` and `high`
+    pub(super) fn vector_from_lanes(
+        &mut self,
+        low: X86NodeRef<A>,
+        high: X86NodeRef<A>,
+        typ: Type,
+    ) -> X86NodeRef<A> {
+        if let NodeKind::Constant { value: 0, .. } = high.kind() {
+            return self.cast(low, typ, CastOperationKind::ZeroExtend);
+        }
+
+        // replace a single lane where the other comes from an existing value
+        let (vector, value, lane) = match (low.kind(), high.kind()) {
+            (NodeKind::VectorLane { vector, lane: 0 }, _) => (vector.clone(), high.clone(), 1),
+            (_, NodeKind::VectorLane { vector, lane: 1 }) => (vector.clone(), low.clone(), 0),
+            _ => (
+                self.cast(low.clone(), typ, CastOperationKind::ZeroExtend),
+                high.clone(),
+                1,
+            ),
+        };
+
+        let value = self.cast(value, LANE, CastOperationKind::Reinterpret);
+
+        self.node(X86Node {
+            typ,
+            kind: NodeKind::VectorInsertLane {
+                vector,
+                value,
+                lane,
+            },
+        })
+    }
+
+    fn vector_operation(
+        &mut self,
+        kind: VectorOperationKind,
+        lhs: X86NodeRef<A>,
+        rhs: X86NodeRef<A>,
+        element_width: u64,
+    ) -> X86NodeRef<A> {
+        self.node(X86Node {
+            typ: *lhs.typ(),
+            kind: NodeKind::VectorOperation {
+                kind,
+                lhs,
+                rhs,
+                element_width: u16::try_from(element_width).unwrap(),
+            },
+        })
+    }
+
+    fn lane_shift(
+        &mut self,
+        lane: X86NodeRef<A>,
+        amount: u64,
+        kind: ShiftOperationKind,
+    ) -> X86NodeRef<A> {
+        let amount = self.constant(amount, Type::Signed(64));
+        self.shift(lane, amount, kind)
+    }
+
+    pub(super) fn vector_unary_operation(&mut self, op: UnaryOperationKind<A>) -> X86NodeRef<A> {
+        match op {
+            UnaryOperationKind::Complement(value) => {
+                let (low, high) = self.vector_lanes(&value);
+                let low = self.unary_operation(UnaryOperationKind::Complement(low));
+                let high = self.unary_operation(UnaryOperationKind::Complement(high));
+                self.vector_from_lanes(low, high, *value.typ())
+            }
+            UnaryOperationKind::Negate(value) => {
+                let zero = self.constant(0, *value.typ());
+                self.vector_binary_operation(BinaryOperationKind::Sub(zero, value))
+            }
+            // expanded into operations on each lane
+            op => self.wide_unary_operation(op),
+        }
+    }
+
+    pub(super) fn vector_binary_operation(&mut self, op: BinaryOperationKind<A>) -> X86NodeRef<A> {
+        use BinaryOperationKind::*;
+
+        let equal = matches!(op, CompareEqual(..));
+
+        match op {
+            And(lhs, rhs) => self.vector_operation(VectorOperationKind::And, lhs, rhs, 64),
+            Or(lhs, rhs) => self.vector_operation(VectorOperationKind::Or, lhs, rhs, 64),
+            Xor(lhs, rhs) => self.vector_operation(VectorOperationKind::Xor, lhs, rhs, 64),
+            Add(lhs, rhs) => {
+                let typ = *lhs.typ();
+                let (lhs_low, lhs_high) = self.vector_lanes(&lhs);
+                let (rhs_low, rhs_high) = self.vector_lanes(&rhs);
+
+                let low = self.binary_operation(Add(lhs_low.clone(), rhs_low));
+                let carry = self.binary_operation(CompareLessThan(low.clone(), lhs_low));
+                let carry = self.cast(carry, LANE, CastOperationKind::ZeroExtend);
+
+                let high = self.binary_operation(Add(lhs_high, rhs_high));
+                let high = self.binary_operation(Add(high, carry));
+
+                self.vector_from_lanes(low, high, typ)
+            }
+            Sub(lhs, rhs) => {
+                let typ = *lhs.typ();
+                let (lhs_low, lhs_high) = self.vector_lanes(&lhs);
+                let (rhs_low, rhs_high) = self.vector_lanes(&rhs);
+
+                let low = self.binary_operation(Sub(lhs_low.clone(), rhs_low.clone()));
+                let borrow = self.binary_operation(CompareLessThan(lhs_low, rhs_low));
+                let borrow = self.cast(borrow, LANE, CastOperationKind::ZeroExtend);
+
+                let high = self.binary_operation(Sub(lhs_high, rhs_high));
+                let high = self.binary_operation(Sub(high, borrow));
+
+                self.vector_from_lanes(low, high, typ)
+            }
+            CompareEqual(lhs, rhs) | CompareNotEqual(lhs, rhs) => {
+                let (lhs_low, lhs_high) = self.vector_lanes(&lhs);
+                let (rhs_low, rhs_high) = self.vector_lanes(&rhs);
+
+                let low = self.binary_operation(Xor(lhs_low, rhs_low));
+                let high = self.binary_operation(Xor(lhs_high, rhs_high));
+                let difference = self.binary_operation(Or(low, high));
+                let zero = self.constant(0, LANE);
+
+                self.binary_operation(if equal {
+                    CompareEqual(difference, zero)
+                } else {
+                    CompareNotEqual(difference, zero)
+                })
+            }
+            // expanded into operations on each lane, such as ordered compares
+            op => self.wide_binary_operation(op),
+        }
+    }
+
+    pub(super) fn vector_shift(
+        &mut self,
+        value: X86NodeRef<A>,
+        amount: X86NodeRef<A>,
+        kind: ShiftOperationKind,
+    ) -> X86NodeRef<A> {
+        use ShiftOperationKind::*;
+
+        if let RotateLeft | RotateRight = kind {
+            return self.wide_shift(value, amount, kind);
+        }
+
+        let NodeKind::Constant { value: amount, .. } = amount.kind() else {
+            return self.wide_shift(value, amount, kind);
+        };
+        let amount = *amount;
+
+        if amount == 0 {
+            return value;
+        }
+
+        let typ = *value.typ();
+        let (low, high) = self.vector_lanes(&value);
+
+        let (low, high) = match (kind, amount) {
+            (LogicalShiftLeft | LogicalShiftRight, 128..) => {
+                (self.constant(0, LANE), self.constant(0, LANE))
+            }
+            (LogicalShiftLeft, 64..) => (
+                self.constant(0, LANE),
+                self.lane_shift(low, amount - 64, LogicalShiftLeft),
+            ),
+            (LogicalShiftLeft, _) => {
+                let carried = self.lane_shift(low.clone(), 64 - amount, LogicalShiftRight);
+                let high = self.lane_shift(high, amount, LogicalShiftLeft);
+                (
+                    self.lane_shift(low, amount, LogicalShiftLeft),
+                    self.binary_operation(BinaryOperationKind::Or(high, carried)),
+                )
+            }
+            (LogicalShiftRight, 64..) => (
+                self.lane_shift(high, amount - 64, LogicalShiftRight),
+                self.constant(0, LANE),
+            ),
+            (ArithmeticShiftRight, 64..) => {
+                let sign = self.lane_shift(high.clone(), 63, ArithmeticShiftRight);
+                let low = self.lane_shift(high, amount.min(127) - 64, ArithmeticShiftRight);
+                (low, sign)
+            }
+            (kind @ (LogicalShiftRight | ArithmeticShiftRight), _) => {
+                let carried = self.lane_shift(high.clone(), 64 - amount, LogicalShiftLeft);
+                let low = self.lane_shift(low, amount, LogicalShiftRight);
+                (
+                    self.binary_operation(BinaryOperationKind::Or(low, carried)),
+                    self.lane_shift(high, amount, kind),
+                )
+            }
+            (RotateLeft | RotateRight, _) => unreachable!(),
+        };
+
+        self.vector_from_lanes(low, high, typ)
+    }
+
+    pub(super) fn vector_cast(
+        &mut self,
+        value: X86NodeRef<A>,
+        target: Type,
+        kind: CastOperationKind,
+    ) -> X86NodeRef<A> {
+        match (is_vector(value.typ()), is_vector(&target), kind) {
+            (false, true, CastOperationKind::SignExtend) => {
+                let low = self.cast(value, Type::Signed(64), CastOperationKind::SignExtend);
+                let high =
+                    self.lane_shift(low.clone(), 63, ShiftOperationKind::ArithmeticShiftRight);
+                self.vector_from_lanes(low, high, target)
+            }
+            (true, false, _) => {
+                let low = self.vector_lane(value, 0);
+                let low = self.cast(
+                    low,
+                    Type::Unsigned(target.width()),
+                    CastOperationKind::Truncate,
+                );
+                self.cast(low, target, CastOperationKind::Reinterpret)
+            }
+            // keeps the value, as when folding a constant
+            (_, _, CastOperationKind::Convert) if matches!(value.typ(), Type::Signed(_)) => {
+                self.vector_cast(value, target, CastOperationKind::SignExtend)
+            }
+            (_, _, CastOperationKind::Convert | CastOperationKind::Broadcast) => {
+                self.vector_cast(value, target, CastOperationKind::ZeroExtend)
+            }
+            // zero extension to, or reinterpreting between, 128-bit values only moves the
+            // value between registers
+            (_, _, kind) => self.node(X86Node {
+                typ: target,
+                kind: NodeKind::Cast { value, kind },
+            }),
+        }
+    }
+
+    pub(super) fn vector_select(
+        &mut self,
+        condition: X86NodeRef<A>,
+        true_value: X86NodeRef<A>,
+        false_value: X86NodeRef<A>,
+    ) -> X86NodeRef<A> {
+        let (true_low, true_high) = self.vector_lanes(&true_value);
+        let (false_low, false_high) = self.vector_lanes(&false_value);
+
+        let low = self.select(condition.clone(), true_low, false_low);
+        let high = self.select(condition, true_high, false_high);
+
+        self.vector_from_lanes(low, high, *true_value.typ())
+    }
+
+    pub(super) fn vector_bit_extract(
+        &mut self,
+        value: X86NodeRef<A>,
+        start: u64,
+        length: u64,
+    ) -> X86NodeRef<A> {
+        if length > 64 {
+            let start = self.constant(start, Type::Signed(64));
+            let shifted = self.vector_shift(value, start, ShiftOperationKind::LogicalShiftRight);
+
+            let (low, high) = self.vector_lanes(&shifted);
+            let high = self.lane_bit_extract(high, 0, length - 64);
+
+            return self.vector_from_lanes(low, high, Type::Unsigned(length.try_into().unwrap()));
+        }
+
+        let offset = start % 64;
+
+        if offset + length <= 64 {
+            let lane = self.vector_lane(value, u8::try_from(start / 64).unwrap());
+            return self.lane_bit_extract(lane, offset, length);
+        }
+
+        // straddles both lanes
+        let (low, high) = self.vector_lanes(&value);
+        let low = self.lane_shift(low, offset, ShiftOperationKind::LogicalShiftRight);
+        let high = self.lane_shift(high, 64 - offset, ShiftOperationKind::LogicalShiftLeft);
+        let joined = self.binary_operation(BinaryOperationKind::Or(low, high));
+
+        self.lane_bit_extract(joined, 0, length)
+    }
+
+    fn lane_bit_extract(&mut self, lane: X86NodeRef<A>, start: u64, length: u64) -> X86NodeRef<A> {
+        let start = self.constant(start, Type::Signed(64));
+        let length = self.constant(length, Type::Signed(64));
+        self.bit_extract(lane, start, length)
+    }
+
+    pub(super) fn vector_bit_insert(
+        &mut self,
+        target: X86NodeRef<A>,
+        source: X86NodeRef<A>,
+        start: u64,
+        length: u64,
+    ) -> X86NodeRef<A> {
+        let typ = *target.typ();
+
+        if start == 0 && length >= u64::from(typ.width()) {
+            return self.cast(source, typ, CastOperationKind::ZeroExtend);
+        }
+
+        let offset = start % 64;
+
+        // split at the lane boundary
+        if offset + length > 64 {
+            let low_length = 64 - offset;
+
+            let low = if is_vector(source.typ()) {
+                self.vector_bit_extract(source.clone(), 0, low_length)
+            } else {
+                self.lane_bit_extract(source.clone(), 0, low_length)
+            };
+            let high = if is_vector(source.typ()) {
+                self.vector_bit_extract(source, low_length, length - low_length)
+            } else {
+                let source = self.cast(source, LANE, CastOperationKind::ZeroExtend);
+                self.lane_shift(source, low_length, ShiftOperationKind::LogicalShiftRight)
+            };
+
+            let target = self.vector_bit_insert(target, low, start, low_length);
+            return self.vector_bit_insert(target, high, start + low_length, length - low_length);
+        }
+
+        let source = if is_vector(source.typ()) {
+            self.vector_lane(source, 0)
+        } else {
+            source
+        };
+
+        let lane = u8::try_from(start / 64).unwrap();
+        let current = self.vector_lane(target.clone(), lane);
+
+        let offset = self.constant(offset, Type::Signed(64));
+        let length = self.constant(length, Type::Signed(64));
+        let updated = self.bit_insert(current, source, offset, length);
+
+        let (low, high) = self.vector_lanes(&target);
+        if lane == 0 {
+            self.vector_from_lanes(updated, high, typ)
+        } else {
+            self.vector_from_lanes(low, updated, typ)
+        }
+    }
+
+    /// Recognises `source` being inserted into `target` as the next element of
+    /// a lane-wise operation
+    pub(super) fn lanewise_insert(
+        &self,
+        target: &X86NodeRef<A>,
+        source: &X86NodeRef<A>,
+        start: &X86NodeRef<A>,
+        length: &X86NodeRef<A>,
+    ) -> Option<Lanewise<A>> {
+        let (NodeKind::Constant { value: start, .. }, NodeKind::Constant { value: length, .. }) =
+            (start.kind(), length.kind())
+        else {
+            return None;
+        };
+        let (start, length) = (*start, *length);
+
+        if !has_elements(target.typ()) || !matches!(length, 8 | 16 | 32 | 64) {
+            return None;
+        }
+
+        let (kind, lhs, rhs) = self.lanewise_source(source, length)?;
+
+        if [&lhs, &rhs].iter().any(|element| {
+            element.start != start
+                || element.length != length
+                || element.vector.typ().width() != target.typ().width()
+        }) {
+            return None;
+        }
+
+        let elements = if start == 0 {
+            0
+        } else {
+            let previous = self.partial_lanewise.get(target)?;
+
+            (previous.kind == kind
+                && previous.lhs == lhs.vector
+                && previous.rhs == rhs.vector
+                && previous.element_width == length
+                && previous.elements * length == start)
+                .then_some(previous.elements)?
+        };
+
+        Some(Lanewise {
+            kind,
+            lhs: lhs.vector,
+            rhs: rhs.vector,
+            element_width: length,
+            elements: elements + 1,
+        })
+    }
+
+    /// Replaces `inserted` with a single vector operation if `lanewise` covers
+    /// all of its elements, otherwise remembers how many have been inserted
+    pub(super) fn complete_lanewise(
+        &mut self,
+        inserted: X86NodeRef<A>,
+        lanewise: Lanewise<A>,
+    ) -> X86NodeRef<A> {
+        if lanewise.elements * lanewise.element_width == u64::from(inserted.typ().width()) {
+            // a single element covering the whole value is already a scalar operation
+            if lanewise.elements == 1 {
+                return inserted;
+            }
+
+            let Lanewise {
+                kind,
+                lhs,
+                rhs,
+                element_width,
+                ..
+            } = lanewise;
+
+            let operation = self.vector_operation(kind, lhs, rhs, element_width);
+            return self.cast(operation, *inserted.typ(), CastOperationKind::Reinterpret);
+        }
+
+        self.partial_lanewise.insert(inserted.clone(), lanewise);
+        inserted
+    }
+
+    /// Operation and elements it is applied to if `source` computes one
+    /// `length`-bit element of a lane-wise operation
+    fn lanewise_source(
+        &self,
+        source: &X86NodeRef<A>,
+        length: u64,
+    ) -> Option<(VectorOperationKind, Element<A>, Element<A>)> {
+        use BinaryOperationKind::*;
+
+        // only the low `length` bits are inserted, so any cast of the result is
+        // irrelevant
+        let mut source = source;
+        while let NodeKind::Cast { value, kind } = source.kind() {
+            if matches!(
+                kind,
+                CastOperationKind::Convert | CastOperationKind::Broadcast
+            ) {
+                return None;
+            }
+            source = value;
+        }
+
+        let (kind, lhs, rhs) = match source.kind() {
+            NodeKind::BinaryOperation(Add(lhs, rhs)) => (VectorOperationKind::Add, lhs, rhs),
+            NodeKind::BinaryOperation(Sub(lhs, rhs)) => (VectorOperationKind::Sub, lhs, rhs),
+            NodeKind::BinaryOperation(And(lhs, rhs)) => (VectorOperationKind::And, lhs, rhs),
+            NodeKind::BinaryOperation(Or(lhs, rhs)) => (VectorOperationKind::Or, lhs, rhs),
+            NodeKind::BinaryOperation(Xor(lhs, rhs)) => (VectorOperationKind::Xor, lhs, rhs),
+            NodeKind::Select {
+                condition,
+                true_value,
+                false_value,
+            } => {
+                let (NodeKind::Constant { value: ones, .. }, NodeKind::Constant { value: 0, .. }) =
+                    (true_value.kind(), false_value.kind())
+                else {
+                    return None;
+                };
+
+                if *ones != mask(u32::try_from(length).unwrap()) {
+                    return None;
+                }
+
+                let NodeKind::BinaryOperation(comparison) = condition.kind() else {
+                    return None;
+                };
+
+                match comparison {
+                    CompareEqual(lhs, rhs) => (VectorOperationKind::CompareEqual, lhs, rhs),
+                    CompareGreaterThan(lhs, rhs) => {
+                        (VectorOperationKind::CompareGreaterThan, lhs, rhs)
+                    }
+                    CompareLessThan(lhs, rhs) => {
+                        (VectorOperationKind::CompareGreaterThan, rhs, lhs)
+                    }
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        };
+
+        let (lhs, lhs_signed) = self.element_operand(lhs)?;
+        let (rhs, rhs_signed) = self.element_operand(rhs)?;
+
+        // SSE only compares signed integers
+        if kind == VectorOperationKind::CompareGreaterThan && !(lhs_signed && rhs_signed) {
+            return None;
+        }
+
+        Some((kind, lhs, rhs))
+    }
+
+    /// Element extracted by `operand`, looking through extensions, and whether
+    /// it is operated on as a signed integer
+    fn element_operand(&self, operand: &X86NodeRef<A>) -> Option<(Element<A>, bool)> {
+        let mut signed = matches!(operand.typ(), Type::Signed(_));
+
+        let mut operand = operand;
+        while let NodeKind::Cast { value, kind } = operand.kind() {
+            match kind {
+                CastOperationKind::ZeroExtend => signed = false,
+                CastOperationKind::SignExtend | CastOperationKind::Reinterpret => (),
+                _ => return None,
+            }
+            operand = value;
+        }
+
+        self.elements
+            .get(operand)
+            .cloned()
+            .map(|element| (element, signed))
+    }
+
+    /// Loads a constant into the low lane of an `xmm` register
+    pub(super) fn vector_constant_to_operand(&mut self, value: u64) -> Operand<A> {
+        let constant = self.constant(value, LANE);
+        let low = self.to_operand_reg_promote(&constant);
+        self.lane_to_xmm(low)
+    }
+
+    /// Moves the 64-bit `low` to the low lane of a new `xmm` register, clearing
+    /// the high lane
+    fn lane_to_xmm(&mut self, low: Operand<A>) -> Operand<A> {
+        let dst = self.next_vreg();
+        self.push_instruction(Instruction::movq(low, Operand::vxmm(Width::_64, dst)));
+        Operand::vxmm(Width::_128, dst)
+    }
+
+    pub(super) fn vector_lane_to_operand(
+        &mut self,
+        vector: &X86NodeRef<A>,
+        lane: u8,
+    ) -> Operand<A> {
+        let vector = self.to_operand(vector);
+        let dst = Operand::vreg(Width::_64, self.next_vreg());
+
+        self.push_instruction(if lane == 0 {
+            Instruction::movq(vector.with_width(Width::_64), dst)
+        } else {
+            Instruction::pextrq(lane, vector, dst)
+        });
+
+        dst
+    }
+
+    pub(super) fn vector_insert_lane_to_operand(
+        &mut self,
+        vector: &X86NodeRef<A>,
+        value: &X86NodeRef<A>,
+        lane: u8,
+    ) -> Operand<A> {
+        let vector = self.to_operand(vector);
+        let value = self.to_operand_reg_promote(value);
+        let dst = Operand::vreg(Width::_128, self.next_vreg());
+
+        self.push_instruction(Instruction::mov(vector, dst).unwrap());
+        self.push_instruction(Instruction::pinsrq(lane, value, dst));
+
+        dst
+    }
+
+    /// Lowers a cast to or between 128-bit values, which zero extends or keeps
+    /// the bits of `value`
+    pub(super) fn vector_cast_to_operand(&mut self, value: &X86NodeRef<A>) -> Operand<A> {
+        if is_vector(value.typ()) {
+            return self.to_operand(value);
+        }
+
+        let low = self.cast(value.clone(), LANE, CastOperationKind::ZeroExtend);
+        let low = self.to_operand_reg_promote(&low);
+        self.lane_to_xmm(low)
+    }
+
+    pub(super) fn vector_operation_to_operand(
+        &mut self,
+        kind: VectorOperationKind,
+        lhs: &X86NodeRef<A>,
+        rhs: &X86NodeRef<A>,
+        element_width: u16,
+        typ: &Type,
+    ) -> Operand<A> {
+        // 64-bit values are operated on in the low lane of an `xmm` register
+        let (lhs, rhs) = if is_vector(typ) {
+            (self.to_operand(lhs), self.to_operand(rhs))
+        } else {
+            let lhs = self.to_operand_reg_promote(lhs);
+            let rhs = self.to_operand_reg_promote(rhs);
+            (self.lane_to_xmm(lhs), self.lane_to_xmm(rhs))
+        };
+
+        let dst = Operand::vreg(Width::_128, self.next_vreg());
+        self.push_instruction(Instruction::mov(lhs, dst).unwrap());
+
+        let element = Width::from_uncanonicalized(element_width).unwrap();
+        self.push_instruction(match kind {
+            VectorOperationKind::Add => Instruction::padd(element, rhs, dst),
+            VectorOperationKind::Sub => Instruction::psub(element, rhs, dst),
+            VectorOperationKind::And => Instruction::pand(rhs, dst),
+            VectorOperationKind::Or => Instruction::por(rhs, dst),
+            VectorOperationKind::Xor => Instruction::pxor(rhs, dst),
+            VectorOperationKind::CompareEqual => Instruction::pcmpeq(element, rhs, dst),
+            VectorOperationKind::CompareGreaterThan => Instruction::pcmpgt(element, rhs, dst),
+        });
+
+        if is_vector(typ) {
+            dst
+        } else {
+            let low = Operand::vreg(Width::_64, self.next_vreg());
+            self.push_instruction(Instruction::movq(dst.with_width(Width::_64), low));
+            low
+        }
+    }
+}
+
+#[ktest]
+fn vector_types() {
+    assert!(is_vector(&Type::Unsigned(128)));
+    assert!(is_vector(&Type::Signed(96)));
+    assert!(!is_vector(&Type::Unsigned(64)));
+
+    assert!(has_elements(&Type::Unsigned(64)));
+    assert!(has_elements(&Type::Signed(128)));
+    assert!(!has_elements(&Type::Unsigned(96)));
+}
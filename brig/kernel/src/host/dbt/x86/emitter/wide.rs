@@ -0,0 +1,1009 @@
+//! Lowering of integers wider than 128 bits
+//!
+//! Values too wide for an `xmm` register, such as SVE vectors and predicates,
+//! are split into 64-bit limbs as soon as they are produced, and every
+//! operation on them is expanded into operations on the limbs. Only the limbs
+//! are ever lowered to operands.
+//!
+//! The same expansions cover operations on 128-bit values that have no SSE
+//! equivalent, such as shifts by a variable amount, ordered compares and
+//! division.
+
+use {
+    crate::host::dbt::{
+        Alloc,
+        bitvector::limb_count,
+        emitter::Type,
+        x86::{
+            Emitter,
+            emitter::{
+                BinaryOperationKind, CastOperationKind, NodeKind, ShiftOperationKind,
+                UnaryOperationKind, X86Emitter, X86Node, X86NodeRef, vector,
+            },
+        },
+    },
+    alloc::{vec, vec::Vec},
+    common::mask::mask,
+};
+
+/// Type of each limb
+const LIMB: Type = Type::Unsigned(64);
+
+/// Limbs of a value, least significant first
+type Limbs<A> = Vec<X86NodeRef<A>>;
+
+/// Whether `typ` is an integer wider than 128 bits
+pub(super) fn is_wide(typ: &Type) -> bool {
+    matches!(typ, Type::Unsigned(129..) | Type::Signed(129..))
+}
+
+/// Whether `typ` is an integer wider than a general purpose register
+pub(super) fn is_multiword(typ: &Type) -> bool {
+    is_wide(typ) || vector::is_vector(typ)
+}
+
+/// Whether the operand of `op` is wider than 128 bits
+pub(super) fn is_wide_unary_operation<A: Alloc>(op: &UnaryOperationKind<A>) -> bool {
+    use UnaryOperationKind::*;
+
+    let (Not(value) | Negate(value) | Complement(value) | Power2(value) | Absolute(value)
+    | Ceil(value) | Floor(value) | SquareRoot(value)) = op;
+
+    is_wide(value.typ())
+}
+
+/// Whether either operand of `op` is wider than 128 bits
+pub(super) fn is_wide_operation<A: Alloc>(op: &BinaryOperationKind<A>) -> bool {
+    use BinaryOperationKind::*;
+
+    let (Add(left, right)
+    | Sub(left, right)
+    | Multiply(left, right)
+    | Divide(left, right)
+    | Modulo(left, right)
+    | And(left, right)
+    | Or(left, right)
+    | Xor(left, right)
+    | PowI(left, right)
+    | CompareEqual(left, right)
+    | CompareNotEqual(left, right)
+    | CompareLessThan(left, right)
+    | CompareLessThanOrEqual(left, right)
+    | CompareGreaterThan(left, right)
+    | CompareGreaterThanOrEqual(left, right)) = op;
+
+    is_wide(left.typ()) || is_wide(right.typ())
+}
+
+/// Number of limbs holding a value of type `typ`
+fn limbs_of(typ: &Type) -> usize {
+    limb_count(typ.width())
+}
+
+impl<'a, 'ctx, A: Alloc> X86Emitter<'ctx, A> {
+    /// The `count` least significant limbs of `value`, zero extended
+    fn limbs(&mut self, value: &X86NodeRef<A>, count: usize) -> Limbs<A> {
+        let mut limbs = match value.kind() {
+            NodeKind::Limbs(limbs) => limbs.iter().cloned().collect(),
+            NodeKind::Constant { value, .. } => vec![self.constant(*value, LIMB)],
+            _ if vector::is_vector(value.typ()) => {
+                let (low, high) = self.vector_lanes(value);
+                vec![low, high]
+            }
+            _ => vec![self.to_limb(value.clone())],
+        };
+
+        limbs.truncate(count);
+        while limbs.len() < count {
+            limbs.push(self.constant(0, LIMB));
+        }
+
+        limbs
+    }
+
+    /// Value of type `typ` made up of `limbs`
+    fn from_limbs(&mut self, mut limbs: Limbs<A>, typ: Type) -> X86NodeRef<A> {
+        let width = typ.width();
+
+        if !is_multiword(&typ) {
+            let low = self.cast(
+                limbs.swap_remove(0),
+                Type::Unsigned(width),
+                CastOperationKind::Truncate,
+            );
+            return self.cast(low, typ, CastOperationKind::Reinterpret);
+        }
+
+        limbs.truncate(limb_count(width));
+        while limbs.len() < limb_count(width) {
+            limbs.push(self.constant(0, LIMB));
+        }
+
+        // keep the bits of the top limb beyond `width` clear
+        let used = width % 64;
+        if used != 0 {
+            let top = limbs.pop().unwrap();
+            let mask = self.constant(mask(used), LIMB);
+            limbs.push(self.binary_operation(BinaryOperationKind::And(top, mask)));
+        }
+
+        if vector::is_vector(&typ) {
+            let high = limbs.pop().unwrap();
+            let low = limbs.pop().unwrap();
+            return self.vector_from_lanes(low, high, typ);
+        }
+
+        let mut stored = Vec::new_in(self.ctx().allocator());
+        stored.extend(limbs);
+
+        self.node(X86Node {
+            typ,
+            kind: NodeKind::Limbs(stored),
+        })
+    }
+
+    /// `value` as an unsigned 64-bit integer, zero extended
+    fn to_limb(&mut self, value: X86NodeRef<A>) -> X86NodeRef<A> {
+        match value.typ().width() {
+            64 if *value.typ() == LIMB => value,
+            64 => self.cast(value, LIMB, CastOperationKind::Reinterpret),
+            _ => self.cast(value, LIMB, CastOperationKind::ZeroExtend),
+        }
+    }
+
+    fn limb_shift(
+        &mut self,
+        limb: X86NodeRef<A>,
+        amount: u64,
+        kind: ShiftOperationKind,
+    ) -> X86NodeRef<A> {
+        let amount = self.constant(amount, Type::Signed(64));
+        self.shift(limb, amount, kind)
+    }
+
+    /// Sign extends the top limb of a `width`-bit value to the whole limb
+    fn sign_extend_limb(&mut self, limb: X86NodeRef<A>, width: u16) -> X86NodeRef<A> {
+        let unused = u64::from((64 - width % 64) % 64);
+        if unused == 0 {
+            return limb;
+        }
+
+        let shifted = self.limb_shift(limb, unused, ShiftOperationKind::LogicalShiftLeft);
+        self.limb_shift(shifted, unused, ShiftOperationKind::ArithmeticShiftRight)
+    }
+
+    /// Applies `op` to each pair of limbs
+    fn limbwise(
+        &mut self,
+        op: fn(X86NodeRef<A>, X86NodeRef<A>) -> BinaryOperationKind<A>,
+        lhs: &[X86NodeRef<A>],
+        rhs: &[X86NodeRef<A>],
+    ) -> Limbs<A> {
+        lhs.iter()
+            .zip(rhs)
+            .map(|(lhs, rhs)| self.binary_operation(op(lhs.clone(), rhs.clone())))
+            .collect()
+    }
+
+    fn limbs_add(&mut self, lhs: &[X86NodeRef<A>], rhs: &[X86NodeRef<A>]) -> Limbs<A> {
+        use BinaryOperationKind::*;
+
+        let mut carry = self.constant(0, LIMB);
+
+        lhs.iter()
+            .zip(rhs)
+            .map(|(lhs, rhs)| {
+                let partial = self.binary_operation(Add(lhs.clone(), rhs.clone()));
+                let sum = self.binary_operation(Add(partial.clone(), carry.clone()));
+
+                let carry_partial =
+                    self.binary_operation(CompareLessThan(partial.clone(), lhs.clone()));
+                let carry_sum = self.binary_operation(CompareLessThan(sum.clone(), partial));
+                let carry_out = self.binary_operation(Or(carry_partial, carry_sum));
+                carry = self.cast(carry_out, LIMB, CastOperationKind::ZeroExtend);
+
+                sum
+            })
+            .collect()
+    }
+
+    fn limbs_sub(&mut self, lhs: &[X86NodeRef<A>], rhs: &[X86NodeRef<A>]) -> Limbs<A> {
+        use BinaryOperationKind::*;
+
+        let mut borrow = self.constant(0, LIMB);
+
+        lhs.iter()
+            .zip(rhs)
+            .map(|(lhs, rhs)| {
+                let partial = self.binary_operation(Sub(lhs.clone(), rhs.clone()));
+                let difference = self.binary_operation(Sub(partial.clone(), borrow.clone()));
+
+                let borrow_partial =
+                    self.binary_operation(CompareLessThan(lhs.clone(), rhs.clone()));
+                let borrow_difference =
+                    self.binary_operation(CompareLessThan(partial, borrow.clone()));
+                let borrow_out = self.binary_operation(Or(borrow_partial, borrow_difference));
+                borrow = self.cast(borrow_out, LIMB, CastOperationKind::ZeroExtend);
+
+                difference
+            })
+            .collect()
+    }
+
+    fn limbs_equal(&mut self, lhs: &[X86NodeRef<A>], rhs: &[X86NodeRef<A>]) -> X86NodeRef<A> {
+        let zero = self.constant(0, LIMB);
+
+        let difference = self
+            .limbwise(BinaryOperationKind::Xor, lhs, rhs)
+            .into_iter()
+            .fold(zero.clone(), |difference, limb| {
+                self.binary_operation(BinaryOperationKind::Or(difference, limb))
+            });
+
+        self.binary_operation(BinaryOperationKind::CompareEqual(difference, zero))
+    }
+
+    /// Whether `lhs` is less than `rhs` as unsigned integers, deciding from the
+    /// most significant limb that differs
+    fn limbs_less_than(&mut self, lhs: &[X86NodeRef<A>], rhs: &[X86NodeRef<A>]) -> X86NodeRef<A> {
+        use BinaryOperationKind::*;
+
+        let mut less = self.constant(0, Type::Unsigned(1));
+
+        for (lhs, rhs) in lhs.iter().zip(rhs) {
+            let limb_less = self.binary_operation(CompareLessThan(lhs.clone(), rhs.clone()));
+            let limb_equal = self.binary_operation(CompareEqual(lhs.clone(), rhs.clone()));
+            let carried = self.binary_operation(And(limb_equal, less));
+            less = self.binary_operation(Or(limb_less, carried));
+        }
+
+        less
+    }
+
+    /// Product of `lhs` and `rhs` truncated to their number of limbs, summing
+    /// the products of each pair of 32-bit halves, which cannot overflow a limb
+    fn limbs_multiply(&mut self, lhs: &[X86NodeRef<A>], rhs: &[X86NodeRef<A>]) -> Limbs<A> {
+        use {BinaryOperationKind::*, ShiftOperationKind::LogicalShiftRight};
+
+        let low_half = self.constant(u64::from(u32::MAX), LIMB);
+        let mut halves = |limbs: &[X86NodeRef<A>]| {
+            limbs
+                .iter()
+                .flat_map(|limb| {
+                    let low = self.binary_operation(And(limb.clone(), low_half.clone()));
+                    let high = self.limb_shift(limb.clone(), 32, LogicalShiftRight);
+                    [low, high]
+                })
+                .collect::<Vec<_>>()
+        };
+        let (lhs, rhs) = (halves(lhs), halves(rhs));
+        let count = lhs.len();
+
+        // each column sums fewer than 2^32 values below 2^32
+        let mut columns = vec![self.constant(0, LIMB); count];
+        for (i, left) in lhs.iter().enumerate() {
+            for (j, right) in rhs[..count - i].iter().enumerate() {
+                let product = self.binary_operation(Multiply(left.clone(), right.clone()));
+
+                let low = self.binary_operation(And(product.clone(), low_half.clone()));
+                columns[i + j] = self.binary_operation(Add(columns[i + j].clone(), low));
+
+                if i + j + 1 < count {
+                    let high = self.limb_shift(product, 32, LogicalShiftRight);
+                    columns[i + j + 1] =
+                        self.binary_operation(Add(columns[i + j + 1].clone(), high));
+                }
+            }
+        }
+
+        let mut carry = self.constant(0, LIMB);
+        let digits = columns
+            .into_iter()
+            .map(|column| {
+                let sum = self.binary_operation(Add(column, carry.clone()));
+                carry = self.limb_shift(sum.clone(), 32, LogicalShiftRight);
+                self.binary_operation(And(sum, low_half.clone()))
+            })
+            .collect::<Vec<_>>();
+
+        digits
+            .chunks(2)
+            .map(|pair| {
+                let high =
+                    self.limb_shift(pair[1].clone(), 32, ShiftOperationKind::LogicalShiftLeft);
+                self.binary_operation(Or(pair[0].clone(), high))
+            })
+            .collect()
+    }
+
+    /// Quotient and remainder of `lhs` divided by `rhs`, truncating towards
+    /// zero if `typ` is signed
+    fn limbs_divide(&mut self, lhs: Limbs<A>, rhs: Limbs<A>, typ: Type) -> (Limbs<A>, Limbs<A>) {
+        if !matches!(typ, Type::Signed(_)) {
+            return self.limbs_divide_unsigned(&lhs, &rhs, typ.width());
+        }
+
+        let (lhs, lhs_negative) = self.limbs_magnitude(lhs, typ);
+        let (rhs, rhs_negative) = self.limbs_magnitude(rhs, typ);
+
+        let (quotient, remainder) = self.limbs_divide_unsigned(&lhs, &rhs, typ.width());
+
+        // the remainder takes the sign of the dividend
+        let quotient_negative =
+            self.binary_operation(BinaryOperationKind::Xor(lhs_negative.clone(), rhs_negative));
+        (
+            self.limbs_negate_if(quotient, quotient_negative),
+            self.limbs_negate_if(remainder, lhs_negative),
+        )
+    }
+
+    /// Quotient and remainder of `lhs` divided by `rhs` as unsigned `width`-bit
+    /// integers, by restoring division one bit at a time
+    fn limbs_divide_unsigned(
+        &mut self,
+        lhs: &[X86NodeRef<A>],
+        rhs: &[X86NodeRef<A>],
+        width: u16,
+    ) -> (Limbs<A>, Limbs<A>) {
+        use {BinaryOperationKind::*, ShiftOperationKind::*};
+
+        let zero = self.constant(0, LIMB);
+        let one = self.constant(1, LIMB);
+        let mut quotient = vec![zero.clone(); lhs.len()];
+        let mut remainder = vec![zero.clone(); lhs.len()];
+
+        for bit in (0..u64::from(width)).rev() {
+            let (limb, offset) = (usize::try_from(bit / 64).unwrap(), bit % 64);
+
+            // the bit shifted out of the remainder is only set once it exceeds any divisor
+            let top = remainder.last().unwrap().clone();
+            let top = self.limb_shift(top, 63, LogicalShiftRight);
+            let overflow = self.binary_operation(CompareNotEqual(top, zero.clone()));
+
+            let mut shifted = self.limbs_shift_left(&remainder, 1);
+            let next = self.limb_shift(lhs[limb].clone(), offset, LogicalShiftRight);
+            let next = self.binary_operation(And(next, one.clone()));
+            shifted[0] = self.binary_operation(Or(shifted[0].clone(), next));
+
+            let less = self.limbs_less_than(&shifted, rhs);
+            let at_least = self.unary_operation(UnaryOperationKind::Not(less));
+            let subtract = self.binary_operation(Or(overflow, at_least));
+
+            let difference = self.limbs_sub(&shifted, rhs);
+            remainder = difference
+                .into_iter()
+                .zip(shifted)
+                .map(|(difference, kept)| self.select(subtract.clone(), difference, kept))
+                .collect();
+
+            let set = self.cast(subtract, LIMB, CastOperationKind::ZeroExtend);
+            let set = self.limb_shift(set, offset, LogicalShiftLeft);
+            quotient[limb] = self.binary_operation(Or(quotient[limb].clone(), set));
+        }
+
+        (quotient, remainder)
+    }
+
+    /// Largest integer whose square is at most `limbs` as an unsigned
+    /// `width`-bit integer, one bit at a time
+    fn limbs_square_root(&mut self, limbs: Limbs<A>, width: u16) -> Limbs<A> {
+        use BinaryOperationKind::*;
+
+        let mut root = vec![self.constant(0, LIMB); limbs.len()];
+        let mut remaining = limbs;
+
+        // the root only has bits above the one being tried, so adding it is an OR
+        let top = u64::from(width.saturating_sub(1)) & !1;
+        for bit in (0..=top).rev().step_by(2) {
+            let (limb, offset) = (usize::try_from(bit / 64).unwrap(), bit % 64);
+            let bit_value = self.constant(1 << offset, LIMB);
+
+            let mut candidate = root.clone();
+            candidate[limb] = self.binary_operation(Or(candidate[limb].clone(), bit_value.clone()));
+
+            let zero = self.constant(0, LIMB);
+            let shifted = self.limbs_shift_right(&root, 1, zero);
+            let mut set = shifted.clone();
+            set[limb] = self.binary_operation(Or(set[limb].clone(), bit_value));
+
+            let less = self.limbs_less_than(&remaining, &candidate);
+            let difference = self.limbs_sub(&remaining, &candidate);
+
+            remaining = remaining
+                .into_iter()
+                .zip(difference)
+                .map(|(kept, difference)| self.select(less.clone(), kept, difference))
+                .collect();
+            root = shifted
+                .into_iter()
+                .zip(set)
+                .map(|(kept, set)| self.select(less.clone(), kept, set))
+                .collect();
+        }
+
+        root
+    }
+
+    /// Absolute value of the signed `limbs` of type `typ`, which are returned
+    /// unchanged if unsigned, and whether they were negative
+    fn limbs_magnitude(&mut self, mut limbs: Limbs<A>, typ: Type) -> (Limbs<A>, X86NodeRef<A>) {
+        let Type::Signed(width) = typ else {
+            return (limbs, self.constant(0, Type::Unsigned(1)));
+        };
+
+        // negated across whole limbs, so the sign is extended to the top of the last
+        let top = limbs.pop().unwrap();
+        let top = self.sign_extend_limb(top, width);
+        limbs.push(top.clone());
+
+        let sign = self.limb_shift(top, 63, ShiftOperationKind::LogicalShiftRight);
+        let zero = self.constant(0, LIMB);
+        let negative = self.binary_operation(BinaryOperationKind::CompareNotEqual(sign, zero));
+
+        (self.limbs_negate_if(limbs, negative.clone()), negative)
+    }
+
+    /// Negates `limbs` if `condition` is set
+    fn limbs_negate_if(&mut self, limbs: Limbs<A>, condition: X86NodeRef<A>) -> Limbs<A> {
+        let zero = vec![self.constant(0, LIMB); limbs.len()];
+        let negated = self.limbs_sub(&zero, &limbs);
+
+        negated
+            .into_iter()
+            .zip(limbs)
+            .map(|(negated, limb)| self.select(condition.clone(), negated, limb))
+            .collect()
+    }
+
+    /// `base` to the power of `exponent` by repeated squaring, selecting each
+    /// multiplication by a bit of `exponent` unless it is a constant
+    fn wide_power(&mut self, base: X86NodeRef<A>, exponent: X86NodeRef<A>) -> X86NodeRef<A> {
+        use {BinaryOperationKind::*, ShiftOperationKind::LogicalShiftRight};
+
+        let typ = *base.typ();
+        let count = limbs_of(&typ);
+
+        let mut base = self.limbs(&base, count);
+        let one = self.constant(1, LIMB);
+        let mut power = self.limbs(&one, count);
+
+        let constant = match exponent.kind() {
+            NodeKind::Constant { value, .. } => Some(*value),
+            _ => None,
+        };
+        let exponent = self.to_limb(exponent);
+        let bits = constant.map_or(64, |value| u64::from(u64::BITS - value.leading_zeros()));
+
+        for bit in 0..bits {
+            power = match constant {
+                Some(value) if (value >> bit) & 1 == 0 => power,
+                Some(_) => self.limbs_multiply(&power, &base),
+                None => {
+                    let multiplied = self.limbs_multiply(&power, &base);
+
+                    let set = self.limb_shift(exponent.clone(), bit, LogicalShiftRight);
+                    let set = self.binary_operation(And(set, one.clone()));
+                    let set = self.binary_operation(CompareEqual(set, one.clone()));
+
+                    multiplied
+                        .into_iter()
+                        .zip(power)
+                        .map(|(multiplied, kept)| self.select(set.clone(), multiplied, kept))
+                        .collect()
+                }
+            };
+
+            if bit + 1 < bits {
+                base = self.limbs_multiply(&base, &base);
+            }
+        }
+
+        self.from_limbs(power, typ)
+    }
+
+    /// Limbs shifted left by a constant `amount`
+    fn limbs_shift_left(&mut self, limbs: &[X86NodeRef<A>], amount: u64) -> Limbs<A> {
+        let (words, bits) = (amount / 64, amount % 64);
+
+        (0..limbs.len())
+            .map(|index| {
+                let limb = |offset: u64| {
+                    (index as u64)
+                        .checked_sub(words + offset)
+                        .map(|source| limbs[source as usize].clone())
+                };
+
+                let Some(current) = limb(0) else {
+                    return self.constant(0, LIMB);
+                };
+
+                if bits == 0 {
+                    return current;
+                }
+
+                let current = self.limb_shift(current, bits, ShiftOperationKind::LogicalShiftLeft);
+                match limb(1) {
+                    Some(below) => {
+                        let carried = self.limb_shift(
+                            below,
+                            64 - bits,
+                            ShiftOperationKind::LogicalShiftRight,
+                        );
+                        self.binary_operation(BinaryOperationKind::Or(current, carried))
+                    }
+                    None => current,
+                }
+            })
+            .collect()
+    }
+
+    /// Limbs shifted right by a constant `amount`, with `fill` shifted in
+    /// above the most significant limb
+    fn limbs_shift_right(
+        &mut self,
+        limbs: &[X86NodeRef<A>],
+        amount: u64,
+        fill: X86NodeRef<A>,
+    ) -> Limbs<A> {
+        let (words, bits) = (amount / 64, amount % 64);
+
+        (0..limbs.len())
+            .map(|index| {
+                let limb = |offset: u64| {
+                    usize::try_from(index as u64 + words + offset)
+                        .ok()
+                        .and_then(|source| limbs.get(source))
+                        .unwrap_or(&fill)
+                        .clone()
+                };
+
+                let current = limb(0);
+                if bits == 0 {
+                    return current;
+                }
+
+                let current = self.limb_shift(current, bits, ShiftOperationKind::LogicalShiftRight);
+                let carried =
+                    self.limb_shift(limb(1), 64 - bits, ShiftOperationKind::LogicalShiftLeft);
+                self.binary_operation(BinaryOperationKind::Or(current, carried))
+            })
+            .collect()
+    }
+
+    /// Limbs shifted by a variable `amount`, selecting between every possible
+    /// number of whole limbs shifted
+    fn limbs_shift_variable(
+        &mut self,
+        limbs: &[X86NodeRef<A>],
+        amount: X86NodeRef<A>,
+        left: bool,
+        fill: X86NodeRef<A>,
+    ) -> Limbs<A> {
+        use {BinaryOperationKind::*, ShiftOperationKind::*};
+
+        let amount = self.to_limb(amount);
+        let words = self.limb_shift(amount.clone(), 6, LogicalShiftRight);
+        let sixty_three = self.constant(63, LIMB);
+        let bits = self.binary_operation(And(amount, sixty_three.clone()));
+        // `64 - bits` is out of range for a shift when `bits` is zero, so the
+        // neighbouring limb is shifted by one and then by `63 - bits`
+        let remaining = self.binary_operation(Xor(bits.clone(), sixty_three));
+        let one = self.constant(1, LIMB);
+
+        let mut shifted = vec![
+            if left {
+                self.constant(0, LIMB)
+            } else {
+                fill.clone()
+            };
+            limbs.len()
+        ];
+
+        for count in 0..limbs.len() {
+            let count_node = self.constant(count as u64, LIMB);
+            let selected = self.binary_operation(CompareEqual(words.clone(), count_node));
+
+            for (index, result) in shifted.iter_mut().enumerate() {
+                let (current, neighbour) = if left {
+                    let limb = |offset: usize| {
+                        index
+                            .checked_sub(count + offset)
+                            .map(|source| limbs[source].clone())
+                    };
+                    (limb(0), limb(1))
+                } else {
+                    let limb = |offset: usize| {
+                        Some(limbs.get(index + count + offset).unwrap_or(&fill).clone())
+                    };
+                    (limb(0), limb(1))
+                };
+
+                let Some(current) = current else {
+                    continue;
+                };
+
+                let (near, far) = if left {
+                    (LogicalShiftLeft, LogicalShiftRight)
+                } else {
+                    (LogicalShiftRight, LogicalShiftLeft)
+                };
+
+                let mut candidate = self.shift(current, bits.clone(), near);
+                if let Some(neighbour) = neighbour {
+                    let neighbour = self.shift(neighbour, one.clone(), far.clone());
+                    let carried = self.shift(neighbour, remaining.clone(), far);
+                    candidate = self.binary_operation(Or(candidate, carried));
+                }
+
+                *result = self.select(selected.clone(), candidate, result.clone());
+            }
+        }
+
+        shifted
+    }
+
+    pub(super) fn wide_unary_operation(&mut self, op: UnaryOperationKind<A>) -> X86NodeRef<A> {
+        match op {
+            UnaryOperationKind::Complement(value) => {
+                let typ = *value.typ();
+                let limbs = self
+                    .limbs(&value, limbs_of(&typ))
+                    .into_iter()
+                    .map(|limb| self.unary_operation(UnaryOperationKind::Complement(limb)))
+                    .collect();
+                self.from_limbs(limbs, typ)
+            }
+            UnaryOperationKind::Negate(value) => {
+                let zero = self.constant(0, *value.typ());
+                self.wide_binary_operation(BinaryOperationKind::Sub(zero, value))
+            }
+            UnaryOperationKind::Not(value) => {
+                let zero = self.constant(0, *value.typ());
+                self.wide_binary_operation(BinaryOperationKind::CompareEqual(value, zero))
+            }
+            UnaryOperationKind::Absolute(value) => {
+                let typ = *value.typ();
+                let limbs = self.limbs(&value, limbs_of(&typ));
+                let (limbs, _) = self.limbs_magnitude(limbs, typ);
+                self.from_limbs(limbs, typ)
+            }
+            UnaryOperationKind::Power2(value) => {
+                let typ = *value.typ();
+                let one = self.constant(1, LIMB);
+                let one = self.from_limbs(vec![one], typ);
+                self.wide_shift(one, value, ShiftOperationKind::LogicalShiftLeft)
+            }
+            // integers are already whole
+            UnaryOperationKind::Ceil(value) | UnaryOperationKind::Floor(value) => value,
+            UnaryOperationKind::SquareRoot(value) => {
+                let typ = *value.typ();
+                let limbs = self.limbs(&value, limbs_of(&typ));
+                let root = self.limbs_square_root(limbs, typ.width());
+                self.from_limbs(root, typ)
+            }
+        }
+    }
+
+    pub(super) fn wide_binary_operation(&mut self, op: BinaryOperationKind<A>) -> X86NodeRef<A> {
+        use BinaryOperationKind::*;
+
+        let (Add(lhs, rhs)
+        | Sub(lhs, rhs)
+        | Multiply(lhs, rhs)
+        | Divide(lhs, rhs)
+        | Modulo(lhs, rhs)
+        | And(lhs, rhs)
+        | Or(lhs, rhs)
+        | Xor(lhs, rhs)
+        | PowI(lhs, rhs)
+        | CompareEqual(lhs, rhs)
+        | CompareNotEqual(lhs, rhs)
+        | CompareLessThan(lhs, rhs)
+        | CompareLessThanOrEqual(lhs, rhs)
+        | CompareGreaterThan(lhs, rhs)
+        | CompareGreaterThanOrEqual(lhs, rhs)) = &op;
+
+        // the exponent is an integer of any width, only the base is wide
+        if let PowI(base, exponent) = &op {
+            return self.wide_power(base.clone(), exponent.clone());
+        }
+
+        let typ = if is_multiword(lhs.typ()) {
+            *lhs.typ()
+        } else {
+            *rhs.typ()
+        };
+        let count = limbs_of(&typ);
+
+        let mut lhs = self.limbs(lhs, count);
+        let mut rhs = self.limbs(rhs, count);
+
+        let result = match op {
+            Add(..) => self.limbs_add(&lhs, &rhs),
+            Sub(..) => self.limbs_sub(&lhs, &rhs),
+            Multiply(..) => self.limbs_multiply(&lhs, &rhs),
+            Divide(..) | Modulo(..) => {
+                let (quotient, remainder) = self.limbs_divide(lhs, rhs, typ);
+                if let Divide(..) = op {
+                    quotient
+                } else {
+                    remainder
+                }
+            }
+            And(..) => self.limbwise(And, &lhs, &rhs),
+            Or(..) => self.limbwise(Or, &lhs, &rhs),
+            Xor(..) => self.limbwise(Xor, &lhs, &rhs),
+            CompareEqual(..) => return self.limbs_equal(&lhs, &rhs),
+            CompareNotEqual(..) => {
+                let equal = self.limbs_equal(&lhs, &rhs);
+                return self.unary_operation(UnaryOperationKind::Not(equal));
+            }
+            op => {
+                // signed integers compare as unsigned once their sign bits are flipped
+                if let Type::Signed(width) = typ {
+                    let sign = self.constant(1u64 << ((width - 1) % 64), LIMB);
+                    for limbs in [&mut lhs, &mut rhs] {
+                        let top = limbs.pop().unwrap();
+                        limbs.push(self.binary_operation(Xor(top, sign.clone())));
+                    }
+                }
+
+                return match op {
+                    CompareLessThan(..) => self.limbs_less_than(&lhs, &rhs),
+                    CompareGreaterThan(..) => self.limbs_less_than(&rhs, &lhs),
+                    CompareLessThanOrEqual(..) => {
+                        let greater = self.limbs_less_than(&rhs, &lhs);
+                        self.unary_operation(UnaryOperationKind::Not(greater))
+                    }
+                    CompareGreaterThanOrEqual(..) => {
+                        let less = self.limbs_less_than(&lhs, &rhs);
+                        self.unary_operation(UnaryOperationKind::Not(less))
+                    }
+                    _ => unreachable!(),
+                };
+            }
+        };
+
+        self.from_limbs(result, typ)
+    }
+
+    /// Shifts a value wider than 64 bits by a constant or variable `amount`
+    pub(super) fn wide_shift(
+        &mut self,
+        value: X86NodeRef<A>,
+        amount: X86NodeRef<A>,
+        kind: ShiftOperationKind,
+    ) -> X86NodeRef<A> {
+        use ShiftOperationKind::*;
+
+        if let RotateLeft | RotateRight = kind {
+            return self.wide_rotate(value, amount, kind == RotateLeft);
+        }
+
+        let typ = *value.typ();
+        let mut limbs = self.limbs(&value, limbs_of(&typ));
+
+        let fill = match kind {
+            LogicalShiftLeft | LogicalShiftRight => self.constant(0, LIMB),
+            ArithmeticShiftRight => {
+                let top = limbs.pop().unwrap();
+                let top = self.sign_extend_limb(top, typ.width());
+                limbs.push(top.clone());
+                self.limb_shift(top, 63, ArithmeticShiftRight)
+            }
+            RotateLeft | RotateRight => unreachable!(),
+        };
+
+        let shifted = match (amount.kind(), &kind) {
+            (NodeKind::Constant { value: amount, .. }, LogicalShiftLeft) => {
+                self.limbs_shift_left(&limbs, *amount)
+            }
+            (NodeKind::Constant { value: amount, .. }, _) => {
+                self.limbs_shift_right(&limbs, *amount, fill)
+            }
+            (_, LogicalShiftLeft) => self.limbs_shift_variable(&limbs, amount, true, fill),
+            (_, _) => self.limbs_shift_variable(&limbs, amount, false, fill),
+        };
+
+        self.from_limbs(shifted, typ)
+    }
+
+    /// Rotates a value wider than 64 bits as the bits shifted out one way
+    /// combined with those shifted out the other
+    fn wide_rotate(
+        &mut self,
+        value: X86NodeRef<A>,
+        amount: X86NodeRef<A>,
+        left: bool,
+    ) -> X86NodeRef<A> {
+        use {BinaryOperationKind::*, ShiftOperationKind::*};
+
+        let width = u64::from(value.typ().width());
+
+        // shifting by the whole width leaves no bits, so a rotate by zero is unchanged
+        let (amount, remaining) = match amount.kind() {
+            NodeKind::Constant { value: amount, .. } => (
+                self.constant(amount % width, LIMB),
+                self.constant(width - amount % width, LIMB),
+            ),
+            _ => {
+                let amount = self.to_limb(amount);
+                let width = self.constant(width, LIMB);
+                let amount = self.binary_operation(Modulo(amount, width.clone()));
+                (amount.clone(), self.binary_operation(Sub(width, amount)))
+            }
+        };
+
+        let (near, far) = if left {
+            (LogicalShiftLeft, LogicalShiftRight)
+        } else {
+            (LogicalShiftRight, LogicalShiftLeft)
+        };
+
+        let near = self.wide_shift(value.clone(), amount, near);
+        let far = self.wide_shift(value, remaining, far);
+        self.binary_operation(Or(near, far))
+    }
+
+    /// Casts to or from a value wider than 128 bits
+    pub(super) fn wide_cast(
+        &mut self,
+        value: X86NodeRef<A>,
+        target: Type,
+        kind: CastOperationKind,
+    ) -> X86NodeRef<A> {
+        let count = limbs_of(&target);
+
+        match kind {
+            CastOperationKind::SignExtend => {
+                let width = value.typ().width();
+                let mut limbs = self.limbs(&value, limb_count(width));
+
+                let top = limbs.pop().unwrap();
+                let top = self.sign_extend_limb(top, width);
+                let fill =
+                    self.limb_shift(top.clone(), 63, ShiftOperationKind::ArithmeticShiftRight);
+
+                limbs.push(top);
+                limbs.resize(count.max(limbs.len()), fill);
+
+                self.from_limbs(limbs, target)
+            }
+            // keeps the value, as when folding a constant
+            CastOperationKind::Convert if matches!(value.typ(), Type::Signed(_)) => {
+                self.wide_cast(value, target, CastOperationKind::SignExtend)
+            }
+            // zero extension, truncation, reinterpretation and the remaining
+            // conversions only add or drop limbs
+            _ => {
+                let limbs = self.limbs(&value, count);
+                self.from_limbs(limbs, target)
+            }
+        }
+    }
+
+    pub(super) fn wide_select(
+        &mut self,
+        condition: X86NodeRef<A>,
+        true_value: X86NodeRef<A>,
+        false_value: X86NodeRef<A>,
+    ) -> X86NodeRef<A> {
+        let typ = *true_value.typ();
+        let count = limbs_of(&typ);
+
+        let true_limbs = self.limbs(&true_value, count);
+        let false_limbs = self.limbs(&false_value, count);
+
+        let limbs = true_limbs
+            .into_iter()
+            .zip(false_limbs)
+            .map(|(true_limb, false_limb)| self.select(condition.clone(), true_limb, false_limb))
+            .collect();
+
+        self.from_limbs(limbs, typ)
+    }
+
+    /// Extracts `length` bits from `start` of a value wider than 64 bits,
+    /// either of which may be variable
+    pub(super) fn wide_bit_extract(
+        &mut self,
+        value: X86NodeRef<A>,
+        start: X86NodeRef<A>,
+        length: X86NodeRef<A>,
+    ) -> X86NodeRef<A> {
+        let typ = *value.typ();
+        let shifted = self.wide_shift(value, start, ShiftOperationKind::LogicalShiftRight);
+
+        if let NodeKind::Constant { value: length, .. } = length.kind() {
+            let target = Type::Unsigned(u16::try_from(*length).unwrap());
+            let limbs = self.limbs(&shifted, limbs_of(&target));
+            return self.from_limbs(limbs, target);
+        }
+
+        let field = self.wide_field(typ, length);
+        self.binary_operation(BinaryOperationKind::And(shifted, field))
+    }
+
+    /// Inserts `length` bits of `source` into a value wider than 64 bits at
+    /// `start`, either of which may be variable
+    pub(super) fn wide_bit_insert(
+        &mut self,
+        target: X86NodeRef<A>,
+        source: X86NodeRef<A>,
+        start: X86NodeRef<A>,
+        length: X86NodeRef<A>,
+    ) -> X86NodeRef<A> {
+        use {BinaryOperationKind::*, ShiftOperationKind::LogicalShiftLeft};
+
+        let typ = *target.typ();
+
+        let field = self.wide_field(typ, length);
+        let source = self.limbs(&source, limbs_of(&typ));
+        let source = self.from_limbs(source, typ);
+        let source = self.binary_operation(And(source, field.clone()));
+
+        let field = self.shift(field, start.clone(), LogicalShiftLeft);
+        let source = self.shift(source, start, LogicalShiftLeft);
+
+        let kept = self.unary_operation(UnaryOperationKind::Complement(field));
+        let kept = self.binary_operation(And(target, kept));
+        self.binary_operation(Or(kept, source))
+    }
+
+    /// Value of type `typ` with its `length` least significant bits set
+    fn wide_field(&mut self, typ: Type, length: X86NodeRef<A>) -> X86NodeRef<A> {
+        let ones = vec![self.constant(u64::MAX, LIMB); limbs_of(&typ)];
+        let ones = self.from_limbs(ones, typ);
+
+        let width = self.constant(u64::from(typ.width()), LIMB);
+        let length = self.to_limb(length);
+        let unused = self.binary_operation(BinaryOperationKind::Sub(width, length));
+
+        self.shift(ones, unused, ShiftOperationKind::LogicalShiftRight)
+    }
+
+    pub(super) fn read_wide_register(&mut self, offset: u64, typ: Type) -> X86NodeRef<A> {
+        let limbs = (0..limbs_of(&typ))
+            .map(|index| self.read_register(offset + index as u64 * 8, LIMB))
+            .collect();
+
+        self.from_limbs(limbs, typ)
+    }
+
+    /// Writes each limb of `value`, the most significant only as wide as the
+    /// bits of `value` it holds
+    pub(super) fn write_wide_register(&mut self, offset: u64, value: X86NodeRef<A>) {
+        let width = value.typ().width();
+        let limbs = self.limbs(&value, limb_count(width));
+
+        for (index, limb) in limbs.into_iter().enumerate() {
+            let remaining = width - u16::try_from(index * 64).unwrap();
+
+            let limb = if remaining < 64 {
+                self.cast(limb, Type::Unsigned(remaining), CastOperationKind::Truncate)
+            } else {
+                limb
+            };
+
+            self.write_register(offset + index as u64 * 8, limb);
+        }
+    }
+
+    /// Reads a value stored in consecutive stack variables from `id`, one per
+    /// limb
+    pub(super) fn read_wide_stack_variable(&mut self, id: usize, typ: Type) -> X86NodeRef<A> {
+        let limbs = (0..limbs_of(&typ))
+            .map(|index| self.read_stack_variable(id + index, LIMB))
+            .collect();
+
+        self.from_limbs(limbs, typ)
+    }
+
+    pub(super) fn write_wide_stack_variable(&mut self, id: usize, value: X86NodeRef<A>) {
+        let limbs = self.limbs(&value, limbs_of(value.typ()));
+
+        for (index, limb) in limbs.into_iter().enumerate() {
+            self.write_stack_variable(id + index, limb);
+        }
+    }
+}
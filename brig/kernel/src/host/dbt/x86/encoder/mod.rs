@@ -1,6 +1,7 @@
 use {
     crate::host::dbt::{
         Alloc,
+        persist::Symbol,
         x86::{
             emitter::{ARG_REGS, X86Block},
             encoder::width::Width,
@@ -12,8 +13,8 @@ use {
     derive_where::derive_where,
     displaydoc::Display,
     iced_x86::code_asm::{
-        AsmMemoryOperand, AsmRegister8, AsmRegister16, AsmRegister32, AsmRegister64, CodeAssembler,
-        CodeLabel, qword_ptr,
+        AsmMemoryOperand, AsmRegister8, AsmRegister16, AsmRegister32, AsmRegister64,
+        AsmRegisterXmm, CodeAssembler, CodeLabel, qword_ptr,
     },
 };
 
@@ -29,11 +30,23 @@ mod or;
 mod setne;
 mod shl;
 mod shr;
+mod sse;
 mod sub;
 mod test;
+mod tlblookup;
 pub mod width;
 mod xor;
 
+/// Tag of a chain slot that has not been linked, never equal to a PC as they
+/// are 4 byte aligned
+pub const CHAIN_UNLINKED_TAG: u64 = 1;
+/// Offset of the immediate within an encoded [`Opcode::MOVREL`]
+pub const RELOCATION_OFFSET: usize = 2;
+/// Offset of the tag immediate within an encoded [`Opcode::CHAINTAG`]
+pub const CHAIN_TAG_OFFSET: usize = RELOCATION_OFFSET;
+/// Offset of the jump displacement within an encoded [`Opcode::CHAINJMP`]
+pub const CHAIN_TARGET_OFFSET: usize = 3;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
 pub enum Opcode<A: Alloc> {
     /// mov {0}, {1}
@@ -119,6 +132,10 @@ pub enum Opcode<A: Alloc> {
     SETLE(Operand<A>),
     /// setae {0}
     SETAE(Operand<A>),
+    /// setp {0}
+    SETP(Operand<A>),
+    /// setnp {0}
+    SETNP(Operand<A>),
     /// je {0}
     JE(Operand<A>),
     /// jne {0}
@@ -140,6 +157,74 @@ pub enum Opcode<A: Alloc> {
         nr_input_args: usize,
         nr_output_args: usize,
     },
+
+    /// movq {0}, {1}
+    MOVQ(Operand<A>, Operand<A>),
+    /// adds {0}, {1}
+    FADD(Operand<A>, Operand<A>),
+    /// subs {0}, {1}
+    FSUB(Operand<A>, Operand<A>),
+    /// muls {0}, {1}
+    FMUL(Operand<A>, Operand<A>),
+    /// divs {0}, {1}
+    FDIV(Operand<A>, Operand<A>),
+    /// sqrts {0}, {1}
+    FSQRT(Operand<A>, Operand<A>),
+    /// ucomis {0}, {1}
+    UCOMIS(Operand<A>, Operand<A>),
+    /// cvtsi2s {0}, {1}
+    CVTSI2F(Operand<A>, Operand<A>),
+    /// cvts2si {0}, {1}
+    CVTF2SI(Operand<A>, Operand<A>),
+    /// cvts2s {0}, {1}
+    CVTF2F(Operand<A>, Operand<A>),
+    /// ldmxcsr {0}
+    LDMXCSR(Operand<A>),
+    /// stmxcsr {0}
+    STMXCSR(Operand<A>),
+    /// movdqu {0}, {1}
+    MOVDQU(Operand<A>, Operand<A>),
+    /// pextrq {0}, {1}, {2}
+    PEXTRQ(Operand<A>, Operand<A>, Operand<A>),
+    /// pinsrq {0}, {1}, {2}
+    PINSRQ(Operand<A>, Operand<A>, Operand<A>),
+    /// padd<{0}> {1}, {2}
+    PADD(Width, Operand<A>, Operand<A>),
+    /// psub<{0}> {1}, {2}
+    PSUB(Width, Operand<A>, Operand<A>),
+    /// pcmpeq<{0}> {1}, {2}
+    PCMPEQ(Width, Operand<A>, Operand<A>),
+    /// pcmpgt<{0}> {1}, {2}
+    PCMPGT(Width, Operand<A>, Operand<A>),
+    /// pand {0}, {1}
+    PAND(Operand<A>, Operand<A>),
+    /// por {0}, {1}
+    POR(Operand<A>, Operand<A>),
+    /// pxor {0}, {1}
+    PXOR(Operand<A>, Operand<A>),
+
+    /// mov {0}, <chain tag>
+    CHAINTAG(Operand<A>),
+    /// jne +5; jmp <chain target>
+    CHAINJMP,
+    /// mov {2}, {1:#x} <{0}>
+    MOVREL(Symbol, u64, Operand<A>),
+    /// tlblookup {host}, {index} <{width}>
+    TLBLOOKUP {
+        host: Operand<A>,
+        index: Operand<A>,
+        width: Width,
+        value: Option<Operand<A>>,
+    },
+}
+
+/// Labels of instructions that are patched after assembly
+#[derive(Default)]
+pub struct PatchLabels {
+    /// Alternating [`Opcode::CHAINTAG`] and [`Opcode::CHAINJMP`] instructions
+    pub chain: Vec<CodeLabel>,
+    /// [`Opcode::MOVREL`] instructions and the symbol each refers to
+    pub relocations: Vec<(CodeLabel, Symbol)>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Display, Hash)]
@@ -176,6 +261,38 @@ pub enum PhysicalRegister {
     R14,
     /// r15
     R15,
+    /// xmm0
+    XMM0,
+    /// xmm1
+    XMM1,
+    /// xmm2
+    XMM2,
+    /// xmm3
+    XMM3,
+    /// xmm4
+    XMM4,
+    /// xmm5
+    XMM5,
+    /// xmm6
+    XMM6,
+    /// xmm7
+    XMM7,
+    /// xmm8
+    XMM8,
+    /// xmm9
+    XMM9,
+    /// xmm10
+    XMM10,
+    /// xmm11
+    XMM11,
+    /// xmm12
+    XMM12,
+    /// xmm13
+    XMM13,
+    /// xmm14
+    XMM14,
+    /// xmm15
+    XMM15,
 }
 
 impl PhysicalRegister {
@@ -197,6 +314,22 @@ impl PhysicalRegister {
             PhysicalRegister::R13 => 13,
             PhysicalRegister::R14 => 14,
             PhysicalRegister::R15 => 15,
+            PhysicalRegister::XMM0 => 16,
+            PhysicalRegister::XMM1 => 17,
+            PhysicalRegister::XMM2 => 18,
+            PhysicalRegister::XMM3 => 19,
+            PhysicalRegister::XMM4 => 20,
+            PhysicalRegister::XMM5 => 21,
+            PhysicalRegister::XMM6 => 22,
+            PhysicalRegister::XMM7 => 23,
+            PhysicalRegister::XMM8 => 24,
+            PhysicalRegister::XMM9 => 25,
+            PhysicalRegister::XMM10 => 26,
+            PhysicalRegister::XMM11 => 27,
+            PhysicalRegister::XMM12 => 28,
+            PhysicalRegister::XMM13 => 29,
+            PhysicalRegister::XMM14 => 30,
+            PhysicalRegister::XMM15 => 31,
         }
     }
 
@@ -218,9 +351,31 @@ impl PhysicalRegister {
             13 => PhysicalRegister::R13,
             14 => PhysicalRegister::R14,
             15 => PhysicalRegister::R15,
+            16 => PhysicalRegister::XMM0,
+            17 => PhysicalRegister::XMM1,
+            18 => PhysicalRegister::XMM2,
+            19 => PhysicalRegister::XMM3,
+            20 => PhysicalRegister::XMM4,
+            21 => PhysicalRegister::XMM5,
+            22 => PhysicalRegister::XMM6,
+            23 => PhysicalRegister::XMM7,
+            24 => PhysicalRegister::XMM8,
+            25 => PhysicalRegister::XMM9,
+            26 => PhysicalRegister::XMM10,
+            27 => PhysicalRegister::XMM11,
+            28 => PhysicalRegister::XMM12,
+            29 => PhysicalRegister::XMM13,
+            30 => PhysicalRegister::XMM14,
+            31 => PhysicalRegister::XMM15,
             _ => unreachable!(),
         }
     }
+
+    /// Whether this is one of the SSE registers rather than a general purpose
+    /// register
+    pub fn is_xmm(&self) -> bool {
+        self.index() >= 16
+    }
 }
 
 impl From<&PhysicalRegister> for AsmRegister64 {
@@ -246,6 +401,7 @@ impl From<&PhysicalRegister> for AsmRegister64 {
             PhysicalRegister::R13 => r13,
             PhysicalRegister::R14 => r14,
             PhysicalRegister::R15 => r15,
+            xmm => panic!("{xmm} is not a general purpose register"),
         }
     }
 }
@@ -279,6 +435,7 @@ impl From<&PhysicalRegister> for AsmRegister8 {
             PhysicalRegister::R13 => r13b,
             PhysicalRegister::R14 => r14b,
             PhysicalRegister::R15 => r15b,
+            xmm => panic!("{xmm} is not a general purpose register"),
         }
     }
 }
@@ -312,6 +469,7 @@ impl From<&PhysicalRegister> for AsmRegister16 {
             PhysicalRegister::R13 => r13w,
             PhysicalRegister::R14 => r14w,
             PhysicalRegister::R15 => r15w,
+            xmm => panic!("{xmm} is not a general purpose register"),
         }
     }
 }
@@ -339,6 +497,7 @@ impl From<&PhysicalRegister> for AsmRegister32 {
             PhysicalRegister::R13 => r13d,
             PhysicalRegister::R14 => r14d,
             PhysicalRegister::R15 => r15d,
+            xmm => panic!("{xmm} is not a general purpose register"),
         }
     }
 }
@@ -355,6 +514,41 @@ impl From<PhysicalRegister> for AsmRegister32 {
     }
 }
 
+impl From<&PhysicalRegister> for AsmRegisterXmm {
+    fn from(phys: &PhysicalRegister) -> Self {
+        use iced_x86::code_asm::{
+            xmm0, xmm1, xmm2, xmm3, xmm4, xmm5, xmm6, xmm7, xmm8, xmm9, xmm10, xmm11, xmm12, xmm13,
+            xmm14, xmm15,
+        };
+
+        match phys {
+            PhysicalRegister::XMM0 => xmm0,
+            PhysicalRegister::XMM1 => xmm1,
+            PhysicalRegister::XMM2 => xmm2,
+            PhysicalRegister::XMM3 => xmm3,
+            PhysicalRegister::XMM4 => xmm4,
+            PhysicalRegister::XMM5 => xmm5,
+            PhysicalRegister::XMM6 => xmm6,
+            PhysicalRegister::XMM7 => xmm7,
+            PhysicalRegister::XMM8 => xmm8,
+            PhysicalRegister::XMM9 => xmm9,
+            PhysicalRegister::XMM10 => xmm10,
+            PhysicalRegister::XMM11 => xmm11,
+            PhysicalRegister::XMM12 => xmm12,
+            PhysicalRegister::XMM13 => xmm13,
+            PhysicalRegister::XMM14 => xmm14,
+            PhysicalRegister::XMM15 => xmm15,
+            gpr => panic!("{gpr} is not an SSE register"),
+        }
+    }
+}
+
+impl From<PhysicalRegister> for AsmRegisterXmm {
+    fn from(phys: PhysicalRegister) -> Self {
+        Self::from(&phys)
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
 pub enum SegmentRegister {
     /// fs
@@ -367,6 +561,8 @@ pub enum SegmentRegister {
 pub enum Register {
     PhysicalRegister(PhysicalRegister),
     VirtualRegister(usize),
+    /// Virtual register allocated to one of the SSE registers
+    VirtualXmmRegister(usize),
     GlobalRegister(usize),
 }
 
@@ -375,6 +571,7 @@ impl Display for Register {
         match self {
             Register::PhysicalRegister(pr) => write!(f, "%{pr}"),
             Register::VirtualRegister(vr) => write!(f, "v{vr}"),
+            Register::VirtualXmmRegister(vr) => write!(f, "x{vr}"),
             Register::GlobalRegister(gr) => write!(f, "g{gr}"),
         }
     }
@@ -522,13 +719,26 @@ impl<A: Alloc> Operand<A> {
         }
     }
 
+    /// Virtual register wide enough for `width_in_bits`, so 128-bit values are
+    /// allocated to SSE registers
     pub fn vreg(width_in_bits: Width, reg: usize) -> Operand<A> {
+        if width_in_bits == Width::_128 {
+            return Self::vxmm(width_in_bits, reg);
+        }
+
         Operand {
             kind: OperandKind::Register(Register::VirtualRegister(reg)),
             width_in_bits: (width_in_bits),
         }
     }
 
+    pub fn vxmm(width_in_bits: Width, reg: usize) -> Operand<A> {
+        Operand {
+            kind: OperandKind::Register(Register::VirtualXmmRegister(reg)),
+            width_in_bits: (width_in_bits),
+        }
+    }
+
     pub fn greg(width_in_bits: Width, reg: usize) -> Operand<A> {
         Operand {
             kind: OperandKind::Register(Register::GlobalRegister(reg)),
@@ -605,6 +815,15 @@ impl<A: Alloc> Operand<A> {
         }
     }
 
+    /// The same operand accessed at a different width, such as the low 64 bits
+    /// of an SSE register
+    pub fn with_width(self, width_in_bits: Width) -> Operand<A> {
+        Operand {
+            width_in_bits,
+            ..self
+        }
+    }
+
     pub fn as_register(&self) -> Option<Register> {
         match self.kind {
             OperandKind::Register(r) => Some(r),
@@ -761,6 +980,23 @@ fn segment_memory_operand_to_iced(
     }
 }
 
+/// Encodes `mov dst, imm64` with a fixed encoding, returning a label at its
+/// start so the immediate can be found at [`RELOCATION_OFFSET`]
+fn encode_movabs(assembler: &mut CodeAssembler, dst: PhysicalRegister, value: u64) -> CodeLabel {
+    let index = dst.index() as u8;
+
+    // REX.W (REX.B selecting r8-r15), mov r64, imm64
+    let mut bytes = [0; 10];
+    bytes[0] = 0x48 | (index >> 3);
+    bytes[1] = 0xb8 | (index & 0b111);
+    bytes[RELOCATION_OFFSET..].copy_from_slice(&value.to_le_bytes());
+
+    let mut label = assembler.create_label();
+    assembler.set_label(&mut label).unwrap();
+    assembler.db(&bytes).unwrap();
+    label
+}
+
 impl<A: Alloc> Instruction<A> {
     pub fn adc(a: Operand<A>, b: Operand<A>, c: Operand<A>) -> Self {
         Self(Opcode::ADC(a, b, c))
@@ -770,6 +1006,11 @@ impl<A: Alloc> Instruction<A> {
         if src.width() != dst.width() {
             return Err(());
         }
+
+        if src.width() == Width::_128 {
+            return Ok(Self(Opcode::MOVDQU(src, dst)));
+        }
+
         Ok(Self(Opcode::MOV(src, dst)))
     }
 
@@ -889,6 +1130,12 @@ impl<A: Alloc> Instruction<A> {
     pub fn setae(r: Operand<A>) -> Self {
         Self(Opcode::SETAE(r))
     }
+    pub fn setp(r: Operand<A>) -> Self {
+        Self(Opcode::SETP(r))
+    }
+    pub fn setnp(r: Operand<A>) -> Self {
+        Self(Opcode::SETNP(r))
+    }
 
     pub fn je(block: Ref<X86Block<A>>) -> Self {
         Self(Opcode::JE(Operand::target(block)))
@@ -930,6 +1177,143 @@ impl<A: Alloc> Instruction<A> {
         })
     }
 
+    /// Moves between a general purpose and an SSE register
+    pub fn movq(src: Operand<A>, dst: Operand<A>) -> Self {
+        assert_eq!(src.width(), dst.width());
+        Self(Opcode::MOVQ(src, dst))
+    }
+
+    pub fn fadd(src: Operand<A>, dst: Operand<A>) -> Self {
+        Self(Opcode::FADD(src, dst))
+    }
+
+    pub fn fsub(src: Operand<A>, dst: Operand<A>) -> Self {
+        Self(Opcode::FSUB(src, dst))
+    }
+
+    pub fn fmul(src: Operand<A>, dst: Operand<A>) -> Self {
+        Self(Opcode::FMUL(src, dst))
+    }
+
+    pub fn fdiv(src: Operand<A>, dst: Operand<A>) -> Self {
+        Self(Opcode::FDIV(src, dst))
+    }
+
+    pub fn fsqrt(src: Operand<A>, dst: Operand<A>) -> Self {
+        Self(Opcode::FSQRT(src, dst))
+    }
+
+    /// Sets flags like [`Instruction::cmp`], but for floating point values
+    /// with the parity flag set if they are unordered
+    pub fn ucomis(op0: Operand<A>, op1: Operand<A>) -> Self {
+        Self(Opcode::UCOMIS(op0, op1))
+    }
+
+    /// Converts the signed integer in `src` to a floating point value
+    pub fn cvtsi2f(src: Operand<A>, dst: Operand<A>) -> Self {
+        Self(Opcode::CVTSI2F(src, dst))
+    }
+
+    /// Converts `src` to a signed integer, rounding according to `MXCSR`
+    pub fn cvtf2si(src: Operand<A>, dst: Operand<A>) -> Self {
+        Self(Opcode::CVTF2SI(src, dst))
+    }
+
+    /// Converts between single and double precision
+    pub fn cvtf2f(src: Operand<A>, dst: Operand<A>) -> Self {
+        assert_ne!(src.width(), dst.width());
+        Self(Opcode::CVTF2F(src, dst))
+    }
+
+    pub fn ldmxcsr(src: Operand<A>) -> Self {
+        Self(Opcode::LDMXCSR(src))
+    }
+
+    pub fn stmxcsr(dst: Operand<A>) -> Self {
+        Self(Opcode::STMXCSR(dst))
+    }
+
+    /// Copies the 64-bit lane `lane` of the SSE register `src` to `dst`
+    pub fn pextrq(lane: u8, src: Operand<A>, dst: Operand<A>) -> Self {
+        Self(Opcode::PEXTRQ(
+            Operand::imm(Width::_8, lane.into()),
+            src,
+            dst,
+        ))
+    }
+
+    /// Replaces the 64-bit lane `lane` of the SSE register `dst` with `src`
+    pub fn pinsrq(lane: u8, src: Operand<A>, dst: Operand<A>) -> Self {
+        Self(Opcode::PINSRQ(
+            Operand::imm(Width::_8, lane.into()),
+            src,
+            dst,
+        ))
+    }
+
+    /// Adds each `element`-wide lane of `src` to that of `dst`
+    pub fn padd(element: Width, src: Operand<A>, dst: Operand<A>) -> Self {
+        Self(Opcode::PADD(element, src, dst))
+    }
+
+    pub fn psub(element: Width, src: Operand<A>, dst: Operand<A>) -> Self {
+        Self(Opcode::PSUB(element, src, dst))
+    }
+
+    /// Sets each `element`-wide lane of `dst` to all ones if it is equal to
+    /// that of `src`, otherwise zero
+    pub fn pcmpeq(element: Width, src: Operand<A>, dst: Operand<A>) -> Self {
+        Self(Opcode::PCMPEQ(element, src, dst))
+    }
+
+    /// Sets each `element`-wide lane of `dst` to all ones if it is greater
+    /// than that of `src` as a signed integer, otherwise zero
+    pub fn pcmpgt(element: Width, src: Operand<A>, dst: Operand<A>) -> Self {
+        Self(Opcode::PCMPGT(element, src, dst))
+    }
+
+    pub fn pand(src: Operand<A>, dst: Operand<A>) -> Self {
+        Self(Opcode::PAND(src, dst))
+    }
+
+    pub fn por(src: Operand<A>, dst: Operand<A>) -> Self {
+        Self(Opcode::POR(src, dst))
+    }
+
+    pub fn pxor(src: Operand<A>, dst: Operand<A>) -> Self {
+        Self(Opcode::PXOR(src, dst))
+    }
+
+    pub fn chaintag(dst: Operand<A>) -> Self {
+        Self(Opcode::CHAINTAG(dst))
+    }
+
+    pub fn chainjmp() -> Self {
+        Self(Opcode::CHAINJMP)
+    }
+
+    /// Loads the current address of `symbol` into `dst`
+    pub fn movrel(symbol: Symbol, address: u64, dst: Operand<A>) -> Self {
+        Self(Opcode::MOVREL(symbol, address, dst))
+    }
+
+    /// Replaces the guest virtual address in `host` with the host address of a
+    /// `width` access through the software TLB, clobbering `index` which must
+    /// hold the same address. Writes pass the `value` for the slow path.
+    pub fn tlblookup(
+        host: Operand<A>,
+        index: Operand<A>,
+        width: Width,
+        value: Option<Operand<A>>,
+    ) -> Self {
+        Self(Opcode::TLBLOOKUP {
+            host,
+            index,
+            width,
+            value,
+        })
+    }
+
     alu_op!(add, ADD);
     alu_op!(sub, SUB);
     alu_op!(or, OR);
@@ -939,6 +1323,7 @@ impl<A: Alloc> Instruction<A> {
         &self,
         assembler: &mut CodeAssembler,
         label_map: &HashMapA<Ref<X86Block<A>>, CodeLabel, A>,
+        labels: &mut PatchLabels,
     ) {
         use {
             Opcode::*,
@@ -965,6 +1350,10 @@ impl<A: Alloc> Instruction<A> {
             ADC(src, dst, carry) => adc::encode(assembler, src, dst, carry),
             CMP(left, right) => cmp::encode(assembler, left, right),
             XOR(src, dst) => xor::encode(assembler, src, dst),
+            MOVQ(..) | FADD(..) | FSUB(..) | FMUL(..) | FDIV(..) | FSQRT(..) | UCOMIS(..)
+            | CVTSI2F(..) | CVTF2SI(..) | CVTF2F(..) | LDMXCSR(..) | STMXCSR(..) | MOVDQU(..)
+            | PEXTRQ(..) | PINSRQ(..) | PADD(..) | PSUB(..) | PCMPEQ(..) | PCMPGT(..)
+            | PAND(..) | POR(..) | PXOR(..) => sse::encode(assembler, &self.0),
 
             // control flow
             JNE(Operand {
@@ -1018,6 +1407,40 @@ impl<A: Alloc> Instruction<A> {
                 assembler.ret().unwrap();
             }
 
+            // chain slots and relocations are patched after assembly, so are emitted as raw
+            // bytes to fix their encodings
+            CHAINTAG(Operand {
+                kind: R(PHYS(dst)),
+                width_in_bits: Width::_64,
+            }) => {
+                let label = encode_movabs(assembler, *dst, CHAIN_UNLINKED_TAG);
+                labels.chain.push(label);
+            }
+            CHAINJMP => {
+                // unlinked slots jump to the next instruction
+                let mut label = assembler.create_label();
+                assembler.set_label(&mut label).unwrap();
+                assembler.db(&[0x75, 0x05, 0xe9, 0, 0, 0, 0]).unwrap();
+                labels.chain.push(label);
+            }
+            MOVREL(
+                symbol,
+                address,
+                Operand {
+                    kind: R(PHYS(dst)),
+                    width_in_bits: Width::_64,
+                },
+            ) => {
+                let label = encode_movabs(assembler, *dst, *address);
+                labels.relocations.push((label, *symbol));
+            }
+            TLBLOOKUP {
+                host,
+                index,
+                width,
+                value,
+            } => tlblookup::encode(assembler, host, index, *width, value.as_ref(), labels),
+
             SETA(Operand {
                 kind: R(PHYS(dst)), ..
             }) => {
@@ -1033,6 +1456,16 @@ impl<A: Alloc> Instruction<A> {
             }) => {
                 assembler.setae::<AsmRegister8>(dst.into()).unwrap();
             }
+            SETP(Operand {
+                kind: R(PHYS(dst)), ..
+            }) => {
+                assembler.setp::<AsmRegister8>(dst.into()).unwrap();
+            }
+            SETNP(Operand {
+                kind: R(PHYS(dst)), ..
+            }) => {
+                assembler.setnp::<AsmRegister8>(dst.into()).unwrap();
+            }
             SETE(Operand {
                 kind: R(PHYS(dst)), ..
             }) => {
@@ -1384,7 +1817,13 @@ impl<A: Alloc> Instruction<A> {
             | Opcode::MOVSX(src, dst)
             | Opcode::LEA(src, dst)
             | Opcode::CMOVE(src, dst)
-            | Opcode::CMOVNE(src, dst) => [
+            | Opcode::CMOVNE(src, dst)
+            | Opcode::MOVQ(src, dst)
+            | Opcode::FSQRT(src, dst)
+            | Opcode::CVTSI2F(src, dst)
+            | Opcode::CVTF2SI(src, dst)
+            | Opcode::CVTF2F(src, dst)
+            | Opcode::MOVDQU(src, dst) => [
                 Some((OperandDirection::In, src)),
                 Some((OperandDirection::Out, dst)),
                 None,
@@ -1398,7 +1837,18 @@ impl<A: Alloc> Instruction<A> {
             | Opcode::ADD(src, dst)
             | Opcode::SUB(src, dst)
             | Opcode::AND(src, dst)
-            | Opcode::IMUL(src, dst) => [
+            | Opcode::IMUL(src, dst)
+            | Opcode::FADD(src, dst)
+            | Opcode::FSUB(src, dst)
+            | Opcode::FMUL(src, dst)
+            | Opcode::FDIV(src, dst)
+            | Opcode::PADD(_, src, dst)
+            | Opcode::PSUB(_, src, dst)
+            | Opcode::PCMPEQ(_, src, dst)
+            | Opcode::PCMPGT(_, src, dst)
+            | Opcode::PAND(src, dst)
+            | Opcode::POR(src, dst)
+            | Opcode::PXOR(src, dst) => [
                 Some((OperandDirection::In, src)),
                 Some((OperandDirection::InOut, dst)),
                 None,
@@ -1416,8 +1866,8 @@ impl<A: Alloc> Instruction<A> {
             Opcode::CALL { function, .. } => {
                 [Some((OperandDirection::In, function)), None, None].into_iter()
             }
-            Opcode::RET | Opcode::NOP => [None, None, None].into_iter(),
-            Opcode::TEST(op0, op1) | Opcode::CMP(op0, op1) => [
+            Opcode::RET | Opcode::NOP | Opcode::CHAINJMP => [None, None, None].into_iter(),
+            Opcode::TEST(op0, op1) | Opcode::CMP(op0, op1) | Opcode::UCOMIS(op0, op1) => [
                 Some((OperandDirection::In, op0)),
                 Some((OperandDirection::In, op1)),
                 None,
@@ -1436,17 +1886,28 @@ impl<A: Alloc> Instruction<A> {
             | Opcode::SETC(r)
             | Opcode::SETGE(r)
             | Opcode::SETL(r)
-            | Opcode::SETLE(r) => [Some((OperandDirection::Out, r)), None, None].into_iter(),
+            | Opcode::SETLE(r)
+            | Opcode::SETP(r)
+            | Opcode::SETNP(r)
+            | Opcode::STMXCSR(r) => [Some((OperandDirection::Out, r)), None, None].into_iter(),
             Opcode::NOT(r) | Opcode::NEG(r) => {
                 [Some((OperandDirection::InOut, r)), None, None].into_iter()
             }
-            Opcode::BEXTR(ctrl, src, dst) => [
+            Opcode::BEXTR(ctrl, src, dst) | Opcode::PEXTRQ(ctrl, src, dst) => [
                 Some((OperandDirection::In, ctrl)),
                 Some((OperandDirection::In, src)),
                 Some((OperandDirection::Out, dst)),
             ]
             .into_iter(),
-            Opcode::INT(n) => [Some((OperandDirection::In, n)), None, None].into_iter(),
+            Opcode::PINSRQ(lane, src, dst) => [
+                Some((OperandDirection::In, lane)),
+                Some((OperandDirection::In, src)),
+                Some((OperandDirection::InOut, dst)),
+            ]
+            .into_iter(),
+            Opcode::INT(n) | Opcode::LDMXCSR(n) => {
+                [Some((OperandDirection::In, n)), None, None].into_iter()
+            }
             Opcode::ADC(a, b, c) => [
                 Some((OperandDirection::In, a)),
                 Some((OperandDirection::In, b)),
@@ -1454,7 +1915,9 @@ impl<A: Alloc> Instruction<A> {
             ]
             .into_iter(),
             Opcode::PUSH(src) => [Some((OperandDirection::In, src)), None, None].into_iter(),
-            Opcode::POP(dest) => [Some((OperandDirection::Out, dest)), None, None].into_iter(),
+            Opcode::POP(dest) | Opcode::CHAINTAG(dest) | Opcode::MOVREL(_, _, dest) => {
+                [Some((OperandDirection::Out, dest)), None, None].into_iter()
+            }
             Opcode::DEAD => panic!(),
             Opcode::OUT(port, value) => [
                 Some((OperandDirection::In, port)),
@@ -1462,6 +1925,14 @@ impl<A: Alloc> Instruction<A> {
                 None,
             ]
             .into_iter(),
+            Opcode::TLBLOOKUP {
+                host, index, value, ..
+            } => [
+                Some((OperandDirection::InOut, host)),
+                Some((OperandDirection::InOut, index)),
+                value.as_mut().map(|value| (OperandDirection::In, value)),
+            ]
+            .into_iter(),
         }
     }
 
@@ -1472,7 +1943,13 @@ impl<A: Alloc> Instruction<A> {
             | Opcode::MOVSX(src, dst)
             | Opcode::LEA(src, dst)
             | Opcode::CMOVE(src, dst)
-            | Opcode::CMOVNE(src, dst) => {
+            | Opcode::CMOVNE(src, dst)
+            | Opcode::MOVQ(src, dst)
+            | Opcode::FSQRT(src, dst)
+            | Opcode::CVTSI2F(src, dst)
+            | Opcode::CVTF2SI(src, dst)
+            | Opcode::CVTF2F(src, dst)
+            | Opcode::MOVDQU(src, dst) => {
                 [(OperandDirection::In, src), (OperandDirection::Out, dst)]
                     .into_iter()
                     .collect()
@@ -1485,7 +1962,18 @@ impl<A: Alloc> Instruction<A> {
             | Opcode::ADD(src, dst)
             | Opcode::SUB(src, dst)
             | Opcode::AND(src, dst)
-            | Opcode::IMUL(src, dst) => {
+            | Opcode::IMUL(src, dst)
+            | Opcode::FADD(src, dst)
+            | Opcode::FSUB(src, dst)
+            | Opcode::FMUL(src, dst)
+            | Opcode::FDIV(src, dst)
+            | Opcode::PADD(_, src, dst)
+            | Opcode::PSUB(_, src, dst)
+            | Opcode::PCMPEQ(_, src, dst)
+            | Opcode::PCMPGT(_, src, dst)
+            | Opcode::PAND(src, dst)
+            | Opcode::POR(src, dst)
+            | Opcode::PXOR(src, dst) => {
                 [(OperandDirection::In, src), (OperandDirection::InOut, dst)]
                     .into_iter()
                     .collect()
@@ -1500,8 +1988,8 @@ impl<A: Alloc> Instruction<A> {
             Opcode::JMP(tgt) | Opcode::JNE(tgt) | Opcode::JE(tgt) => {
                 [((OperandDirection::In, tgt))].into_iter().collect()
             }
-            Opcode::RET | Opcode::NOP => alloc::vec![],
-            Opcode::TEST(op0, op1) | Opcode::CMP(op0, op1) => {
+            Opcode::RET | Opcode::NOP | Opcode::CHAINJMP => alloc::vec![],
+            Opcode::TEST(op0, op1) | Opcode::CMP(op0, op1) | Opcode::UCOMIS(op0, op1) => {
                 [((OperandDirection::In, op0)), ((OperandDirection::In, op1))]
                     .into_iter()
                     .collect()
@@ -1519,18 +2007,30 @@ impl<A: Alloc> Instruction<A> {
             | Opcode::SETC(r)
             | Opcode::SETGE(r)
             | Opcode::SETL(r)
-            | Opcode::SETLE(r) => [((OperandDirection::Out, r))].into_iter().collect(),
+            | Opcode::SETLE(r)
+            | Opcode::SETP(r)
+            | Opcode::SETNP(r)
+            | Opcode::STMXCSR(r) => [((OperandDirection::Out, r))].into_iter().collect(),
             Opcode::NOT(r) | Opcode::NEG(r) => {
                 [((OperandDirection::InOut, r))].into_iter().collect()
             }
-            Opcode::BEXTR(ctrl, src, dst) => [
+            Opcode::BEXTR(ctrl, src, dst) | Opcode::PEXTRQ(ctrl, src, dst) => [
                 ((OperandDirection::In, ctrl)),
                 ((OperandDirection::In, src)),
                 ((OperandDirection::Out, dst)),
             ]
             .into_iter()
             .collect(),
-            Opcode::INT(n) => [((OperandDirection::In, n))].into_iter().collect(),
+            Opcode::PINSRQ(lane, src, dst) => [
+                ((OperandDirection::In, lane)),
+                ((OperandDirection::In, src)),
+                ((OperandDirection::InOut, dst)),
+            ]
+            .into_iter()
+            .collect(),
+            Opcode::INT(n) | Opcode::LDMXCSR(n) => {
+                [((OperandDirection::In, n))].into_iter().collect()
+            }
             Opcode::ADC(a, b, c) => [
                 ((OperandDirection::In, a)),
                 ((OperandDirection::In, b)),
@@ -1539,7 +2039,9 @@ impl<A: Alloc> Instruction<A> {
             .into_iter()
             .collect(),
             Opcode::PUSH(src) => [((OperandDirection::In, src))].into_iter().collect(),
-            Opcode::POP(dest) => [((OperandDirection::Out, dest))].into_iter().collect(),
+            Opcode::POP(dest) | Opcode::CHAINTAG(dest) | Opcode::MOVREL(_, _, dest) => {
+                [((OperandDirection::Out, dest))].into_iter().collect()
+            }
             Opcode::DEAD => panic!(),
             Opcode::OUT(port, value) => [
                 ((OperandDirection::In, port)),
@@ -1547,6 +2049,15 @@ impl<A: Alloc> Instruction<A> {
             ]
             .into_iter()
             .collect(),
+            Opcode::TLBLOOKUP {
+                host, index, value, ..
+            } => [
+                (OperandDirection::InOut, host),
+                (OperandDirection::InOut, index),
+            ]
+            .into_iter()
+            .chain(value.map(|value| (OperandDirection::In, value)))
+            .collect(),
             Opcode::CALL {
                 function,
                 nr_input_args,
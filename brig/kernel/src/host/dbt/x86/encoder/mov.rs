@@ -227,13 +227,63 @@ pub fn encode<A: Alloc>(assembler: &mut CodeAssembler, src: &Operand<A>, dst: &O
             },
         ) => {
             assembler
-                .mov::<AsmRegister64, AsmMemoryOperand>(
+                .mov::<AsmRegister32, AsmMemoryOperand>(
                     dst.into(),
                     segment_memory_operand_to_iced(*seg_reg, *index, *scale, *displacement),
                 )
                 .unwrap();
         }
         // MOV R -> M
+        (
+            Operand {
+                kind: R(PHYS(src)),
+                width_in_bits: Width::_32,
+            },
+            Operand {
+                kind:
+                    M {
+                        base: None,
+                        index,
+                        scale,
+                        displacement,
+                        segment_override: Some(seg_reg),
+                    },
+                width_in_bits: Width::_32,
+            },
+        ) => {
+            assembler
+                .mov::<AsmMemoryOperand, AsmRegister32>(
+                    segment_memory_operand_to_iced(*seg_reg, *index, *scale, *displacement),
+                    src.into(),
+                )
+                .unwrap();
+        }
+        // MOV R -> M
+        (
+            Operand {
+                kind: R(PHYS(src)),
+                width_in_bits: Width::_64,
+            },
+            Operand {
+                kind:
+                    M {
+                        base: None,
+                        index,
+                        scale,
+                        displacement,
+                        segment_override: Some(seg_reg),
+                    },
+                width_in_bits: Width::_64,
+            },
+        ) => {
+            assembler
+                .mov::<AsmMemoryOperand, AsmRegister64>(
+                    segment_memory_operand_to_iced(*seg_reg, *index, *scale, *displacement),
+                    src.into(),
+                )
+                .unwrap();
+        }
+        // MOV R -> M
         (
             Operand {
                 kind: R(PHYS(src)),
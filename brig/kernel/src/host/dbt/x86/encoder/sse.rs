@@ -0,0 +1,302 @@
+//! SSE instructions
+//!
+//! For scalar floating point instructions the width of an `xmm` operand selects
+//! between the single (`ss`) and double (`sd`) precision forms, while packed
+//! integer instructions carry their element width in the opcode.
+
+use {
+    crate::host::dbt::{
+        Alloc,
+        x86::encoder::{
+            Opcode, Operand,
+            OperandKind::{Immediate as I, Memory as M, Register as R},
+            PhysicalRegister,
+            Register::PhysicalRegister as PHYS,
+            Width, memory_operand_to_iced, segment_memory_operand_to_iced,
+        },
+    },
+    iced_x86::{
+        IcedError,
+        code_asm::{
+            AsmMemoryOperand, AsmRegister32, AsmRegister64, AsmRegisterXmm, CodeAssembler,
+            dword_ptr, xmmword_ptr,
+        },
+    },
+};
+
+type XmmFn = fn(&mut CodeAssembler, AsmRegisterXmm, AsmRegisterXmm) -> Result<(), IcedError>;
+
+pub fn encode<A: Alloc>(assembler: &mut CodeAssembler, opcode: &Opcode<A>) {
+    use Opcode::*;
+
+    match opcode {
+        MOVQ(src, dst) => {
+            let (src_reg, dst_reg) = (register(src), register(dst));
+
+            match (src_reg.is_xmm(), dst_reg.is_xmm(), src.width()) {
+                (false, true, Width::_32) => {
+                    assembler.movd::<AsmRegisterXmm, AsmRegister32>(dst_reg.into(), src_reg.into())
+                }
+                (false, true, Width::_64) => {
+                    assembler.movq::<AsmRegisterXmm, AsmRegister64>(dst_reg.into(), src_reg.into())
+                }
+                (true, false, Width::_32) => {
+                    assembler.movd::<AsmRegister32, AsmRegisterXmm>(dst_reg.into(), src_reg.into())
+                }
+                (true, false, Width::_64) => {
+                    assembler.movq::<AsmRegister64, AsmRegisterXmm>(dst_reg.into(), src_reg.into())
+                }
+                _ => panic!("cannot encode movq {src}, {dst}"),
+            }
+            .unwrap();
+        }
+        FADD(src, dst) => scalar(
+            assembler,
+            src,
+            dst,
+            CodeAssembler::addss,
+            CodeAssembler::addsd,
+        ),
+        FSUB(src, dst) => scalar(
+            assembler,
+            src,
+            dst,
+            CodeAssembler::subss,
+            CodeAssembler::subsd,
+        ),
+        FMUL(src, dst) => scalar(
+            assembler,
+            src,
+            dst,
+            CodeAssembler::mulss,
+            CodeAssembler::mulsd,
+        ),
+        FDIV(src, dst) => scalar(
+            assembler,
+            src,
+            dst,
+            CodeAssembler::divss,
+            CodeAssembler::divsd,
+        ),
+        FSQRT(src, dst) => scalar(
+            assembler,
+            src,
+            dst,
+            CodeAssembler::sqrtss,
+            CodeAssembler::sqrtsd,
+        ),
+        // flags are those of `op1 - op0`, matching CMP
+        UCOMIS(op0, op1) => scalar(
+            assembler,
+            op1,
+            op0,
+            CodeAssembler::ucomiss,
+            CodeAssembler::ucomisd,
+        ),
+        CVTSI2F(src, dst) => {
+            let src_reg = register(src);
+            let dst_reg = AsmRegisterXmm::from(register(dst));
+
+            match (src.width(), dst.width()) {
+                (Width::_32, Width::_32) => {
+                    assembler.cvtsi2ss::<AsmRegisterXmm, AsmRegister32>(dst_reg, src_reg.into())
+                }
+                (Width::_64, Width::_32) => {
+                    assembler.cvtsi2ss::<AsmRegisterXmm, AsmRegister64>(dst_reg, src_reg.into())
+                }
+                (Width::_32, Width::_64) => {
+                    assembler.cvtsi2sd::<AsmRegisterXmm, AsmRegister32>(dst_reg, src_reg.into())
+                }
+                (Width::_64, Width::_64) => {
+                    assembler.cvtsi2sd::<AsmRegisterXmm, AsmRegister64>(dst_reg, src_reg.into())
+                }
+                _ => panic!("cannot encode cvtsi2s {src}, {dst}"),
+            }
+            .unwrap();
+        }
+        CVTF2SI(src, dst) => {
+            let src_reg = AsmRegisterXmm::from(register(src));
+            let dst_reg = register(dst);
+
+            match (src.width(), dst.width()) {
+                (Width::_32, Width::_32) => {
+                    assembler.cvtss2si::<AsmRegister32, AsmRegisterXmm>(dst_reg.into(), src_reg)
+                }
+                (Width::_32, Width::_64) => {
+                    assembler.cvtss2si::<AsmRegister64, AsmRegisterXmm>(dst_reg.into(), src_reg)
+                }
+                (Width::_64, Width::_32) => {
+                    assembler.cvtsd2si::<AsmRegister32, AsmRegisterXmm>(dst_reg.into(), src_reg)
+                }
+                (Width::_64, Width::_64) => {
+                    assembler.cvtsd2si::<AsmRegister64, AsmRegisterXmm>(dst_reg.into(), src_reg)
+                }
+                _ => panic!("cannot encode cvts2si {src}, {dst}"),
+            }
+            .unwrap();
+        }
+        CVTF2F(src, dst) => {
+            let src_reg = AsmRegisterXmm::from(register(src));
+            let dst_reg = AsmRegisterXmm::from(register(dst));
+
+            match (src.width(), dst.width()) {
+                (Width::_32, Width::_64) => assembler.cvtss2sd(dst_reg, src_reg),
+                (Width::_64, Width::_32) => assembler.cvtsd2ss(dst_reg, src_reg),
+                _ => panic!("cannot encode cvts2s {src}, {dst}"),
+            }
+            .unwrap();
+        }
+        LDMXCSR(src) => assembler.ldmxcsr(dword_ptr(memory(src))).unwrap(),
+        STMXCSR(dst) => assembler.stmxcsr(dword_ptr(memory(dst))).unwrap(),
+        MOVDQU(src, dst) => match (src.kind, dst.kind) {
+            (R(_), R(_)) => assembler.movdqu(xmm(dst), xmm(src)),
+            (M { .. }, R(_)) => assembler.movdqu(xmm(dst), xmmword_ptr(memory(src))),
+            (R(_), M { .. }) => assembler.movdqu(xmmword_ptr(memory(dst)), xmm(src)),
+            _ => panic!("cannot encode movdqu {src}, {dst}"),
+        }
+        .unwrap(),
+        PEXTRQ(lane, src, dst) => assembler
+            .pextrq(
+                AsmRegister64::from(register(dst)),
+                xmm(src),
+                immediate(lane),
+            )
+            .unwrap(),
+        PINSRQ(lane, src, dst) => assembler
+            .pinsrq(
+                xmm(dst),
+                AsmRegister64::from(register(src)),
+                immediate(lane),
+            )
+            .unwrap(),
+        PADD(element, src, dst) => packed(
+            assembler,
+            *element,
+            src,
+            dst,
+            [
+                CodeAssembler::paddb,
+                CodeAssembler::paddw,
+                CodeAssembler::paddd,
+                CodeAssembler::paddq,
+            ],
+        ),
+        PSUB(element, src, dst) => packed(
+            assembler,
+            *element,
+            src,
+            dst,
+            [
+                CodeAssembler::psubb,
+                CodeAssembler::psubw,
+                CodeAssembler::psubd,
+                CodeAssembler::psubq,
+            ],
+        ),
+        PCMPEQ(element, src, dst) => packed(
+            assembler,
+            *element,
+            src,
+            dst,
+            [
+                CodeAssembler::pcmpeqb,
+                CodeAssembler::pcmpeqw,
+                CodeAssembler::pcmpeqd,
+                CodeAssembler::pcmpeqq,
+            ],
+        ),
+        PCMPGT(element, src, dst) => packed(
+            assembler,
+            *element,
+            src,
+            dst,
+            [
+                CodeAssembler::pcmpgtb,
+                CodeAssembler::pcmpgtw,
+                CodeAssembler::pcmpgtd,
+                CodeAssembler::pcmpgtq,
+            ],
+        ),
+        PAND(src, dst) => assembler.pand(xmm(dst), xmm(src)).unwrap(),
+        POR(src, dst) => assembler.por(xmm(dst), xmm(src)).unwrap(),
+        PXOR(src, dst) => assembler.pxor(xmm(dst), xmm(src)).unwrap(),
+        _ => panic!("{opcode} is not an SSE instruction"),
+    }
+}
+
+/// Encodes `dst op= src` on two `xmm` registers
+fn scalar<A: Alloc>(
+    assembler: &mut CodeAssembler,
+    src: &Operand<A>,
+    dst: &Operand<A>,
+    single: XmmFn,
+    double: XmmFn,
+) {
+    let encode = match (src.width(), dst.width()) {
+        (Width::_32, Width::_32) => single,
+        (Width::_64, Width::_64) => double,
+        _ => panic!("mismatched or non-floating point widths: {src}, {dst}"),
+    };
+
+    encode(assembler, register(dst).into(), register(src).into()).unwrap();
+}
+
+/// Encodes `dst op= src` on each `element`-wide lane of two `xmm` registers,
+/// with `forms` holding the byte, word, doubleword and quadword instructions
+fn packed<A: Alloc>(
+    assembler: &mut CodeAssembler,
+    element: Width,
+    src: &Operand<A>,
+    dst: &Operand<A>,
+    forms: [XmmFn; 4],
+) {
+    let encode = match element {
+        Width::_8 => forms[0],
+        Width::_16 => forms[1],
+        Width::_32 => forms[2],
+        Width::_64 => forms[3],
+        Width::_128 => panic!("no packed form with 128-bit elements: {src}, {dst}"),
+    };
+
+    encode(assembler, xmm(dst), xmm(src)).unwrap();
+}
+
+fn xmm<A: Alloc>(operand: &Operand<A>) -> AsmRegisterXmm {
+    register(operand).into()
+}
+
+fn immediate<A: Alloc>(operand: &Operand<A>) -> i32 {
+    let I(value) = operand.kind else {
+        panic!("expected an immediate, got {operand}");
+    };
+
+    i32::try_from(value).unwrap()
+}
+
+fn register<A: Alloc>(operand: &Operand<A>) -> PhysicalRegister {
+    let R(PHYS(reg)) = operand.kind else {
+        panic!("expected a physical register, got {operand}");
+    };
+
+    reg
+}
+
+fn memory<A: Alloc>(operand: &Operand<A>) -> AsmMemoryOperand {
+    match operand.kind {
+        M {
+            base: Some(PHYS(base)),
+            index,
+            scale,
+            displacement,
+            segment_override: None,
+        } => memory_operand_to_iced(base, index, scale, displacement),
+        M {
+            base: None,
+            index,
+            scale,
+            displacement,
+            segment_override: Some(segment),
+        } => segment_memory_operand_to_iced(segment, index, scale, displacement),
+        _ => panic!("expected a memory operand, got {operand}"),
+    }
+}
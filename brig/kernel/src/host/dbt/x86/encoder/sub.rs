@@ -3,12 +3,14 @@ use {
         Alloc,
         x86::encoder::{
             Operand,
-            OperandKind::{Immediate as I, Register as R},
+            OperandKind::{Immediate as I, Memory as M, Register as R},
             Register::PhysicalRegister as PHYS,
-            Width,
+            Width, segment_memory_operand_to_iced,
         },
     },
-    iced_x86::code_asm::{AsmRegister8, AsmRegister32, AsmRegister64, CodeAssembler},
+    iced_x86::code_asm::{
+        AsmMemoryOperand, AsmRegister8, AsmRegister32, AsmRegister64, CodeAssembler, qword_ptr,
+    },
 };
 
 pub fn encode<A: Alloc>(assembler: &mut CodeAssembler, src: &Operand<A>, dst: &Operand<A>) {
@@ -88,6 +90,36 @@ pub fn encode<A: Alloc>(assembler: &mut CodeAssembler, src: &Operand<A>, dst: &O
                 .sub::<AsmRegister8, i32>(dst.into(), i32::try_from(*src).unwrap())
                 .unwrap();
         }
+        // SUB IMM -> M
+        (
+            Operand {
+                kind: I(src),
+                width_in_bits: _,
+            },
+            Operand {
+                kind:
+                    M {
+                        base: None,
+                        index,
+                        scale,
+                        displacement,
+                        segment_override: Some(seg_reg),
+                    },
+                width_in_bits: Width::_64,
+            },
+        ) => {
+            assembler
+                .sub::<AsmMemoryOperand, i32>(
+                    qword_ptr(segment_memory_operand_to_iced(
+                        *seg_reg,
+                        *index,
+                        *scale,
+                        *displacement,
+                    )),
+                    i32::try_from(*src).unwrap(),
+                )
+                .unwrap();
+        }
         _ => todo!("sub {src} {dst}"),
     }
 }
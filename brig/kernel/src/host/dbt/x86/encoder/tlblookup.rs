@@ -0,0 +1,170 @@
+//! Inline lookup of a guest virtual address in the software TLB
+//!
+//! The host register is looked up in place and holds the host address to
+//! access once done. On a miss the caller saved registers other than the host
+//! register are preserved around a call to the slow path, which is made with
+//! the stack aligned as the host function expects.
+
+use {
+    crate::host::dbt::{
+        Alloc,
+        persist::Symbol,
+        softmmu::{
+            self, ENTRY_COUNT, ENTRY_SHIFT, PAGE_SHIFT, addend_displacement, bounce_displacement,
+            tag_displacement, tag_mask,
+        },
+        x86::encoder::{
+            MemoryScale, Operand,
+            OperandKind::Register as R,
+            PatchLabels, PhysicalRegister,
+            Register::{self, PhysicalRegister as PHYS},
+            SegmentRegister, Width, encode_movabs, segment_memory_operand_to_iced,
+        },
+    },
+    iced_x86::code_asm::{
+        AsmMemoryOperand, AsmRegister8, AsmRegister16, AsmRegister32, AsmRegister64,
+        AsmRegisterXmm, CodeAssembler, esi, qword_ptr, rax, rdi, rsp, xmmword_ptr,
+    },
+};
+
+/// Registers the slow path may clobber, in the order they are pushed
+const SAVED: &[PhysicalRegister] = &[
+    PhysicalRegister::RAX,
+    PhysicalRegister::RCX,
+    PhysicalRegister::RDX,
+    PhysicalRegister::RSI,
+    PhysicalRegister::RDI,
+    PhysicalRegister::R8,
+    PhysicalRegister::R9,
+    PhysicalRegister::R10,
+    PhysicalRegister::R11,
+];
+
+pub fn encode<A: Alloc>(
+    assembler: &mut CodeAssembler,
+    host: &Operand<A>,
+    index: &Operand<A>,
+    width: Width,
+    value: Option<&Operand<A>>,
+    labels: &mut PatchLabels,
+) {
+    let host = register(host);
+    let index = register(index);
+    let write = value.is_some();
+    let size = u64::from(width.as_u16() / 8);
+
+    let entry = |displacement| {
+        segment_memory_operand_to_iced(
+            SegmentRegister::FS,
+            Some(Register::PhysicalRegister(index)),
+            MemoryScale::S1,
+            displacement,
+        )
+    };
+    let host_reg = AsmRegister64::from(host);
+    let index_reg = AsmRegister64::from(index);
+
+    // byte offset of the entry of the page
+    assembler
+        .shr::<AsmRegister64, u32>(index_reg, PAGE_SHIFT - ENTRY_SHIFT)
+        .unwrap();
+    assembler
+        .and::<AsmRegister64, i32>(
+            index_reg,
+            i32::try_from((ENTRY_COUNT - 1) << ENTRY_SHIFT).unwrap(),
+        )
+        .unwrap();
+
+    // the page bits and the low bits of a misaligned access are left set on a
+    // mismatch
+    assembler
+        .xor::<AsmRegister64, AsmMemoryOperand>(host_reg, entry(tag_displacement(write)))
+        .unwrap();
+    assembler
+        .test::<AsmRegister64, i32>(host_reg, tag_mask(size) as i32)
+        .unwrap();
+
+    let mut miss = assembler.create_label();
+    let mut done = assembler.create_label();
+    assembler.jne(miss).unwrap();
+
+    assembler
+        .xor::<AsmRegister64, AsmMemoryOperand>(host_reg, entry(tag_displacement(write)))
+        .unwrap();
+    assembler
+        .add::<AsmRegister64, AsmMemoryOperand>(host_reg, entry(addend_displacement()))
+        .unwrap();
+    assembler.jmp(done).unwrap();
+
+    assembler.set_label(&mut miss).unwrap();
+    assembler
+        .xor::<AsmRegister64, AsmMemoryOperand>(host_reg, entry(tag_displacement(write)))
+        .unwrap();
+
+    // the slow path writes the value from the bounce buffer
+    if let Some(value) = value {
+        let bounce = segment_memory_operand_to_iced(
+            SegmentRegister::FS,
+            None,
+            MemoryScale::S1,
+            bounce_displacement(),
+        );
+        let value = register(value);
+
+        match width {
+            Width::_8 => assembler.mov::<AsmMemoryOperand, AsmRegister8>(bounce, value.into()),
+            Width::_16 => assembler.mov::<AsmMemoryOperand, AsmRegister16>(bounce, value.into()),
+            Width::_32 => assembler.mov::<AsmMemoryOperand, AsmRegister32>(bounce, value.into()),
+            Width::_64 => assembler.mov::<AsmMemoryOperand, AsmRegister64>(bounce, value.into()),
+            Width::_128 => assembler.movdqu(xmmword_ptr(bounce), AsmRegisterXmm::from(value)),
+        }
+        .unwrap();
+    }
+
+    let saved = || SAVED.iter().copied().filter(|reg| *reg != host);
+    saved().for_each(|reg| assembler.push::<AsmRegister64>(reg.into()).unwrap());
+
+    assembler
+        .mov::<AsmRegister64, AsmRegister64>(rdi, host_reg)
+        .unwrap();
+    assembler
+        .mov::<AsmRegister32, u32>(esi, u32::try_from(size).unwrap())
+        .unwrap();
+
+    // align the stack, keeping the original stack pointer on top of it
+    assembler
+        .mov::<AsmRegister64, AsmRegister64>(rax, rsp)
+        .unwrap();
+    assembler.and::<AsmRegister64, i32>(rsp, -16).unwrap();
+    assembler.push::<AsmRegister64>(rax).unwrap();
+    assembler.push::<AsmRegister64>(rax).unwrap();
+
+    let function = if write {
+        softmmu::write_miss as u64
+    } else {
+        softmmu::read_miss as u64
+    };
+    let label = encode_movabs(assembler, PhysicalRegister::RAX, function);
+    labels.relocations.push((label, Symbol::function(function)));
+    assembler.call::<AsmRegister64>(rax).unwrap();
+
+    assembler
+        .mov::<AsmRegister64, AsmMemoryOperand>(rsp, qword_ptr(rsp))
+        .unwrap();
+    assembler
+        .mov::<AsmRegister64, AsmRegister64>(host_reg, rax)
+        .unwrap();
+
+    saved()
+        .rev()
+        .for_each(|reg| assembler.pop::<AsmRegister64>(reg.into()).unwrap());
+
+    assembler.set_label(&mut done).unwrap();
+}
+
+fn register<A: Alloc>(operand: &Operand<A>) -> PhysicalRegister {
+    match operand.kind {
+        R(PHYS(reg)) => reg,
+        _ => panic!("cannot encode tlblookup with {operand}"),
+    }
+}
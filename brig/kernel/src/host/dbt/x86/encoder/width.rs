@@ -9,6 +9,8 @@ pub enum Width {
     _16,
     _32,
     _64,
+    /// Only held in SSE registers or memory
+    _128,
 }
 
 impl PartialOrd for Width {
@@ -30,6 +32,7 @@ impl Width {
             9..=16 => Ok(Self::_16),
             17..=32 => Ok(Self::_32),
             33..=64 => Ok(Self::_64),
+            65..=128 => Ok(Self::_128),
             0 => Err(WidthError::Zero),
             _ => Ok(Self::_64), /* todo: fix PhysicalCount and other oversized registers
                                  *n => Err(WidthError::Oversize(n)), */
@@ -42,6 +45,7 @@ impl Width {
             Width::_16 => 16,
             Width::_32 => 32,
             Width::_64 => 64,
+            Width::_128 => 128,
         }
     }
 }
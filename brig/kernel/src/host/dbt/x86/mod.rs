@@ -1,11 +1,16 @@
 use {
     crate::host::dbt::{
         Alloc, Translation,
+        chain::ChainSlot,
         emitter::Emitter,
+        persist::Relocation,
+        softmmu::MemoryAccessKind,
         x86::{
             emitter::{X86Block, X86BlockMark, X86Emitter, X86NodeRef},
-            encoder::{Instruction, Opcode, OperandKind},
-            register_allocator::naive::FreshAllocator,
+            encoder::{Instruction, Opcode, OperandKind, PatchLabels},
+            register_allocator::{
+                RegisterAllocatorKind, linear_scan::LinearScanAllocator, naive::FreshAllocator,
+            },
         },
     },
     alloc::{alloc::Global, collections::VecDeque, vec::Vec},
@@ -16,16 +21,31 @@ use {
         rudder::Model,
     },
     core::fmt::Debug,
-    iced_x86::code_asm::{
-        AsmMemoryOperand, AsmRegister64, CodeAssembler, IcedError, qword_ptr, rax,
+    iced_x86::{
+        BlockEncoderOptions,
+        code_asm::{AsmMemoryOperand, AsmRegister64, CodeAssembler, IcedError, qword_ptr, rax},
     },
 };
 
 pub mod dot;
 pub mod emitter;
 pub mod encoder;
+pub mod peephole;
 pub mod register_allocator;
 
+/// Run the peephole passes over each block before register allocation
+const PEEPHOLE_OPTIMISATION: bool = true;
+
+/// Machine code of a translation and the locations within it that are patched
+/// after assembly
+pub struct AssembledCode {
+    pub code: Vec<u8>,
+    /// Offsets of the chain slots
+    pub chain_slots: Vec<ChainSlot>,
+    /// Absolute addresses loaded by the code
+    pub relocations: Vec<Relocation>,
+}
+
 struct CachedFunction<A: Alloc> {
     entry_block: Ref<X86Block<A>>,
     result: Option<X86NodeRef<A>>,
@@ -42,15 +62,22 @@ pub struct X86TranslationContext<A: Alloc> {
 
     pc_offset: u64,
     sctlr_el1_offset: u64,
+    tcr_el1_offset: u64,
     ttbr0_el1_offset: u64,
     ttbr1_el1_offset: u64,
     n_offset: u64,
     z_offset: u64,
     c_offset: u64,
     v_offset: u64,
+    fpcr_offset: u64,
+    fpsr_offset: u64,
+    /// Offsets of the guest registers cached in host registers within a block
+    cached_registers: Vec<u64, A>,
 
     global_register_offset: usize,
     memory_mask: bool,
+    register_allocator: RegisterAllocatorKind,
+    memory_access: MemoryAccessKind,
 }
 
 impl<A: Alloc> Debug for X86TranslationContext<A> {
@@ -99,6 +126,11 @@ impl<'a, A: Alloc> X86TranslationContext<A> {
     ) -> Self {
         let mut arena = Arena::new_in(allocator.clone());
 
+        let mut cached_registers = Vec::new_in(allocator.clone());
+        cached_registers.extend((0..31).map(|n| model.reg_offset(alloc::format!("R{n}"))));
+        cached_registers.extend((0..4).map(|el| model.reg_offset(alloc::format!("SP_EL{el}"))));
+        cached_registers.push(model.reg_offset("_PC"));
+
         let initial_block = arena.insert(X86Block::new_in(allocator.clone()));
         let panic_block = arena.insert(X86Block::new_in(allocator.clone()));
 
@@ -112,14 +144,20 @@ impl<'a, A: Alloc> X86TranslationContext<A> {
 
             pc_offset: model.reg_offset("_PC"),
             sctlr_el1_offset: model.reg_offset("SCTLR_EL1_bits"),
+            tcr_el1_offset: model.reg_offset("TCR_EL1_bits"),
             ttbr0_el1_offset: model.reg_offset("_TTBR0_EL1_bits"),
             ttbr1_el1_offset: model.reg_offset("_TTBR1_EL1_bits"),
             n_offset: model.reg_offset("PSTATE_N"),
             z_offset: model.reg_offset("PSTATE_Z"),
             c_offset: model.reg_offset("PSTATE_C"),
             v_offset: model.reg_offset("PSTATE_V"),
+            fpcr_offset: model.reg_offset("FPCR_bits"),
+            fpsr_offset: model.reg_offset("FPSR_bits"),
+            cached_registers,
             global_register_offset,
             memory_mask,
+            register_allocator: RegisterAllocatorKind::Fresh,
+            memory_access: MemoryAccessKind::Direct,
         };
 
         // add panic to the panic block
@@ -152,10 +190,31 @@ impl<'a, A: Alloc> X86TranslationContext<A> {
         self.panic_block
     }
 
-    pub fn compile(mut self, num_virtual_registers: usize) -> Translation {
+    /// Selects the register allocator used to assemble the translation
+    pub fn set_register_allocator(&mut self, register_allocator: RegisterAllocatorKind) {
+        self.register_allocator = register_allocator;
+    }
+
+    /// Selects how guest memory accesses are lowered, which must be set before
+    /// emitting
+    pub fn set_memory_access(&mut self, memory_access: MemoryAccessKind) {
+        self.memory_access = memory_access;
+    }
+
+    pub fn compile(self, num_virtual_registers: usize) -> Translation {
+        let AssembledCode { code, .. } = self.assemble(num_virtual_registers);
+
+        log::trace!("making executable");
+
+        Translation::new(&code)
+    }
+
+    /// Allocates registers and assembles the translation into raw machine code
+    pub fn assemble(mut self, num_virtual_registers: usize) -> AssembledCode {
         let mut assembler = CodeAssembler::new(64).unwrap();
 
         let mut label_map = hashmap_in(self.allocator());
+        let mut patch_labels = PatchLabels::default();
 
         log::trace!("{}", dot::render(self.arena(), self.initial_block()));
 
@@ -182,18 +241,35 @@ impl<'a, A: Alloc> X86TranslationContext<A> {
             }
         }
 
+        if PEEPHOLE_OPTIMISATION {
+            log::trace!("optimising blocks");
+
+            all_blocks.iter().for_each(|block| {
+                peephole::optimise(block.get_mut(self.arena_mut()).instructions_mut());
+            });
+        }
+
         log::trace!("allocating registers");
 
         let global_register_offset = self.global_register_offset;
 
-        all_blocks.iter().for_each(|block| {
-            block
-                .get_mut(self.arena_mut())
-                .allocate_registers(&mut FreshAllocator::new(
-                    num_virtual_registers,
-                    global_register_offset,
-                ));
-        });
+        match self.register_allocator {
+            RegisterAllocatorKind::Fresh => all_blocks.iter().for_each(|block| {
+                block
+                    .get_mut(self.arena_mut())
+                    .allocate_registers(&mut FreshAllocator::new(
+                        num_virtual_registers,
+                        global_register_offset,
+                    ));
+            }),
+            RegisterAllocatorKind::LinearScan => {
+                // the initial block is the first to be encoded, and so the entry
+                assert_eq!(all_blocks.first(), Some(&self.initial_block()));
+
+                LinearScanAllocator::new(global_register_offset)
+                    .allocate_translation(self.arena_mut(), &all_blocks);
+            }
+        }
 
         log::trace!("encoding all blocks");
 
@@ -234,7 +310,7 @@ impl<'a, A: Alloc> X86TranslationContext<A> {
 
             // all but last
             for instr in rest {
-                instr.encode(&mut assembler, &label_map);
+                instr.encode(&mut assembler, &label_map, &mut patch_labels);
             }
 
             assert!(matches!(
@@ -252,19 +328,40 @@ impl<'a, A: Alloc> X86TranslationContext<A> {
                 }
             }
 
-            last.encode(&mut assembler, &label_map);
+            last.encode(&mut assembler, &label_map, &mut patch_labels);
         }
 
         log::trace!("assembling");
-        let code = assembler.assemble(0).unwrap();
-
-        log::trace!("making executable");
-
-        let res = Translation::new(code);
-
-        log::trace!("done");
-
-        res
+        let result = assembler
+            .assemble_options(0, BlockEncoderOptions::RETURN_NEW_INSTRUCTION_OFFSETS)
+            .unwrap();
+
+        // each slot is a tag followed by its jump
+        let chain_slots = patch_labels
+            .chain
+            .chunks_exact(2)
+            .map(|labels| {
+                let [tag, jump] = labels else { unreachable!() };
+                ChainSlot::from_offsets(
+                    result.label_ip(tag).unwrap() as usize,
+                    result.label_ip(jump).unwrap() as usize,
+                )
+            })
+            .collect();
+
+        let relocations = patch_labels
+            .relocations
+            .iter()
+            .map(|(label, symbol)| {
+                Relocation::from_offset(result.label_ip(label).unwrap() as usize, *symbol)
+            })
+            .collect();
+
+        AssembledCode {
+            code: result.inner.code_buffer,
+            chain_slots,
+            relocations,
+        }
     }
 
     pub fn create_block(&mut self) -> Ref<X86Block<A>> {
@@ -277,6 +374,12 @@ impl<'a, A: Alloc> X86TranslationContext<A> {
         self.writes_to_pc = true;
     }
 
+    /// Clears the "PC was written to" flag, for translating the next block of a
+    /// superblock
+    pub fn clear_pc_write_flag(&mut self) {
+        self.writes_to_pc = false;
+    }
+
     /// Gets the value of the "PC was written to" flag
     pub fn get_pc_write_flag(&self) -> bool {
         self.writes_to_pc
@@ -0,0 +1,284 @@
+//! Peephole optimisation of each block's instructions before register
+//! allocation
+//!
+//! Virtual registers are only live within the block that defines them, so
+//! every use of one can be found without looking beyond the block.
+
+use {
+    crate::host::dbt::{
+        Alloc,
+        x86::encoder::{
+            Instruction, Opcode, Operand, OperandDirection, OperandKind, PhysicalRegister,
+            Register, UseDefMut, width::Width,
+        },
+    },
+    alloc::{alloc::Global, vec::Vec},
+    proc_macro_lib::ktest,
+};
+
+/// Runs every peephole pass over `instructions`
+pub fn optimise<A: Alloc>(instructions: &mut Vec<Instruction<A>, A>) {
+    propagate_copies(instructions);
+    remove_redundant_flags(instructions);
+}
+
+/// Replaces uses of virtual registers that are only ever a copy of another
+/// with the original, removing the `mov` between them
+///
+/// Chains of moves collapse one at a time, as each replaced use may be the
+/// source of the next move.
+fn propagate_copies<A: Alloc>(instructions: &mut Vec<Instruction<A>, A>) {
+    let mut removed = alloc::vec![false; instructions.len()];
+
+    for index in 0..instructions.len() {
+        let Opcode::MOV(source, destination) = instructions[index].0 else {
+            continue;
+        };
+
+        let (OperandKind::Register(from), OperandKind::Register(to)) =
+            (source.kind, destination.kind)
+        else {
+            continue;
+        };
+
+        let same_class = matches!(
+            (from, to),
+            (Register::VirtualRegister(_), Register::VirtualRegister(_))
+                | (
+                    Register::VirtualXmmRegister(_),
+                    Register::VirtualXmmRegister(_)
+                )
+        );
+
+        if from == to {
+            removed[index] = true;
+            continue;
+        }
+
+        if !same_class || source.width() != destination.width() {
+            continue;
+        }
+
+        let Some(last_use) = last_use_of_copy(instructions, index, from, to, source.width()) else {
+            continue;
+        };
+
+        instructions[index + 1..=last_use]
+            .iter_mut()
+            .flat_map(|instruction| instruction.get_use_defs_mut())
+            .for_each(|use_def| {
+                if let UseDefMut::Use(register) = use_def {
+                    if *register == to {
+                        *register = from;
+                    }
+                }
+            });
+
+        removed[index] = true;
+    }
+
+    let mut index = 0;
+    instructions.retain(|_| {
+        index += 1;
+        !removed[index - 1]
+    });
+}
+
+/// Index of the last use of `to`, copied from `from` by the `mov` at `index`,
+/// if every use can read `from` instead
+///
+/// `to` must not be written anywhere else, every use must read it at the width
+/// of the copy, and `from` must not be written again before the last use.
+fn last_use_of_copy<A: Alloc>(
+    instructions: &[Instruction<A>],
+    index: usize,
+    from: Register,
+    to: Register,
+    width: Width,
+) -> Option<usize> {
+    let defines = |instruction: &Instruction<A>, register: Register| {
+        instruction
+            .get_operands_copy()
+            .iter()
+            .any(|(direction, operand)| {
+                operand.kind == OperandKind::Register(register)
+                    && matches!(direction, OperandDirection::Out | OperandDirection::InOut)
+            })
+    };
+
+    if instructions
+        .iter()
+        .enumerate()
+        .any(|(other, instruction)| other != index && defines(instruction, to))
+    {
+        return None;
+    }
+
+    let mut last_use = None;
+
+    for (offset, instruction) in instructions[index + 1..].iter().enumerate() {
+        for (_, operand) in instruction.get_operands_copy() {
+            let compatible = match operand.kind {
+                OperandKind::Register(register) if register == to => operand.width() == width,
+                OperandKind::Memory {
+                    base,
+                    index: memory_index,
+                    ..
+                } if base == Some(to) || memory_index == Some(to) => {
+                    // addresses are always read as 64 bits
+                    width.as_u16() == 64
+                }
+                _ => continue,
+            };
+
+            if !compatible {
+                return None;
+            }
+
+            last_use = Some(index + 1 + offset);
+        }
+    }
+
+    // `from` may only be written by the instruction making the last use
+    let last_use = last_use?;
+    instructions[index + 1..last_use]
+        .iter()
+        .all(|instruction| !defines(instruction, from))
+        .then_some(last_use)
+}
+
+/// How an instruction affects the status flags
+pub(super) enum Flags {
+    /// Neither read nor written
+    Unaffected,
+    /// Every status flag is overwritten without being read
+    Overwritten,
+    /// Read, or partially or conditionally written
+    Used,
+}
+
+pub(super) fn flags<A: Alloc>(instruction: &Instruction<A>) -> Flags {
+    match instruction.0 {
+        Opcode::MOV(..)
+        | Opcode::MOVZX(..)
+        | Opcode::MOVSX(..)
+        | Opcode::LEA(..)
+        | Opcode::MOVQ(..)
+        | Opcode::MOVDQU(..)
+        | Opcode::MOVREL(..)
+        | Opcode::NOT(_)
+        | Opcode::NOP => Flags::Unaffected,
+        Opcode::ADD(..)
+        | Opcode::SUB(..)
+        | Opcode::AND(..)
+        | Opcode::OR(..)
+        | Opcode::XOR(..)
+        | Opcode::NEG(_)
+        | Opcode::CMP(..)
+        | Opcode::TEST(..) => Flags::Overwritten,
+        _ => Flags::Used,
+    }
+}
+
+/// Removes comparisons whose flags are never read, and tests of a register
+/// whose flags were just set identically by a logical operation on it
+fn remove_redundant_flags<A: Alloc>(instructions: &mut Vec<Instruction<A>, A>) {
+    let removed = (0..instructions.len())
+        .map(|index| sets_redundant_flags(instructions, index))
+        .collect::<Vec<_>>();
+
+    let mut index = 0;
+    instructions.retain(|_| {
+        index += 1;
+        !removed[index - 1]
+    });
+}
+
+/// Whether the instruction at `index` is a comparison that can be removed
+fn sets_redundant_flags<A: Alloc>(instructions: &[Instruction<A>], index: usize) -> bool {
+    let (Opcode::CMP(left, right) | Opcode::TEST(left, right)) = instructions[index].0 else {
+        return false;
+    };
+
+    let unread = instructions[index + 1..]
+        .iter()
+        .map(flags)
+        .find(|flags| !matches!(flags, Flags::Unaffected))
+        .is_some_and(|flags| matches!(flags, Flags::Overwritten));
+
+    // `and`, `or` and `xor` set the flags exactly as a test of their result
+    let retested = matches!(instructions[index].0, Opcode::TEST(..))
+        && left == right
+        && index
+            .checked_sub(1)
+            .and_then(|previous| match instructions[previous].0 {
+                Opcode::AND(_, result) | Opcode::OR(_, result) | Opcode::XOR(_, result) => {
+                    Some(result)
+                }
+                _ => None,
+            })
+            .is_some_and(|result| result == left);
+
+    unread || retested
+}
+
+#[ktest]
+fn peephole_propagates_copies() {
+    let v = |index| Operand::<Global>::vreg(Width::_64, index);
+    let register_file = |offset| {
+        Operand::mem_base_displ(
+            Width::_64,
+            Register::PhysicalRegister(PhysicalRegister::RBP),
+            offset,
+        )
+    };
+
+    let mut instructions = alloc::vec![
+        Instruction::mov(register_file(0), v(0)).unwrap(),
+        Instruction::mov(v(0), v(1)).unwrap(),
+        Instruction::mov(v(1), v(2)).unwrap(),
+        Instruction::add(Operand::imm(Width::_64, 1), v(2)),
+        Instruction::mov(v(1), register_file(8)).unwrap(),
+        Instruction::ret(),
+    ];
+
+    optimise(&mut instructions);
+
+    // `v2` is modified so must remain a copy, but `v1` is read from `v0`
+    assert_eq!(
+        instructions,
+        alloc::vec![
+            Instruction::mov(register_file(0), v(0)).unwrap(),
+            Instruction::mov(v(0), v(2)).unwrap(),
+            Instruction::add(Operand::imm(Width::_64, 1), v(2)),
+            Instruction::mov(v(0), register_file(8)).unwrap(),
+            Instruction::ret(),
+        ]
+    );
+}
+
+#[ktest]
+fn peephole_removes_redundant_flags() {
+    let v = |index| Operand::<Global>::vreg(Width::_64, index);
+
+    let mut instructions = alloc::vec![
+        Instruction::cmp(v(0), v(1)),
+        Instruction::mov(v(1), v(2)).unwrap(),
+        Instruction::and(v(0), v(2)),
+        Instruction::test(v(2), v(2)),
+        Instruction::sete(Operand::vreg(Width::_8, 3)),
+        Instruction::ret(),
+    ];
+
+    optimise(&mut instructions);
+
+    assert_eq!(
+        instructions,
+        alloc::vec![
+            Instruction::mov(v(1), v(2)).unwrap(),
+            Instruction::and(v(0), v(2)),
+            Instruction::sete(Operand::vreg(Width::_8, 3)),
+            Instruction::ret(),
+        ]
+    );
+}
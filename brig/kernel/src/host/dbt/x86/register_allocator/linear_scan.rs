@@ -0,0 +1,1002 @@
+//! Linear scan register allocation over every block of a translation
+//!
+//! Liveness is computed over the whole control flow graph rather than one block
+//! at a time, so stack variables, which carry values between blocks, are kept
+//! in registers like any virtual register. Each register is given a single
+//! interval spanning every point it is live at in the order the blocks are
+//! encoded, while physical registers named by instructions keep their exact
+//! live ranges so that intervals can fit around them.
+//!
+//! When registers run out, the register live the longest is spilled, preferring
+//! stack variables. These are left in their slot in the stack variable area of
+//! the register file, which is where every stack variable is kept by
+//! [`FreshAllocator`](super::naive::FreshAllocator). Any other register is given
+//! a slot after those of the stack variables, and is loaded into and stored from
+//! a scratch register around each instruction accessing it. Scratch registers
+//! are reserved from allocation, as many as the most spilled registers accessed
+//! by a single instruction.
+//!
+//! Copies are coalesced by preferring the register of the other side of a `mov`,
+//! after which the `mov` is removed.
+
+use {
+    crate::host::dbt::{
+        Alloc as MemAlloc,
+        x86::{
+            emitter::X86Block,
+            encoder::{
+                Instruction, Opcode, Operand, OperandDirection, OperandKind, PhysicalRegister,
+                Register, width::Width,
+            },
+            register_allocator::{CALLER_SAVED, RegisterAllocator},
+        },
+    },
+    alloc::{alloc::Global, vec::Vec},
+    common::{
+        arena::{Arena, Ref},
+        hashmap::{HashMap, HashSet},
+    },
+    proc_macro_lib::ktest,
+};
+
+/// General purpose registers available for allocation, those preserved across
+/// calls last
+const GENERAL_PURPOSE: &[PhysicalRegister] = &[
+    PhysicalRegister::RAX,
+    PhysicalRegister::RCX,
+    PhysicalRegister::RDX,
+    PhysicalRegister::RSI,
+    PhysicalRegister::RDI,
+    PhysicalRegister::R8,
+    PhysicalRegister::R9,
+    PhysicalRegister::R10,
+    PhysicalRegister::R11,
+    PhysicalRegister::RBX,
+    PhysicalRegister::R12,
+    PhysicalRegister::R13,
+    PhysicalRegister::R14,
+];
+
+pub struct LinearScanAllocator {
+    global_register_offset: usize,
+}
+
+/// Registers read and written by an instruction, and the blocks it may branch
+/// to
+struct Access {
+    uses: Vec<Register>,
+    defs: Vec<Register>,
+    targets: Vec<usize>,
+}
+
+/// Positions an allocated register is occupied between, inclusive
+///
+/// Instruction `i` reads its operands at position `2i` and writes them at `2i +
+/// 1`, so a register last read by an instruction can be reused for a register
+/// it writes.
+#[derive(Debug, Clone, Copy)]
+struct Interval {
+    start: usize,
+    end: usize,
+}
+
+impl RegisterAllocator for LinearScanAllocator {
+    fn allocate<A: MemAlloc>(&mut self, instructions: &mut Vec<Instruction<A>, A>) {
+        self.allocate_blocks(&mut [instructions], |_| None);
+    }
+}
+
+impl LinearScanAllocator {
+    pub fn new(global_register_offset: usize) -> Self {
+        Self {
+            global_register_offset,
+        }
+    }
+
+    /// Allocates registers for every block of a translation, in the order they
+    /// are encoded, the first being the entry block
+    pub fn allocate_translation<A: MemAlloc>(
+        &mut self,
+        arena: &mut Arena<X86Block<A>, A>,
+        blocks: &[Ref<X86Block<A>>],
+    ) {
+        let mut instructions = blocks
+            .iter()
+            .map(|block| {
+                let instructions = block.get_mut(arena).instructions_mut();
+                let allocator = instructions.allocator().clone();
+                core::mem::replace(instructions, Vec::new_in(allocator))
+            })
+            .collect::<Vec<_>>();
+
+        self.allocate_blocks(&mut instructions.iter_mut().collect::<Vec<_>>(), |target| {
+            blocks.iter().position(|block| *block == target)
+        });
+
+        blocks
+            .iter()
+            .zip(instructions)
+            .for_each(|(block, instructions)| {
+                *block.get_mut(arena).instructions_mut() = instructions;
+            });
+    }
+
+    fn allocate_blocks<A: MemAlloc>(
+        &mut self,
+        blocks: &mut [&mut Vec<Instruction<A>, A>],
+        block_index: impl Fn(Ref<X86Block<A>>) -> Option<usize>,
+    ) {
+        let accesses = blocks
+            .iter()
+            .map(|instructions| {
+                instructions
+                    .iter()
+                    .map(|instruction| access(instruction, &block_index))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let firsts = accesses
+            .iter()
+            .scan(0, |first, accesses| {
+                let block_first = *first;
+                *first += accesses.len();
+                Some(block_first)
+            })
+            .collect::<Vec<_>>();
+
+        let live_in = live_in(&accesses);
+
+        let mut intervals = HashMap::<Register, Interval>::default();
+        let mut fixed_positions = HashMap::<PhysicalRegister, Vec<usize>>::default();
+
+        for (block, accesses) in accesses.iter().enumerate() {
+            walk(accesses, firsts[block], &live_in, |position, live| {
+                live.iter().for_each(|register| match register {
+                    Register::PhysicalRegister(physical) => {
+                        fixed_positions.entry(*physical).or_default().push(position)
+                    }
+                    _ => {
+                        intervals
+                            .entry(*register)
+                            .and_modify(|interval| {
+                                interval.start = interval.start.min(position);
+                                interval.end = interval.end.max(position);
+                            })
+                            .or_insert(Interval {
+                                start: position,
+                                end: position,
+                            });
+                    }
+                })
+            });
+        }
+
+        let fixed = fixed_positions
+            .into_iter()
+            .map(|(register, positions)| (register, ranges(positions)))
+            .collect::<HashMap<_, _>>();
+
+        let calls = accesses
+            .iter()
+            .flatten()
+            .zip(blocks.iter().flat_map(|instructions| instructions.iter()))
+            .enumerate()
+            .filter(|(_, (_, instruction))| matches!(instruction.0, Opcode::CALL { .. }))
+            .map(|(index, (access, _))| (index, access))
+            .collect::<Vec<_>>();
+
+        // allocated in order of their start
+        let mut intervals = intervals.into_iter().collect::<Vec<_>>();
+        intervals.sort_by_key(|(_, interval)| (interval.start, interval.end));
+
+        let hints = hints(blocks);
+
+        // stack variable IDs are allocated from zero, so other registers are spilled
+        // to the slots after the last
+        let first_slot = accesses
+            .iter()
+            .flatten()
+            .flat_map(|access| access.uses.iter().chain(&access.defs))
+            .filter_map(|register| match register {
+                Register::GlobalRegister(id) => Some(id + 1),
+                _ => None,
+            })
+            .max()
+            .unwrap_or_default();
+
+        // retried with more scratch registers reserved until there are enough for
+        // every instruction, which may spill more registers
+        let mut scratch_counts = (0, 0);
+        let (assignment, slots, scratch) = loop {
+            let scratch = (
+                scratch(scratch_counts.0, false, &fixed),
+                scratch(scratch_counts.1, true, &fixed),
+            );
+            let reserved = scratch
+                .0
+                .iter()
+                .chain(&scratch.1)
+                .copied()
+                .collect::<Vec<_>>();
+
+            let assignment = assign(&intervals, &fixed, &hints, &calls, &reserved);
+
+            let slots = intervals
+                .iter()
+                .map(|(register, _)| *register)
+                .filter(|register| {
+                    !matches!(register, Register::GlobalRegister(_))
+                        && !assignment.contains_key(register)
+                })
+                .enumerate()
+                // two slots each, as SSE registers hold 128 bits
+                .map(|(index, register)| (register, first_slot + 2 * index))
+                .collect::<HashMap<_, _>>();
+
+            let needed = accesses
+                .iter()
+                .flatten()
+                .map(|access| {
+                    let spilled = spilled_registers(access, &slots);
+                    let xmm = spilled.iter().filter(|register| is_xmm(register)).count();
+                    (spilled.len() - xmm, xmm)
+                })
+                .fold((0, 0), |(gprs, xmms), (gpr, xmm)| {
+                    (gprs.max(gpr), xmms.max(xmm))
+                });
+
+            if needed.0 <= scratch_counts.0 && needed.1 <= scratch_counts.1 {
+                break (assignment, slots, scratch);
+            }
+
+            scratch_counts = (
+                scratch_counts.0.max(needed.0),
+                scratch_counts.1.max(needed.1),
+            );
+        };
+
+        // caller saved registers holding a value across each call
+        let saves = calls
+            .iter()
+            .map(|(index, access)| {
+                let use_position = 2 * index;
+
+                let saved = CALLER_SAVED
+                    .iter()
+                    .copied()
+                    .filter(|register| {
+                        let allocated = intervals.iter().any(|(allocated, interval)| {
+                            assignment.get(allocated) == Some(register)
+                                && interval.start <= use_position
+                                && use_position < interval.end
+                        });
+
+                        // registers written by the call are meant to be overwritten
+                        let named = !access.defs.contains(&Register::PhysicalRegister(*register))
+                            && fixed.get(register).is_some_and(|ranges| {
+                                ranges.iter().any(|&(start, end)| {
+                                    start <= use_position && use_position < end
+                                })
+                            });
+
+                        allocated || named
+                    })
+                    .collect::<Vec<_>>();
+
+                (*index, saved)
+            })
+            .collect::<HashMap<_, _>>();
+
+        for (block, instructions) in blocks.iter_mut().enumerate() {
+            let allocator = instructions.allocator().clone();
+            let allocated = core::mem::replace(&mut **instructions, Vec::new_in(allocator));
+
+            // stack variables that may be read before they are written keep their
+            // value from the stack variable area
+            if block == 0 {
+                live_in[0]
+                    .iter()
+                    .filter_map(|register| match register {
+                        Register::GlobalRegister(id) => {
+                            assignment.get(register).map(|physical| (*id, *physical))
+                        }
+                        _ => None,
+                    })
+                    .for_each(|(id, physical)| {
+                        instructions.push(
+                            Instruction::mov(
+                                self.stack_slot(Width::_64, id),
+                                Operand::preg(Width::_64, physical),
+                            )
+                            .unwrap(),
+                        )
+                    });
+            }
+
+            for (index, mut instruction) in allocated.into_iter().enumerate() {
+                let access = &accesses[block][index];
+
+                // each spilled register accessed is given a scratch register
+                let (mut gprs, mut xmms) = (scratch.0.iter(), scratch.1.iter());
+                let spilled = spilled_registers(access, &slots)
+                    .into_iter()
+                    .map(|register| {
+                        let physical = if is_xmm(&register) {
+                            xmms.next()
+                        } else {
+                            gprs.next()
+                        };
+
+                        (register, *physical.unwrap())
+                    })
+                    .collect::<Vec<_>>();
+
+                self.rewrite(&mut instruction, &assignment, &spilled);
+
+                if matches!(instruction.0, Opcode::MOV(src, dst) if src == dst) {
+                    continue;
+                }
+
+                let saved = saves
+                    .get(&(firsts[block] + index))
+                    .map(Vec::as_slice)
+                    .unwrap_or_default();
+
+                for register in saved {
+                    instructions.push(Instruction::push(Operand::preg(Width::_64, *register)));
+                }
+
+                for (register, physical) in &spilled {
+                    if access.uses.contains(register) {
+                        let (slot, scratch) = self.spill_slot(register, slots[register], *physical);
+                        instructions.push(Instruction::mov(slot, scratch).unwrap());
+                    }
+                }
+
+                instructions.push(instruction);
+
+                for (register, physical) in &spilled {
+                    if access.defs.contains(register) {
+                        let (slot, scratch) = self.spill_slot(register, slots[register], *physical);
+                        instructions.push(Instruction::mov(scratch, slot).unwrap());
+                    }
+                }
+
+                for register in saved.iter().rev() {
+                    instructions.push(Instruction::pop(Operand::preg(Width::_64, *register)));
+                }
+            }
+        }
+    }
+
+    /// Replaces the registers of `instruction` with the physical registers
+    /// assigned to them or the scratch registers of spilled registers, and
+    /// spilled stack variables with their slot
+    fn rewrite<A: MemAlloc>(
+        &self,
+        instruction: &mut Instruction<A>,
+        assignment: &HashMap<Register, PhysicalRegister>,
+        spilled: &[(Register, PhysicalRegister)],
+    ) {
+        let allocate = |register: &mut Register| {
+            let physical = assignment.get(register).or_else(|| {
+                spilled
+                    .iter()
+                    .find(|(spilled, _)| spilled == register)
+                    .map(|(_, scratch)| scratch)
+            });
+
+            if let Some(physical) = physical {
+                *register = Register::PhysicalRegister(*physical);
+            }
+        };
+
+        instruction
+            .get_operands_mut()
+            .flatten()
+            .for_each(|(_, operand)| match operand.kind {
+                OperandKind::Register(Register::GlobalRegister(id))
+                    if !assignment.contains_key(&Register::GlobalRegister(id)) =>
+                {
+                    *operand = self.stack_slot(operand.width(), id);
+                }
+                OperandKind::Register(ref mut register) => allocate(register),
+                OperandKind::Memory {
+                    ref mut base,
+                    ref mut index,
+                    ..
+                } => base.iter_mut().chain(index.iter_mut()).for_each(allocate),
+                _ => (),
+            });
+    }
+
+    /// Slot of the stack variable `id` in the register file
+    fn stack_slot<A: MemAlloc>(&self, width: Width, id: usize) -> Operand<A> {
+        Operand::mem_base_displ(
+            width,
+            Register::PhysicalRegister(PhysicalRegister::RBP),
+            i32::try_from(self.global_register_offset + (id * 8)).unwrap(),
+        )
+    }
+
+    /// Slot a spilled register is kept in, starting at stack variable slot
+    /// `id`, and the scratch register it is accessed through
+    fn spill_slot<A: MemAlloc>(
+        &self,
+        register: &Register,
+        id: usize,
+        scratch: PhysicalRegister,
+    ) -> (Operand<A>, Operand<A>) {
+        let width = if is_xmm(register) {
+            Width::_128
+        } else {
+            Width::_64
+        };
+
+        (self.stack_slot(width, id), Operand::preg(width, scratch))
+    }
+}
+
+/// Spilled registers other than stack variables read or written by an
+/// instruction, each once
+fn spilled_registers(access: &Access, slots: &HashMap<Register, usize>) -> Vec<Register> {
+    let mut spilled = Vec::new();
+
+    access
+        .uses
+        .iter()
+        .chain(&access.defs)
+        .filter(|register| slots.contains_key(register))
+        .for_each(|register| {
+            if !spilled.contains(register) {
+                spilled.push(*register);
+            }
+        });
+
+    spilled
+}
+
+/// Registers reserved to access spilled registers through, `count` of the
+/// general purpose or SSE registers that are never named by instructions,
+/// preferring those preserved across calls
+fn scratch(
+    count: usize,
+    xmm: bool,
+    fixed: &HashMap<PhysicalRegister, Vec<(usize, usize)>>,
+) -> Vec<PhysicalRegister> {
+    let candidates = if xmm {
+        (16..32).rev().map(PhysicalRegister::from_index).collect()
+    } else {
+        GENERAL_PURPOSE.iter().rev().copied().collect::<Vec<_>>()
+    };
+
+    let scratch = candidates
+        .into_iter()
+        .filter(|register| !fixed.contains_key(register))
+        .take(count)
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        scratch.len(),
+        count,
+        "not enough scratch registers for spilled registers"
+    );
+
+    scratch
+}
+
+/// Whether `register` can be allocated or constrains allocation
+fn is_tracked(register: &Register) -> bool {
+    // stack pointer, register file pointer and the debug register for panics
+    !matches!(
+        register,
+        Register::PhysicalRegister(
+            PhysicalRegister::RSP | PhysicalRegister::RBP | PhysicalRegister::R15
+        )
+    )
+}
+
+fn is_xmm(register: &Register) -> bool {
+    match register {
+        Register::VirtualXmmRegister(_) => true,
+        Register::PhysicalRegister(physical) => physical.is_xmm(),
+        Register::VirtualRegister(_) | Register::GlobalRegister(_) => false,
+    }
+}
+
+fn access<A: MemAlloc>(
+    instruction: &Instruction<A>,
+    block_index: &impl Fn(Ref<X86Block<A>>) -> Option<usize>,
+) -> Access {
+    let mut access = Access {
+        uses: Vec::new(),
+        defs: Vec::new(),
+        targets: Vec::new(),
+    };
+
+    if let Opcode::RET = instruction.0 {
+        // the execution result is returned in RAX
+        access
+            .uses
+            .push(Register::PhysicalRegister(PhysicalRegister::RAX));
+        return access;
+    }
+
+    // `xor r, r` does not depend on the previous value of `r`
+    let clears = matches!(
+        instruction.0,
+        Opcode::XOR(src, dst) | Opcode::PXOR(src, dst) if src == dst
+    );
+    // conditional moves leave the destination unchanged if the condition fails
+    let conditional = matches!(instruction.0, Opcode::CMOVE(..) | Opcode::CMOVNE(..));
+
+    for (direction, operand) in instruction.get_operands_copy() {
+        match operand.kind {
+            OperandKind::Register(register) => {
+                let reads = match direction {
+                    OperandDirection::In => true,
+                    OperandDirection::InOut => !clears,
+                    OperandDirection::Out => conditional,
+                    OperandDirection::None => false,
+                };
+
+                if reads {
+                    access.uses.push(register);
+                }
+
+                if matches!(direction, OperandDirection::Out | OperandDirection::InOut) {
+                    access.defs.push(register);
+                }
+            }
+            OperandKind::Memory { base, index, .. } => {
+                access.uses.extend(base.into_iter().chain(index));
+            }
+            OperandKind::Target(target) => access.targets.extend(block_index(target)),
+            _ => (),
+        }
+    }
+
+    access.uses.retain(|register| is_tracked(register));
+    access.defs.retain(|register| is_tracked(register));
+
+    access
+}
+
+/// Registers live on entry to each block
+fn live_in(accesses: &[Vec<Access>]) -> Vec<HashSet<Register>> {
+    let mut live_in = alloc::vec![HashSet::default(); accesses.len()];
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+
+        for (block, accesses) in accesses.iter().enumerate().rev() {
+            let live = walk(accesses, 0, &live_in, |_, _| ());
+
+            if live != live_in[block] {
+                live_in[block] = live;
+                changed = true;
+            }
+        }
+    }
+
+    live_in
+}
+
+/// Walks a block backwards from its end, calling `visit` with each position in
+/// the block and the registers occupied at it, and returns the registers live
+/// on entry
+fn walk(
+    accesses: &[Access],
+    first: usize,
+    live_in: &[HashSet<Register>],
+    mut visit: impl FnMut(usize, &HashSet<Register>),
+) -> HashSet<Register> {
+    let mut live = HashSet::default();
+
+    for (index, access) in accesses.iter().enumerate().rev() {
+        let position = 2 * (first + index);
+
+        for target in &access.targets {
+            live.extend(live_in[*target].iter().copied());
+        }
+
+        // written registers are occupied even if never read
+        live.extend(access.defs.iter().copied());
+        visit(position + 1, &live);
+
+        for def in &access.defs {
+            live.remove(def);
+        }
+        live.extend(access.uses.iter().copied());
+        visit(position, &live);
+    }
+
+    live
+}
+
+/// Merges positions into sorted, disjoint, inclusive ranges
+fn ranges(mut positions: Vec<usize>) -> Vec<(usize, usize)> {
+    positions.sort_unstable();
+    positions.dedup();
+
+    let mut ranges = Vec::<(usize, usize)>::new();
+    for position in positions {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == position => *end = position,
+            _ => ranges.push((position, position)),
+        }
+    }
+
+    ranges
+}
+
+/// Whether any of the sorted `ranges` overlaps `start..=end`
+fn overlaps(ranges: &[(usize, usize)], start: usize, end: usize) -> bool {
+    let first = ranges.partition_point(|&(_, range_end)| range_end < start);
+    ranges
+        .get(first)
+        .is_some_and(|&(range_start, _)| range_start <= end)
+}
+
+/// Registers copied to or from each register, whose physical register is
+/// preferred so that the copy can be removed
+fn hints<A: MemAlloc>(blocks: &[&mut Vec<Instruction<A>, A>]) -> HashMap<Register, Vec<Register>> {
+    let mut hints = HashMap::<Register, Vec<Register>>::default();
+
+    blocks
+        .iter()
+        .flat_map(|instructions| instructions.iter())
+        .filter_map(|instruction| match instruction.0 {
+            Opcode::MOV(
+                Operand {
+                    kind: OperandKind::Register(src),
+                    ..
+                },
+                Operand {
+                    kind: OperandKind::Register(dst),
+                    ..
+                },
+            ) => Some((src, dst)),
+            _ => None,
+        })
+        .filter(|(src, dst)| is_tracked(src) && is_tracked(dst) && is_xmm(src) == is_xmm(dst))
+        .for_each(|(src, dst)| {
+            hints.entry(src).or_default().push(dst);
+            hints.entry(dst).or_default().push(src);
+        });
+
+    hints
+}
+
+/// Assigns a physical register other than those `reserved` to each interval,
+/// in order of their start, leaving spilled registers unassigned
+///
+/// Every SSE register is clobbered by calls, so SSE registers live across a
+/// call are always spilled.
+fn assign(
+    intervals: &[(Register, Interval)],
+    fixed: &HashMap<PhysicalRegister, Vec<(usize, usize)>>,
+    hints: &HashMap<Register, Vec<Register>>,
+    calls: &[(usize, &Access)],
+    reserved: &[PhysicalRegister],
+) -> HashMap<Register, PhysicalRegister> {
+    let mut assignment = HashMap::<Register, PhysicalRegister>::default();
+    let mut active = Vec::<(Register, Interval)>::new();
+
+    let conflicts = |physical: &PhysicalRegister, interval: &Interval| {
+        fixed
+            .get(physical)
+            .is_some_and(|ranges| overlaps(ranges, interval.start, interval.end))
+    };
+
+    for (register, interval) in intervals {
+        active.retain(|(_, active)| active.end >= interval.start);
+
+        let xmm = is_xmm(register);
+
+        let spans_call = calls.iter().any(|(index, _)| {
+            let use_position = 2 * index;
+            interval.start <= use_position && use_position < interval.end
+        });
+
+        if xmm && spans_call {
+            continue;
+        }
+
+        let candidates = if xmm {
+            (16..32).map(PhysicalRegister::from_index).collect()
+        } else if spans_call {
+            GENERAL_PURPOSE.iter().rev().copied().collect()
+        } else {
+            GENERAL_PURPOSE.to_vec()
+        };
+
+        let choice = hints
+            .get(register)
+            .into_iter()
+            .flatten()
+            .filter_map(|partner| match partner {
+                Register::PhysicalRegister(physical) => Some(*physical),
+                partner => assignment.get(partner).copied(),
+            })
+            .chain(candidates)
+            .find(|physical| {
+                physical.is_xmm() == xmm
+                    && !reserved.contains(physical)
+                    && !active
+                        .iter()
+                        .any(|(active, _)| assignment.get(active) == Some(physical))
+                    && !conflicts(physical, interval)
+            });
+
+        if let Some(physical) = choice {
+            assignment.insert(*register, physical);
+            active.push((*register, *interval));
+            continue;
+        }
+
+        // take the register of the register live the longest, preferring stack
+        // variables as they are spilled to their slot without scratch registers
+        let cost = |register: &Register, interval: &Interval| {
+            (
+                matches!(register, Register::GlobalRegister(_)),
+                interval.end,
+            )
+        };
+
+        let victim = active
+            .iter()
+            .enumerate()
+            .filter(|(_, (active, _))| {
+                is_xmm(active) == xmm && !conflicts(&assignment[active], interval)
+            })
+            .max_by_key(|(_, (active, active_interval))| cost(active, active_interval))
+            .map(|(index, (active, active_interval))| (index, cost(active, active_interval)));
+
+        match victim {
+            Some((index, victim_cost)) if victim_cost > cost(register, interval) => {
+                let (victim, _) = active.swap_remove(index);
+                let physical = assignment.remove(&victim).unwrap();
+
+                assignment.insert(*register, physical);
+                active.push((*register, *interval));
+            }
+            _ => (),
+        }
+    }
+
+    assignment
+}
+
+#[ktest]
+fn linear_scan_coalesces_stack_variables() {
+    let instructions = &mut alloc::vec![
+        Instruction::mov(
+            Operand::<Global>::imm(Width::_64, 5),
+            Operand::greg(Width::_64, 0)
+        )
+        .unwrap(),
+        Instruction::mov(Operand::greg(Width::_64, 0), Operand::vreg(Width::_64, 0)).unwrap(),
+        Instruction::mov(
+            Operand::vreg(Width::_64, 0),
+            Operand::mem_base_displ(
+                Width::_64,
+                Register::PhysicalRegister(PhysicalRegister::RBP),
+                8,
+            ),
+        )
+        .unwrap(),
+        Instruction::mov(
+            Operand::imm(Width::_32, 0),
+            Operand::preg(Width::_32, PhysicalRegister::RAX),
+        )
+        .unwrap(),
+        Instruction::ret(),
+    ];
+
+    LinearScanAllocator::new(0x1000).allocate(instructions);
+
+    // both copies of the value share a register, so the `mov` between them is removed
+    assert_eq!(
+        *instructions,
+        alloc::vec![
+            Instruction::mov(
+                Operand::imm(Width::_64, 5),
+                Operand::preg(Width::_64, PhysicalRegister::RAX)
+            )
+            .unwrap(),
+            Instruction::mov(
+                Operand::preg(Width::_64, PhysicalRegister::RAX),
+                Operand::mem_base_displ(
+                    Width::_64,
+                    Register::PhysicalRegister(PhysicalRegister::RBP),
+                    8,
+                ),
+            )
+            .unwrap(),
+            Instruction::mov(
+                Operand::imm(Width::_32, 0),
+                Operand::preg(Width::_32, PhysicalRegister::RAX),
+            )
+            .unwrap(),
+            Instruction::ret(),
+        ]
+    );
+}
+
+#[ktest]
+fn linear_scan_spills_stack_variables() {
+    const GLOBAL_REGISTER_OFFSET: usize = 0x1000;
+    const STACK_VARIABLES: usize = 16;
+
+    let register_file = |offset| {
+        Operand::<Global>::mem_base_displ(
+            Width::_64,
+            Register::PhysicalRegister(PhysicalRegister::RBP),
+            offset,
+        )
+    };
+
+    let mut instructions = (0..STACK_VARIABLES)
+        .map(|id| {
+            Instruction::mov(
+                Operand::imm(Width::_64, id as u64),
+                Operand::greg(Width::_64, id),
+            )
+            .unwrap()
+        })
+        .collect::<Vec<_>>();
+
+    (0..STACK_VARIABLES).for_each(|id| {
+        instructions.push(
+            Instruction::mov(Operand::greg(Width::_64, id), Operand::vreg(Width::_64, id)).unwrap(),
+        );
+        instructions.push(
+            Instruction::mov(
+                Operand::vreg(Width::_64, id),
+                register_file(i32::try_from(id * 8).unwrap()),
+            )
+            .unwrap(),
+        );
+    });
+
+    instructions.push(
+        Instruction::mov(
+            Operand::imm(Width::_32, 0),
+            Operand::preg(Width::_32, PhysicalRegister::RAX),
+        )
+        .unwrap(),
+    );
+    instructions.push(Instruction::ret());
+
+    LinearScanAllocator::new(GLOBAL_REGISTER_OFFSET).allocate(&mut instructions);
+
+    let spilled = STACK_VARIABLES - GENERAL_PURPOSE.len();
+
+    // a spilled variable is written to and read from its slot
+    let slot_accesses = instructions
+        .iter()
+        .flat_map(|instruction| instruction.get_operands_copy())
+        .filter(|(_, operand)| {
+            matches!(
+                operand.kind,
+                OperandKind::Memory { displacement, .. }
+                    if displacement >= GLOBAL_REGISTER_OFFSET as i32
+            )
+        })
+        .count();
+    assert_eq!(slot_accesses, 2 * spilled);
+
+    // the copy out of every variable kept in a register is removed
+    assert_eq!(
+        instructions.len(),
+        3 * STACK_VARIABLES + 2 - GENERAL_PURPOSE.len()
+    );
+
+    assert!(instructions.iter().all(|instruction| {
+        instruction.get_operands_copy().iter().all(|(_, operand)| {
+            !matches!(
+                operand.kind,
+                OperandKind::Register(Register::VirtualRegister(_) | Register::GlobalRegister(_))
+            )
+        })
+    }));
+}
+
+#[ktest]
+fn linear_scan_spills_virtual_registers() {
+    const GLOBAL_REGISTER_OFFSET: usize = 0x1000;
+    const VIRTUAL_REGISTERS: usize = 16;
+
+    let mut instructions = (0..VIRTUAL_REGISTERS)
+        .map(|id| {
+            Instruction::mov(
+                Operand::<Global>::imm(Width::_64, id as u64),
+                Operand::vreg(Width::_64, id),
+            )
+            .unwrap()
+        })
+        .collect::<Vec<_>>();
+
+    (0..VIRTUAL_REGISTERS).for_each(|id| {
+        instructions.push(
+            Instruction::mov(
+                Operand::vreg(Width::_64, id),
+                Operand::mem_base_displ(
+                    Width::_64,
+                    Register::PhysicalRegister(PhysicalRegister::RBP),
+                    i32::try_from(id * 8).unwrap(),
+                ),
+            )
+            .unwrap(),
+        );
+    });
+
+    instructions.push(Instruction::ret());
+
+    LinearScanAllocator::new(GLOBAL_REGISTER_OFFSET).allocate(&mut instructions);
+
+    // one register is reserved as scratch
+    let spilled = VIRTUAL_REGISTERS - (GENERAL_PURPOSE.len() - 1);
+
+    // a spilled register is stored to its slot after being written, and loaded
+    // from it before being read
+    let slot_accesses = instructions
+        .iter()
+        .flat_map(|instruction| instruction.get_operands_copy())
+        .filter(|(_, operand)| {
+            matches!(
+                operand.kind,
+                OperandKind::Memory { displacement, .. }
+                    if displacement >= GLOBAL_REGISTER_OFFSET as i32
+            )
+        })
+        .count();
+    assert_eq!(slot_accesses, 2 * spilled);
+    assert_eq!(instructions.len(), 2 * VIRTUAL_REGISTERS + 1 + 2 * spilled);
+
+    assert!(instructions.iter().all(|instruction| {
+        instruction.get_operands_copy().iter().all(|(_, operand)| {
+            !matches!(
+                operand.kind,
+                OperandKind::Register(Register::VirtualRegister(_))
+            )
+        })
+    }));
+}
+
+#[ktest]
+fn linear_scan_spills_xmm_registers_across_calls() {
+    const GLOBAL_REGISTER_OFFSET: usize = 0x1000;
+
+    let instructions = &mut alloc::vec![
+        Instruction::movq(
+            Operand::<Global>::preg(Width::_64, PhysicalRegister::RAX),
+            Operand::vxmm(Width::_64, 0),
+        ),
+        Instruction::call(Operand::vreg(Width::_64, 0), 0, 0),
+        Instruction::movq(
+            Operand::vxmm(Width::_64, 0),
+            Operand::preg(Width::_64, PhysicalRegister::RAX),
+        ),
+        Instruction::ret(),
+    ];
+
+    LinearScanAllocator::new(GLOBAL_REGISTER_OFFSET).allocate(instructions);
+
+    // every SSE register is clobbered by the call, so the value is kept in its slot
+    let slot_accesses = instructions
+        .iter()
+        .flat_map(|instruction| instruction.get_operands_copy())
+        .filter(|(_, operand)| {
+            matches!(
+                operand.kind,
+                OperandKind::Memory { displacement, .. }
+                    if displacement >= GLOBAL_REGISTER_OFFSET as i32
+            ) && operand.width() == Width::_128
+        })
+        .count();
+    assert_eq!(slot_accesses, 2);
+}
@@ -5,17 +5,45 @@ use {
             encoder::{
                 Instruction, MemoryScale, Opcode, Operand, PhysicalRegister, Register, width::Width,
             },
-            register_allocator::naive::FreshAllocator, //solid_state::SolidStateRegisterAllocator,
+            register_allocator::{linear_scan::LinearScanAllocator, naive::FreshAllocator}, //solid_state::SolidStateRegisterAllocator,
         },
     },
     alloc::{alloc::Global, vec::Vec},
     proc_macro_lib::ktest,
+    serde::{Deserialize, Serialize},
 };
 
+pub mod linear_scan;
 //pub mod reverse_scan;
 pub mod naive;
 //pub mod solid_state;
 
+/// Register allocator used when assembling a translation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum RegisterAllocatorKind {
+    /// [`FreshAllocator`], allocating each block on its own
+    Fresh,
+    /// [`LinearScanAllocator`], allocating every block of a translation together
+    LinearScan,
+}
+
+/// General purpose registers not preserved across calls
+///
+/// Every SSE register is clobbered by calls too, but cannot be pushed, so
+/// [`LinearScanAllocator`] spills SSE registers live across a call and
+/// [`FreshAllocator`] moves them below the stack pointer around it
+const CALLER_SAVED: &[PhysicalRegister] = &[
+    PhysicalRegister::RAX,
+    PhysicalRegister::RCX,
+    PhysicalRegister::RDX,
+    PhysicalRegister::RSI,
+    PhysicalRegister::RDI,
+    PhysicalRegister::R8,
+    PhysicalRegister::R9,
+    PhysicalRegister::R10,
+    PhysicalRegister::R11,
+];
+
 pub trait RegisterAllocator {
     // A is for the generic memory allocator, NOT anything to do with the register
     // allocator
@@ -44,6 +72,9 @@ fn conflicted_physical_allocation() {
     ];
 
     let mut allocator = FreshAllocator::new(2, 0);
+    allocator.allocate(&mut instrs.clone());
+
+    let mut allocator = LinearScanAllocator::new(0);
     allocator.allocate(&mut instrs);
 }
 
@@ -323,6 +354,9 @@ fn shr_full() {
     ];
 
     let mut allocator = FreshAllocator::new(14, 0);
+    allocator.allocate(&mut instructions.clone());
+
+    let mut allocator = LinearScanAllocator::new(0);
     allocator.allocate(&mut instructions);
 }
 
@@ -6,7 +6,7 @@ use {
                 Instruction, Opcode, Operand, OperandKind, PhysicalRegister, Register, UseDef,
                 UseDefMut, width::Width,
             },
-            register_allocator::RegisterAllocator,
+            register_allocator::{CALLER_SAVED, RegisterAllocator},
         },
     },
     alloc::vec::Vec,
@@ -67,7 +67,8 @@ impl RegisterAllocator for FreshAllocator {
 
             instruction.get_use_defs_mut().for_each(|ud| {
                 let (UseDefMut::Def(reg) | UseDefMut::Use(reg) | UseDefMut::UseDef(reg)) = ud;
-                if let Register::VirtualRegister(vreg) = &*reg {
+                if let Register::VirtualRegister(vreg) | Register::VirtualXmmRegister(vreg) = &*reg
+                {
                     *reg = Register::PhysicalRegister(PhysicalRegister::from_index(
                         *self.allocation_plan.get(vreg).unwrap(),
                     ));
@@ -191,7 +192,7 @@ impl FreshAllocator {
 
                                 // start new live range if past the current end
                                 if instruction_index >= last_range.1.unwrap_or_default() {
-                                    if let Register::VirtualRegister(_) = reg {
+                                    if let Register::VirtualRegister(_) | Register::VirtualXmmRegister(_) = reg {
                                         if let Opcode::CMOVNE(_, _) = instr_clone.0 {
                                             // do nothing for CMOVNE
                                         } else {
@@ -245,7 +246,8 @@ impl FreshAllocator {
     }
 
     fn build_allocation_plan<M: MemAlloc>(&mut self, instructions: &mut [Instruction<M>]) {
-        let mut physical_used = 0u16;
+        // general purpose registers in the low 16 bits, SSE registers in the high 16
+        let mut physical_used = 0u32;
 
         instructions.iter().enumerate().for_each(|(instruction_index, _instruction)| {
             {
@@ -256,7 +258,7 @@ impl FreshAllocator {
                         assert!(physical_used.bit_test(idx.index()));
                         physical_used.bit_reset(idx.index());
                     }
-                    Register::VirtualRegister(idx) => {
+                    Register::VirtualRegister(idx) | Register::VirtualXmmRegister(idx) => {
                         let phys_reg = *self.allocation_plan.get(&*idx).unwrap();
                         assert!(physical_used.bit_test(phys_reg));
                         physical_used.bit_reset(phys_reg);
@@ -271,7 +273,7 @@ impl FreshAllocator {
 
             started_registers.iter().filter_map(|reg| if let Register::PhysicalRegister(idx) = reg { Some(idx.index()) } else { None }).for_each(|idx| {
                 if physical_used.bit_test(idx) {
-                    let currently_live_registers = self.live_ranges.iter().filter(|(_, ranges)| ranges.iter().any(|(start, end)| (*start <= instruction_index) && (instruction_index < end.unwrap()))).filter_map(|(reg, _)| if let Register::VirtualRegister(idx) = reg { Some(*idx) } else { None }).collect::<Vec<usize>>();
+                    let currently_live_registers = self.live_ranges.iter().filter(|(_, ranges)| ranges.iter().any(|(start, end)| (*start <= instruction_index) && (instruction_index < end.unwrap()))).filter_map(|(reg, _)| virtual_index(reg)).collect::<Vec<usize>>();
 
                     // vregs that use our just-started physical register
                     let mut vregs = self.allocation_plan.iter().filter(|(vreg, preg)| **preg == idx && currently_live_registers.contains(vreg)).map(|(vreg, _)| *vreg).collect::<Vec<_>>();
@@ -284,7 +286,9 @@ impl FreshAllocator {
                     // todo: maybe only need to check intersections with start of current range
 
                     // virt so should onyl have one range
-                    let vreg_range = self.live_ranges.get(&Register::VirtualRegister(conflicting_vreg)).unwrap()[0];
+                    let is_xmm = PhysicalRegister::from_index(idx).is_xmm();
+                    let conflicting_register = if is_xmm { Register::VirtualXmmRegister(conflicting_vreg) } else { Register::VirtualRegister(conflicting_vreg) };
+                    let vreg_range = self.live_ranges.get(&conflicting_register).unwrap()[0];
 
 
                     // now we need to choose a new phys reg
@@ -293,7 +297,7 @@ impl FreshAllocator {
                 let intersecting_registers=    query_intersections(vreg_range, &self.live_ranges);
 
                let intersecting_physicals = intersecting_registers.iter().filter_map(|reg| match reg {
-                    Register::VirtualRegister(idx) => self.allocation_plan.get(&*idx).copied(), // intersects in the future but not yet allocated
+                    Register::VirtualRegister(idx) | Register::VirtualXmmRegister(idx) => self.allocation_plan.get(&*idx).copied(), // intersects in the future but not yet allocated
                     Register::PhysicalRegister(idx) => Some(idx.index()),
                     Register::GlobalRegister(_) => None
                 }).collect::<Vec<_>>();
@@ -303,15 +307,7 @@ impl FreshAllocator {
                     for idx in intersecting_physicals {
                         temp_physical_used.bit_set(idx);
                     }
-                    let reallocated_phys_index = {
-                        let first_empty = temp_physical_used.trailing_ones();
-
-                        if first_empty > 16 {
-                            panic!("ran out of registers :(");
-                        }
-
-                        usize::try_from(first_empty).unwrap()
-                    };
+                    let reallocated_phys_index = first_free(temp_physical_used, is_xmm);
                     physical_used.bit_set(reallocated_phys_index);
 
                     self.allocation_plan.insert(conflicting_vreg, reallocated_phys_index);
@@ -320,21 +316,13 @@ impl FreshAllocator {
                 }
             });
 
-            started_registers.iter().filter_map(|reg| if let Register::VirtualRegister(idx) = reg { Some(idx) } else { None }).for_each(|vreg_idx| {
-                let phys_index = {
-                    let first_empty = physical_used.trailing_ones();
-
-                    if first_empty > 16 {
-                        panic!("ran out of registers :(");
-                    }
-
-                    usize::try_from(first_empty).unwrap()
-                };
+            started_registers.iter().filter_map(|reg| virtual_index(reg).map(|idx| (idx, matches!(reg, Register::VirtualXmmRegister(_))))).for_each(|(vreg_idx, is_xmm)| {
+                let phys_index = first_free(physical_used, is_xmm);
 
                 physical_used.bit_set(phys_index);
 
                 // assert that virtual register never re-starts
-                if let Some(old_preg) = self.allocation_plan.insert(*vreg_idx, phys_index) {
+                if let Some(old_preg) = self.allocation_plan.insert(vreg_idx, phys_index) {
                     panic!("cannot re-start virtual register! vreg = {vreg_idx}, old_preg = {old_preg}, new allocation = {phys_index}");
                 }
             })
@@ -342,18 +330,6 @@ impl FreshAllocator {
     }
 
     fn insert_register_saves<M: MemAlloc>(&self, instructions: &mut Vec<Instruction<M>, M>) {
-        const CALLER_SAVED: &[PhysicalRegister] = &[
-            PhysicalRegister::RAX,
-            PhysicalRegister::RCX,
-            PhysicalRegister::RDX,
-            PhysicalRegister::RSI,
-            PhysicalRegister::RDI,
-            PhysicalRegister::R8,
-            PhysicalRegister::R9,
-            PhysicalRegister::R10,
-            PhysicalRegister::R11,
-        ];
-
         let mut new_instructions = alloc::vec![];
 
         for (index, instr) in instructions.iter().enumerate() {
@@ -370,7 +346,7 @@ impl FreshAllocator {
                     })
                     .map(|(reg, _)| match reg {
                         Register::PhysicalRegister(preg) => *preg,
-                        Register::VirtualRegister(virt) => {
+                        Register::VirtualRegister(virt) | Register::VirtualXmmRegister(virt) => {
                             PhysicalRegister::from_index(*self.allocation_plan.get(virt).unwrap())
                         }
                         Register::GlobalRegister(_) => todo!(),
@@ -382,12 +358,32 @@ impl FreshAllocator {
                     .filter(|r| live_registers.contains(r))
                     .collect::<Vec<_>>();
 
+                // SSE registers cannot be pushed, so are moved below the stack pointer
+                let xmm_to_save = live_registers
+                    .iter()
+                    .filter(|r| r.is_xmm())
+                    .collect::<Vec<_>>();
+
                 for reg in to_save.iter() {
                     new_instructions.push(Instruction::push(Operand::preg(Width::_64, **reg)))
                 }
 
+                for reg in xmm_to_save.iter() {
+                    new_instructions.push(adjust_stack(-16));
+                    new_instructions.push(
+                        Instruction::mov(Operand::preg(Width::_128, **reg), stack_top()).unwrap(),
+                    );
+                }
+
                 new_instructions.push(*instr);
 
+                for reg in xmm_to_save.iter().rev() {
+                    new_instructions.push(
+                        Instruction::mov(stack_top(), Operand::preg(Width::_128, **reg)).unwrap(),
+                    );
+                    new_instructions.push(adjust_stack(16));
+                }
+
                 for reg in to_save.iter().rev() {
                     new_instructions.push(Instruction::pop(Operand::preg(Width::_64, **reg)))
                 }
@@ -401,6 +397,51 @@ impl FreshAllocator {
     }
 }
 
+/// Moves the stack pointer by `offset` bytes without affecting flags
+fn adjust_stack<M: MemAlloc>(offset: i32) -> Instruction<M> {
+    Instruction::lea(
+        Operand::mem_base_displ(
+            Width::_64,
+            Register::PhysicalRegister(PhysicalRegister::RSP),
+            offset,
+        ),
+        Operand::preg(Width::_64, PhysicalRegister::RSP),
+    )
+}
+
+/// The 128 bits at the top of the stack
+fn stack_top<M: MemAlloc>() -> Operand<M> {
+    Operand::mem_base(
+        Width::_128,
+        Register::PhysicalRegister(PhysicalRegister::RSP),
+    )
+}
+
+/// Index of a virtual register of either class
+fn virtual_index(register: &Register) -> Option<usize> {
+    match register {
+        Register::VirtualRegister(idx) | Register::VirtualXmmRegister(idx) => Some(*idx),
+        _ => None,
+    }
+}
+
+/// Finds the lowest unused physical register of the requested class
+fn first_free(physical_used: u32, is_xmm: bool) -> usize {
+    let (used, base) = if is_xmm {
+        ((physical_used >> 16) as u16, 16)
+    } else {
+        (physical_used as u16, 0)
+    };
+
+    let first_empty = used.trailing_ones();
+
+    if first_empty >= 16 {
+        panic!("ran out of registers :(");
+    }
+
+    base + usize::try_from(first_empty).unwrap()
+}
+
 fn query_intersections(
     (x_start, x_end): (usize, Option<usize>),
     live_ranges: &HashMap<Register, Vec<(usize, Option<usize>)>>,
@@ -3,11 +3,19 @@ use {
         arch::x86::memory::PhysAddrExt,
         devices::{Bus, pcie::PCIEBus},
     },
-    acpi::{AcpiHandler, AcpiTables, PciConfigRegions, PhysicalMapping},
+    acpi::{
+        AcpiHandler, AcpiTables, PciConfigRegions, PhysicalMapping, PlatformInfo,
+        platform::ProcessorState,
+    },
+    alloc::vec::Vec,
     core::ptr::NonNull,
+    spin::Once,
     x86_64::PhysAddr,
 };
 
+/// Local APIC IDs of the application processors waiting to be started
+static APPLICATION_PROCESSORS: Once<Vec<u32>> = Once::INIT;
+
 pub struct ACPIBus;
 
 impl Bus<PhysAddr> for ACPIBus {
@@ -15,10 +23,32 @@ impl Bus<PhysAddr> for ACPIBus {
         let tables =
             unsafe { AcpiTables::from_rsdp(Handler, probe_data.as_u64() as usize) }.unwrap();
 
+        APPLICATION_PROCESSORS.call_once(|| match tables.platform_info() {
+            Ok(PlatformInfo {
+                processor_info: Some(processor_info),
+                ..
+            }) => processor_info
+                .application_processors
+                .iter()
+                .filter(|processor| matches!(processor.state, ProcessorState::WaitingForSipi))
+                .map(|processor| processor.local_apic_id)
+                .collect(),
+            _ => Vec::new(),
+        });
+
         PCIEBus.probe(PciConfigRegions::new(&tables).unwrap())
     }
 }
 
+/// Local APIC IDs of the application processors described by the MADT that are
+/// waiting to be started
+pub fn application_processors() -> &'static [u32] {
+    APPLICATION_PROCESSORS
+        .get()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+}
+
 #[derive(Clone)]
 struct Handler;
 
@@ -15,6 +15,18 @@ pub fn init() {
     LAPIC.call_once(|| Mutex::new(LocalApic::new()));
 }
 
+/// Enables the local APIC of a core started after [`init`], which is accessed
+/// through the same registers and shares the timer frequency calibrated on the
+/// boot core
+pub fn init_local() {
+    let mut lapic = LAPIC.get().unwrap().lock();
+
+    unsafe {
+        lapic.inner.enable();
+        lapic.inner.disable_timer();
+    }
+}
+
 pub struct LocalApic {
     pub inner: x2apic::lapic::LocalApic,
     pub frequency: u32,
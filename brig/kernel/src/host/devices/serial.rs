@@ -19,6 +19,21 @@ impl UART16550Device {
 
         index
     }
+
+    /// Blocks until a byte is received
+    pub fn receive(&mut self) -> u8 {
+        self.0.receive()
+    }
+
+    pub fn try_receive(&mut self) -> Option<u8> {
+        self.0.try_receive().ok()
+    }
+
+    /// Sends bytes as-is, without the handling of backspace and delete
+    /// performed when writing strings
+    pub fn send_raw(&mut self, bytes: &[u8]) {
+        bytes.iter().for_each(|byte| self.0.send_raw(*byte));
+    }
 }
 
 impl fmt::Write for UART16550Device {
@@ -1,4 +1,7 @@
-use alloc::{string::String, vec::Vec};
+use {
+    alloc::{string::String, vec::Vec},
+    core::ops::Range,
+};
 
 pub mod tar;
 pub mod vfs;
@@ -22,4 +25,8 @@ pub trait Filesystem {
     fn size<S: AsRef<str>>(&mut self, filename: S) -> Result<usize, Error>;
 
     fn read_to_vec<S: AsRef<str>>(&mut self, filename: S) -> Result<Vec<u8>, Error>;
+
+    /// Returns the range of bytes on the underlying block device holding the
+    /// contents of the file, which can be overwritten in place
+    fn device_range<S: AsRef<str>>(&mut self, filename: S) -> Result<Range<usize>, Error>;
 }
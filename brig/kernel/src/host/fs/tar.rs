@@ -4,12 +4,16 @@ use {
         fs::{Error, Filesystem},
     },
     alloc::{borrow::ToOwned, string::String, vec::Vec},
+    core::ops::Range,
     tar_no_std::{ArchiveEntry, TarArchive},
 };
 
 pub struct TarFilesystem<'device, B> {
     _dev: &'device mut B,
     archive: TarArchive,
+    /// Address of the first byte of the archive, which starts at the beginning
+    /// of the device
+    archive_start: usize,
 }
 
 impl<'device, B: BlockDevice> TarFilesystem<'device, B> {
@@ -27,10 +31,25 @@ impl<'device, B: BlockDevice> TarFilesystem<'device, B> {
                 dev.read(chunk, block_index).unwrap();
             });
 
-            TarArchive::new(buf.into()).unwrap()
+            buf.into_boxed_slice()
         };
 
-        Self { _dev: dev, archive }
+        // moving the box does not move the archive contents
+        let archive_start = archive.as_ptr() as usize;
+        let archive = TarArchive::new(archive).unwrap();
+
+        Self {
+            _dev: dev,
+            archive,
+            archive_start,
+        }
+    }
+
+    fn entry<S: AsRef<str>>(&self, filename: S) -> Result<ArchiveEntry<'_>, Error> {
+        self.archive
+            .entries()
+            .find(|e| e.filename().as_str().unwrap() == (filename.as_ref().trim_start_matches('/')))
+            .ok_or(Error::NotFound(filename.as_ref().to_owned()))
     }
 }
 
@@ -40,21 +59,11 @@ impl<'device, B: BlockDevice> Filesystem for TarFilesystem<'device, B> {
     }
 
     fn size<S: AsRef<str>>(&mut self, filename: S) -> Result<usize, Error> {
-        let entry = self
-            .archive
-            .entries()
-            .find(|e| e.filename().as_str().unwrap() == (filename.as_ref().trim_start_matches('/')))
-            .ok_or(Error::NotFound(filename.as_ref().to_owned()))?;
-
-        Ok(entry.size())
+        Ok(self.entry(filename)?.size())
     }
 
     fn read_to_vec<S: AsRef<str>>(&mut self, filename: S) -> Result<Vec<u8>, Error> {
-        let entry = self
-            .archive
-            .entries()
-            .find(|e| e.filename().as_str().unwrap() == (filename.as_ref().trim_start_matches('/')))
-            .ok_or(Error::NotFound(filename.as_ref().to_owned()))?;
+        let entry = self.entry(filename)?;
 
         let mut buffer = alloc::vec![0; entry.size()];
 
@@ -62,6 +71,13 @@ impl<'device, B: BlockDevice> Filesystem for TarFilesystem<'device, B> {
 
         Ok(buffer)
     }
+
+    fn device_range<S: AsRef<str>>(&mut self, filename: S) -> Result<Range<usize>, Error> {
+        let entry = self.entry(filename)?;
+        let start = entry.data().as_ptr() as usize - self.archive_start;
+
+        Ok(start..start + entry.size())
+    }
 }
 
 pub struct TarFile<'fs> {
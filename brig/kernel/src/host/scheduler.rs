@@ -1,17 +1,26 @@
 use {
     crate::{
+        guest::GuestExecutionContext,
         host::{arch::CoreStorage, devices::lapic::LAPIC},
         tasks::{Task, TaskControlBlock, create_idle_task},
     },
     alloc::collections::LinkedList,
+    core::sync::atomic::Ordering,
     log::trace,
-    x86::current::segmentation::wrgsbase,
+    spin::Mutex,
+    x86::current::segmentation::{rdfsbase, rdgsbase, wrfsbase, wrgsbase},
+    x86_64::instructions::interrupts::without_interrupts,
 };
 
 pub const TIMER_FREQUENCY: u32 = 1000;
 
 pub struct Scheduler {
-    run_queue: LinkedList<Task>,
+    /// Tasks placed on this core, locked as any core may start or stop them
+    ///
+    /// The lock is taken by the timer interrupt, so it is only held with
+    /// interrupts disabled and nothing is allocated or freed while holding it,
+    /// as the interrupted task may hold the heap lock.
+    run_queue: Mutex<LinkedList<Task>>,
     idle_task: Task,
 }
 
@@ -20,34 +29,57 @@ impl Scheduler {
         let idle = create_idle_task();
         Self::activate(&idle);
         Self {
-            run_queue: LinkedList::new(),
+            run_queue: Mutex::new(LinkedList::new()),
             idle_task: idle,
         }
     }
 
-    pub fn get_local_mut() -> &'static mut Self {
+    pub fn get_local() -> &'static Self {
         CoreStorage::this_mut().get::<Self>().unwrap()
     }
 
-    pub fn add_to_runqueue(&mut self, task: Task) {
-        self.run_queue.push_back(task);
+    pub fn add_to_runqueue(&self, task: Task) {
+        let mut node = LinkedList::new();
+        node.push_back(task);
+
+        without_interrupts(|| self.run_queue.lock().append(&mut node));
     }
 
-    pub fn remove_from_runqueue(&mut self, task: &Task) {
-        self.run_queue = self
-            .run_queue
-            .clone()
-            .into_iter()
-            .filter(|t| t != task)
-            .collect();
+    pub fn remove_from_runqueue(&self, task: &Task) {
+        // unlinked under the lock, but only dropped once it is released
+        let _removed = without_interrupts(|| {
+            let mut run_queue = self.run_queue.lock();
+            let mut removed = LinkedList::new();
+
+            let mut cursor = run_queue.cursor_front_mut();
+            while let Some(current) = cursor.current() {
+                if &*current == task {
+                    removed.append(&mut cursor.remove_current_as_list().unwrap());
+                } else {
+                    cursor.move_next();
+                }
+            }
+
+            removed
+        });
     }
 
     pub fn activate(t: &Task) {
         // TODO: cannot be called remotely
         // TODO: abstract to arch-specific
+        let fs_base = t.get_tcb().fs_base.load(Ordering::Relaxed);
+
         unsafe {
             wrgsbase(t.get_tcb() as *const TaskControlBlock as u64);
+            wrfsbase(fs_base);
+            core::arch::asm!(
+                "fxrstor64 [{}]",
+                in(reg) t.get_tcb().fpu_state.get(),
+                options(nostack, readonly)
+            );
         }
+
+        GuestExecutionContext::switched_to(fs_base);
     }
 }
 
@@ -87,25 +119,31 @@ pub fn local_run() -> ! {
 }
 
 pub fn schedule() {
-    // TODO: Fix and think about this deadlock -- maybe need to disable local
-    // interrupts when adding/removing from runqueue?
-    let scheduler = Scheduler::get_local_mut();
+    let scheduler = Scheduler::get_local();
+
+    // FS base and SSE state may have been changed by the running task, save them
+    // before switching
+    unsafe {
+        let current = &*(rdgsbase() as *const TaskControlBlock);
+        current.fs_base.store(rdfsbase(), Ordering::Relaxed);
+        core::arch::asm!("fxsave64 [{}]", in(reg) current.fpu_state.get(), options(nostack));
+    }
 
     // TODO: some sort of priority queue based on vruntimes
 
-    if scheduler.run_queue.is_empty() {
-        Scheduler::activate(&scheduler.idle_task);
-    } else {
-        let next = if scheduler.run_queue.len() == 1 {
-            scheduler.run_queue.front().unwrap()
-        } else {
-            let front = scheduler.run_queue.pop_front().unwrap();
+    let next = {
+        let mut run_queue = scheduler.run_queue.lock();
 
-            scheduler.run_queue.push_back(front);
+        // rotate the front task to the back by relinking, rather than popping and
+        // pushing it, which would free and allocate a node
+        if run_queue.len() > 1 {
+            let mut rest = run_queue.split_off(1);
+            rest.append(&mut run_queue);
+            *run_queue = rest;
+        }
 
-            scheduler.run_queue.back().unwrap()
-        };
+        run_queue.back().cloned()
+    };
 
-        Scheduler::activate(next);
-    }
+    Scheduler::activate(next.as_ref().unwrap_or(&scheduler.idle_task));
 }
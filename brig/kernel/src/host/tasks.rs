@@ -8,7 +8,12 @@ use {
         collections::LinkedList,
         sync::{Arc, Weak},
     },
-    core::{alloc::Layout, mem::size_of},
+    core::{
+        alloc::Layout,
+        cell::UnsafeCell,
+        mem::size_of,
+        sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
     spin::{Mutex, Once},
     x86::current::segmentation::rdgsbase,
 };
@@ -22,7 +27,9 @@ static TASK_MANAGER: Once<Mutex<TaskManager>> = Once::INIT;
 
 struct TaskManager {
     tasks: LinkedList<Task>,
-    schedulers: LinkedList<&'static mut Scheduler>,
+    schedulers: LinkedList<&'static Scheduler>,
+    /// Index of the scheduler the next started task is placed on
+    next_scheduler: usize,
 }
 
 #[derive(Clone)]
@@ -39,6 +46,9 @@ impl PartialEq for &Task {
 pub struct InnerTask {
     tcb: TaskControlBlock,
     stack: *mut u8,
+    /// Index of the scheduler whose runqueue holds the task, `usize::MAX` if
+    /// the task is not started
+    scheduler: AtomicUsize,
 }
 
 // todo: verify stack ptr is safe to send + sync
@@ -55,6 +65,28 @@ impl Drop for InnerTask {
 pub struct TaskControlBlock {
     pub context: *mut MachineContext,
     pub parent: Weak<InnerTask>,
+    /// FS base of the task, saved and restored on context switch
+    pub fs_base: AtomicU64,
+    /// x87/SSE state of the task, saved and restored on context switch
+    pub fpu_state: UnsafeCell<FpuState>,
+}
+
+/// `FXSAVE64` image of the x87, MMX and SSE registers
+#[repr(C, align(16))]
+pub struct FpuState([u8; 512]);
+
+impl FpuState {
+    /// Offset of the MXCSR register within the image
+    const MXCSR_OFFSET: usize = 24;
+
+    /// Default x87 control word and MXCSR, matching the state after `FNINIT`
+    /// and reset respectively
+    fn new() -> Self {
+        let mut image = [0; 512];
+        image[..2].copy_from_slice(&0x037fu16.to_le_bytes());
+        image[Self::MXCSR_OFFSET..Self::MXCSR_OFFSET + 4].copy_from_slice(&0x1f80u32.to_le_bytes());
+        Self(image)
+    }
 }
 
 // todo: verify machine context ptr is safe to send + sync
@@ -65,11 +97,11 @@ impl TaskManager {
         Self {
             tasks: LinkedList::new(),
             schedulers: LinkedList::new(),
+            next_scheduler: 0,
         }
     }
 
-    pub fn register_scheduler(&mut self, scheduler: &'static mut Scheduler) {
-        // TODO: Cannot be called remotely
+    pub fn register_scheduler(&mut self, scheduler: &'static Scheduler) {
         self.schedulers.push_back(scheduler);
     }
 
@@ -79,18 +111,28 @@ impl TaskManager {
         task
     }
 
+    /// Places the task on the runqueue of the next scheduler, round-robin, so
+    /// that started tasks are spread across host cores
+    ///
+    /// The runqueue belongs to another core's scheduler, and is locked by
+    /// [`Scheduler::add_to_runqueue`] as that core may be scheduling from it.
     pub fn start_task(&mut self, task: &Task) {
+        let index = self.next_scheduler % self.schedulers.len();
+        self.next_scheduler = index + 1;
+
+        task.inner.scheduler.store(index, Ordering::Relaxed);
         self.schedulers
-            .back_mut()
+            .iter()
+            .nth(index)
             .unwrap()
             .add_to_runqueue(task.clone());
     }
 
     pub fn stop_task(&mut self, task: &Task) {
-        self.schedulers
-            .back_mut()
-            .unwrap()
-            .remove_from_runqueue(task);
+        let index = task.inner.scheduler.swap(usize::MAX, Ordering::Relaxed);
+        if let Some(scheduler) = self.schedulers.iter().nth(index) {
+            scheduler.remove_from_runqueue(task);
+        }
         self.tasks = self
             .tasks
             .clone()
@@ -128,8 +170,11 @@ impl Task {
             tcb: TaskControlBlock {
                 context: context as *mut MachineContext,
                 parent: weak.clone(),
+                fs_base: AtomicU64::new(0),
+                fpu_state: UnsafeCell::new(FpuState::new()),
             },
             stack,
+            scheduler: AtomicUsize::new(usize::MAX),
         });
 
         Self { inner }
@@ -139,6 +184,11 @@ impl Task {
         &self.inner.tcb
     }
 
+    /// Sets the FS base to be loaded when this task is next scheduled
+    pub fn set_fs_base(&self, fs_base: u64) {
+        self.inner.tcb.fs_base.store(fs_base, Ordering::Relaxed);
+    }
+
     pub fn start(&self) {
         TASK_MANAGER.get().unwrap().lock().start_task(self);
     }
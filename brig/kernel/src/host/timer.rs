@@ -1,6 +1,10 @@
 use {
     crate::{
-        host::{arch::x86::irq::assign_irq, objects::tickable::Tickable},
+        guest::MAX_GUEST_CORES,
+        host::{
+            arch::{CoreStorage, x86::irq::assign_irq},
+            objects::tickable::Tickable,
+        },
         println,
         scheduler::{self, TIMER_FREQUENCY},
     },
@@ -12,8 +16,9 @@ use {
         rate::{Fraction, Hertz, Rate},
     },
     proc_macro_lib::irq_handler,
-    spin::{Lazy, Mutex},
+    spin::{Lazy, Mutex, Once},
     x86::time::rdtscp,
+    x86_64::instructions::interrupts::without_interrupts,
 };
 
 const ENABLE_MEASUREMENTS: bool = true;
@@ -45,13 +50,17 @@ impl GlobalClock {
 
 #[irq_handler(with_code = false)]
 fn timer_interrupt() {
-    // Our hacked in timer frequency is 1000 Hz, a period of 1ms -> so, that's
-    // 1,000,000 nanoseconds in a 1ms period
-    GLOBAL_CLOCK.increment(Hertz::new(TIMER_FREQUENCY).to_duration().unwrap());
+    // every core takes timer interrupts to schedule, but only the boot core
+    // keeps time
+    if CoreStorage::id() == 0 {
+        // Our hacked in timer frequency is 1000 Hz, a period of 1ms -> so,
+        // that's 1,000,000 nanoseconds in a 1ms period
+        GLOBAL_CLOCK.increment(Hertz::new(TIMER_FREQUENCY).to_duration().unwrap());
 
-    let current_time = GLOBAL_CLOCK.now(); // TODO: compute this period from timer interrupt frequency
+        let current_time = GLOBAL_CLOCK.now(); // TODO: compute this period from timer interrupt frequency
 
-    handle_tickables(current_time);
+        handle_tickables(current_time);
+    }
 
     scheduler::schedule();
 
@@ -101,8 +110,11 @@ impl Measurement {
 }
 
 fn handle_tickables(current_time: Nanoseconds<u64>) {
-    TICKABLES
-        .lock()
+    tick_due(&mut TICKABLES.lock(), current_time);
+}
+
+fn tick_due(tickables: &mut [TickableState], current_time: Nanoseconds<u64>) {
+    tickables
         .iter_mut()
         .filter(|s| current_time >= s.time_at_last_tick + s.interval)
         .for_each(|s| {
@@ -124,3 +136,152 @@ struct TickableState {
     interval: Nanoseconds<u64>,
     time_at_last_tick: Nanoseconds<u64>,
 }
+
+static VIRTUAL_CLOCK: Once<VirtualClock> = Once::new();
+
+/// Clock advanced by the number of executed guest instructions rather than host
+/// time, so that guest timing does not depend on how fast the host runs
+///
+/// Every instruction takes `1 << shift` nanoseconds of virtual time. Each guest
+/// core keeps its own time from the instructions it executed, and tickables
+/// are ticked as the earliest time of any core executing instructions passes
+/// their deadline, so that a core running ahead cannot hurry the others. Guest
+/// cores are responsible for advancing their time, and for returning to their
+/// block execution loop by the time the next tickable is due.
+pub struct VirtualClock {
+    shift: u32,
+    /// Time tickables have been ticked up to
+    nanoseconds_since_boot: AtomicU64,
+    /// Time of each core, `u64::MAX` while it is not executing instructions
+    core_nanoseconds: [AtomicU64; MAX_GUEST_CORES],
+    tickables: Mutex<Vec<TickableState>>,
+}
+
+impl VirtualClock {
+    /// Enables the virtual clock with the supplied shift, which must match any
+    /// previous call
+    pub fn enable(shift: u32) -> &'static Self {
+        let clock = VIRTUAL_CLOCK.call_once(|| {
+            log::info!("guest time derived from instruction count, shift {shift}");
+
+            Self {
+                shift,
+                nanoseconds_since_boot: AtomicU64::new(0),
+                core_nanoseconds: [const { AtomicU64::new(u64::MAX) }; MAX_GUEST_CORES],
+                tickables: Mutex::new(Vec::new()),
+            }
+        });
+
+        assert_eq!(
+            clock.shift, shift,
+            "virtual clock already enabled with a different shift"
+        );
+
+        clock
+    }
+
+    /// The virtual clock, if it has been enabled
+    pub fn get() -> Option<&'static Self> {
+        VIRTUAL_CLOCK.get()
+    }
+
+    pub fn now(&self) -> Nanoseconds<u64> {
+        Nanoseconds::new(self.nanoseconds_since_boot.load(Ordering::Relaxed))
+    }
+
+    pub fn register_tickable(&self, interval: Nanoseconds<u64>, tickable: Arc<dyn Tickable>) {
+        without_interrupts(|| {
+            self.tickables.lock().push(TickableState {
+                tickable,
+                interval,
+                time_at_last_tick: Nanoseconds::new(0),
+            })
+        });
+    }
+
+    /// Starts counting the time of `core` from the current time, as it starts
+    /// or resumes executing instructions
+    pub fn resume(&self, core: usize) {
+        self.core_nanoseconds[core].store(self.now().0, Ordering::Relaxed);
+    }
+
+    /// Stops counting the time of `core` while it does not execute
+    /// instructions, so that the other cores are not held back by it
+    pub fn suspend(&self, core: usize) {
+        self.core_nanoseconds[core].store(u64::MAX, Ordering::Relaxed);
+        self.synchronise();
+    }
+
+    /// Number of guest instructions `core` can execute before the next
+    /// tickable is due
+    pub fn instructions_until_deadline(&self, core: usize) -> u64 {
+        let now = match self.core_nanoseconds[core].load(Ordering::Relaxed) {
+            u64::MAX => self.now().0,
+            now => now,
+        };
+
+        self.next_deadline()
+            .map(|deadline| deadline.saturating_sub(now).div_ceil(1 << self.shift))
+            .unwrap_or(u64::MAX)
+    }
+
+    /// Advances the time of `core` by the supplied number of executed guest
+    /// instructions, ticking any tickables that are now due
+    pub fn advance(&self, core: usize, instructions: u64) {
+        self.core_nanoseconds[core].fetch_add(instructions << self.shift, Ordering::Relaxed);
+        self.synchronise();
+    }
+
+    /// Advances the time of every core to the next deadline if none are
+    /// executing instructions, returning whether there was one
+    pub fn skip_to_deadline(&self) -> bool {
+        let running = self
+            .core_nanoseconds
+            .iter()
+            .any(|core| core.load(Ordering::Relaxed) != u64::MAX);
+
+        match self.next_deadline() {
+            Some(deadline) if !running => {
+                self.tick_to(deadline);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Time the next tickable is due, if any are registered
+    fn next_deadline(&self) -> Option<u64> {
+        without_interrupts(|| {
+            self.tickables
+                .lock()
+                .iter()
+                .map(|s| (s.time_at_last_tick + s.interval).0)
+                .min()
+        })
+    }
+
+    /// Ticks tickables up to the earliest time of any core executing
+    /// instructions
+    fn synchronise(&self) {
+        let earliest = self
+            .core_nanoseconds
+            .iter()
+            .map(|core| core.load(Ordering::Relaxed))
+            .min()
+            .unwrap();
+
+        if earliest != u64::MAX {
+            self.tick_to(earliest);
+        }
+    }
+
+    fn tick_to(&self, time: u64) {
+        if self
+            .nanoseconds_since_boot
+            .fetch_max(time, Ordering::Relaxed)
+            < time
+        {
+            without_interrupts(|| tick_due(&mut self.tickables.lock(), Nanoseconds::new(time)));
+        }
+    }
+}
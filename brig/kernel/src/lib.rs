@@ -2,6 +2,7 @@
 #![feature(abi_x86_interrupt)] // needed for interrupts
 #![feature(allocator_api)] // needed for pci config regions and alignedallocator
 #![feature(btree_cursors)]
+#![feature(linked_list_cursors)] // scheduler runqueue removal
 #![feature(int_roundings)]
 #![feature(new_zeroed_alloc)] // bump allocator
 #![feature(btreemap_alloc)]
@@ -21,7 +22,7 @@ use {
                     VirtualMemoryArea,
                 },
             },
-            dbt::models,
+            dbt::{models, persist, replay},
             devices::manager::SharedDeviceManager,
             fs::{Filesystem, tar::TarFilesystem},
             memory::bytes,
@@ -40,6 +41,9 @@ mod logger;
 mod tests;
 mod util;
 
+/// Alias of the block device containing the guest data tarfile
+const GUEST_DATA_DISK: &str = "disk00:03.0";
+
 pub static BOOTLOADER_CONFIG: BootloaderConfig = {
     let mut config = BootloaderConfig::new_default();
     config.mappings.physical_memory = Some(Mapping::FixedAddress(PHYSICAL_MEMORY_OFFSET.as_u64()));
@@ -70,8 +74,9 @@ pub fn start(boot_info: &'static mut BootInfo) -> ! {
     timer::init();
     tasks::init();
 
-    // occurs per core
+    // occurs per core, the other cores register theirs in `start_secondary`
     tasks::register_scheduler();
+    host::arch::start_secondary_cores(start_secondary);
 
     {
         let continue_start_task = tasks::create_task(continue_start);
@@ -81,19 +86,27 @@ pub fn start(boot_info: &'static mut BootInfo) -> ! {
     scheduler::local_run();
 }
 
+/// Entry point of every host core other than the boot core, once it has been
+/// initialized
+fn start_secondary() -> ! {
+    tasks::register_scheduler();
+    scheduler::local_run();
+}
+
 fn continue_start() {
     // let serial_in_task = tasks::create_task(serial_in);
     // serial_in_task.start();
 
     let device_manager = SharedDeviceManager::get();
     let device = device_manager
-        .get_device_by_alias("disk00:03.0")
+        .get_device_by_alias(GUEST_DATA_DISK)
         .expect("disk not found");
 
     let mut dev = device.lock();
     let mut fs = TarFilesystem::mount(dev.as_block());
 
     models::load_all(&mut fs);
+    persist::init(&mut fs, GUEST_DATA_DISK);
 
     let test_config = {
         let file = fs
@@ -102,6 +115,14 @@ fn continue_start() {
         postcard::from_bytes(&file).unwrap()
     };
 
+    let replay_config = {
+        let file = fs
+            .read_to_vec("replay_config.postcard")
+            .expect("failed to load replay configuration file");
+        postcard::from_bytes(&file).unwrap()
+    };
+    replay::init(replay_config);
+
     guest::start(&mut fs, test_config);
 }
 
@@ -118,6 +139,7 @@ fn serial_in() {
                 Ok(s) => match s {
                     "\u{3}" => {
                         log::error!("received Ctrl-C, terminating");
+                        persist::flush();
                         qemu_exit();
                     }
                     _ => log::debug!("{:?}", s),
@@ -29,3 +29,13 @@ pub enum TestConfig {
     // Run all tests
     All,
 }
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ReplayConfig {
+    // Execute normally
+    None,
+    // Log asynchronous events to the hyperport
+    Record,
+    // Inject the asynchronous events of the supplied log
+    Replay(String),
+}